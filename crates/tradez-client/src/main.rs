@@ -1,9 +1,19 @@
+use alloy_primitives::hex::FromHex;
 use clap::{Parser, Subcommand};
 use jsonrpsee::http_client::HttpClientBuilder;
 use rlp::Encodable;
+use tradez_kernel::account::Account;
+use tradez_octez::smart_rollup_node::SmartRollupClient;
 use tradez_types::{
+    address::Address,
     api::TradezRpcClient,
-    position::{APIOrder, CancelOrder, Faucet},
+    commitment::{AccountProof, STATE_COMMITMENT_STR_PATH, StateCommitment},
+    currencies::Currencies,
+    delegate::{DelegateGrant, DelegateScope, RevokeDelegate},
+    oracle::OracleUpdate,
+    position::{APIOrder, CancelOrder, Faucet, Side, Transfer},
+    stops::{APIOcoOrder, APIStopOrder},
+    units::{Price, Qty},
 };
 
 pub mod wallet;
@@ -66,6 +76,45 @@ enum WalletCommand {
         /// Price of the position
         #[arg(short, long)]
         price: u64,
+        /// Visible size of an iceberg order, the whole size is shown when omitted
+        #[arg(short, long)]
+        display: Option<u64>,
+    },
+    /// Place a stop order, trailing when `trail` is set
+    StopOrder {
+        /// Side of the order placed on trigger (0 = buy, 1 = sell)
+        #[arg(long)]
+        side: u8,
+        /// Size of the order
+        #[arg(long)]
+        size: u64,
+        /// Price at which the stop triggers
+        #[arg(long)]
+        trigger_price: u64,
+        /// Distance the trigger keeps from the best price, fixed stop when omitted
+        #[arg(long)]
+        trail: Option<u64>,
+        /// How much worse than the trigger the order is priced on trigger
+        #[arg(long, default_value_t = 0u64)]
+        slippage: u64,
+    },
+    /// Place a take-profit limit order and a stop-loss, filling one cancels the other
+    BracketOrder {
+        /// Side of both legs (0 = buy, 1 = sell)
+        #[arg(long)]
+        side: u8,
+        /// Size of both legs
+        #[arg(long)]
+        size: u64,
+        /// Price of the take-profit limit order
+        #[arg(long)]
+        price: u64,
+        /// Price at which the stop-loss triggers
+        #[arg(long)]
+        trigger_price: u64,
+        /// How much worse than the trigger the stop-loss is priced on trigger
+        #[arg(long, default_value_t = 0u64)]
+        slippage: u64,
     },
     /// Close an existing position
     ClosePosition {
@@ -83,6 +132,57 @@ enum WalletCommand {
         #[arg(short, long, default_value_t = 0u8)]
         currency: u8,
     },
+    /// Move available balance to another account
+    Transfer {
+        /// Address of the recipient
+        /// Hexadecimal string representation of the address
+        #[arg(short, long)]
+        to: String,
+
+        /// Amount to transfer
+        #[arg(short, long)]
+        amount: u64,
+
+        /// Currency to transfer (0 = USDC, 1 = XTZ)
+        #[arg(short, long, default_value_t = 0u8)]
+        currency: u8,
+
+        /// Above the last nonce of the wallet, the current time in milliseconds when omitted
+        #[arg(short, long)]
+        nonce: Option<u64>,
+    },
+    /// Let another key place and cancel orders for this wallet, it can never move funds out
+    AddDelegate {
+        /// Address of the delegate key
+        /// Hexadecimal string representation of the address
+        #[arg(long)]
+        key: String,
+        /// Level from which the key can't sign anymore
+        #[arg(long)]
+        expiry_level: u32,
+        /// Largest value of an order the key may place (microUSDC), no limit when omitted
+        #[arg(long)]
+        max_notional: Option<u64>,
+        /// Only let the key cancel orders
+        #[arg(long, default_value_t = false)]
+        cancel_only: bool,
+    },
+    /// Revoke a delegate key for good
+    RevokeDelegate {
+        /// Address of the delegate key
+        /// Hexadecimal string representation of the address
+        #[arg(long)]
+        key: String,
+    },
+    /// Send the XTZ/USDC price as one of the whitelisted oracles
+    OracleUpdate {
+        /// Price observed (microUSDC)
+        #[arg(long)]
+        price: u64,
+        /// L1 level the price was observed at
+        #[arg(long)]
+        level: u32,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -103,6 +203,41 @@ enum GetInfosCommand {
         #[arg(short, long)]
         address: String,
     },
+    /// Get the margin of an address: assets and debt valued at the mark price
+    Margin {
+        /// Address to get the margin for
+        /// Hexadecimal string representation of the address
+        #[arg(short, long)]
+        address: String,
+    },
+    /// Get the perpetual position of an address
+    Position {
+        /// Address to get the position for
+        /// Hexadecimal string representation of the address
+        #[arg(short, long)]
+        address: String,
+    },
+    /// Get the index price and the last funding rate of the perpetual
+    Funding {},
+    /// Get the index price: the median of the fresh oracle quotes
+    IndexPrice {},
+    /// Get the delegate keys of an address
+    Delegates {
+        /// Address to get the delegate keys for
+        /// Hexadecimal string representation of the address
+        #[arg(short, long)]
+        address: String,
+    },
+    /// Get the fills of an address, oldest first
+    Fills {
+        /// Address to get the fills for
+        /// Hexadecimal string representation of the address
+        #[arg(short, long)]
+        address: String,
+        /// Sequence number of the first fill, the next_cursor of the previous page
+        #[arg(short, long)]
+        cursor: Option<u64>,
+    },
     /// Get orders of an address
     Orders {
         /// Address to get orders for
@@ -112,6 +247,54 @@ enum GetInfosCommand {
     },
     /// Get the trade history
     History {},
+    /// Get the tick size, lot size and minimums of the market
+    MarketConfig {},
+    /// Audit balances against the minted supply and the order book
+    Audit {},
+    /// Get the injection progress of the sequencer and whether it diverged from the rollup
+    Status {},
+    /// Verify the balances of an address against the state root committed on the rollup
+    VerifyBalance {
+        /// Address to verify
+        /// Hexadecimal string representation of the address
+        #[arg(short, long)]
+        address: String,
+        /// URL of the smart rollup node
+        #[arg(short, long, default_value_t = String::from("http://localhost:8732"))]
+        rollup_url: String,
+    },
+}
+
+/// Nonce above the ones the wallet used before: the current time in milliseconds.
+/// Account a proof of the sequencer shows for `address` under the root committed on the
+/// rollup, or why the proof doesn't.
+fn verified_account(
+    proof: &AccountProof,
+    address: &Address,
+    commitment: &StateCommitment,
+) -> Result<Account, &'static str> {
+    if proof.level != commitment.level || proof.root != commitment.root {
+        return Err("not against the committed root");
+    }
+    // The first leaf is the order book
+    if proof.proof.index == 0 {
+        return Err("not an account leaf");
+    }
+    if !proof.verify() {
+        return Err("invalid proof");
+    }
+    let account: Account = rlp::decode(&proof.account).map_err(|_| "undecodable account")?;
+    if account.address != *address {
+        return Err("proof of another account");
+    }
+    Ok(account)
+}
+
+fn time_nonce() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
 #[tokio::main]
@@ -144,23 +327,91 @@ async fn main() {
                     println!("Fetching balance for wallet: {}", wallet_cmd.name);
                     // Implement balance fetching logic here
                 }
-                WalletCommand::OpenPosition { side, size, price } => {
+                WalletCommand::OpenPosition {
+                    side,
+                    size,
+                    price,
+                    display,
+                } => {
+                    let side = if side == 0 { Side::Bid } else { Side::Ask };
+                    let market_config = TradezRpcClient::get_market_config(&client).await.unwrap();
+                    let size = market_config.round_qty(Qty(size));
+                    let price = market_config.round_price(Price(price), side);
+                    let display = display
+                        .map(|display| market_config.round_qty(Qty(display)))
+                        .unwrap_or(Qty::ZERO);
+                    println!(
+                        "Rounded to market rules: size={} price={} display={}",
+                        size, price, display
+                    );
                     let api_order = APIOrder {
-                        side: if side == 0 {
-                            tradez_types::position::Side::Bid
-                        } else {
-                            tradez_types::position::Side::Ask
-                        },
-                        // TODO: Fix
-                        nonce: 0,
+                        side,
+                        nonce: time_nonce(),
                         size,
                         price,
+                        display,
                     };
                     let signature = wallet.sign_message(&api_order.rlp_bytes()).unwrap();
                     let _result = TradezRpcClient::send_order(&client, api_order, signature)
                         .await
                         .unwrap();
                 }
+                WalletCommand::StopOrder {
+                    side,
+                    size,
+                    trigger_price,
+                    trail,
+                    slippage,
+                } => {
+                    let side = if side == 0 { Side::Bid } else { Side::Ask };
+                    let market_config = TradezRpcClient::get_market_config(&client).await.unwrap();
+                    let stop_order = APIStopOrder {
+                        side,
+                        size: market_config.round_qty(Qty(size)),
+                        trigger_price: market_config.round_price(Price(trigger_price), side),
+                        trail: market_config.round_price(Price(trail.unwrap_or(0)), side),
+                        slippage: market_config.round_price(Price(slippage), side),
+                        nonce: time_nonce(),
+                    };
+                    println!("Rounded to market rules: {:?}", stop_order);
+                    let signature = wallet.sign_message(&stop_order.rlp_bytes()).unwrap();
+                    let _result = TradezRpcClient::send_stop_order(&client, stop_order, signature)
+                        .await
+                        .unwrap();
+                }
+                WalletCommand::BracketOrder {
+                    side,
+                    size,
+                    price,
+                    trigger_price,
+                    slippage,
+                } => {
+                    let side = if side == 0 { Side::Bid } else { Side::Ask };
+                    let market_config = TradezRpcClient::get_market_config(&client).await.unwrap();
+                    let size = market_config.round_qty(Qty(size));
+                    let oco_order = APIOcoOrder {
+                        limit: APIOrder {
+                            side,
+                            size,
+                            price: market_config.round_price(Price(price), side),
+                            nonce: time_nonce(),
+                            display: Qty::ZERO,
+                        },
+                        stop: APIStopOrder {
+                            side,
+                            size,
+                            trigger_price: market_config.round_price(Price(trigger_price), side),
+                            trail: Price(0),
+                            slippage: market_config.round_price(Price(slippage), side),
+                            nonce: 0,
+                        },
+                    };
+                    println!("Rounded to market rules: {:?}", oco_order);
+                    let signature = wallet.sign_message(&oco_order.rlp_bytes()).unwrap();
+                    let _result = TradezRpcClient::send_oco_order(&client, oco_order, signature)
+                        .await
+                        .unwrap();
+                }
                 WalletCommand::ClosePosition { position_id } => {
                     println!(
                         "Closing position with ID: {} for wallet: {}",
@@ -195,6 +446,71 @@ async fn main() {
                         .await
                         .unwrap();
                 }
+                WalletCommand::Transfer {
+                    to,
+                    amount,
+                    currency,
+                    nonce,
+                } => {
+                    let nonce = nonce.unwrap_or_else(time_nonce);
+                    let transfer = Transfer {
+                        to: Address::from_hex(&to).unwrap(),
+                        currency: match currency {
+                            1 => Currencies::XTZ,
+                            _ => Currencies::USDC,
+                        },
+                        amount,
+                        nonce,
+                    };
+                    println!("Sending transfer: {:?}", transfer);
+                    let signature = wallet.sign_message(&transfer.rlp_bytes()).unwrap();
+                    let _result = TradezRpcClient::transfer(&client, transfer, signature)
+                        .await
+                        .unwrap();
+                }
+                WalletCommand::AddDelegate {
+                    key,
+                    expiry_level,
+                    max_notional,
+                    cancel_only,
+                } => {
+                    let grant = DelegateGrant {
+                        key: Address::from_hex(&key).unwrap(),
+                        scope: if cancel_only {
+                            DelegateScope::Cancel
+                        } else {
+                            DelegateScope::Trade
+                        },
+                        expiry_level,
+                        max_notional: max_notional.unwrap_or(0),
+                    };
+                    println!("Granting delegate: {:?}", grant);
+                    let signature = wallet.sign_message(&grant.rlp_bytes()).unwrap();
+                    let _result = TradezRpcClient::add_delegate(&client, grant, signature)
+                        .await
+                        .unwrap();
+                }
+                WalletCommand::RevokeDelegate { key } => {
+                    let revoke = RevokeDelegate {
+                        key: Address::from_hex(&key).unwrap(),
+                    };
+                    println!("Revoking delegate: {:?}", revoke.key);
+                    let signature = wallet.sign_message(&revoke.rlp_bytes()).unwrap();
+                    let _result = TradezRpcClient::revoke_delegate(&client, revoke, signature)
+                        .await
+                        .unwrap();
+                }
+                WalletCommand::OracleUpdate { price, level } => {
+                    let update = OracleUpdate {
+                        price: Price(price),
+                        level,
+                    };
+                    println!("Sending oracle update: {:?}", update);
+                    let signature = wallet.sign_message(&update.rlp_bytes()).unwrap();
+                    let _result = TradezRpcClient::send_oracle_update(&client, update, signature)
+                        .await
+                        .unwrap();
+                }
             }
         }
         AppSubcommand::Get(get_cmd) => match get_cmd.command {
@@ -211,6 +527,44 @@ async fn main() {
                     .unwrap();
                 println!("Balances: {:?}", balances);
             }
+            GetInfosCommand::Margin { address } => {
+                println!("Fetching margin for address: {}", address);
+                let margin = TradezRpcClient::get_margin(&client, address).await.unwrap();
+                println!("Margin: {:?} (equity: {})", margin, margin.equity());
+            }
+            GetInfosCommand::Position { address } => {
+                println!("Fetching position for address: {}", address);
+                let position = TradezRpcClient::get_position(&client, address)
+                    .await
+                    .unwrap();
+                println!("Position: {:?}", position);
+            }
+            GetInfosCommand::Funding {} => {
+                println!("Fetching funding...");
+                let status = TradezRpcClient::get_perp_status(&client).await.unwrap();
+                println!("Funding: {:?}", status);
+            }
+            GetInfosCommand::IndexPrice {} => {
+                println!("Fetching index price...");
+                match TradezRpcClient::get_index_price(&client).await.unwrap() {
+                    Some(price) => println!("Index price: {}", price),
+                    None => println!("No index price: too few fresh oracle quotes"),
+                }
+            }
+            GetInfosCommand::Delegates { address } => {
+                println!("Fetching delegates for address: {}", address);
+                let delegates = TradezRpcClient::get_delegates(&client, address)
+                    .await
+                    .unwrap();
+                println!("Delegates: {:?}", delegates);
+            }
+            GetInfosCommand::Fills { address, cursor } => {
+                println!("Fetching fills for address: {}", address);
+                let fills = TradezRpcClient::get_fills(&client, address, cursor)
+                    .await
+                    .unwrap();
+                println!("Fills: {:?}", fills);
+            }
             GetInfosCommand::Orders { address } => {
                 println!("Fetching orders for address: {}", address);
                 let orders = TradezRpcClient::get_orders(&client, address).await.unwrap();
@@ -221,6 +575,107 @@ async fn main() {
                 let history = TradezRpcClient::get_history(&client).await.unwrap();
                 println!("History: {:?}", history);
             }
+            GetInfosCommand::MarketConfig {} => {
+                println!("Fetching market config...");
+                let config = TradezRpcClient::get_market_config(&client).await.unwrap();
+                println!("Market config: {:?}", config);
+            }
+            GetInfosCommand::Audit {} => {
+                println!("Auditing balances...");
+                let report = TradezRpcClient::get_audit(&client).await.unwrap();
+                println!("Audit: {:#?}", report);
+            }
+            GetInfosCommand::Status {} => {
+                println!("Fetching sequencer status...");
+                let status = TradezRpcClient::get_status(&client).await.unwrap();
+                println!("Status: {:#?}", status);
+            }
+            GetInfosCommand::VerifyBalance {
+                address,
+                rollup_url,
+            } => {
+                let rollup_client = SmartRollupClient::new(&rollup_url);
+                let Some(commitment) = rollup_client
+                    .get_value(STATE_COMMITMENT_STR_PATH)
+                    .await
+                    .unwrap()
+                else {
+                    println!("No state committed on the rollup yet");
+                    return;
+                };
+                let commitment: StateCommitment = rlp::decode(&commitment).unwrap();
+                println!(
+                    "Rollup state root at level {}: {}",
+                    commitment.level, commitment.root.0
+                );
+                let expected = Address::from_hex(&address).unwrap();
+                let proof =
+                    TradezRpcClient::get_account_proof(&client, address, Some(commitment.level))
+                        .await
+                        .unwrap();
+                let account = match verified_account(&proof, &expected, &commitment) {
+                    Ok(account) => account,
+                    Err(reason) => {
+                        println!("Proof REJECTED for {:?}: {}", expected, reason);
+                        return;
+                    }
+                };
+                println!("Proof verified for {:?}", account.address);
+                for currency in [Currencies::USDC, Currencies::XTZ] {
+                    println!(
+                        "{:?}: available={} locked={}",
+                        currency,
+                        account.available(currency),
+                        account.locked(currency)
+                    );
+                }
+            }
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tradez_types::commitment::{Hash, MerkleProof, merkle_root};
+
+    use super::*;
+
+    #[test]
+    fn proofs_of_the_asked_account_only() {
+        let (a, b) = (Address::from([1; 20]), Address::from([2; 20]));
+        let orderbook = vec![0xc0];
+        let accounts = [Account::new(a).rlp_bytes(), Account::new(b).rlp_bytes()];
+        let leaves = [
+            Hash::leaf(&orderbook),
+            Hash::leaf(&accounts[0]),
+            Hash::leaf(&accounts[1]),
+        ];
+        let commitment = StateCommitment {
+            level: 7,
+            root: merkle_root(&leaves),
+        };
+        let proof = |index: usize, data: &[u8]| AccountProof {
+            level: 7,
+            root: commitment.root,
+            account: data.to_vec(),
+            proof: MerkleProof::new(&leaves, index).unwrap(),
+        };
+
+        assert!(verified_account(&proof(1, &accounts[0]), &a, &commitment).is_ok());
+        assert_eq!(
+            verified_account(&proof(2, &accounts[1]), &a, &commitment).err(),
+            Some("proof of another account")
+        );
+        assert_eq!(
+            verified_account(&proof(0, &orderbook), &a, &commitment).err(),
+            Some("not an account leaf")
+        );
+        let mut stale = proof(1, &accounts[0]);
+        stale.level = 6;
+        assert!(verified_account(&stale, &a, &commitment).is_err());
+        assert_eq!(
+            verified_account(&proof(1, &accounts[1]), &a, &commitment).err(),
+            Some("invalid proof")
+        );
+    }
+}
@@ -2,36 +2,84 @@ use std::collections::{BTreeSet, HashMap};
 
 use rlp::{Decodable, Encodable};
 use tezos_smart_rollup::host::{Runtime, RuntimeError};
-use tezos_smart_rollup_host::path::{RefPath, concat};
-use tradez_types::{address::Address, currencies::Currencies, error::TradezError};
+use tezos_smart_rollup_host::{
+    path::{OwnedPath, RefPath, concat},
+    runtime::ValueType,
+};
+use tradez_types::{
+    address::Address, currencies::Currencies, error::TradezError, margin::MarginState,
+    perpetual::PerpPosition, units::Price,
+};
 
 #[derive(Debug, Clone)]
 pub struct Account {
     pub address: Address,
     pub nonce: u64,
+    /// Available balances, free to be reserved or withdrawn.
     pub balances: HashMap<Currencies, u64>,
+    /// Balances reserved by the open orders of the account.
+    pub locked: HashMap<Currencies, u64>,
+    /// Borrowed amounts, interest included.
+    pub debts: HashMap<Currencies, u64>,
+    /// Level up to which interest has been charged on the debts.
+    pub debt_level: u32,
+    /// Position in the market when it is a perpetual.
+    pub position: PerpPosition,
+    /// Under liquidation since it fell below the maintenance margin, until it is closed out
+    /// or meets it again. The liquidation fee is charged when it starts only.
+    pub liquidating: bool,
     // TODO: Optimize, currently it's stored at two places
     pub orders: BTreeSet<u64>,
 }
 
+// Written in a fixed currency order: the same account must always encode to the same bytes,
+// they are hashed into the state commitment.
+fn append_balances(s: &mut rlp::RlpStream, balances: &HashMap<Currencies, u64>) {
+    s.begin_list(balances.len());
+    for currency in [Currencies::USDC, Currencies::XTZ] {
+        let Some(balance) = balances.get(&currency) else {
+            continue;
+        };
+        s.begin_list(2);
+        match currency {
+            Currencies::USDC => s.append(&0u8),
+            Currencies::XTZ => s.append(&1u8),
+        };
+        s.append(balance);
+    }
+}
+
+fn decode_balances(balances_rlp: rlp::Rlp) -> Result<HashMap<Currencies, u64>, rlp::DecoderError> {
+    let mut balances = HashMap::new();
+    for i in 0..balances_rlp.item_count()? {
+        let entry_rlp = balances_rlp.at(i)?;
+        let currency_value: u8 = entry_rlp.val_at(0)?;
+        let balance: u64 = entry_rlp.val_at(1)?;
+        let currency = match currency_value {
+            0 => Currencies::USDC,
+            1 => Currencies::XTZ,
+            _ => return Err(rlp::DecoderError::Custom("Invalid currency value")),
+        };
+        balances.insert(currency, balance);
+    }
+    Ok(balances)
+}
+
 impl Encodable for Account {
     fn rlp_append(&self, s: &mut rlp::RlpStream) {
-        s.begin_list(4);
+        s.begin_list(9);
         s.append(&self.address);
         s.append(&self.nonce);
-        s.begin_list(self.balances.len());
-        for (currency, balance) in &self.balances {
-            s.begin_list(2);
-            match currency {
-                Currencies::USDC => s.append(&0u8),
-                Currencies::XTZ => s.append(&1u8),
-            };
-            s.append(balance);
-        }
+        append_balances(s, &self.balances);
         s.begin_list(self.orders.len());
         for order_id in &self.orders {
             s.append(order_id);
         }
+        append_balances(s, &self.locked);
+        append_balances(s, &self.debts);
+        s.append(&self.debt_level);
+        s.append(&self.position);
+        s.append(&self.liquidating);
     }
 }
 
@@ -39,35 +87,82 @@ impl Decodable for Account {
     fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
         let address: Address = rlp.val_at(0)?;
         let nonce: u64 = rlp.val_at(1)?;
-        let balances_rlp = rlp.at(2)?;
-        let mut balances = HashMap::new();
-        for i in 0..balances_rlp.item_count()? {
-            let entry_rlp = balances_rlp.at(i)?;
-            let currency_value: u8 = entry_rlp.val_at(0)?;
-            let balance: u64 = entry_rlp.val_at(1)?;
-            let currency = match currency_value {
-                0 => Currencies::USDC,
-                1 => Currencies::XTZ,
-                _ => return Err(rlp::DecoderError::Custom("Invalid currency value")),
-            };
-            balances.insert(currency, balance);
-        }
+        let balances = decode_balances(rlp.at(2)?)?;
         let orders_rlp = rlp.at(3)?;
         let mut orders = BTreeSet::new();
         for i in 0..orders_rlp.item_count()? {
             let order_id: u64 = orders_rlp.at(i)?.as_val()?;
             orders.insert(order_id);
         }
+        // Accounts saved before locked balances, debts, positions then liquidations were
+        // tracked have fewer items
+        let item_count = rlp.item_count()?;
+        let locked = match item_count {
+            4 => HashMap::new(),
+            _ => decode_balances(rlp.at(4)?)?,
+        };
+        let (debts, debt_level) = match item_count {
+            4 | 5 => (HashMap::new(), 0),
+            _ => (decode_balances(rlp.at(5)?)?, rlp.val_at(6)?),
+        };
+        let position = match item_count {
+            4..=7 => PerpPosition::default(),
+            _ => rlp.val_at(7)?,
+        };
+        let liquidating = match item_count {
+            4..=8 => false,
+            _ => rlp.val_at(8)?,
+        };
         Ok(Account {
             address,
             nonce,
             balances,
+            locked,
+            debts,
+            debt_level,
+            position,
+            liquidating,
             orders,
         })
     }
 }
 
 pub const ACCOUNT_KEY_PREFIX: RefPath = RefPath::assert_from(b"/accounts");
+/// Addresses of every saved account, in creation order. The durable storage can't list
+/// the keys under `/accounts`, the audit and the state commitment walk the accounts through
+/// it. It is kept in pages, so that a new account writes a single one.
+pub const ACCOUNT_INDEX_PATH: RefPath = RefPath::assert_from(b"/accounts_index");
+/// Number of indexed accounts.
+pub const ACCOUNT_COUNT_STR_PATH: &str = "/accounts_index/count";
+const ACCOUNT_COUNT_PATH: RefPath = RefPath::assert_from(b"/accounts_index/count");
+/// Addresses per page of the index.
+pub const ACCOUNT_INDEX_PAGE_SIZE: u64 = 256;
+
+fn index_path(suffix: &str) -> Result<OwnedPath, TradezError> {
+    Ok(concat(
+        &ACCOUNT_INDEX_PATH,
+        &RefPath::assert_from(suffix.as_bytes()),
+    )?)
+}
+
+fn page_path(page: u64) -> Result<OwnedPath, TradezError> {
+    index_path(&format!("/pages/{}", page))
+}
+
+/// Where the position of an account in the index is kept.
+fn position_path(address: &Address) -> Result<OwnedPath, TradezError> {
+    index_path(&format!("/positions/{:x}", address.0))
+}
+
+fn read_page(host: &mut impl Runtime, page: u64) -> Result<Vec<Address>, TradezError> {
+    match host.store_read_all(&page_path(page)?) {
+        Ok(data) => rlp::Rlp::new(&data)
+            .as_list()
+            .map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(vec![]),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
 
 impl Account {
     pub fn new(address: Address) -> Self {
@@ -75,10 +170,138 @@ impl Account {
             address,
             nonce: 0,
             balances: HashMap::new(),
+            locked: HashMap::new(),
+            debts: HashMap::new(),
+            debt_level: 0,
+            position: PerpPosition::default(),
+            liquidating: false,
             orders: BTreeSet::new(),
         }
     }
 
+    pub fn available(&self, currency: Currencies) -> u64 {
+        self.balances.get(&currency).copied().unwrap_or(0)
+    }
+
+    pub fn locked(&self, currency: Currencies) -> u64 {
+        self.locked.get(&currency).copied().unwrap_or(0)
+    }
+
+    pub fn debt(&self, currency: Currencies) -> u64 {
+        self.debts.get(&currency).copied().unwrap_or(0)
+    }
+
+    /// Available and locked balances together.
+    pub fn holdings(&self, currency: Currencies) -> u64 {
+        self.available(currency)
+            .saturating_add(self.locked(currency))
+    }
+
+    /// Moves `amount` from the available to the locked balance.
+    /// Returns false, leaving the account untouched, if not enough is available.
+    pub fn lock(&mut self, currency: Currencies, amount: u64) -> bool {
+        let Some(available) = self.available(currency).checked_sub(amount) else {
+            return false;
+        };
+        self.balances.insert(currency, available);
+        let locked = self.locked.entry(currency).or_insert(0);
+        *locked = locked.checked_add(amount).unwrap();
+        true
+    }
+
+    /// Takes `amount` out of the locked balance, when an order spends or releases it.
+    /// Returns false, leaving the account untouched, if less than `amount` is locked.
+    pub fn unlock(&mut self, currency: Currencies, amount: u64) -> bool {
+        let Some(locked) = self.locked(currency).checked_sub(amount) else {
+            return false;
+        };
+        self.locked.insert(currency, locked);
+        true
+    }
+
+    /// Gives back `amount` of the locked balance to the available one.
+    pub fn release(&mut self, currency: Currencies, amount: u64) -> bool {
+        if !self.unlock(currency, amount) {
+            return false;
+        }
+        let available = self.balances.entry(currency).or_insert(0);
+        *available = available.checked_add(amount).unwrap();
+        true
+    }
+
+    /// Credits `amount` lent against the collateral of the account. Interest must have been
+    /// accrued up to the current level first, the new debt is charged from there.
+    pub fn borrow(&mut self, currency: Currencies, amount: u64) {
+        let available = self.balances.entry(currency).or_insert(0);
+        *available = available.checked_add(amount).unwrap();
+        let debt = self.debts.entry(currency).or_insert(0);
+        *debt = debt.checked_add(amount).unwrap();
+    }
+
+    /// Pays the debts back with the available balances, as far as they go.
+    /// Returns what was repaid per currency.
+    pub fn repay_from_available(&mut self) -> Vec<(Currencies, u64)> {
+        let mut repaid = vec![];
+        for currency in [Currencies::USDC, Currencies::XTZ] {
+            let amount = self.debt(currency).min(self.available(currency));
+            if amount == 0 {
+                continue;
+            }
+            self.balances
+                .insert(currency, self.available(currency) - amount);
+            self.debts.insert(currency, self.debt(currency) - amount);
+            repaid.push((currency, amount));
+        }
+        repaid
+    }
+
+    /// Cancels `amount` of a debt that won't be paid back.
+    pub fn write_off(&mut self, currency: Currencies, amount: u64) -> bool {
+        let Some(debt) = self.debt(currency).checked_sub(amount) else {
+            return false;
+        };
+        self.debts.insert(currency, debt);
+        true
+    }
+
+    /// True while the account owes something or holds a perpetual position.
+    pub fn uses_margin(&self) -> bool {
+        !self.position.is_flat() || self.debts.values().any(|debt| *debt > 0)
+    }
+
+    /// Charges `rate_ppm` of the debts for every level since the last charge, rounded up.
+    /// Returns the interest added per currency.
+    pub fn accrue_interest(&mut self, level: u32, rate_ppm: u64) -> Vec<(Currencies, u64)> {
+        let elapsed = level.saturating_sub(self.debt_level);
+        self.debt_level = self.debt_level.max(level);
+        let mut charged = vec![];
+        for currency in [Currencies::USDC, Currencies::XTZ] {
+            let debt = self.debt(currency);
+            let interest = (debt as u128 * rate_ppm as u128 * elapsed as u128).div_ceil(1_000_000);
+            if interest == 0 {
+                continue;
+            }
+            let interest = u64::try_from(interest).unwrap();
+            self.debts
+                .insert(currency, debt.checked_add(interest).unwrap());
+            charged.push((currency, interest));
+        }
+        charged
+    }
+
+    /// Margin of the account with its XTZ and its position valued at `mark_price`.
+    pub fn margin(&self, mark_price: Price) -> Option<MarginState> {
+        MarginState::new(
+            mark_price,
+            (
+                self.holdings(Currencies::USDC),
+                self.holdings(Currencies::XTZ),
+            ),
+            (self.debt(Currencies::USDC), self.debt(Currencies::XTZ)),
+        )?
+        .with_position(&self.position)
+    }
+
     pub fn load(
         host: &mut impl Runtime,
         address: &Address,
@@ -106,12 +329,98 @@ impl Account {
             &ACCOUNT_KEY_PREFIX,
             &RefPath::assert_from(address.as_bytes()),
         )?;
+        match host.store_read_all(&key) {
+            Err(RuntimeError::PathNotFound) => Account::index(host, self.address)?,
+            // A new account joins the commitment with the index, an existing one is hashed again
+            _ => crate::commitment::mark_dirty(host, self.address)?,
+        }
         let mut stream = rlp::RlpStream::new();
         self.rlp_append(&mut stream);
         let data = stream.out();
         host.store_write_all(&key, &data)
             .map_err(TradezError::DatabaseRuntimeError)
     }
+
+    /// Adds an account at the end of the index.
+    fn index(host: &mut impl Runtime, address: Address) -> Result<(), TradezError> {
+        let count = Account::count(host)?;
+        let page = count / ACCOUNT_INDEX_PAGE_SIZE;
+        let mut addresses = read_page(host, page)?;
+        addresses.push(address);
+        host.store_write_all(&page_path(page)?, &rlp::encode_list(&addresses))
+            .map_err(TradezError::DatabaseRuntimeError)?;
+        host.store_write_all(&position_path(&address)?, &count.rlp_bytes())
+            .map_err(TradezError::DatabaseRuntimeError)?;
+        host.store_write_all(&ACCOUNT_COUNT_PATH, &(count + 1).rlp_bytes())
+            .map_err(TradezError::DatabaseRuntimeError)
+    }
+
+    /// Moves an index written as a single list of addresses to pages.
+    fn migrate_index(host: &mut impl Runtime) -> Result<(), TradezError> {
+        let legacy: Vec<Address> = match host.store_has(&ACCOUNT_INDEX_PATH) {
+            Ok(Some(ValueType::Value | ValueType::ValueWithSubtree)) => {
+                let data = host
+                    .store_read_all(&ACCOUNT_INDEX_PATH)
+                    .map_err(TradezError::DatabaseRuntimeError)?;
+                rlp::Rlp::new(&data)
+                    .as_list()
+                    .map_err(|e| TradezError::DataStoreError(e.to_string()))?
+            }
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(TradezError::DatabaseRuntimeError(e)),
+        };
+        host.store_delete_value(&ACCOUNT_INDEX_PATH)
+            .map_err(TradezError::DatabaseRuntimeError)?;
+        for address in legacy {
+            Account::index(host, address)?;
+        }
+        Ok(())
+    }
+
+    /// Number of accounts ever saved.
+    pub fn count(host: &mut impl Runtime) -> Result<u64, TradezError> {
+        Account::migrate_index(host)?;
+        match host.store_read_all(&ACCOUNT_COUNT_PATH) {
+            Ok(data) => rlp::decode(&data).map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(0),
+            Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+        }
+    }
+
+    /// Address of the account at `position` in the index.
+    pub fn address_at(
+        host: &mut impl Runtime,
+        position: u64,
+    ) -> Result<Option<Address>, TradezError> {
+        let page = read_page(host, position / ACCOUNT_INDEX_PAGE_SIZE)?;
+        Ok(page
+            .get((position % ACCOUNT_INDEX_PAGE_SIZE) as usize)
+            .copied())
+    }
+
+    /// Position of an account in the index, None if it was never saved.
+    pub fn position(
+        host: &mut impl Runtime,
+        address: &Address,
+    ) -> Result<Option<u64>, TradezError> {
+        match host.store_read_all(&position_path(address)?) {
+            Ok(data) => rlp::decode(&data)
+                .map(Some)
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(None),
+            Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+        }
+    }
+
+    /// Addresses of every account ever saved, in index order.
+    pub fn addresses(host: &mut impl Runtime) -> Result<Vec<Address>, TradezError> {
+        let count = Account::count(host)?;
+        let mut addresses = Vec::with_capacity(count as usize);
+        for page in 0..count.div_ceil(ACCOUNT_INDEX_PAGE_SIZE) {
+            addresses.extend(read_page(host, page)?);
+        }
+        Ok(addresses)
+    }
 }
 
 #[cfg(test)]
@@ -124,10 +433,22 @@ mod tests {
         balances.insert(Currencies::XTZ, 500u64);
         let orders = BTreeSet::new();
         let address = Address::from([0u8; 20]);
+        let mut locked = HashMap::new();
+        locked.insert(Currencies::USDC, 250u64);
+        let mut debts = HashMap::new();
+        debts.insert(Currencies::XTZ, 42u64);
         let account = Account {
             address: address.clone(),
             nonce: 100,
             balances: balances.clone(),
+            locked: locked.clone(),
+            debts: debts.clone(),
+            debt_level: 7,
+            position: PerpPosition {
+                size: -5,
+                entry_price: Price(2_000_000),
+            },
+            liquidating: true,
             orders: orders.clone(),
         };
         let mut stream = rlp::RlpStream::new();
@@ -137,5 +458,60 @@ mod tests {
         let decoded_account = Account::decode(&rlp).unwrap();
         assert_eq!(decoded_account.address, address);
         assert_eq!(decoded_account.balances, balances);
+        assert_eq!(decoded_account.locked, locked);
+        assert_eq!(decoded_account.debts, debts);
+        assert_eq!(decoded_account.debt_level, 7);
+        assert_eq!(decoded_account.position, account.position);
+        assert!(decoded_account.liquidating);
+    }
+
+    #[test]
+    fn test_lock_and_release() {
+        let mut account = Account::new(Address::from([0u8; 20]));
+        account.balances.insert(Currencies::USDC, 1000);
+        assert!(!account.lock(Currencies::USDC, 1001));
+        assert!(account.lock(Currencies::USDC, 600));
+        assert_eq!(account.available(Currencies::USDC), 400);
+        assert_eq!(account.locked(Currencies::USDC), 600);
+
+        assert!(account.unlock(Currencies::USDC, 100));
+        assert!(account.release(Currencies::USDC, 500));
+        assert!(!account.release(Currencies::USDC, 1));
+        assert_eq!(account.available(Currencies::USDC), 900);
+        assert_eq!(account.locked(Currencies::USDC), 0);
+    }
+
+    #[test]
+    fn test_borrow_interest_and_repay() {
+        let mut account = Account::new(Address::from([0u8; 20]));
+        account.balances.insert(Currencies::USDC, 1_000_000);
+        // Interest runs from the last charge, brought up to date before borrowing
+        assert_eq!(account.accrue_interest(10, 1), vec![]);
+        account.borrow(Currencies::USDC, 3_000_000);
+        assert!(account.lock(Currencies::USDC, 4_000_000));
+        assert_eq!(account.debt(Currencies::USDC), 3_000_000);
+        assert_eq!(account.holdings(Currencies::USDC), 4_000_000);
+
+        assert_eq!(account.accrue_interest(15, 2), vec![(Currencies::USDC, 30)]);
+        // Never charged twice for the same levels
+        assert_eq!(account.accrue_interest(12, 2), vec![]);
+        assert_eq!(account.debt(Currencies::USDC), 3_000_030);
+
+        // Nothing available, nothing repaid
+        assert_eq!(account.repay_from_available(), vec![]);
+        assert!(account.release(Currencies::USDC, 4_000_000));
+        assert_eq!(
+            account.repay_from_available(),
+            vec![(Currencies::USDC, 3_000_030)]
+        );
+        assert_eq!(account.debt(Currencies::USDC), 0);
+        assert_eq!(account.available(Currencies::USDC), 999_970);
+        assert!(!account.uses_margin());
+
+        account.borrow(Currencies::XTZ, 10);
+        assert!(account.uses_margin());
+        assert!(!account.write_off(Currencies::XTZ, 11));
+        assert!(account.write_off(Currencies::XTZ, 10));
+        assert!(!account.uses_margin());
     }
 }
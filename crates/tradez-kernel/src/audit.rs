@@ -0,0 +1,173 @@
+use tezos_smart_rollup::prelude::Runtime;
+use tradez_types::{
+    currencies::Currencies,
+    error::TradezError,
+    market::{BPS_DENOMINATOR, MarketConfig, MarketKind},
+    orderbook::OrderBook,
+    position::{Order, Side},
+    supply::{AuditReport, CurrencyAudit, SupplyLedger},
+    units::{Price, Qty, Rounding},
+};
+
+use crate::{account::Account, liquidation::load_margin_accounts};
+
+const CURRENCIES: [Currencies; 2] = [Currencies::USDC, Currencies::XTZ];
+
+/// Currency and amount an order of `qty` at `price` keeps locked. On the spot market an ask
+/// locks its quantity and a bid its limit price times its quantity. On a perpetual both sides
+/// lock the initial margin of their notional. What the user owes is rounded up.
+pub fn reservation(
+    config: &MarketConfig,
+    side: Side,
+    price: Price,
+    qty: Qty,
+) -> Option<(Currencies, u64)> {
+    match (config.kind, side) {
+        (MarketKind::Spot, Side::Ask) => Some((Currencies::XTZ, qty.raw())),
+        (MarketKind::Spot, Side::Bid) => price
+            .checked_notional(qty, Rounding::Up)
+            .map(|notional| (Currencies::USDC, notional)),
+        (MarketKind::Perpetual, _) => {
+            let notional = price.checked_notional(qty, Rounding::Up)? as u128;
+            let margin =
+                (notional * config.initial_margin_bps as u128).div_ceil(BPS_DENOMINATOR as u128);
+            Some((Currencies::USDC, u64::try_from(margin).ok()?))
+        }
+    }
+}
+
+/// Currency and amount a resting order keeps locked for its remaining quantity.
+pub fn order_reservation(config: &MarketConfig, order: &Order) -> (Currencies, u64) {
+    reservation(config, order.side, order.price, order.remaining)
+        .unwrap_or((Currencies::USDC, u64::MAX))
+}
+
+/// What the open orders of an account reserve, per currency (USDC, XTZ).
+pub fn account_reservations(
+    config: &MarketConfig,
+    orderbook: &OrderBook,
+    account: &Account,
+) -> (u64, u64) {
+    let mut reserved = (0u64, 0u64);
+    for id in &account.orders {
+        // Stops reserve nothing until they trigger
+        let Some(order) = orderbook.get_order(*id) else {
+            continue;
+        };
+        match order_reservation(config, &order) {
+            (Currencies::USDC, amount) => reserved.0 = reserved.0.saturating_add(amount),
+            (Currencies::XTZ, amount) => reserved.1 = reserved.1.saturating_add(amount),
+        }
+    }
+    reserved
+}
+
+/// Checks that the accounts hold and owe exactly what the supply ledger says is circulating
+/// and outstanding, and that what they lock is what the order book reserves.
+pub fn audit(host: &mut impl Runtime) -> Result<AuditReport, TradezError> {
+    let config = MarketConfig::load(host)?;
+    let ledger = SupplyLedger::load(host)?;
+    let orderbook = OrderBook::load(host)?;
+    let addresses = Account::addresses(host)?;
+    let margin_accounts = load_margin_accounts(host)?;
+
+    let mut report = AuditReport {
+        accounts: addresses.len() as u64,
+        currencies: CURRENCIES
+            .into_iter()
+            .map(|currency| CurrencyAudit {
+                currency,
+                supply: *ledger.get(currency),
+                available: 0,
+                locked: 0,
+                reserved_by_orders: 0,
+                debt: 0,
+            })
+            .collect(),
+        discrepancies: vec![],
+    };
+    // Every long of the perpetual has a short on the other side
+    let mut open_interest = 0i128;
+
+    for address in addresses {
+        let Some(account) = Account::load(host, &address)? else {
+            report
+                .discrepancies
+                .push(format!("Indexed account {:?} not found", address));
+            continue;
+        };
+        for totals in report.currencies.iter_mut() {
+            totals.available = totals
+                .available
+                .saturating_add(account.available(totals.currency));
+            totals.locked = totals
+                .locked
+                .saturating_add(account.locked(totals.currency));
+            totals.debt = totals.debt.saturating_add(account.debt(totals.currency));
+        }
+        open_interest += account.position.size as i128;
+        // The liquidation engine only watches the margin accounts
+        if account.uses_margin() && !margin_accounts.contains(&address) {
+            report.discrepancies.push(format!(
+                "Account {:?} uses margin but isn't a margin account",
+                address
+            ));
+        }
+        let reserved = account_reservations(&config, &orderbook, &account);
+        let locked = (
+            account.locked(Currencies::USDC),
+            account.locked(Currencies::XTZ),
+        );
+        if locked != reserved {
+            report.discrepancies.push(format!(
+                "Account {:?} locks {:?} but its orders reserve {:?}",
+                address, locked, reserved
+            ));
+        }
+    }
+
+    for order in orderbook.orders() {
+        let (currency, amount) = order_reservation(&config, order);
+        if let Some(totals) = report
+            .currencies
+            .iter_mut()
+            .find(|totals| totals.currency == currency)
+        {
+            totals.reserved_by_orders = totals.reserved_by_orders.saturating_add(amount);
+        }
+    }
+
+    if open_interest != 0 {
+        report.discrepancies.push(format!(
+            "Perpetual positions don't net out: {} microXTZ more long than short",
+            open_interest
+        ));
+    }
+
+    for totals in &report.currencies {
+        let held = totals.available.saturating_add(totals.locked);
+        if totals.supply.circulating() != Some(held) {
+            report.discrepancies.push(format!(
+                "{:?}: accounts hold {} but {:?} is circulating",
+                totals.currency,
+                held,
+                totals.supply.circulating()
+            ));
+        }
+        if totals.supply.outstanding_debt() != Some(totals.debt) {
+            report.discrepancies.push(format!(
+                "{:?}: accounts owe {} but {:?} is outstanding",
+                totals.currency,
+                totals.debt,
+                totals.supply.outstanding_debt()
+            ));
+        }
+        if totals.locked != totals.reserved_by_orders {
+            report.discrepancies.push(format!(
+                "{:?}: accounts lock {} but the order book reserves {}",
+                totals.currency, totals.locked, totals.reserved_by_orders
+            ));
+        }
+    }
+    Ok(report)
+}
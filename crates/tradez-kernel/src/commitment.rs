@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use alloy_primitives::{B256, Signature};
+use rlp::Encodable;
+use tezos_smart_rollup::prelude::Runtime;
+use tezos_smart_rollup_host::{
+    path::{OwnedPath, RefPath},
+    runtime::RuntimeError,
+};
+use tradez_types::{
+    SequencedInput,
+    address::Address,
+    commitment::{Hash, StateCommitment},
+    error::TradezError,
+    orderbook::{Event, OrderBook},
+    setup::RollupSetup,
+};
+
+use crate::account::Account;
+
+/// Level of the last input processed.
+const INPUT_LEVEL_PATH: RefPath = RefPath::assert_from(b"/tradez/input_level");
+/// Number of inputs processed, where the sequencer and the rollup are in the input stream.
+pub const INPUT_COUNT_STR_PATH: &str = "/tradez/input_count";
+const INPUT_COUNT_PATH: RefPath = RefPath::assert_from(b"/tradez/input_count");
+
+/// Nodes of the Merkle tree of the state commitment, under their height and position, the
+/// leaves at height 0: the order book first, then the accounts in index order. A commitment
+/// only hashes again the leaves that changed since the last one and the nodes above them.
+pub const STATE_TREE_STR_PATH: &str = "/tradez/state_tree";
+/// Number of leaves of the tree.
+const STATE_TREE_LEAVES_PATH: RefPath = RefPath::assert_from(b"/tradez/state_tree_leaves");
+/// Accounts saved again since the last commitment.
+const DIRTY_ACCOUNTS_PATH: RefPath = RefPath::assert_from(b"/tradez/dirty_accounts");
+
+pub fn node_path(height: u32, position: u64) -> String {
+    format!("{}/{}/{}", STATE_TREE_STR_PATH, height, position)
+}
+
+/// Node of the tree, the default hash for one not written yet.
+fn read_node(host: &mut impl Runtime, height: u32, position: u64) -> Result<Hash, TradezError> {
+    let path = OwnedPath::try_from(node_path(height, position))
+        .map_err(|e| TradezError::DataStoreError(format!("{:?}", e)))?;
+    match host.store_read_all(&path) {
+        Ok(data) if data.len() == 32 => Ok(Hash(B256::from_slice(&data))),
+        Ok(_) => Err(TradezError::DataStoreError(format!(
+            "Invalid node at {}",
+            node_path(height, position)
+        ))),
+        Err(RuntimeError::PathNotFound) => Ok(Hash::default()),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+fn write_node(
+    host: &mut impl Runtime,
+    height: u32,
+    position: u64,
+    node: Hash,
+) -> Result<(), TradezError> {
+    let path = OwnedPath::try_from(node_path(height, position))
+        .map_err(|e| TradezError::DataStoreError(format!("{:?}", e)))?;
+    host.store_write_all(&path, node.0.as_slice())
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+/// Writes a leaf and hashes again the nodes above it in a tree of `leaf_count` leaves. A node
+/// without sibling moves up a level unchanged, as in
+/// [`merkle_root`](tradez_types::commitment::merkle_root). Leaves set in increasing positions
+/// leave every node of the tree up to date.
+fn set_leaf(
+    host: &mut impl Runtime,
+    position: u64,
+    leaf: Hash,
+    leaf_count: u64,
+) -> Result<(), TradezError> {
+    write_node(host, 0, position, leaf)?;
+    let (mut node, mut position, mut count, mut height) = (leaf, position, leaf_count, 0);
+    while count > 1 {
+        if position ^ 1 < count {
+            let sibling = read_node(host, height, position ^ 1)?;
+            node = if position.is_multiple_of(2) {
+                Hash::node(&node, &sibling)
+            } else {
+                Hash::node(&sibling, &node)
+            };
+        }
+        position /= 2;
+        count = count.div_ceil(2);
+        height += 1;
+        write_node(host, height, position, node)?;
+    }
+    Ok(())
+}
+
+/// Root of the tree of `leaf_count` leaves.
+fn tree_root(host: &mut impl Runtime, leaf_count: u64) -> Result<Hash, TradezError> {
+    let (mut count, mut height) = (leaf_count, 0);
+    while count > 1 {
+        count = count.div_ceil(2);
+        height += 1;
+    }
+    read_node(host, height, 0)
+}
+
+fn read_u64(host: &mut impl Runtime, path: &RefPath) -> Result<u64, TradezError> {
+    match host.store_read_all(path) {
+        Ok(data) => rlp::decode(&data).map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(0),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+fn load_dirty(host: &mut impl Runtime) -> Result<Vec<Address>, TradezError> {
+    match host.store_read_all(&DIRTY_ACCOUNTS_PATH) {
+        Ok(data) => rlp::Rlp::new(&data)
+            .as_list()
+            .map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(vec![]),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+/// Has the leaf of an existing account hashed again at the next commitment.
+pub fn mark_dirty(host: &mut impl Runtime, address: Address) -> Result<(), TradezError> {
+    let mut dirty = load_dirty(host)?;
+    if dirty.contains(&address) {
+        return Ok(());
+    }
+    dirty.push(address);
+    host.store_write_all(&DIRTY_ACCOUNTS_PATH, &rlp::encode_list(&dirty))
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+pub fn load_input_count(host: &mut impl Runtime) -> Result<u64, TradezError> {
+    match host.store_read_all(&INPUT_COUNT_PATH) {
+        Ok(data) => rlp::decode(&data).map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(0),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+/// Counts one more input.
+pub fn count_input(host: &mut impl Runtime) -> Result<(), TradezError> {
+    let count = load_input_count(host)? + 1;
+    host.store_write_all(&INPUT_COUNT_PATH, &count.rlp_bytes())
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+/// Commits the state of the previous level when the first input of a later level comes in.
+/// The sequencer and the rollup meet that point at the same place of the input stream, so
+/// they compute the same roots.
+pub fn commit_on_new_level(host: &mut impl Runtime, level: u32) -> Result<(), TradezError> {
+    match stored_level(host)? {
+        Some(previous) if previous >= level => return Ok(()),
+        Some(previous) => {
+            commit_state(host, previous)?;
+        }
+        None => {}
+    }
+    host.store_write_all(&INPUT_LEVEL_PATH, &level.rlp_bytes())
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+fn stored_level(host: &mut impl Runtime) -> Result<Option<u32>, TradezError> {
+    match host.store_read_all(&INPUT_LEVEL_PATH) {
+        Ok(data) => Ok(Some(
+            rlp::decode(&data).map_err(|e| TradezError::DataStoreError(e.to_string()))?,
+        )),
+        Err(RuntimeError::PathNotFound) => Ok(None),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+/// Level the kernel processes an input at. The level the sequencer stamped is taken only
+/// when the sequencer of the setup signed it, and never past the inbox level; any other
+/// input is dated by the inbox. The level never goes back, so that no input revives an
+/// expired delegate or slips out of a halt.
+pub fn input_level(
+    host: &mut impl Runtime,
+    input: &SequencedInput,
+    inbox_level: u32,
+) -> Result<u32, TradezError> {
+    let signer = Signature::from_raw(&input.signature)
+        .ok()
+        .and_then(|signature| {
+            signature
+                .recover_address_from_msg(SequencedInput::payload(input.level, &input.input))
+                .ok()
+        })
+        .map(Address::from);
+    let level = match RollupSetup::load_sequencer(host)? {
+        Some(sequencer) if signer == Some(sequencer) => input.level.min(inbox_level),
+        _ => inbox_level,
+    };
+    Ok(level.max(stored_level(host)?.unwrap_or(0)))
+}
+
+/// Computes the Merkle root of the accounts and the order book, keeps it in the durable
+/// storage and publishes it in the outbox. Only the order book, the accounts saved since the
+/// last commitment and the new ones are hashed again, every account the first time.
+pub fn commit_state(host: &mut impl Runtime, level: u32) -> Result<StateCommitment, TradezError> {
+    let accounts = Account::count(host)?;
+    let leaf_count = accounts + 1;
+    let mut leaves = BTreeMap::new();
+    leaves.insert(0, Hash::leaf(&OrderBook::load(host)?.rlp_bytes()));
+    let indexed = read_u64(host, &STATE_TREE_LEAVES_PATH)?.saturating_sub(1);
+    let mut positions: BTreeSet<u64> = (indexed..accounts).collect();
+    for address in load_dirty(host)? {
+        if let Some(position) = Account::position(host, &address)? {
+            positions.insert(position);
+        }
+    }
+    for position in positions {
+        let address = Account::address_at(host, position)?.ok_or_else(|| {
+            TradezError::DataStoreError(format!("No indexed account at {}", position))
+        })?;
+        let account = Account::load(host, &address)?.unwrap_or(Account::new(address));
+        leaves.insert(position + 1, Hash::leaf(&account.rlp_bytes()));
+    }
+    for (position, leaf) in leaves {
+        set_leaf(host, position, leaf, leaf_count)?;
+    }
+    match host.store_delete(&DIRTY_ACCOUNTS_PATH) {
+        Ok(()) | Err(RuntimeError::PathNotFound) => {}
+        Err(e) => return Err(TradezError::DatabaseRuntimeError(e)),
+    }
+    host.store_write_all(&STATE_TREE_LEAVES_PATH, &leaf_count.rlp_bytes())
+        .map_err(TradezError::DatabaseRuntimeError)?;
+
+    let root = tree_root(host, leaf_count)?;
+    let commitment = StateCommitment { level, root };
+    commitment.save(host)?;
+    host.write_debug(&format!("State commitment: {:?}\n", commitment));
+    let event = Event::StateCommitment { level, root };
+    host.write_output(&event.rlp_bytes())
+        .map_err(TradezError::DatabaseRuntimeError)?;
+    Ok(commitment)
+}
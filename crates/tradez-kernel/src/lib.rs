@@ -1,30 +1,44 @@
 extern crate alloc;
 
+use std::collections::BTreeMap;
+
 use alloy_primitives::Signature;
 use rlp::Encodable;
 use tezos_smart_rollup::inbox::InboxMessage;
 use tezos_smart_rollup::michelson::MichelsonBytes;
 use tezos_smart_rollup::prelude::*;
+use tezos_smart_rollup_host::input::Message;
 use tradez_types::{
-    KernelMessage, SignedInput,
+    KernelMessage, SequencedInput, SignedInput,
     address::Address,
     currencies::Currencies,
+    delegate::{
+        self, DelegateGrant, DelegateKey, DelegateRejection, DelegatedAction, RevokeDelegate,
+    },
+    fills::{self, Fill, FillRole},
+    margin::MarginState,
+    market::{
+        BPS_DENOMINATOR, MarketConfig, MarketKind, MarketPhase, MarketStatus, OrderRejection,
+    },
+    oracle::{OracleConfig, OracleFeed, OracleUpdate},
     orderbook::{Event, OrderBook},
-    position::{APIOrder, CancelOrder, Faucet, Side},
+    perpetual::PerpStatus,
+    position::{APIOrder, CancelOrder, Faucet, Side, Transfer},
+    stops::{APIOcoOrder, APIStopOrder, StopBook, StopOrder},
+    supply::SupplyLedger,
+    units::{Price, Qty, Rounding},
 };
 
 use crate::account::Account;
 
 pub mod account;
+pub mod audit;
+pub mod commitment;
+pub mod liquidation;
 
-const DECIMALS: u128 = 1_000_000;
-
-fn opposite_side(side: Side) -> Side {
-    match side {
-        Side::Bid => Side::Ask,
-        Side::Ask => Side::Bid,
-    }
-}
+/// An input the kernel refused, which the sequencer keeps out of the rollup.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Refused;
 
 fn get_or_load_account<'a>(
     host: &mut impl Runtime,
@@ -42,16 +56,6 @@ fn get_or_load_account<'a>(
     &mut cache[len - 1].1
 }
 
-fn quote_value(qty: u64, price: u64) -> Option<u64> {
-    let product = (qty as u128).checked_mul(price as u128)?;
-    let value = product / DECIMALS;
-    if value > u64::MAX as u128 {
-        None
-    } else {
-        Some(value as u64)
-    }
-}
-
 fn trading_fee(amount: u64) -> u64 {
     if amount == 0 {
         0
@@ -61,179 +65,1108 @@ fn trading_fee(amount: u64) -> u64 {
     }
 }
 
-fn amount_with_fee(amount: u64) -> Option<u64> {
-    let fee = trading_fee(amount);
-    amount.checked_add(fee)
+fn reject_order(host: &mut impl Runtime, user: Address, nonce: u64, reason: &str) {
+    host.write_debug(&format!("Order rejected: {}\n", reason));
+    let event = Event::Rejected {
+        user,
+        nonce,
+        reason: reason.to_string(),
+    };
+    host.write_output(&event.rlp_bytes()).unwrap();
 }
 
+/// Rejects an input that carries no nonce: a cancel, an oracle update or a change of the
+/// delegate keys.
+fn reject_input(host: &mut impl Runtime, user: Address, reason: &str) {
+    host.write_debug(&format!("Input rejected: {}\n", reason));
+    let event = Event::Rejected {
+        user,
+        nonce: 0,
+        reason: reason.to_string(),
+    };
+    host.write_output(&event.rlp_bytes()).unwrap();
+}
+
+fn reject_transfer(host: &mut impl Runtime, from: Address, nonce: u64, reason: &str) {
+    host.write_debug(&format!("Transfer rejected: {}\n", reason));
+    let event = Event::TransferRejected {
+        from,
+        nonce,
+        reason: reason.to_string(),
+    };
+    host.write_output(&event.rlp_bytes()).unwrap();
+}
+
+/// Settles one trade, except what the orders reserved which is settled per order by
+/// `settle_order_fills`. On the spot market the taker pays its fee on top of the trade, in
+/// the currency it gives, and what its available balance doesn't cover is lent. On a
+/// perpetual the trade moves the positions of both sides and the taker pays its fee in USDC.
+/// Returns the fee of the taker.
 #[allow(clippy::too_many_arguments)]
 fn handle_trade_event(
     host: &mut impl Runtime,
+    market: &mut Market,
     accounts: &mut Vec<(Address, Account)>,
     taker_side: Side,
-    order_price: u64,
     maker_user: Address,
     taker_user: Address,
-    qty: u64,
+    price: Price,
+    qty: Qty,
     trade_value: u64,
-) -> u64 {
-    let maker_side = opposite_side(taker_side);
-    let taker_fee = match taker_side {
-        Side::Ask => trading_fee(qty),
-        Side::Bid => trading_fee(trade_value),
+) -> (Currencies, u64) {
+    let (bid_user, ask_user) = match taker_side {
+        Side::Bid => (taker_user, maker_user),
+        Side::Ask => (maker_user, taker_user),
     };
+    if market.config.kind == MarketKind::Perpetual {
+        let fee = trading_fee(trade_value);
+        market.supply.collect_fee(Currencies::USDC, fee);
+        debit(
+            market,
+            get_or_load_account(host, accounts, taker_user),
+            Currencies::USDC,
+            fee,
+        );
+        for (user, side) in [(bid_user, Side::Bid), (ask_user, Side::Ask)] {
+            let account = get_or_load_account(host, accounts, user);
+            let Some(realized) = account.position.fill(side, qty, price) else {
+                host.write_debug("Position size overflow\n");
+                continue;
+            };
+            settle_perp(market, account, realized);
+        }
+        return (Currencies::USDC, fee);
+    }
 
-    if maker_user == taker_user {
-        let account = get_or_load_account(host, accounts, maker_user);
+    let bid_account = get_or_load_account(host, accounts, bid_user);
+    let xtz_balance = bid_account.balances.entry(Currencies::XTZ).or_insert(0);
+    *xtz_balance = xtz_balance.checked_add(qty.raw()).unwrap();
 
-        match maker_side {
-            Side::Ask => {
-                let maker_usdc_balance = account.balances.entry(Currencies::USDC).or_insert(0);
-                *maker_usdc_balance = maker_usdc_balance.checked_add(trade_value).unwrap();
-            }
-            Side::Bid => {
-                let maker_xtz_balance = account.balances.entry(Currencies::XTZ).or_insert(0);
-                *maker_xtz_balance = maker_xtz_balance.checked_add(qty).unwrap();
+    let ask_account = get_or_load_account(host, accounts, ask_user);
+    let usdc_balance = ask_account.balances.entry(Currencies::USDC).or_insert(0);
+    *usdc_balance = usdc_balance.checked_add(trade_value).unwrap();
+
+    let (currency, fee) = match taker_side {
+        Side::Bid => (Currencies::USDC, trading_fee(trade_value)),
+        Side::Ask => (Currencies::XTZ, trading_fee(qty.raw())),
+    };
+    market.supply.collect_fee(currency, fee);
+    debit(
+        market,
+        get_or_load_account(host, accounts, taker_user),
+        currency,
+        fee,
+    );
+    (currency, fee)
+}
+
+/// Takes `amount` from an account, lending what its available balance doesn't cover.
+fn debit(market: &mut Market, account: &mut Account, currency: Currencies, amount: u64) {
+    accrue_interest(market, account);
+    let borrowed = amount.saturating_sub(account.available(currency));
+    account.borrow(currency, borrowed);
+    market.supply.borrow(currency, borrowed);
+    let available = account.available(currency) - amount;
+    account.balances.insert(currency, available);
+}
+
+/// Pays a perpetual settlement, a realized profit or funding, to an account, or takes it
+/// when negative.
+fn settle_perp(market: &mut Market, account: &mut Account, amount: i64) {
+    market.supply.settle_perp(amount);
+    if amount >= 0 {
+        let available = account.balances.entry(Currencies::USDC).or_insert(0);
+        *available = available.checked_add(amount as u64).unwrap();
+    } else {
+        debit(market, account, Currencies::USDC, amount.unsigned_abs());
+    }
+}
+
+/// Settles what an order reserved for its trades within an input. Once rounded, the
+/// reservation isn't linear in the remaining quantity: what the fills release is the
+/// difference between the reservation before and after them. On the spot market a bid pays
+/// its trades out of it and gets back what they didn't cost, an ask delivers its XTZ. On a
+/// perpetual the margin goes back to the available balance.
+fn settle_order_fills(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    accounts: &mut Vec<(Address, Account)>,
+    id: u64,
+    fills: OrderFills,
+) {
+    if fills.price.is_zero() {
+        // Market orders reserve nothing, what they deliver comes out of the available balance
+        let account = get_or_load_account(host, accounts, fills.user);
+        match (market.config.kind, fills.side) {
+            (MarketKind::Spot, Side::Bid) => debit(market, account, Currencies::USDC, fills.paid),
+            (MarketKind::Spot, Side::Ask) => {
+                debit(market, account, Currencies::XTZ, fills.filled.raw())
             }
+            (MarketKind::Perpetual, _) => {}
         }
+        return;
+    }
+    let remaining = market
+        .orderbook
+        .get_order(id)
+        .map(|order| order.remaining)
+        .unwrap_or(Qty::ZERO);
+    let reserved = |qty| audit::reservation(&market.config, fills.side, fills.price, qty);
+    let released = fills
+        .filled
+        .checked_add(remaining)
+        .and_then(reserved)
+        .zip(reserved(remaining))
+        .and_then(|((currency, before), (_, after))| Some((currency, before.checked_sub(after)?)));
+    let Some((currency, released)) = released else {
+        host.write_debug("Failed to compute released value for order\n");
+        return;
+    };
+    let account = get_or_load_account(host, accounts, fills.user);
+    let settled = match (market.config.kind, fills.side) {
+        (MarketKind::Spot, Side::Bid) => match released.checked_sub(fills.paid) {
+            Some(refund) => {
+                account.unlock(currency, fills.paid) && account.release(currency, refund)
+            }
+            // Each trade rounds its value up, several of them can cost more than released
+            None => {
+                let unlocked = account.unlock(currency, released);
+                debit(market, account, currency, fills.paid - released);
+                unlocked
+            }
+        },
+        (MarketKind::Spot, Side::Ask) => account.unlock(currency, released),
+        (MarketKind::Perpetual, _) => account.release(currency, released),
+    };
+    if !settled {
+        host.write_debug(&format!(
+            "Locked balance lower than released value for order {}\n",
+            id
+        ));
+    }
+}
 
-        match taker_side {
-            Side::Bid => {
-                let taker_xtz_balance = account.balances.entry(Currencies::XTZ).or_insert(0);
-                *taker_xtz_balance = taker_xtz_balance.checked_add(qty).unwrap();
-
-                if taker_fee > 0 {
-                    let taker_usdc_balance = account.balances.entry(Currencies::USDC).or_insert(0);
-                    if let Some(updated) = taker_usdc_balance.checked_sub(taker_fee) {
-                        *taker_usdc_balance = updated;
-                    } else {
-                        host.write_debug("Taker bid insufficient USDC for fee\n");
-                    }
-                }
+/// Fills of one order within an input.
+struct OrderFills {
+    user: Address,
+    side: Side,
+    /// Limit price the order reserved at.
+    price: Price,
+    filled: Qty,
+    /// USDC paid or received for the fills, at the trade prices.
+    paid: u64,
+}
 
-                if let Some(reserved_value) = quote_value(qty, order_price) {
-                    if reserved_value >= trade_value {
-                        let refund = reserved_value - trade_value;
-                        if refund > 0 {
-                            let taker_usdc_balance =
-                                account.balances.entry(Currencies::USDC).or_insert(0);
-                            *taker_usdc_balance = taker_usdc_balance.checked_add(refund).unwrap();
-                        }
-                    } else {
-                        host.write_debug("Reserved value lower than trade value for taker bid\n");
-                    }
-                } else {
-                    host.write_debug("Failed to compute reserved value for taker bid\n");
+/// Checks that the locked balances of an account are exactly what its open orders reserve.
+/// Debug kernels stop on a mismatch.
+fn check_locked_balances(
+    host: &mut impl Runtime,
+    config: &MarketConfig,
+    orderbook: &OrderBook,
+    account: &Account,
+) {
+    let reserved = audit::account_reservations(config, orderbook, account);
+    let locked = (
+        account.locked(Currencies::USDC),
+        account.locked(Currencies::XTZ),
+    );
+    if locked != reserved {
+        host.write_debug(&format!(
+            "Locked balances {:?} of {:?} differ from open orders reservations {:?}\n",
+            locked, account.address, reserved
+        ));
+    }
+    debug_assert_eq!(locked, reserved, "locked balances of {:?}", account.address);
+}
+
+/// State of the market an input works on, loaded before the input and saved after it.
+struct Market {
+    orderbook: OrderBook,
+    stop_book: StopBook,
+    status: MarketStatus,
+    config: MarketConfig,
+    supply: SupplyLedger,
+    perp: PerpStatus,
+    margin_accounts: Vec<Address>,
+    oracle_config: OracleConfig,
+    oracle: OracleFeed,
+    /// Trades executed so far, the id of the next one.
+    trade_count: u64,
+    /// Level the sequencer stamped the input with.
+    level: u32,
+    /// Mark price, level and trade count of the last scan of every margin account.
+    last_scan: Option<(Price, u32, u64)>,
+    /// Accounts the input saved, checked for liquidation even without a full scan.
+    touched: Vec<Address>,
+}
+
+impl Market {
+    fn load(host: &mut impl Runtime, level: u32) -> Self {
+        let config = MarketConfig::load(host).unwrap();
+        let status = match MarketStatus::try_load(host).unwrap() {
+            Some(status) => status,
+            None => {
+                let status = MarketStatus::opening(&config, level);
+                if let MarketPhase::Auction { until_level } = status.phase {
+                    let event = Event::AuctionStarted { until_level };
+                    host.write_output(&event.rlp_bytes()).unwrap();
                 }
+                status
             }
-            Side::Ask => {
-                if taker_fee > 0 {
-                    let taker_xtz_balance = account.balances.entry(Currencies::XTZ).or_insert(0);
-                    if let Some(updated) = taker_xtz_balance.checked_sub(taker_fee) {
-                        *taker_xtz_balance = updated;
-                    } else {
-                        host.write_debug("Taker ask insufficient XTZ for fee\n");
+        };
+        Market {
+            orderbook: OrderBook::load(host).unwrap(),
+            stop_book: StopBook::load(host).unwrap(),
+            status,
+            config,
+            supply: SupplyLedger::load(host).unwrap(),
+            perp: PerpStatus::load(host).unwrap(),
+            margin_accounts: liquidation::load_margin_accounts(host).unwrap(),
+            oracle_config: OracleConfig::load(host).unwrap(),
+            oracle: OracleFeed::load(host).unwrap(),
+            trade_count: fills::load_trade_count(host).unwrap(),
+            level,
+            last_scan: liquidation::load_last_scan(host).unwrap(),
+            touched: vec![],
+        }
+    }
+
+    fn save(&self, host: &mut impl Runtime) {
+        self.orderbook.save(host).unwrap();
+        self.stop_book.save(host).unwrap();
+        self.status.save(host).unwrap();
+        self.supply.save(host).unwrap();
+        liquidation::save_margin_accounts(host, &self.margin_accounts).unwrap();
+        self.oracle.save(host).unwrap();
+        fills::save_trade_count(host, self.trade_count).unwrap();
+        if let Some(scan) = self.last_scan {
+            liquidation::save_last_scan(host, scan).unwrap();
+        }
+        if self.config.kind == MarketKind::Perpetual {
+            self.perp.save(host).unwrap();
+        }
+    }
+
+    /// Price margins, price bands and liquidations are measured against: the index price of
+    /// the oracles, or the reference price of the book while there is none.
+    fn mark_price(&self) -> Option<Price> {
+        if self.oracle.index_price.is_zero() {
+            self.status.reference_price(&self.orderbook)
+        } else {
+            Some(self.oracle.index_price)
+        }
+    }
+}
+
+/// Brings the interest on the debts of an account up to the current level.
+fn accrue_interest(market: &mut Market, account: &mut Account) {
+    for (currency, interest) in account.accrue_interest(market.level, market.config.borrow_rate_ppm)
+    {
+        market.supply.charge_interest(currency, interest);
+    }
+}
+
+/// Saves the accounts an input touched. What they have available first pays back their debts.
+fn save_accounts(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    accounts: &mut [(Address, Account)],
+) {
+    for (_, account) in accounts.iter_mut() {
+        accrue_interest(market, account);
+        for (currency, amount) in account.repay_from_available() {
+            market.supply.repay(currency, amount);
+        }
+        liquidation::track_margin_account(&mut market.margin_accounts, account);
+        if !market.touched.contains(&account.address) {
+            market.touched.push(account.address);
+        }
+        check_locked_balances(host, &market.config, &market.orderbook, account);
+        account.save(host).unwrap();
+    }
+}
+
+/// Writes the events to the outbox and settles them on the accounts. `limit_prices` holds the
+/// limit price of the resting orders that may trade, incoming orders come from their `Placed`
+/// event. Returns the traded prices, in order.
+fn settle_events(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    accounts: &mut Vec<(Address, Account)>,
+    mut limit_prices: BTreeMap<u64, Price>,
+    events: Vec<Event>,
+) -> Vec<Price> {
+    let mut halt_event = None;
+    let mut prices = vec![];
+    let mut order_fills: BTreeMap<u64, OrderFills> = BTreeMap::new();
+
+    for event in events {
+        host.write_output(&event.rlp_bytes()).unwrap();
+        match event {
+            Event::Placed { id, price, .. } => {
+                limit_prices.insert(id, price);
+            }
+            Event::Trade {
+                maker_id,
+                maker_user,
+                taker_id,
+                taker_user,
+                price,
+                qty,
+                origin_side,
+            } => {
+                prices.push(price);
+                if let Some(until_level) =
+                    market
+                        .status
+                        .record_trade(&market.config, market.level, price)
+                {
+                    halt_event = Some(Event::MarketHalted {
+                        reference_price: market.status.window_start_price.unwrap_or(price),
+                        last_price: price,
+                        until_level,
+                    });
+                }
+                // The first leg of an OCO group to trade cancels the other one
+                for id in [maker_id, taker_id] {
+                    if let Some(sibling) = market.stop_book.unlink(id) {
+                        cancel_order_by_id(host, market, accounts, sibling, "oco");
                     }
                 }
-                let taker_usdc_balance = account.balances.entry(Currencies::USDC).or_insert(0);
-                *taker_usdc_balance = taker_usdc_balance.checked_add(trade_value).unwrap();
+                let Some(trade_value) = price.checked_notional(qty, Rounding::Up) else {
+                    host.write_debug("Failed to compute trade notional value\n");
+                    continue;
+                };
+                let maker_side = match origin_side {
+                    Side::Bid => Side::Ask,
+                    Side::Ask => Side::Bid,
+                };
+                for (id, user, side) in [
+                    (maker_id, maker_user, maker_side),
+                    (taker_id, taker_user, origin_side),
+                ] {
+                    // A resting order that isn't known traded at its own price
+                    let limit_price = limit_prices.get(&id).copied().unwrap_or(price);
+                    let fills = order_fills.entry(id).or_insert(OrderFills {
+                        user,
+                        side,
+                        price: limit_price,
+                        filled: Qty::ZERO,
+                        paid: 0,
+                    });
+                    fills.filled = fills.filled.saturating_add(qty);
+                    fills.paid = fills.paid.saturating_add(trade_value);
+                }
+                let (fee_currency, taker_fee) = handle_trade_event(
+                    host,
+                    market,
+                    accounts,
+                    origin_side,
+                    maker_user,
+                    taker_user,
+                    price,
+                    qty,
+                    trade_value,
+                );
+                let trade_id = market.trade_count;
+                market.trade_count += 1;
+                for (user, order_id, role, side, fee) in [
+                    (maker_user, maker_id, FillRole::Maker, maker_side, 0),
+                    (
+                        taker_user,
+                        taker_id,
+                        FillRole::Taker,
+                        origin_side,
+                        taker_fee,
+                    ),
+                ] {
+                    let fill = Fill {
+                        trade_id,
+                        order_id,
+                        role,
+                        side,
+                        price,
+                        qty,
+                        fee,
+                        fee_currency,
+                        level: market.level,
+                    };
+                    fills::append_fill(host, &user, &fill).unwrap();
+                }
             }
-        }
-    } else {
-        let maker_account = get_or_load_account(host, accounts, maker_user);
-        match maker_side {
-            Side::Ask => {
-                let maker_usdc_balance =
-                    maker_account.balances.entry(Currencies::USDC).or_insert(0);
-                *maker_usdc_balance = maker_usdc_balance.checked_add(trade_value).unwrap();
+            Event::Done { id, user } => {
+                let account = get_or_load_account(host, accounts, user);
+                account.orders.remove(&id);
             }
-            Side::Bid => {
-                let maker_xtz_balance = maker_account.balances.entry(Currencies::XTZ).or_insert(0);
-                *maker_xtz_balance = maker_xtz_balance.checked_add(qty).unwrap();
+            Event::Cancelled { .. }
+            | Event::Rejected { .. }
+            | Event::MarketHalted { .. }
+            | Event::MarketResumed { .. }
+            | Event::AuctionStarted { .. }
+            | Event::AuctionUncrossed { .. }
+            | Event::StopTriggered { .. }
+            | Event::StateCommitment { .. }
+            | Event::Funding { .. }
+            | Event::Liquidated { .. }
+            | Event::IndexPrice { .. }
+            | Event::DelegateUpdated { .. }
+            | Event::Transfer { .. }
+            | Event::TransferRejected { .. } => {}
+        }
+    }
+
+    for (id, fills) in order_fills {
+        settle_order_fills(host, market, accounts, id, fills);
+    }
+
+    if let Some(event) = halt_event {
+        host.write_debug(&format!("Circuit breaker tripped: {:?}\n", event));
+        host.write_output(&event.rlp_bytes()).unwrap();
+    }
+    prices
+}
+
+/// Cancels a resting or a stop order and gives back what it still reserves.
+/// Returns false if the order doesn't exist anymore.
+fn cancel_order_by_id(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    accounts: &mut Vec<(Address, Account)>,
+    id: u64,
+    reason: &str,
+) -> bool {
+    if let Some(stop) = market.stop_book.stops.remove(&id) {
+        // Stops don't reserve anything until they trigger
+        get_or_load_account(host, accounts, stop.user)
+            .orders
+            .remove(&id);
+        let event = Event::Cancelled {
+            id,
+            user: stop.user,
+            reason: reason.to_string(),
+        };
+        host.write_output(&event.rlp_bytes()).unwrap();
+        return true;
+    }
+
+    let Some(order) = market.orderbook.get_order(id) else {
+        return false;
+    };
+    let (currency, reserved) = audit::order_reservation(&market.config, &order);
+    let account = get_or_load_account(host, accounts, order.user);
+    if !account.release(currency, reserved) {
+        host.write_debug("Failed to release the reservation of cancelled order\n");
+    }
+    account.orders.remove(&id);
+
+    let mut events = vec![];
+    market
+        .orderbook
+        .cancel_with_reason(order.side, id, order.user, reason, &mut events);
+    for event in events {
+        host.write_output(&event.rlp_bytes()).unwrap();
+        host.write_debug(&format!("Order book event: {:?}\n", event));
+    }
+    true
+}
+
+/// Uncrosses the book at the end of an auction, every crossing order trades at one price.
+/// Returns the traded prices.
+fn run_auction(host: &mut impl Runtime, market: &mut Market) -> Vec<Price> {
+    let limit_prices = market
+        .orderbook
+        .orders()
+        .map(|order| (order.id, order.price))
+        .collect();
+    let mut events = vec![];
+    let Some((price, qty)) = market
+        .orderbook
+        .uncross(market.status.last_trade_price, &mut events)
+    else {
+        host.write_debug("Auction ended without crossing orders\n");
+        return vec![];
+    };
+    host.write_debug(&format!(
+        "Auction uncrossed: price={}, qty={}\n",
+        price, qty
+    ));
+    events.push(Event::AuctionUncrossed { price, qty });
+
+    let mut accounts = vec![];
+    let prices = settle_events(host, market, &mut accounts, limit_prices, events);
+    save_accounts(host, market, &mut accounts);
+    prices
+}
+
+/// Publishes the median of the fresh oracle quotes as the index price when it changes, or a
+/// zero index price once too few quotes are fresh.
+fn refresh_index_price(host: &mut impl Runtime, market: &mut Market) {
+    let index_price = market
+        .oracle
+        .median_price(&market.oracle_config, market.level)
+        .unwrap_or(Price::ZERO);
+    market.perp.index_price = index_price;
+    if index_price == market.oracle.index_price {
+        return;
+    }
+    market.oracle.index_price = index_price;
+    let sources = market
+        .oracle
+        .fresh_quotes(&market.oracle_config, market.level)
+        .len();
+    let event = Event::IndexPrice {
+        level: market.level,
+        price: index_price,
+        sources: sources as u32,
+    };
+    host.write_output(&event.rlp_bytes()).unwrap();
+}
+
+/// Ends the current market phase when it is over and announces the next one.
+fn advance_market(host: &mut impl Runtime, market: &mut Market) {
+    let Some(ended) = market.status.advance(&market.config, market.level) else {
+        return;
+    };
+    let mut prices = vec![];
+    if let MarketPhase::Auction { .. } = ended {
+        prices = run_auction(host, market);
+    }
+    let event = match market.status.phase {
+        MarketPhase::Auction { until_level } => Some(Event::AuctionStarted { until_level }),
+        MarketPhase::Continuous => Some(Event::MarketResumed {
+            level: market.level,
+        }),
+        // The auction price tripped the circuit breaker again
+        MarketPhase::Halted { .. } => None,
+    };
+    if let Some(event) = event {
+        host.write_debug(&format!("Market phase: {:?}\n", market.status.phase));
+        host.write_output(&event.rlp_bytes()).unwrap();
+    }
+    run_stops(host, market, prices);
+}
+
+/// Charges the funding of a perpetual once every `funding_interval` levels. Positions pay the
+/// rate derived from the gap between the price the book trades at and the index price of the
+/// oracles, longs to shorts when the book is above the index. A period without either price
+/// is skipped.
+fn run_funding(host: &mut impl Runtime, market: &mut Market) {
+    let interval = market.config.funding_interval;
+    if market.config.kind != MarketKind::Perpetual
+        || interval == 0
+        || market.level < market.perp.funding_level.saturating_add(interval)
+    {
+        return;
+    }
+    market.perp.funding_level = market.level;
+    let Some(mark_price) = market.status.reference_price(&market.orderbook) else {
+        host.write_debug("No mark price, funding skipped\n");
+        return;
+    };
+    let Some(rate_ppm) = market
+        .perp
+        .funding_rate(mark_price, market.config.max_funding_rate_ppm)
+    else {
+        host.write_debug("No index price, funding skipped\n");
+        return;
+    };
+    market.perp.funding_rate_ppm = rate_ppm;
+
+    let mut accounts = vec![];
+    // Every open position is a margin account
+    for address in market.margin_accounts.clone() {
+        let Some(mut account) = Account::load(host, &address).unwrap() else {
+            continue;
+        };
+        if account.position.is_flat() {
+            continue;
+        }
+        let payment = account.position.funding_payment(mark_price, rate_ppm);
+        settle_perp(market, &mut account, -payment);
+        accounts.push((address, account));
+    }
+    save_accounts(host, market, &mut accounts);
+
+    let event = Event::Funding {
+        level: market.level,
+        rate_ppm,
+        mark_price,
+        index_price: market.perp.index_price,
+    };
+    host.write_debug(&format!("Funding: {:?}\n", event));
+    host.write_output(&event.rlp_bytes()).unwrap();
+}
+
+/// Closes out the accounts whose equity fell below the maintenance margin at the mark price.
+/// Runs after every input, in the order the accounts started using margin, so that the
+/// sequencer and the rollup liquidate the same accounts the same way. Every margin account is
+/// checked when the mark price, the level (the interest grows the debts) or the trades
+/// changed since the last time, otherwise only the ones the input saved. Nothing is
+/// liquidated while the market can't trade.
+fn run_liquidations(host: &mut impl Runtime, market: &mut Market) {
+    if market.status.is_halted(market.level) || market.status.is_auction() {
+        return;
+    }
+    let Some(mark_price) = market.mark_price() else {
+        return;
+    };
+    let scan = (mark_price, market.level, market.trade_count);
+    let full_scan = market.last_scan != Some(scan);
+    market.last_scan = Some(scan);
+    let touched = std::mem::take(&mut market.touched);
+    let mut prices = vec![];
+    for address in market.margin_accounts.clone() {
+        if !full_scan && !touched.contains(&address) {
+            continue;
+        }
+        let Some(mut account) = Account::load(host, &address).unwrap() else {
+            continue;
+        };
+        // Only to value the debts, the interest is charged when the account is saved
+        account.accrue_interest(market.level, market.config.borrow_rate_ppm);
+        let Some(margin) = account.margin(mark_price) else {
+            continue;
+        };
+        if margin.meets(market.config.maintenance_margin_bps) {
+            if account.liquidating {
+                let mut accounts = vec![(address, Account::load(host, &address).unwrap().unwrap())];
+                accounts[0].1.liquidating = false;
+                save_accounts(host, market, &mut accounts);
             }
+            continue;
         }
+        host.write_debug(&format!("Liquidating {:?}: {:?}\n", address, margin));
+        prices.extend(liquidate(host, market, address, margin));
+    }
+    run_stops(host, market, prices);
+}
 
-        let taker_account = get_or_load_account(host, accounts, taker_user);
+/// Cancels the orders of an account, closes it out with market orders and, when its
+/// liquidation starts, charges it the liquidation fee. Once it holds nothing, what it still
+/// owes is paid back by the insurance fund, and written off as bad debt beyond it. While it
+/// can't be fully closed out, the fund covers its negative equity and what the fund can't is
+/// reported as bad debt. Returns the traded prices.
+fn liquidate(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    address: Address,
+    margin: MarginState,
+) -> Vec<Price> {
+    let mut accounts = vec![];
+    let orders: Vec<u64> = get_or_load_account(host, &mut accounts, address)
+        .orders
+        .iter()
+        .copied()
+        .collect();
+    for id in orders {
+        cancel_order_by_id(host, market, &mut accounts, id, "liquidation");
+        market.stop_book.unlink(id);
+    }
 
-        match taker_side {
-            Side::Bid => {
-                let taker_xtz_balance = taker_account.balances.entry(Currencies::XTZ).or_insert(0);
-                *taker_xtz_balance = taker_xtz_balance.checked_add(qty).unwrap();
+    let mut events = vec![];
+    let account = get_or_load_account(host, &mut accounts, address);
+    for (side, qty) in liquidation::close_out_orders(market.config.kind, account) {
+        market
+            .orderbook
+            .place_market(address, side, qty, 0, &mut events);
+    }
+    let prices = settle_events(host, market, &mut accounts, BTreeMap::new(), events);
 
-                if taker_fee > 0 {
-                    let taker_usdc_balance =
-                        taker_account.balances.entry(Currencies::USDC).or_insert(0);
-                    if let Some(updated) = taker_usdc_balance.checked_sub(taker_fee) {
-                        *taker_usdc_balance = updated;
-                    } else {
-                        host.write_debug("Taker bid insufficient USDC for fee\n");
-                    }
-                }
+    let account = get_or_load_account(host, &mut accounts, address);
+    accrue_interest(market, account);
+    for (currency, amount) in account.repay_from_available() {
+        market.supply.repay(currency, amount);
+    }
+    let started = !account.liquidating;
+    let fee = if started {
+        let exposure = (margin.debt as u128).saturating_add(margin.position_notional as u128);
+        (exposure * market.config.liquidation_fee_bps as u128 / BPS_DENOMINATOR as u128)
+            .min(account.available(Currencies::USDC) as u128) as u64
+    } else {
+        0
+    };
+    account
+        .balances
+        .insert(Currencies::USDC, account.available(Currencies::USDC) - fee);
+    let closed_out = account.position.is_flat()
+        && account.orders.is_empty()
+        && account.available(Currencies::USDC) == 0
+        && account.available(Currencies::XTZ) == 0;
+    account.liquidating = !closed_out;
+    let debts = [
+        (Currencies::USDC, account.debt(Currencies::USDC)),
+        (Currencies::XTZ, account.debt(Currencies::XTZ)),
+    ];
+    // What the account holds no longer backs all it owes
+    let deficit = account.margin(margin.mark_price).map_or(0, |margin| {
+        (-margin.equity()).clamp(0, u64::MAX as i128) as u64
+    });
+    let fund = get_or_load_account(host, &mut accounts, Address::INSURANCE_FUND);
+    let balance = fund.balances.entry(Currencies::USDC).or_insert(0);
+    *balance = balance.checked_add(fee).unwrap();
 
-                if let Some(reserved_value) = quote_value(qty, order_price) {
-                    if reserved_value >= trade_value {
-                        let refund = reserved_value - trade_value;
-                        if refund > 0 {
-                            let taker_usdc_balance =
-                                taker_account.balances.entry(Currencies::USDC).or_insert(0);
-                            *taker_usdc_balance = taker_usdc_balance.checked_add(refund).unwrap();
-                        }
-                    } else {
-                        host.write_debug("Reserved value lower than trade value for taker bid\n");
-                    }
-                } else {
-                    host.write_debug("Failed to compute reserved value for taker bid\n");
-                }
+    let mut covered_deficit = 0u64;
+    let mut bad_debt = 0u64;
+    if closed_out {
+        for (currency, debt) in debts {
+            let fund = get_or_load_account(host, &mut accounts, Address::INSURANCE_FUND);
+            let covered = debt.min(fund.available(currency));
+            fund.balances
+                .insert(currency, fund.available(currency) - covered);
+            let account = get_or_load_account(host, &mut accounts, address);
+            let balance = account.balances.entry(currency).or_insert(0);
+            *balance = balance.checked_add(covered).unwrap();
+            for (currency, amount) in account.repay_from_available() {
+                market.supply.repay(currency, amount);
             }
-            Side::Ask => {
-                if taker_fee > 0 {
-                    let taker_xtz_balance =
-                        taker_account.balances.entry(Currencies::XTZ).or_insert(0);
-                    if let Some(updated) = taker_xtz_balance.checked_sub(taker_fee) {
-                        *taker_xtz_balance = updated;
-                    } else {
-                        host.write_debug("Taker ask insufficient XTZ for fee\n");
-                    }
-                }
-                let taker_usdc_balance =
-                    taker_account.balances.entry(Currencies::USDC).or_insert(0);
-                *taker_usdc_balance = taker_usdc_balance.checked_add(trade_value).unwrap();
+            let uncovered = debt - covered;
+            if uncovered > 0 {
+                account.write_off(currency, uncovered);
+                market.supply.write_off(currency, uncovered);
+                let value = match currency {
+                    Currencies::USDC => Some(uncovered),
+                    Currencies::XTZ => margin
+                        .mark_price
+                        .checked_notional(Qty(uncovered), Rounding::Up),
+                };
+                bad_debt = bad_debt.saturating_add(value.unwrap_or(u64::MAX));
             }
         }
+    } else if deficit > 0 {
+        // Brought back to zero equity, the rest of its debts stays backed by what it holds
+        let fund = get_or_load_account(host, &mut accounts, Address::INSURANCE_FUND);
+        covered_deficit = deficit.min(fund.available(Currencies::USDC));
+        fund.balances.insert(
+            Currencies::USDC,
+            fund.available(Currencies::USDC) - covered_deficit,
+        );
+        let account = get_or_load_account(host, &mut accounts, address);
+        let balance = account.balances.entry(Currencies::USDC).or_insert(0);
+        *balance = balance.checked_add(covered_deficit).unwrap();
+        for (currency, amount) in account.repay_from_available() {
+            market.supply.repay(currency, amount);
+        }
+        bad_debt = deficit - covered_deficit;
+    }
+    if bad_debt > 0 {
+        let outcome = if closed_out {
+            "written off"
+        } else {
+            "left uncovered"
+        };
+        host.write_debug(&format!(
+            "Bad debt of {:?} {}: {}\n",
+            address, outcome, bad_debt
+        ));
     }
 
-    qty
+    // An account that can't be closed out any further is reported once
+    if started || !prices.is_empty() || covered_deficit > 0 || closed_out {
+        let event = Event::Liquidated {
+            user: address,
+            mark_price: margin.mark_price,
+            fee,
+            bad_debt,
+        };
+        host.write_output(&event.rlp_bytes()).unwrap();
+    }
+    save_accounts(host, market, &mut accounts);
+    prices
 }
 
-fn handle_message(host: &mut impl Runtime, msg: impl AsRef<[u8]>) {
-    let Some((_, msg)) = InboxMessage::<MichelsonBytes>::parse(msg.as_ref()).ok() else {
-        return;
+/// Triggers the stops reached by `prices`, then the ones reached by the trades of the
+/// triggered orders, until the cascade ends. Each stop triggers at most once.
+fn run_stops(host: &mut impl Runtime, market: &mut Market, mut prices: Vec<Price>) {
+    while !prices.is_empty() {
+        let triggered = market.stop_book.trigger(&prices);
+        prices.clear();
+        for stop in triggered {
+            host.write_debug(&format!("Stop triggered: {:?}\n", stop));
+            let event = Event::StopTriggered {
+                id: stop.id,
+                user: stop.user,
+                price: stop.trigger_price,
+            };
+            host.write_output(&event.rlp_bytes()).unwrap();
+
+            let mut accounts = vec![];
+            // The take-profit leg goes away first and gives back what it reserved
+            if let Some(sibling) = market.stop_book.unlink(stop.id) {
+                cancel_order_by_id(host, market, &mut accounts, sibling, "oco");
+            }
+            get_or_load_account(host, &mut accounts, stop.user)
+                .orders
+                .remove(&stop.id);
+            let limit_price = market.config.round_price(stop.limit_price(), stop.side);
+            if let Ok((_, trade_prices)) = submit_limit_order(
+                host,
+                market,
+                &mut accounts,
+                stop.user,
+                stop.to_limit_order(limit_price),
+            ) {
+                prices.extend(trade_prices);
+            }
+            save_accounts(host, market, &mut accounts);
+        }
+    }
+}
+
+/// Runs one inbox message. Fails when its input was refused, which the rollup ignores.
+pub fn handle_input(host: &mut impl Runtime, msg: Message) -> Result<(), Refused> {
+    let Some((_, parsed)) = InboxMessage::<MichelsonBytes>::parse(msg.as_ref()).ok() else {
+        return Ok(());
     };
 
-    let mut orderbook = OrderBook::load(host).unwrap();
-    if let InboxMessage::External(data) = msg {
-        let SignedInput { message, signature }: SignedInput<KernelMessage> =
-            rlp::decode(data).unwrap();
+    if let InboxMessage::External(data) = parsed {
+        // Anyone can post an external message, a malformed one is dropped before it counts
+        let Ok(sequenced) = rlp::decode::<SequencedInput>(data) else {
+            host.write_debug("Malformed sequenced input\n");
+            return Err(Refused);
+        };
+        let Ok(SignedInput { message, signature }) =
+            rlp::decode::<SignedInput<KernelMessage>>(&sequenced.input)
+        else {
+            host.write_debug("Malformed signed input\n");
+            return Err(Refused);
+        };
+        let level = match commitment::input_level(host, &sequenced, msg.level) {
+            Ok(level) => level,
+            Err(e) => {
+                host.write_debug(&format!("Failed to date input: {:?}\n", e));
+                return Err(Refused);
+            }
+        };
+
+        commitment::commit_on_new_level(host, level).unwrap();
+        commitment::count_input(host).unwrap();
+        let mut market = Market::load(host, level);
+        advance_market(host, &mut market);
+        refresh_index_price(host, &mut market);
+        run_funding(host, &mut market);
+
         let result = match message {
             KernelMessage::PlaceOrder(order) => {
-                process_place_order(host, &mut orderbook, order, &signature)
+                process_place_order(host, &mut market, order, &signature)
             }
             KernelMessage::CancelOrder(cancel_order) => {
-                process_cancel_order(host, &mut orderbook, cancel_order, &signature)
+                process_cancel_order(host, &mut market, cancel_order, &signature)
+            }
+            KernelMessage::Faucet(faucet) => process_faucet(host, &mut market, faucet, &signature),
+            KernelMessage::PlaceStop(stop) => {
+                process_place_stop(host, &mut market, stop, &signature)
+            }
+            KernelMessage::PlaceOco(oco) => process_place_oco(host, &mut market, oco, &signature),
+            KernelMessage::OracleUpdate(update) => {
+                process_oracle_update(host, &mut market, update, &signature)
+            }
+            KernelMessage::AddDelegate(grant) => {
+                process_add_delegate(host, &mut market, grant, &signature)
+            }
+            KernelMessage::RevokeDelegate(revoke) => {
+                process_revoke_delegate(host, revoke, &signature)
+            }
+            KernelMessage::Transfer(transfer) => {
+                process_transfer(host, &mut market, transfer, &signature)
             }
-            KernelMessage::Faucet(faucet) => process_faucet(host, faucet, &signature),
         };
 
         if result.is_err() {
-            return;
+            host.write_debug("Input refused\n");
         }
+        run_liquidations(host, &mut market);
+        // Saved even for refused inputs, an auction may have been uncrossed before
+        market.save(host);
+
+        #[cfg(feature = "audit")]
+        run_audit(host);
+        return result.map_err(|_| Refused);
     }
-    orderbook.save(host).unwrap();
+    Ok(())
+}
+
+/// Checks a limit order against the market, reserves what it may spend, then matches it
+/// and settles the resulting trades. Returns the order id and the traded prices.
+fn submit_limit_order(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    accounts: &mut Vec<(Address, Account)>,
+    caller: Address,
+    order: APIOrder,
+) -> Result<(u64, Vec<Price>), ()> {
+    if market.status.is_halted(market.level) {
+        reject_order(
+            host,
+            caller,
+            order.nonce,
+            OrderRejection::MarketHalted.as_str(),
+        );
+        return Err(());
+    }
+    let mark_price = market.mark_price();
+    if let Err(rejection) = market
+        .config
+        .validate_limit(order.price, order.size)
+        .and_then(|_| market.config.validate_display(order.display, order.size))
+        .and_then(|_| market.config.validate_band(order.price, mark_price))
+    {
+        reject_order(host, caller, order.nonce, rejection.as_str());
+        return Err(());
+    }
+
+    let reservation = audit::reservation(&market.config, order.side, order.price, order.size);
+    let Some((currency, amount)) = reservation else {
+        let rejection = OrderRejection::NotionalOverflow;
+        reject_order(host, caller, order.nonce, rejection.as_str());
+        return Err(());
+    };
+    // What the balance doesn't cover is borrowed, as long as the account keeps the initial
+    // margin. Checked on a copy so that a rejected order leaves the account untouched.
+    let mark_price = mark_price.unwrap_or(order.price);
+    let mut account = get_or_load_account(host, accounts, caller).clone();
+    let interest = account.accrue_interest(market.level, market.config.borrow_rate_ppm);
+    // A spot order may pay the taker fee on top of its reservation, out of the same currency
+    let taker_fee = match market.config.kind {
+        MarketKind::Spot => trading_fee(amount),
+        MarketKind::Perpetual => 0,
+    };
+    let borrowed = amount.saturating_sub(account.available(currency));
+    let mut adds_risk = amount.saturating_add(taker_fee) > account.available(currency);
+    account.borrow(currency, borrowed);
+    if !account.lock(currency, amount) {
+        reject_order(host, caller, order.nonce, "insufficient_balance");
+        return Err(());
+    }
+    // The margin is checked with the taker fee already paid, borrowed when it has to be
+    let mut paid = account.clone();
+    paid.borrow(currency, taker_fee.saturating_sub(paid.available(currency)));
+    paid.balances
+        .insert(currency, paid.available(currency) - taker_fee);
+    let mut margin = paid.margin(mark_price);
+    if market.config.kind == MarketKind::Perpetual {
+        // Checked against the position the order leaves once fully filled
+        let size = account.position.size as i128;
+        let after = match order.side {
+            Side::Bid => size + order.size.raw() as i128,
+            Side::Ask => size - order.size.raw() as i128,
+        };
+        adds_risk |= after.unsigned_abs() > size.unsigned_abs();
+        let notional = u64::try_from(after.unsigned_abs())
+            .ok()
+            .and_then(|after| mark_price.checked_notional(Qty(after), Rounding::Up));
+        margin = margin
+            .zip(notional)
+            .map(|(margin, position_notional)| MarginState {
+                position_notional,
+                ..margin
+            });
+    }
+    if adds_risk && !margin.is_some_and(|margin| margin.meets(market.config.initial_margin_bps)) {
+        reject_order(host, caller, order.nonce, "insufficient_margin");
+        return Err(());
+    }
+    for (currency, interest) in interest {
+        market.supply.charge_interest(currency, interest);
+    }
+    market.supply.borrow(currency, borrowed);
+    *get_or_load_account(host, accounts, caller) = account;
+
+    let mut events = vec![];
+    let order_id = if market.status.is_auction() {
+        market.orderbook.place_auction_limit(
+            caller,
+            order.side,
+            order.price,
+            order.size,
+            order.display,
+            order.nonce,
+            &mut events,
+        )
+    } else {
+        market.orderbook.place_iceberg(
+            caller,
+            order.side,
+            order.price,
+            order.size,
+            order.display,
+            order.nonce,
+            &mut events,
+        )
+    };
+    // Removed again by its `Done` event if it is filled right away
+    get_or_load_account(host, accounts, caller)
+        .orders
+        .insert(order_id);
+    let prices = settle_events(host, market, accounts, BTreeMap::new(), events);
+    Ok((order_id, prices))
+}
+
+/// Account a signature acts for: the owner of a delegate key allowed to sign `action`, or
+/// the signer's own account. On refusal, returns the owner and why.
+fn resolve_signer(
+    host: &mut impl Runtime,
+    market: &Market,
+    signer: Address,
+    action: DelegatedAction,
+) -> Result<Address, (Address, DelegateRejection)> {
+    let Some(key) = DelegateKey::load(host, &signer).unwrap() else {
+        return Ok(signer);
+    };
+    if key.revoked {
+        return Err((key.owner, DelegateRejection::Revoked));
+    }
+    let grant = delegate::load_delegates(host, &key.owner)
+        .unwrap()
+        .into_iter()
+        .find(|grant| grant.key == signer);
+    let Some(grant) = grant else {
+        return Err((key.owner, DelegateRejection::Revoked));
+    };
+    grant
+        .allows(action, market.level)
+        .map(|_| key.owner)
+        .map_err(|rejection| (key.owner, rejection))
+}
+
+/// Resolves the account an order is placed for, rejecting it when a delegate key signed it
+/// beyond what it was granted.
+fn resolve_order_signer(
+    host: &mut impl Runtime,
+    market: &Market,
+    signer: Address,
+    notional: Option<u64>,
+    nonce: u64,
+) -> Result<Address, ()> {
+    resolve_signer(host, market, signer, DelegatedAction::Place(notional))
+        .map_err(|(owner, rejection)| reject_order(host, owner, nonce, rejection.as_str()))
+}
+
+/// Rejects an order whose nonce isn't above the one of the account it is placed for. An
+/// accepted input moves the account nonce to its own, so that none can be replayed.
+fn check_order_nonce(
+    host: &mut impl Runtime,
+    accounts: &mut Vec<(Address, Account)>,
+    caller: Address,
+    nonce: u64,
+) -> Result<(), ()> {
+    if nonce <= get_or_load_account(host, accounts, caller).nonce {
+        reject_order(host, caller, nonce, "invalid_nonce");
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Largest value the order placed by a stop can have: at its trigger price plus slippage.
+fn stop_notional(stop: &APIStopOrder) -> Option<u64> {
+    stop.trigger_price
+        .raw()
+        .checked_add(stop.slippage.raw())
+        .and_then(|price| Price(price).checked_notional(stop.size, Rounding::Up))
 }
 
 fn process_place_order(
     host: &mut impl Runtime,
-    orderbook: &mut OrderBook,
+    market: &mut Market,
     order: APIOrder,
     signature: &[u8],
 ) -> Result<(), ()> {
@@ -242,172 +1175,159 @@ fn process_place_order(
         order.side, order.size, order.price
     ));
     let signature = Signature::from_raw(signature).map_err(|_| ())?;
-    let caller = Address::from(
+    let signer = Address::from(
         signature
             .recover_address_from_msg(order.rlp_bytes())
             .map_err(|_| ())?,
     );
+    let notional = order.price.checked_notional(order.size, Rounding::Up);
+    let caller = resolve_order_signer(host, market, signer, notional, order.nonce)?;
 
-    let caller_account = Account::load(host, &caller)
-        .unwrap()
-        .unwrap_or(Account::new(caller));
-    let mut accounts = vec![(caller, caller_account)];
+    let mut accounts = vec![];
+    check_order_nonce(host, &mut accounts, caller, order.nonce)?;
+    let (_, prices) = submit_limit_order(host, market, &mut accounts, caller, order)?;
+    get_or_load_account(host, &mut accounts, caller).nonce = order.nonce;
+    save_accounts(host, market, &mut accounts);
 
-    {
-        let caller_account = get_or_load_account(host, &mut accounts, caller);
-        caller_account.nonce = caller_account.nonce.checked_add(1).unwrap();
-        match order.side {
-            Side::Ask => {
-                let balance = caller_account.balances.entry(Currencies::XTZ).or_insert(0);
-                let Some(total_xtz) = amount_with_fee(order.size) else {
-                    host.write_debug("Failed to compute total XTZ with fee for ask order\n");
-                    return Err(());
-                };
-                if *balance < total_xtz {
-                    host.write_debug("Insufficient XTZ balance for ask order\n");
-                    return Err(());
-                }
-                *balance = balance
-                    .checked_sub(order.size)
-                    .expect("balance >= order.size");
-            }
-            Side::Bid => {
-                let balance = caller_account.balances.entry(Currencies::USDC).or_insert(0);
-                let Some(required_usdc) = quote_value(order.size, order.price) else {
-                    host.write_debug("Failed to compute notional value for bid order\n");
-                    return Err(());
-                };
-                let Some(total_usdc) = amount_with_fee(required_usdc) else {
-                    host.write_debug("Failed to compute total USDC with fee for bid order\n");
-                    return Err(());
-                };
-                if *balance < total_usdc {
-                    host.write_debug("Insufficient USDC balance for bid order\n");
-                    return Err(());
-                }
-                *balance = balance
-                    .checked_sub(required_usdc)
-                    .expect("balance >= required_usdc");
-            }
-        }
-    }
+    run_stops(host, market, prices);
+    Ok(())
+}
 
-    let mut events = vec![];
-    let order_id = orderbook.place_limit(
-        caller,
-        order.side,
-        order.price,
-        order.size,
-        order.nonce,
-        &mut events,
+fn process_place_stop(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    stop: APIStopOrder,
+    signature: &[u8],
+) -> Result<(), ()> {
+    host.write_debug(&format!("Received Stop Order: {:?}\n", stop));
+    let signature = Signature::from_raw(signature).map_err(|_| ())?;
+    let signer = Address::from(
+        signature
+            .recover_address_from_msg(stop.rlp_bytes())
+            .map_err(|_| ())?,
     );
+    let caller = resolve_order_signer(host, market, signer, stop_notional(&stop), stop.nonce)?;
+    if let Err(rejection) = market.config.validate_stop(&stop) {
+        reject_order(host, caller, stop.nonce, rejection.as_str());
+        return Err(());
+    }
 
-    let mut taker_total_filled: u64 = 0;
-    let mut taker_done = false;
+    let mut accounts = vec![];
+    check_order_nonce(host, &mut accounts, caller, stop.nonce)?;
 
-    for event in events {
-        host.write_output(&event.rlp_bytes()).unwrap();
-        match event {
-            Event::Placed { .. } => {}
-            Event::Trade {
-                maker_user,
-                taker_user,
-                price,
-                qty,
-                ..
-            } => {
-                let Some(trade_value) = quote_value(qty, price) else {
-                    host.write_debug("Failed to compute trade notional value\n");
-                    continue;
-                };
-                taker_total_filled = taker_total_filled.saturating_add(handle_trade_event(
-                    host,
-                    &mut accounts,
-                    order.side,
-                    order.price,
-                    maker_user,
-                    taker_user,
-                    qty,
-                    trade_value,
-                ));
-            }
-            Event::Done { id, user } => {
-                let account = get_or_load_account(host, &mut accounts, user);
-                account.orders.remove(&id);
-                if user == caller && id == order_id {
-                    taker_done = true;
-                }
-            }
-            Event::Cancelled { .. } => {}
-        }
+    let id = market.orderbook.alloc_id();
+    market.stop_book.insert(StopOrder::new(id, caller, &stop));
+    let account = get_or_load_account(host, &mut accounts, caller);
+    account.orders.insert(id);
+    account.nonce = stop.nonce;
+    save_accounts(host, market, &mut accounts);
+
+    // A stop already beyond the last price triggers right away, a trailing one catches up
+    if let Some(price) = market.status.last_trade_price {
+        run_stops(host, market, vec![price]);
     }
+    Ok(())
+}
 
-    let caller_account = get_or_load_account(host, &mut accounts, caller);
-    if taker_done || taker_total_filled >= order.size {
-        caller_account.orders.remove(&order_id);
-    } else {
-        caller_account.orders.insert(order_id);
+fn process_place_oco(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    oco: APIOcoOrder,
+    signature: &[u8],
+) -> Result<(), ()> {
+    host.write_debug(&format!("Received OCO Order: {:?}\n", oco));
+    let signature = Signature::from_raw(signature).map_err(|_| ())?;
+    let signer = Address::from(
+        signature
+            .recover_address_from_msg(oco.rlp_bytes())
+            .map_err(|_| ())?,
+    );
+    let notional = oco
+        .limit
+        .price
+        .checked_notional(oco.limit.size, Rounding::Up)
+        .zip(stop_notional(&oco.stop))
+        .map(|(limit, stop)| limit.max(stop));
+    let caller = resolve_order_signer(host, market, signer, notional, oco.limit.nonce)?;
+    if let Err(rejection) = market.config.validate_oco(&oco) {
+        reject_order(host, caller, oco.limit.nonce, rejection.as_str());
+        return Err(());
     }
 
-    for (_, account) in &accounts {
-        account.save(host).unwrap();
+    let mut accounts = vec![];
+    check_order_nonce(host, &mut accounts, caller, oco.limit.nonce)?;
+    let (order_id, prices) = submit_limit_order(host, market, &mut accounts, caller, oco.limit)?;
+    get_or_load_account(host, &mut accounts, caller).nonce = oco.limit.nonce;
+    // Every trade of a continuous placement involves the incoming order: when the limit
+    // leg traded right away the stop leg is already cancelled
+    if prices.is_empty() {
+        let stop_id = market.orderbook.alloc_id();
+        market
+            .stop_book
+            .insert(StopOrder::new(stop_id, caller, &oco.stop));
+        market.stop_book.link(order_id, stop_id);
+        get_or_load_account(host, &mut accounts, caller)
+            .orders
+            .insert(stop_id);
+    } else {
+        host.write_debug("OCO limit leg traded on placement, stop leg dropped\n");
     }
+    save_accounts(host, market, &mut accounts);
+
+    run_stops(host, market, prices);
     Ok(())
 }
 
 fn process_cancel_order(
     host: &mut impl Runtime,
-    orderbook: &mut OrderBook,
+    market: &mut Market,
     cancel_order: CancelOrder,
     signature: &[u8],
 ) -> Result<(), ()> {
     host.write_debug("Received Cancel Order\n");
     let signature = Signature::from_raw(signature).map_err(|_| ())?;
-    let caller = Address::from(
+    let signer = Address::from(
         signature
             .recover_address_from_msg(cancel_order.rlp_bytes())
             .map_err(|_| ())?,
     );
-    let mut account = Account::load(host, &caller)
-        .unwrap()
-        .unwrap_or(Account::new(caller));
+    let caller = match resolve_signer(host, market, signer, DelegatedAction::Cancel) {
+        Ok(caller) => caller,
+        Err((owner, rejection)) => {
+            reject_input(host, owner, rejection.as_str());
+            return Err(());
+        }
+    };
+    let mut accounts = vec![];
+    let account = get_or_load_account(host, &mut accounts, caller);
     if !account.orders.contains(&cancel_order.order_id) {
-        host.write_debug("Order not found in account during cancel\n");
+        reject_input(host, caller, "order_not_found");
         return Err(());
     }
 
-    if let Some(order) = orderbook.get_order(cancel_order.order_id) {
-        match order.side {
-            Side::Ask => {
-                let balance = account.balances.entry(Currencies::XTZ).or_insert(0);
-                *balance = balance.checked_add(order.remaining).unwrap();
-            }
-            Side::Bid => {
-                if let Some(refund) = quote_value(order.remaining, order.price) {
-                    let balance = account.balances.entry(Currencies::USDC).or_insert(0);
-                    *balance = balance.checked_add(refund).unwrap();
-                } else {
-                    host.write_debug("Failed to compute refund for cancelled bid order\n");
-                }
-            }
-        }
-        account.orders.remove(&cancel_order.order_id);
-        account.save(host).unwrap();
-
-        let mut events = vec![];
-        orderbook.cancel(order.side, cancel_order.order_id, caller, &mut events);
-        for event in events {
-            host.write_output(&event.rlp_bytes()).unwrap();
-            host.write_debug(&format!("Order book event: {:?}\n", event));
-        }
+    if cancel_order_by_id(
+        host,
+        market,
+        &mut accounts,
+        cancel_order.order_id,
+        "by_user",
+    ) {
+        // The other leg of an OCO group stays on its own
+        market.stop_book.unlink(cancel_order.order_id);
+        save_accounts(host, market, &mut accounts);
         Ok(())
     } else {
-        host.write_debug("Order not found in orderbook during cancel\n");
+        reject_input(host, caller, "order_not_found");
         Err(())
     }
 }
 
-fn process_faucet(host: &mut impl Runtime, faucet: Faucet, signature: &[u8]) -> Result<(), ()> {
+fn process_faucet(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    faucet: Faucet,
+    signature: &[u8],
+) -> Result<(), ()> {
     let signature = Signature::from_raw(signature).map_err(|_| ())?;
     let caller = Address::from(
         signature
@@ -418,26 +1338,224 @@ fn process_faucet(host: &mut impl Runtime, faucet: Faucet, signature: &[u8]) ->
         "Faucet request: user={:?}, amount={} currency={:?}\n",
         caller, faucet.amount, faucet.currency
     ));
-    let mut account = Account::load(host, &caller)
-        .unwrap()
-        .unwrap_or(Account::new(caller));
-    match faucet.currency {
-        Currencies::XTZ => {
-            let balance = account.balances.entry(Currencies::XTZ).or_insert(0);
-            *balance = balance.checked_add(faucet.amount).unwrap();
-        }
-        Currencies::USDC => {
-            let balance = account.balances.entry(Currencies::USDC).or_insert(0);
-            *balance = balance.checked_add(faucet.amount).unwrap();
+    let mut accounts = vec![];
+    let account = get_or_load_account(host, &mut accounts, caller);
+    let balance = account.balances.entry(faucet.currency).or_insert(0);
+    *balance = balance.checked_add(faucet.amount).unwrap();
+    market.supply.mint(faucet.currency, faucet.amount);
+    save_accounts(host, market, &mut accounts);
+    Ok(())
+}
+
+/// Grants a delegate key of the signing account. A key delegates for one account only, and
+/// never for an account of its own.
+fn process_add_delegate(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    grant: DelegateGrant,
+    signature: &[u8],
+) -> Result<(), ()> {
+    let signature = Signature::from_raw(signature).map_err(|_| ())?;
+    let owner = Address::from(
+        signature
+            .recover_address_from_msg(grant.rlp_bytes())
+            .map_err(|_| ())?,
+    );
+    host.write_debug(&format!(
+        "Add delegate: owner={:?}, grant={:?}\n",
+        owner, grant
+    ));
+    let rejection = if DelegateKey::load(host, &owner).unwrap().is_some() {
+        // Delegates can't grant
+        Some(DelegateRejection::OutOfScope)
+    } else if grant.key == owner
+        || DelegateKey::load(host, &grant.key).unwrap().is_some()
+        || Account::load(host, &grant.key).unwrap().is_some()
+    {
+        Some(DelegateRejection::KeyInUse)
+    } else if grant.expiry_level <= market.level {
+        Some(DelegateRejection::AlreadyExpired)
+    } else {
+        None
+    };
+    if let Some(rejection) = rejection {
+        reject_input(host, owner, rejection.as_str());
+        return Err(());
+    }
+
+    DelegateKey {
+        owner,
+        revoked: false,
+    }
+    .save(host, &grant.key)
+    .unwrap();
+    let mut delegates = delegate::load_delegates(host, &owner).unwrap();
+    delegates.push(grant);
+    delegate::save_delegates(host, &owner, &delegates).unwrap();
+    let event = Event::DelegateUpdated {
+        owner,
+        key: grant.key,
+        expiry_level: grant.expiry_level,
+        revoked: false,
+    };
+    host.write_output(&event.rlp_bytes()).unwrap();
+    Ok(())
+}
+
+/// Revokes a delegate key of the signing account for good.
+fn process_revoke_delegate(
+    host: &mut impl Runtime,
+    revoke: RevokeDelegate,
+    signature: &[u8],
+) -> Result<(), ()> {
+    let signature = Signature::from_raw(signature).map_err(|_| ())?;
+    let owner = Address::from(
+        signature
+            .recover_address_from_msg(revoke.rlp_bytes())
+            .map_err(|_| ())?,
+    );
+    host.write_debug(&format!(
+        "Revoke delegate: owner={:?}, key={:?}\n",
+        owner, revoke.key
+    ));
+    match DelegateKey::load(host, &revoke.key).unwrap() {
+        Some(key) if key.owner == owner && !key.revoked => {}
+        _ => {
+            reject_input(host, owner, DelegateRejection::NotFound.as_str());
+            return Err(());
         }
     }
-    account.save(host).unwrap();
+
+    let mut delegates = delegate::load_delegates(host, &owner).unwrap();
+    let Some(position) = delegates.iter().position(|grant| grant.key == revoke.key) else {
+        reject_input(host, owner, DelegateRejection::NotFound.as_str());
+        return Err(());
+    };
+    let grant = delegates.remove(position);
+    delegate::save_delegates(host, &owner, &delegates).unwrap();
+    DelegateKey {
+        owner,
+        revoked: true,
+    }
+    .save(host, &revoke.key)
+    .unwrap();
+    let event = Event::DelegateUpdated {
+        owner,
+        key: revoke.key,
+        expiry_level: grant.expiry_level,
+        revoked: true,
+    };
+    host.write_output(&event.rlp_bytes()).unwrap();
+    Ok(())
+}
+
+fn process_oracle_update(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    update: OracleUpdate,
+    signature: &[u8],
+) -> Result<(), ()> {
+    let signature = Signature::from_raw(signature).map_err(|_| ())?;
+    let caller = Address::from(
+        signature
+            .recover_address_from_msg(update.rlp_bytes())
+            .map_err(|_| ())?,
+    );
+    host.write_debug(&format!(
+        "Oracle update: oracle={:?}, price={}, level={}\n",
+        caller, update.price, update.level
+    ));
+    if let Err(rejection) =
+        market
+            .oracle
+            .submit(&market.oracle_config, caller, update, market.level)
+    {
+        reject_input(host, caller, rejection.as_str());
+        return Err(());
+    }
+    refresh_index_price(host, market);
+    Ok(())
+}
+
+/// Moves available balance from the signer's account to another one. Nothing is borrowed
+/// for a transfer, and an account using margin must keep the initial margin without it.
+/// Delegate keys sign for their own account here, never for their owner's.
+fn process_transfer(
+    host: &mut impl Runtime,
+    market: &mut Market,
+    transfer: Transfer,
+    signature: &[u8],
+) -> Result<(), ()> {
+    let signature = Signature::from_raw(signature).map_err(|_| ())?;
+    let caller = Address::from(
+        signature
+            .recover_address_from_msg(transfer.rlp_bytes())
+            .map_err(|_| ())?,
+    );
+    host.write_debug(&format!(
+        "Transfer: from={:?}, to={:?}, amount={} currency={:?}\n",
+        caller, transfer.to, transfer.amount, transfer.currency
+    ));
+    if transfer.amount == 0 || transfer.to == caller {
+        reject_transfer(host, caller, transfer.nonce, "invalid_transfer");
+        return Err(());
+    }
+
+    let mut accounts = vec![];
+    let mark_price = market.mark_price();
+    let account = get_or_load_account(host, &mut accounts, caller);
+    if transfer.nonce <= account.nonce {
+        reject_transfer(host, caller, transfer.nonce, "invalid_nonce");
+        return Err(());
+    }
+    let Some(available) = account
+        .available(transfer.currency)
+        .checked_sub(transfer.amount)
+    else {
+        reject_transfer(host, caller, transfer.nonce, "insufficient_balance");
+        return Err(());
+    };
+    let mut sent = account.clone();
+    sent.accrue_interest(market.level, market.config.borrow_rate_ppm);
+    sent.balances.insert(transfer.currency, available);
+    if sent.uses_margin()
+        && !mark_price
+            .and_then(|mark_price| sent.margin(mark_price))
+            .is_some_and(|margin| margin.meets(market.config.initial_margin_bps))
+    {
+        reject_transfer(host, caller, transfer.nonce, "insufficient_margin");
+        return Err(());
+    }
+
+    account.balances.insert(transfer.currency, available);
+    account.nonce = transfer.nonce;
+    let recipient = get_or_load_account(host, &mut accounts, transfer.to);
+    let balance = recipient.balances.entry(transfer.currency).or_insert(0);
+    *balance = balance.checked_add(transfer.amount).unwrap();
+    let event = Event::Transfer {
+        from: caller,
+        to: transfer.to,
+        currency: transfer.currency,
+        amount: transfer.amount,
+    };
+    host.write_output(&event.rlp_bytes()).unwrap();
+    save_accounts(host, market, &mut accounts);
     Ok(())
 }
 
+/// Audits the whole state after each input, for debug kernels built with the `audit` feature.
+#[cfg(feature = "audit")]
+fn run_audit(host: &mut impl Runtime) {
+    let report = audit::audit(host).unwrap();
+    for discrepancy in &report.discrepancies {
+        host.write_debug(&format!("Audit discrepancy: {}\n", discrepancy));
+    }
+    debug_assert!(report.is_sound(), "{:?}", report);
+}
+
 #[entrypoint::main]
 pub fn kernel_loop<Host: tezos_smart_rollup_host::runtime::Runtime>(host: &mut Host) {
     while let Some(msg) = host.read_input().unwrap() {
-        handle_message(host, msg);
+        let _ = handle_input(host, msg);
     }
 }
@@ -0,0 +1,164 @@
+use tezos_smart_rollup::prelude::Runtime;
+use tezos_smart_rollup_host::{path::RefPath, runtime::RuntimeError};
+use tradez_types::{
+    address::Address,
+    currencies::Currencies,
+    error::TradezError,
+    market::MarketKind,
+    position::Side,
+    units::{Price, Qty},
+};
+
+use crate::account::Account;
+
+/// Addresses of the accounts that owe something or hold a perpetual position, in the order
+/// they started to. The liquidation engine and the funding payments walk them in that order.
+pub const MARGIN_ACCOUNTS_PATH: RefPath = RefPath::assert_from(b"/tradez/margin_accounts");
+
+pub fn load_margin_accounts(host: &mut impl Runtime) -> Result<Vec<Address>, TradezError> {
+    match host.store_read_all(&MARGIN_ACCOUNTS_PATH) {
+        Ok(data) => rlp::Rlp::new(&data)
+            .as_list()
+            .map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(vec![]),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+pub fn save_margin_accounts(
+    host: &mut impl Runtime,
+    addresses: &[Address],
+) -> Result<(), TradezError> {
+    host.store_write_all(&MARGIN_ACCOUNTS_PATH, &rlp::encode_list(addresses))
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+/// Mark price, level and trade count of the last scan of every margin account. Until one of
+/// them changes, the margins only change for the accounts an input saves.
+pub const LIQUIDATION_SCAN_PATH: RefPath = RefPath::assert_from(b"/tradez/liquidation_scan");
+
+pub fn load_last_scan(host: &mut impl Runtime) -> Result<Option<(Price, u32, u64)>, TradezError> {
+    match host.store_read_all(&LIQUIDATION_SCAN_PATH) {
+        Ok(data) => decode_scan(&rlp::Rlp::new(&data))
+            .map(Some)
+            .map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(None),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+fn decode_scan(rlp: &rlp::Rlp) -> Result<(Price, u32, u64), rlp::DecoderError> {
+    Ok((rlp.val_at(0)?, rlp.val_at(1)?, rlp.val_at(2)?))
+}
+
+pub fn save_last_scan(
+    host: &mut impl Runtime,
+    (mark_price, level, trade_count): (Price, u32, u64),
+) -> Result<(), TradezError> {
+    let mut stream = rlp::RlpStream::new_list(3);
+    stream
+        .append(&mark_price)
+        .append(&level)
+        .append(&trade_count);
+    host.store_write_all(&LIQUIDATION_SCAN_PATH, &stream.out())
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+/// Adds or removes an account from the margin accounts as it starts or stops using margin.
+pub fn track_margin_account(addresses: &mut Vec<Address>, account: &Account) {
+    let position = addresses
+        .iter()
+        .position(|address| *address == account.address);
+    match (position, account.uses_margin()) {
+        (None, true) => addresses.push(account.address),
+        (Some(position), false) => {
+            addresses.remove(position);
+        }
+        _ => {}
+    }
+}
+
+/// Market orders closing out an account whose open orders are cancelled: its perpetual
+/// position, or on the spot market the XTZ it holds when it owes USDC and the XTZ it owes
+/// beyond what it holds.
+pub fn close_out_orders(kind: MarketKind, account: &Account) -> Vec<(Side, Qty)> {
+    let mut orders = vec![];
+    match kind {
+        MarketKind::Perpetual => {
+            let size = account.position.abs_size();
+            if account.position.size > 0 {
+                orders.push((Side::Ask, size));
+            } else if account.position.size < 0 {
+                orders.push((Side::Bid, size));
+            }
+        }
+        MarketKind::Spot => {
+            let xtz = account.available(Currencies::XTZ);
+            if account.debt(Currencies::USDC) > account.available(Currencies::USDC) && xtz > 0 {
+                orders.push((Side::Ask, Qty(xtz)));
+            }
+            let short = account.debt(Currencies::XTZ).saturating_sub(xtz);
+            if short > 0 {
+                orders.push((Side::Bid, Qty(short)));
+            }
+        }
+    }
+    orders
+}
+
+#[cfg(test)]
+mod tests {
+    use tradez_types::{perpetual::PerpPosition, units::Price};
+
+    use super::*;
+
+    #[test]
+    fn close_out() {
+        let mut account = Account::new(Address::from([1u8; 20]));
+        assert!(close_out_orders(MarketKind::Spot, &account).is_empty());
+
+        // Long XTZ on borrowed USDC
+        account.borrow(Currencies::USDC, 500);
+        account.balances.insert(Currencies::USDC, 100);
+        account.balances.insert(Currencies::XTZ, 300);
+        assert_eq!(
+            close_out_orders(MarketKind::Spot, &account),
+            vec![(Side::Ask, Qty(300))]
+        );
+
+        // Short XTZ
+        let mut account = Account::new(Address::from([2u8; 20]));
+        account.borrow(Currencies::XTZ, 300);
+        account.balances.insert(Currencies::XTZ, 50);
+        assert_eq!(
+            close_out_orders(MarketKind::Spot, &account),
+            vec![(Side::Bid, Qty(250))]
+        );
+
+        account.position = PerpPosition {
+            size: -70,
+            entry_price: Price(2_000_000),
+        };
+        assert_eq!(
+            close_out_orders(MarketKind::Perpetual, &account),
+            vec![(Side::Bid, Qty(70))]
+        );
+    }
+
+    #[test]
+    fn margin_accounts() {
+        let mut addresses = vec![];
+        let mut account = Account::new(Address::from([1u8; 20]));
+        track_margin_account(&mut addresses, &account);
+        assert!(addresses.is_empty());
+
+        account.borrow(Currencies::USDC, 10);
+        track_margin_account(&mut addresses, &account);
+        track_margin_account(&mut addresses, &account);
+        assert_eq!(addresses, vec![account.address]);
+
+        account.repay_from_available();
+        track_margin_account(&mut addresses, &account);
+        assert!(addresses.is_empty());
+    }
+}
@@ -7,10 +7,13 @@ pub struct SmartRollupInstallerConfig {
     pub verbose: bool,
 }
 
+/// Builds the installer kernel the rollup is originated with. It upgrades to `kernel_path`
+/// after applying the instructions of `setup_file` to the durable storage, if any.
 pub fn create_installer(
     kernel_path: &Path,
     preimages_folder: &Path,
     output_path: &Path,
+    setup_file: Option<&Path>,
     config: SmartRollupInstallerConfig,
 ) {
     let mut command = Command::new("smart-rollup-installer");
@@ -23,6 +26,9 @@ pub fn create_installer(
         .arg("--preimages-dir")
         .arg(preimages_folder)
         .arg("--display-root-hash");
+    if let Some(setup_file) = setup_file {
+        command.arg("--setup-file").arg(setup_file);
+    }
     run_command(
         &mut command,
         "smart-rollup-installer",
@@ -129,6 +129,26 @@ pub struct ValueError {
     pub msg: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct BatcherQueueResponse {
+    status: String,
+    #[serde(default)]
+    l1_level: Option<u32>,
+}
+
+/// Progress of a message handed to the batcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatcherMessageStatus {
+    /// Waiting in the batcher for an L1 operation.
+    Pending,
+    /// Sent in an L1 operation not included in a block yet.
+    Injected,
+    /// In the rollup inbox, at `l1_level` when the node reports it.
+    Included { l1_level: Option<u32> },
+    /// The batcher doesn't know the message, or forgot it.
+    Unknown,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 enum ValueResponse {
@@ -144,10 +164,11 @@ impl SmartRollupClient {
         }
     }
 
+    /// Hands messages to the batcher of the rollup node. Returns the id it gave each of them.
     pub async fn inject_inbox_messages(
         &self,
         inbox_message: Vec<Vec<u8>>,
-    ) -> Result<(), OctezError> {
+    ) -> Result<Vec<String>, OctezError> {
         let res = self
             .client
             .post(format!("{}/local/batcher/injection", self.api_addr))
@@ -158,23 +179,86 @@ impl SmartRollupClient {
                     .collect::<Vec<String>>(),
             )
             .send()
-            .await
-            .unwrap();
+            .await?;
 
         if res.status() == 200 {
-            Ok(())
+            Ok(res.json().await?)
         } else {
             let err_text = res.text().await.unwrap();
             Err(crate::error::OctezError::HttpResponseError(err_text))
         }
     }
 
+    /// Where a message handed to the batcher is, from its id.
+    pub async fn get_batcher_message_status(
+        &self,
+        message_id: &str,
+    ) -> Result<BatcherMessageStatus, OctezError> {
+        let res = self
+            .client
+            .get(format!(
+                "{}/local/batcher/queue/{}",
+                self.api_addr, message_id
+            ))
+            .send()
+            .await?;
+
+        if res.status() == 404 {
+            return Ok(BatcherMessageStatus::Unknown);
+        }
+        if res.status() != 200 {
+            return Err(OctezError::HttpResponseError(format!(
+                "Unhandled response status: {}",
+                res.status()
+            )));
+        }
+        let content: Option<BatcherQueueResponse> = res.json().await?;
+        Ok(match content {
+            None => BatcherMessageStatus::Unknown,
+            Some(content) => match content.status.as_str() {
+                "pending_batch" | "pending_injection" => BatcherMessageStatus::Pending,
+                "injected" => BatcherMessageStatus::Injected,
+                "included" | "committed" => BatcherMessageStatus::Included {
+                    l1_level: content.l1_level,
+                },
+                _ => BatcherMessageStatus::Unknown,
+            },
+        })
+    }
+
+    /// L1 level of the last block processed by the rollup node.
+    pub async fn get_l1_level(&self) -> Result<u32, OctezError> {
+        let res = self
+            .client
+            .get(format!("{}/global/block/head/level", self.api_addr))
+            .send()
+            .await?;
+
+        if res.status() == 200 {
+            Ok(res.json().await?)
+        } else {
+            Err(OctezError::HttpResponseError(format!(
+                "Unhandled response status: {}",
+                res.status()
+            )))
+        }
+    }
+
     pub async fn get_value(&self, key: &str) -> Result<Option<Vec<u8>>, OctezError> {
+        self.get_value_at("head", key).await
+    }
+
+    /// Value of a durable storage key in the state of `block`, a hash, a level or "head".
+    pub async fn get_value_at(
+        &self,
+        block: &str,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, OctezError> {
         let res = self
             .client
             .get(format!(
-                "{}/global/block/head/durable/wasm_2_0_0/value?key={}",
-                self.api_addr, key
+                "{}/global/block/{}/durable/wasm_2_0_0/value?key={}",
+                self.api_addr, block, key
             ))
             .send()
             .await?;
@@ -199,4 +283,25 @@ impl SmartRollupClient {
             )))
         }
     }
+
+    /// Names of the keys right under a durable storage key in the state of `block`.
+    pub async fn get_subkeys_at(&self, block: &str, key: &str) -> Result<Vec<String>, OctezError> {
+        let res = self
+            .client
+            .get(format!(
+                "{}/global/block/{}/durable/wasm_2_0_0/subkeys?key={}",
+                self.api_addr, block, key
+            ))
+            .send()
+            .await?;
+
+        if res.status() == 200 {
+            Ok(res.json::<Option<Vec<String>>>().await?.unwrap_or_default())
+        } else {
+            Err(OctezError::HttpResponseError(format!(
+                "Unhandled response status: {}",
+                res.status()
+            )))
+        }
+    }
 }
@@ -0,0 +1,158 @@
+use redb::Database;
+use tradez_kernel::commitment::INPUT_COUNT_STR_PATH;
+use tradez_octez::{error::OctezError, smart_rollup_node::SmartRollupClient};
+use tradez_types::setup::RollupSetup;
+
+use crate::host::{self, SequencerHost};
+
+/// Durable storage keys the kernel writes under.
+const KERNEL_STATE_KEYS: [&str; 3] = ["/tradez", "/accounts", "/accounts_index"];
+
+/// Rebuilds the sequencer db from the durable storage of the rollup. The new db is built
+/// aside and only replaces the previous one, kept as a backup, once the rollup state is copied
+/// and the injection log replayed: on any error the previous db stays in place. What its
+/// injection log still holds beyond the inputs the rollup ran is run again on top of the
+/// rollup state: queued inputs wait for injection again, injected ones are followed until
+/// they are included. The trade history and the state commitments served for proofs start
+/// over.
+pub async fn bootstrap(data_dir: &str, client: &SmartRollupClient) -> std::io::Result<()> {
+    let db_path = host::db_path(data_dir);
+    let log = match Database::open(&db_path) {
+        Ok(db) => host::read_injection_log(&db).unwrap_or_else(|| {
+            println!("Injection log of the previous db unreadable, nothing to replay");
+            vec![]
+        }),
+        Err(e) => {
+            println!("Previous db unreadable, nothing to replay: {:?}", e);
+            vec![]
+        }
+    };
+
+    let build_dir = format!("{}/bootstrap", data_dir);
+    if std::path::Path::new(&build_dir).exists() {
+        std::fs::remove_dir_all(&build_dir)?;
+    }
+    std::fs::create_dir_all(&build_dir)?;
+    if let Err(e) = rebuild(&build_dir, client, log).await {
+        println!(
+            "Bootstrap failed, the previous db is left in place: {:?}",
+            e
+        );
+        std::fs::remove_dir_all(&build_dir)?;
+        return Err(e);
+    }
+
+    if std::path::Path::new(&db_path).exists() {
+        let backup = format!("{}.{}.bak", db_path, chrono::Utc::now().timestamp());
+        std::fs::rename(&db_path, &backup)?;
+        println!("Previous db moved to {}", backup);
+    }
+    std::fs::rename(host::db_path(&build_dir), &db_path)?;
+    std::fs::remove_dir_all(&build_dir)
+}
+
+/// Builds a db in `data_dir` from the rollup state, with the inputs of `log` it didn't run
+/// yet run again on top.
+async fn rebuild(
+    data_dir: &str,
+    client: &SmartRollupClient,
+    log: Vec<host::LoggedInput>,
+) -> std::io::Result<()> {
+    // Pinned, so that the rollup doesn't move on while its state is copied
+    let block = client
+        .get_l1_level()
+        .await
+        .map_err(std::io::Error::other)?
+        .to_string();
+    let mut host = SequencerHost::new(data_dir.to_string());
+    let mut copied = 0;
+    for key in KERNEL_STATE_KEYS {
+        copied += copy_subtree(client, &block, &mut host, key)
+            .await
+            .map_err(std::io::Error::other)?;
+    }
+    let input_count: u64 = match client
+        .get_value_at(&block, INPUT_COUNT_STR_PATH)
+        .await
+        .map_err(std::io::Error::other)?
+    {
+        Some(count) => rlp::decode(&count).map_err(std::io::Error::other)?,
+        None => 0,
+    };
+    println!(
+        "Copied {} values of the rollup state at level {}, after {} inputs",
+        copied, block, input_count
+    );
+
+    let replayed: Vec<_> = log
+        .into_iter()
+        .filter(|logged| logged.id >= input_count)
+        .collect();
+    if let Some(first) = replayed.first()
+        && first.id != input_count
+    {
+        println!(
+            "ALERT: inputs {} to {} are in neither the rollup nor the injection log",
+            input_count,
+            first.id - 1
+        );
+    }
+    for logged in &replayed {
+        host.inputs.push_back(logged.input.clone());
+    }
+    host.run_kernel().map_err(std::io::Error::other)?;
+    let injected: Vec<(u64, String)> = replayed
+        .iter()
+        .filter_map(|logged| Some((logged.id, logged.message_id.clone()?)))
+        .collect();
+    host.acknowledge_injections(&injected);
+    println!(
+        "Replayed {} inputs, {} of them already injected",
+        replayed.len(),
+        injected.len()
+    );
+    Ok(())
+}
+
+/// Copies the setup the installer wrote into the rollup, for the values the db doesn't have
+/// yet. The kernel reads them like on the rollup. Returns the number of values copied.
+pub async fn copy_setup(
+    client: &SmartRollupClient,
+    host: &mut SequencerHost,
+) -> Result<usize, OctezError> {
+    let mut copied = 0;
+    for path in RollupSetup::PATHS {
+        if host.read_value(path).is_some() {
+            continue;
+        }
+        if let Some(value) = client.get_value(path).await? {
+            host.write_value(path, value);
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Copies the value of a key and of every key under it. Returns the number of values copied.
+async fn copy_subtree(
+    client: &SmartRollupClient,
+    block: &str,
+    host: &mut SequencerHost,
+    root: &str,
+) -> Result<usize, OctezError> {
+    let mut keys = vec![root.to_string()];
+    let mut copied = 0;
+    while let Some(key) = keys.pop() {
+        if let Some(value) = client.get_value_at(block, &key).await? {
+            host.write_value(&key, value);
+            copied += 1;
+        }
+        for subkey in client.get_subkeys_at(block, &key).await? {
+            // The value of a key is listed among its subkeys as "@"
+            if subkey != "@" {
+                keys.push(format!("{}/{}", key, subkey));
+            }
+        }
+    }
+    Ok(copied)
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
+};
+
+use alloy_primitives::B256;
+use redb::{Database, ReadTransaction, ReadableDatabase};
+use rlp::Decodable;
+use tezos_smart_rollup_host::{
+    Error,
+    dal_parameters::RollupDalParameters,
+    input::Message,
+    metadata::{RAW_ROLLUP_ADDRESS_SIZE, RollupMetadata},
+    path::Path,
+    runtime::{Runtime, RuntimeError, ValueType},
+};
+use tradez_kernel::{
+    account::{ACCOUNT_KEY_PREFIX, Account},
+    commitment::node_path,
+};
+use tradez_types::{
+    address::Address,
+    commitment::{AccountProof, Hash, MerkleProof},
+    error::TradezError,
+    orderbook::{ORDER_BOOK_STR_PATH, OrderBook},
+    position::Side,
+    stops::{STOP_BOOK_STR_PATH, StopBook},
+    units::{Price, Qty},
+};
+
+use crate::host::{
+    MAX_FILE_CHUNK_SIZE, PATH_COMMITMENTS, PATH_HISTORY, TABLE, keys_under, version_key,
+};
+
+/// Accounts kept decoded, the ones loaded first are dropped past it.
+const MAX_CACHED_ACCOUNTS: usize = 10_000;
+
+/// Sizes of the injection queues of the host.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueSizes {
+    /// Inputs waiting to be handed to the rollup node.
+    pub pending: u64,
+    /// Inputs the rollup node accepted that aren't in the rollup inbox yet.
+    pub injected: u64,
+    /// Injected inputs reported as stuck.
+    pub stuck: u64,
+}
+
+/// Decoded state the read RPCs are served from, without waiting on the host. It follows what
+/// the kernel runs commit, the db staying the source of truth.
+pub struct StateCache {
+    db: Arc<Database>,
+    pub orderbook: OrderBook,
+    pub stop_book: StopBook,
+    /// Last L1 level the host knows.
+    pub level: u32,
+    /// As the host last published them.
+    pub queues: QueueSizes,
+    /// Accounts read so far by path, None for the ones that don't exist yet.
+    accounts: HashMap<String, Option<Account>>,
+    /// Paths of the cached accounts, in the order they were loaded.
+    loaded: VecDeque<String>,
+}
+
+fn account_path(address: &Address) -> String {
+    format!("{}/{:x}", ACCOUNT_KEY_PREFIX, address.0)
+}
+
+fn decode<T: Decodable>(data: &[u8]) -> Result<T, TradezError> {
+    T::decode(&rlp::Rlp::new(data)).map_err(|e| TradezError::DataStoreError(e.to_string()))
+}
+
+impl StateCache {
+    pub fn new(db: Arc<Database>) -> Self {
+        let mut cache = Self {
+            db,
+            orderbook: OrderBook::new(),
+            stop_book: StopBook::default(),
+            level: 0,
+            queues: QueueSizes::default(),
+            accounts: HashMap::new(),
+            loaded: VecDeque::new(),
+        };
+        cache.reload_books();
+        cache
+    }
+
+    /// Committed value of a path.
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        let read_txn = self.db.begin_read().unwrap();
+        let table = read_txn.open_table(TABLE).ok()?;
+        table.get(path).unwrap().map(|value| value.value())
+    }
+
+    fn reload_books(&mut self) {
+        self.orderbook = self
+            .read(ORDER_BOOK_STR_PATH)
+            .map(|data| decode(&data).unwrap())
+            .unwrap_or_default();
+        self.stop_book = self
+            .read(STOP_BOOK_STR_PATH)
+            .map(|data| decode(&data).unwrap())
+            .unwrap_or_default();
+    }
+
+    fn load_account(&self, path: &str) -> Result<Option<Account>, TradezError> {
+        self.read(path).map(|data| decode(&data)).transpose()
+    }
+
+    /// Account as last committed, read from the db the first time.
+    pub fn account(&mut self, address: &Address) -> Result<Option<Account>, TradezError> {
+        let path = account_path(address);
+        if let Some(account) = self.accounts.get(&path) {
+            return Ok(account.clone());
+        }
+        let account = self.load_account(&path)?;
+        if self.loaded.len() >= MAX_CACHED_ACCOUNTS
+            && let Some(oldest) = self.loaded.pop_front()
+        {
+            self.accounts.remove(&oldest);
+        }
+        self.accounts.insert(path.clone(), account.clone());
+        self.loaded.push_back(path);
+        Ok(account)
+    }
+
+    /// Decodes again what a committed kernel run wrote among the cached state.
+    pub fn refresh(&mut self, written: &BTreeSet<String>) {
+        if written.contains(ORDER_BOOK_STR_PATH) || written.contains(STOP_BOOK_STR_PATH) {
+            self.reload_books();
+        }
+        for path in written {
+            if self.accounts.contains_key(path) {
+                let account = self.load_account(path).unwrap();
+                self.accounts.insert(path.clone(), account);
+            }
+        }
+    }
+}
+
+/// Account from the cache, only locked for writing the first time it is read.
+pub fn account(
+    cache: &RwLock<StateCache>,
+    address: &Address,
+) -> Result<Option<Account>, TradezError> {
+    if let Some(account) = cache.read().unwrap().accounts.get(&account_path(address)) {
+        return Ok(account.clone());
+    }
+    cache.write().unwrap().account(address)
+}
+
+/// Committed state as the last kernel run left it, for the reads that go through the kernel
+/// loaders. It reads the db without the host and refuses writes.
+pub struct Snapshot {
+    txn: ReadTransaction,
+    /// Last L1 level the host knew when it was taken.
+    pub level: u32,
+}
+
+/// Snapshot of the committed state, taken without waiting on the host.
+pub fn snapshot(cache: &RwLock<StateCache>) -> Snapshot {
+    let cache = cache.read().unwrap();
+    Snapshot {
+        txn: cache.db.begin_read().unwrap(),
+        level: cache.level,
+    }
+}
+
+fn read_only<T>() -> Result<T, RuntimeError> {
+    Err(RuntimeError::HostErr(Error::GenericInvalidAccess))
+}
+
+impl Snapshot {
+    fn read_value(&self, path: &str) -> Option<Vec<u8>> {
+        let table = self.txn.open_table(TABLE).ok()?;
+        table.get(path).unwrap().map(|value| value.value())
+    }
+
+    fn subkeys_of(&self, path: &str) -> Vec<String> {
+        match self.txn.open_table(TABLE) {
+            Ok(table) => keys_under(&table, path),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Trades the kernel ran, oldest first.
+    pub fn history(&self) -> Vec<(u128, Qty, Price, Side)> {
+        let mut history = Vec::new();
+        let Ok(table) = self.txn.open_table(TABLE) else {
+            return history;
+        };
+        let end = format!("{}~", PATH_HISTORY);
+        let mut iter = table.range(PATH_HISTORY..end.as_str()).unwrap();
+        while let Some(Ok((_, value))) = iter.next() {
+            let rlp_data = value.value();
+            let rlp = rlp::Rlp::new(&rlp_data);
+            let timestamp: u128 = rlp.val_at(0).unwrap();
+            let price: Price = rlp.val_at(1).unwrap();
+            let qty: Qty = rlp.val_at(2).unwrap();
+            let side_u8: u8 = rlp.val_at(3).unwrap();
+            let side = match side_u8 {
+                0 => Side::Bid,
+                1 => Side::Ask,
+                _ => continue, // Skip invalid entries
+            };
+            history.push((timestamp, qty, price, side));
+        }
+        history
+    }
+
+    /// Proof of an account against the state commitment of `level`, the last one if omitted.
+    pub fn account_proof(&mut self, address: &Address, level: Option<u32>) -> Option<AccountProof> {
+        let (level, root, leaf_count) = self.commitment(level)?;
+
+        // The order book is the first leaf
+        let index = Account::position(self, address).ok()?? + 1;
+        let account = self.version(&account_path(address), level)?;
+        let proof = MerkleProof::from_nodes(index, leaf_count, |height, position| {
+            let node = self.version(&node_path(height, position), level)?;
+            (node.len() == 32).then(|| Hash(B256::from_slice(&node)))
+        })?;
+        Some(AccountProof {
+            level,
+            root,
+            account,
+            proof,
+        })
+    }
+
+    /// Level, root and leaf count of the state commitment of `level`, the last one if omitted.
+    pub fn commitment(&self, level: Option<u32>) -> Option<(u32, Hash, u64)> {
+        let table = self.txn.open_table(TABLE).ok()?;
+        let (level, commitment) = match level {
+            Some(level) => {
+                let path = format!("{}{:010}", PATH_COMMITMENTS, level);
+                (level, table.get(path.as_str()).unwrap()?.value())
+            }
+            None => {
+                // Levels are zero padded, the last key is the last commitment
+                let end = format!("{}~", PATH_COMMITMENTS);
+                let (path, commitment) = table
+                    .range(PATH_COMMITMENTS..end.as_str())
+                    .unwrap()
+                    .next_back()?
+                    .ok()?;
+                let level = path.value()[PATH_COMMITMENTS.len()..].parse().ok()?;
+                (level, commitment.value())
+            }
+        };
+        let rlp = rlp::Rlp::new(&commitment);
+        Some((level, rlp.val_at(0).ok()?, rlp.val_at(1).ok()?))
+    }
+
+    /// Levels of the first and last commitments kept.
+    pub fn committed_levels(&self) -> Option<RangeInclusive<u32>> {
+        let table = self.txn.open_table(TABLE).ok()?;
+        let end = format!("{}~", PATH_COMMITMENTS);
+        let mut range = table.range(PATH_COMMITMENTS..end.as_str()).unwrap();
+        let level = |key: &str| key[PATH_COMMITMENTS.len()..].parse::<u32>().ok();
+        let first = level(range.next()?.ok()?.0.value())?;
+        let last = match range.next_back() {
+            Some(entry) => level(entry.ok()?.0.value())?,
+            None => first,
+        };
+        Some(first..=last)
+    }
+
+    /// Value of a path as the commitment of `level` left it.
+    fn version(&self, path: &str, level: u32) -> Option<Vec<u8>> {
+        let table = self.txn.open_table(TABLE).ok()?;
+        let start = version_key(path, 0);
+        let end = version_key(path, level);
+        let (_, value) = table
+            .range(start.as_str()..=end.as_str())
+            .unwrap()
+            .next_back()?
+            .ok()?;
+        Some(value.value())
+    }
+}
+
+impl Runtime for Snapshot {
+    fn read_input(&mut self) -> Result<Option<Message>, RuntimeError> {
+        Ok(None)
+    }
+
+    fn write_output(&mut self, _msg: &[u8]) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn write_debug(&self, _msg: &str) {}
+
+    fn last_run_aborted(&self) -> Result<bool, RuntimeError> {
+        Ok(false)
+    }
+
+    fn mark_for_reboot(&mut self) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn reboot_left(&self) -> Result<u32, RuntimeError> {
+        Ok(0)
+    }
+
+    fn restart_forced(&self) -> Result<bool, RuntimeError> {
+        Ok(false)
+    }
+
+    fn reveal_dal_page(
+        &self,
+        _published_level: i32,
+        _slot_index: u8,
+        _page_index: i16,
+        _destination: &mut [u8],
+    ) -> Result<usize, RuntimeError> {
+        Ok(0)
+    }
+
+    fn reveal_dal_parameters(&self) -> RollupDalParameters {
+        RollupDalParameters {
+            number_of_slots: 0,
+            attestation_lag: 0,
+            slot_size: 0,
+            page_size: 0,
+        }
+    }
+
+    fn reveal_metadata(&self) -> RollupMetadata {
+        RollupMetadata {
+            raw_rollup_address: [0; RAW_ROLLUP_ADDRESS_SIZE],
+            origination_level: 0,
+        }
+    }
+
+    fn reveal_preimage(
+        &self,
+        _hash: &[u8; 33],
+        _destination: &mut [u8],
+    ) -> Result<usize, RuntimeError> {
+        read_only()
+    }
+
+    fn runtime_version(&self) -> Result<String, RuntimeError> {
+        Ok(format!("tradez-sequencer-{}", env!("CARGO_PKG_VERSION")))
+    }
+
+    fn store_copy(
+        &mut self,
+        _from_path: &impl Path,
+        _to_path: &impl Path,
+    ) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn store_count_subkeys<T: Path>(&self, prefix: &T) -> Result<u64, RuntimeError> {
+        let prefix = prefix.to_string();
+        let children: BTreeSet<String> = self
+            .subkeys_of(&prefix)
+            .iter()
+            .filter_map(|key| key[prefix.len() + 1..].split('/').next().map(String::from))
+            .collect();
+        let value = self.read_value(&prefix).is_some() as u64;
+        Ok(children.len() as u64 + value)
+    }
+
+    fn store_delete<T: Path>(&mut self, _path: &T) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn store_delete_value<T: Path>(&mut self, _path: &T) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn store_has<T: Path>(&self, path: &T) -> Result<Option<ValueType>, RuntimeError> {
+        let path = path.to_string();
+        let has_value = self.read_value(&path).is_some();
+        let has_subtree = !self.subkeys_of(&path).is_empty();
+        Ok(match (has_value, has_subtree) {
+            (false, false) => None,
+            (true, false) => Some(ValueType::Value),
+            (false, true) => Some(ValueType::Subtree),
+            (true, true) => Some(ValueType::ValueWithSubtree),
+        })
+    }
+
+    fn store_move(
+        &mut self,
+        _from_path: &impl Path,
+        _to_path: &impl Path,
+    ) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn store_read<T: Path>(
+        &self,
+        path: &T,
+        from_offset: usize,
+        max_bytes: usize,
+    ) -> Result<Vec<u8>, RuntimeError> {
+        let value = self.store_read_all(path)?;
+        if from_offset > value.len() {
+            return Err(RuntimeError::HostErr(Error::StoreInvalidAccess));
+        }
+        let end = value
+            .len()
+            .min(from_offset + max_bytes.min(MAX_FILE_CHUNK_SIZE));
+        Ok(value[from_offset..end].to_vec())
+    }
+
+    fn store_read_all(&self, path: &impl Path) -> Result<Vec<u8>, RuntimeError> {
+        self.read_value(&path.to_string())
+            .ok_or(RuntimeError::PathNotFound)
+    }
+
+    fn store_read_slice<T: Path>(
+        &self,
+        path: &T,
+        from_offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<usize, RuntimeError> {
+        let read = self.store_read(path, from_offset, buffer.len())?;
+        buffer[..read.len()].copy_from_slice(&read);
+        Ok(read.len())
+    }
+
+    fn store_value_size(&self, path: &impl Path) -> Result<usize, RuntimeError> {
+        self.store_read_all(path).map(|value| value.len())
+    }
+
+    fn store_write<T: Path>(
+        &mut self,
+        _path: &T,
+        _src: &[u8],
+        _at_offset: usize,
+    ) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn store_write_all<T: Path>(&mut self, _path: &T, _src: &[u8]) -> Result<(), RuntimeError> {
+        read_only()
+    }
+
+    fn upgrade_failed(&self) -> Result<bool, RuntimeError> {
+        Ok(false)
+    }
+}
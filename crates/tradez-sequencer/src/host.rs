@@ -1,88 +1,608 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 
-use redb::{Database, ReadableDatabase, TableDefinition};
-use rlp::Decodable;
+use alloy_signer::SignerSync;
+use alloy_signer_local::PrivateKeySigner;
+use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition, WriteTransaction};
+use rlp::{Decodable, Encodable};
 use tezos_smart_rollup::{inbox::InboxMessage, michelson::MichelsonUnit};
 use tezos_smart_rollup_host::{
+    Error,
     dal_parameters::RollupDalParameters,
     input::Message,
-    metadata::RollupMetadata,
-    runtime::{Runtime, RuntimeError},
+    metadata::{RAW_ROLLUP_ADDRESS_SIZE, RollupMetadata},
+    path::Path,
+    runtime::{Runtime, RuntimeError, ValueType},
 };
-use tradez_types::{
-    orderbook::Event,
-    position::{Price, Qty, Side},
+use tradez_kernel::{
+    account::{ACCOUNT_KEY_PREFIX, Account},
+    commitment::{INPUT_COUNT_STR_PATH, STATE_TREE_STR_PATH},
 };
+use tradez_types::{SequencedInput, commitment::Hash, error::TradezError, orderbook::Event};
+
+use crate::cache::{QueueSizes, StateCache};
+
+pub(crate) const TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("my_data");
+pub(crate) const PATH_HISTORY: &str = "tradez/history/";
+/// Root and leaf count of each state commitment kept, keyed by zero padded level.
+pub(crate) const PATH_COMMITMENTS: &str = "tradez/commitment_roots/";
+/// Nodes of the state tree and accounts as each commitment that changed them left them, keyed
+/// by their path then zero padded level.
+const PATH_VERSIONS: &str = "tradez/versions";
+/// Full copies of the accounts and order book at each commitment, as kept before the versions.
+const PATH_LEGACY_COMMITMENTS: &str = "tradez/commitments/";
+/// Commitments proofs are served against, the ones of older levels are pruned.
+const KEPT_COMMITMENTS: u32 = 1000;
+/// Inputs the kernel ran that the rollup node didn't accept yet, keyed by zero padded id. The
+/// id of an input is the number of inputs the kernel ran before it.
+const PATH_INJECTION_QUEUE: &str = "tradez/injection_queue/";
+/// Inputs the rollup node accepted that aren't in the rollup inbox yet, keyed by zero padded id.
+const PATH_INJECTED: &str = "tradez/injected/";
+
+/// Largest chunk a single `store_read` or `store_write` moves, as in the PVM.
+pub(crate) const MAX_FILE_CHUNK_SIZE: usize = 2048;
+/// Reboots the PVM allows a kernel between two inputs.
+const MAX_REBOOTS: u32 = 1000;
+
+/// Input the batcher of the rollup node accepted, until it lands in the rollup inbox.
+#[derive(Clone, Debug)]
+pub struct InjectedInput {
+    pub id: u64,
+    /// Id the batcher gave the input.
+    pub message_id: String,
+    /// When the batcher accepted it, or the sequencer started.
+    pub injected_at: Instant,
+    /// Whether it was reported as stuck already.
+    pub stuck: bool,
+    /// Fetches of its status that failed since the last one that didn't.
+    pub failed_fetches: u32,
+}
+
+/// Key of the value of a path as of the commitment of a level.
+pub(crate) fn version_key(path: &str, level: u32) -> String {
+    format!("{}{}/{:010}", PATH_VERSIONS, path, level)
+}
+
+pub fn db_path(data_dir: &str) -> String {
+    format!("{}/my_db.redb", data_dir)
+}
 
-const TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("my_data");
-const PATH_HISTORY: &str = "tradez/history/";
+/// Input of the injection log: waiting for injection, or injected with the id the batcher
+/// gave it.
+#[derive(Clone, Debug)]
+pub struct LoggedInput {
+    pub id: u64,
+    pub input: Vec<u8>,
+    pub message_id: Option<String>,
+}
+
+/// Inputs of the injection queue and injected inputs, by id. None if the db can't be read.
+pub fn read_injection_log(db: &Database) -> Option<Vec<LoggedInput>> {
+    let read_txn = db.begin_read().ok()?;
+    let Ok(table) = read_txn.open_table(TABLE) else {
+        return Some(vec![]);
+    };
+    let mut log = vec![];
+    let end = format!("{}~", PATH_INJECTION_QUEUE);
+    for entry in table.range(PATH_INJECTION_QUEUE..end.as_str()).ok()? {
+        let (path, input) = entry.ok()?;
+        log.push(LoggedInput {
+            id: path.value()[PATH_INJECTION_QUEUE.len()..].parse().ok()?,
+            input: input.value(),
+            message_id: None,
+        });
+    }
+    let end = format!("{}~", PATH_INJECTED);
+    for entry in table.range(PATH_INJECTED..end.as_str()).ok()? {
+        let (path, value) = entry.ok()?;
+        let value = value.value();
+        let rlp = rlp::Rlp::new(&value);
+        log.push(LoggedInput {
+            id: path.value()[PATH_INJECTED.len()..].parse().ok()?,
+            input: rlp.val_at(0).ok()?,
+            message_id: Some(rlp.val_at(1).ok()?),
+        });
+    }
+    log.sort_by_key(|input| input.id);
+    Some(log)
+}
+
+/// Keys strictly under a path. They all start with the path and a "/", which sorts right
+/// before "0".
+pub(crate) fn keys_under(
+    table: &impl ReadableTable<&'static str, Vec<u8>>,
+    path: &str,
+) -> Vec<String> {
+    let start = format!("{}/", path);
+    let end = format!("{}0", path);
+    table
+        .range(start.as_str()..end.as_str())
+        .unwrap()
+        .map(|entry| entry.unwrap().0.value().to_string())
+        .collect()
+}
+
+/// Why a kernel run was rolled back.
+#[derive(Debug)]
+pub enum RunError {
+    /// The kernel panicked, with the panic message.
+    Aborted(String),
+    /// The kernel refused the input, with the reason it rejected it for if it gave one.
+    Refused(Option<String>),
+}
 
 pub struct SequencerHost {
     pub inputs: VecDeque<Vec<u8>>,
-    pub db: Database,
+    pub db: Arc<Database>,
+    /// Decoded state the read RPCs are served from, refreshed as kernel runs commit.
+    pub cache: Arc<RwLock<StateCache>>,
     pub event_to_notify: Vec<Event>,
-    pub input_to_send_to_rollup: VecDeque<Vec<u8>>,
+    /// Injection queue of the db, in order, with the id of each input.
+    pub input_to_send_to_rollup: VecDeque<(u64, Vec<u8>)>,
+    /// When the first input of the injection queue joined it, or the sequencer started.
+    pub oldest_pending_since: Option<Instant>,
+    /// Inputs the batcher accepted, in order, until they are included.
+    pub injected: VecDeque<InjectedInput>,
+    /// When the injection may be tried again after a failure, and the delay before that.
+    pub injection_backoff: Option<(Instant, Duration)>,
+    /// Transaction the writes of the running kernel go to, committed once the run completes.
+    /// Inputs join the injection queue in it, so that their effects are never stored without
+    /// them.
+    run: Option<WriteTransaction>,
+    /// Inputs the running kernel read, with their id, until the run is committed.
+    run_inputs: Vec<(u64, Vec<u8>)>,
+    /// Paths the running kernel wrote or deleted.
+    run_written: BTreeSet<String>,
+    /// Reason of the last rejection the kernel emitted in the run.
+    last_rejection: Option<String>,
+    /// Last L1 level seen by the rollup node, stamped on every input. Zero until it is known.
+    pub level: u32,
+    /// Key of the sequencer in the rollup setup, signing the level stamped on every input.
+    pub signer: Option<PrivateKeySigner>,
+    /// Metadata of the rollup the inputs are injected into, revealed to the kernel.
+    pub rollup_metadata: RollupMetadata,
+    /// Whether the kernel asked to run again before reading more inputs.
+    reboot_requested: bool,
+    /// Reboots the kernel may still ask for before new inputs come.
+    reboots_left: u32,
+    /// Whether the last run was rolled back.
+    last_run_aborted: bool,
 }
 
 impl SequencerHost {
     pub fn new(data_dir: String) -> Self {
-        let db = Database::create(format!("{}/my_db.redb", data_dir)).unwrap();
-        Self {
+        let db = Arc::new(Database::create(db_path(&data_dir)).unwrap());
+        let mut input_to_send_to_rollup = VecDeque::new();
+        let mut injected = VecDeque::new();
+        for logged in read_injection_log(&db).unwrap() {
+            match logged.message_id {
+                None => input_to_send_to_rollup.push_back((logged.id, logged.input)),
+                Some(message_id) => injected.push_back(InjectedInput {
+                    id: logged.id,
+                    message_id,
+                    injected_at: Instant::now(),
+                    stuck: false,
+                    failed_fetches: 0,
+                }),
+            }
+        }
+        let oldest_pending_since = (!input_to_send_to_rollup.is_empty()).then(Instant::now);
+        let host = Self {
+            cache: Arc::new(RwLock::new(StateCache::new(db.clone()))),
             db,
             inputs: VecDeque::new(),
             event_to_notify: Vec::new(),
-            input_to_send_to_rollup: VecDeque::new(),
-        }
+            input_to_send_to_rollup,
+            oldest_pending_since,
+            injected,
+            injection_backoff: None,
+            run: None,
+            run_inputs: Vec::new(),
+            run_written: BTreeSet::new(),
+            last_rejection: None,
+            level: 0,
+            signer: None,
+            rollup_metadata: RollupMetadata {
+                raw_rollup_address: [0; RAW_ROLLUP_ADDRESS_SIZE],
+                origination_level: 0,
+            },
+            reboot_requested: false,
+            reboots_left: MAX_REBOOTS,
+            last_run_aborted: false,
+        };
+        host.publish_progress();
+        host
+    }
+
+    /// Follows the L1 level, which never goes back.
+    pub fn follow_level(&mut self, level: u32) {
+        self.level = self.level.max(level);
+        self.publish_progress();
+    }
+
+    /// Publishes the level and the sizes of the injection queues to the cache.
+    pub fn publish_progress(&self) {
+        let mut cache = self.cache.write().unwrap();
+        cache.level = self.level;
+        cache.queues = QueueSizes {
+            pending: self.input_to_send_to_rollup.len() as u64,
+            injected: self.injected.len() as u64,
+            stuck: self.injected.iter().filter(|input| input.stuck).count() as u64,
+        };
     }
 
     pub fn add_inputs(&mut self, new_inputs: Vec<Vec<u8>>) {
         for input in new_inputs {
-            self.inputs.push_back(input.clone());
-            self.input_to_send_to_rollup.push_back(input);
-        }
-    }
-
-    pub fn read_history(&self) -> Vec<(u128, Qty, Price, Side)> {
-        let mut history = Vec::new();
-        let read_txn = self.db.begin_read().unwrap();
-        let table = read_txn.open_table(TABLE).unwrap();
-        let prefix = PATH_HISTORY;
-        let mut iter = table.range(prefix..).unwrap();
-        while let Some(Ok((_, value))) = iter.next() {
-            let rlp_data = value.value();
-            let rlp = rlp::Rlp::new(&rlp_data);
-            let timestamp: u128 = rlp.val_at(0).unwrap();
-            let price: Price = rlp.val_at(1).unwrap();
-            let qty: Qty = rlp.val_at(2).unwrap();
-            let side_u8: u8 = rlp.val_at(3).unwrap();
-            let side = match side_u8 {
-                0 => Side::Bid,
-                1 => Side::Ask,
-                _ => continue, // Skip invalid entries
+            let signature = match &self.signer {
+                Some(signer) => signer
+                    .sign_message_sync(&SequencedInput::payload(self.level, &input))
+                    .unwrap()
+                    .as_bytes()
+                    .to_vec(),
+                None => vec![],
             };
-            history.push((timestamp, qty, price, side));
+            let input = SequencedInput {
+                level: self.level,
+                input,
+                signature,
+            }
+            .rlp_bytes()
+            .to_vec();
+            self.inputs.push_back(input);
+        }
+    }
+
+    /// First inputs of the injection queue, as many as fit in `max_bytes`. The first one is
+    /// always taken, whatever its size.
+    pub fn pending_injections(&self, max_bytes: usize) -> Vec<(u64, Vec<u8>)> {
+        let mut batch = vec![];
+        let mut bytes = 0;
+        for (id, input) in &self.input_to_send_to_rollup {
+            bytes += input.len();
+            if !batch.is_empty() && bytes > max_bytes {
+                break;
+            }
+            batch.push((*id, input.clone()));
+        }
+        batch
+    }
+
+    /// Size of the inputs waiting for injection.
+    pub fn pending_bytes(&self) -> usize {
+        self.input_to_send_to_rollup
+            .iter()
+            .map(|(_, input)| input.len())
+            .sum()
+    }
+
+    /// Moves the inputs the batcher accepted, with the id it gave each of them, from the
+    /// injection queue to the injected inputs.
+    pub fn acknowledge_injections(&mut self, accepted: &[(u64, String)]) {
+        let now = Instant::now();
+        let mut newly_injected = vec![];
+        let write_txn = self.db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(TABLE).unwrap();
+            for (id, message_id) in accepted {
+                let path = format!("{}{:020}", PATH_INJECTION_QUEUE, id);
+                let Some(input) = table
+                    .remove(path.as_str())
+                    .unwrap()
+                    .map(|input| input.value())
+                else {
+                    continue;
+                };
+                let mut rlp_stream = rlp::RlpStream::new();
+                rlp_stream.begin_list(2).append(&input).append(message_id);
+                let path = format!("{}{:020}", PATH_INJECTED, id);
+                table
+                    .insert(path.as_str(), rlp_stream.out().to_vec())
+                    .unwrap();
+                newly_injected.push(InjectedInput {
+                    id: *id,
+                    message_id: message_id.clone(),
+                    injected_at: now,
+                    stuck: false,
+                    failed_fetches: 0,
+                });
+            }
+        }
+        write_txn.commit().unwrap();
+        self.input_to_send_to_rollup
+            .retain(|(id, _)| !accepted.iter().any(|(accepted, _)| accepted == id));
+        self.injected.extend(newly_injected);
+        if self.input_to_send_to_rollup.is_empty() {
+            self.oldest_pending_since = None;
+        }
+        self.publish_progress();
+    }
+
+    /// Forgets the injected inputs that reached the rollup inbox, or the batcher doesn't know.
+    pub fn confirm_inclusions(&mut self, ids: &[u64]) {
+        let write_txn = self.db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(TABLE).unwrap();
+            for id in ids {
+                let path = format!("{}{:020}", PATH_INJECTED, id);
+                table.remove(path.as_str()).unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+        self.injected.retain(|injected| !ids.contains(&injected.id));
+        self.publish_progress();
+    }
+
+    /// Commits the writes of the input the kernel ran, which then waits for injection.
+    pub fn write_value(&mut self, path: &str, value: Vec<u8>) {
+        self.track_write(path);
+        self.update_table(|table| {
+            table.insert(path, value).unwrap();
+        });
+    }
+
+    fn track_write(&mut self, path: &str) {
+        if self.run.is_some() {
+            self.run_written.insert(path.to_string());
+        }
+    }
+
+    /// Runs `f` on the table, in the transaction of the running kernel if any.
+    fn update_table<R>(&mut self, f: impl FnOnce(&mut redb::Table<&str, Vec<u8>>) -> R) -> R {
+        match &self.run {
+            Some(write_txn) => {
+                let mut table = write_txn.open_table(TABLE).unwrap();
+                f(&mut table)
+            }
+            None => {
+                let write_txn = self.db.begin_write().unwrap();
+                let result = {
+                    let mut table = write_txn.open_table(TABLE).unwrap();
+                    f(&mut table)
+                };
+                write_txn.commit().unwrap();
+                result
+            }
+        }
+    }
+
+    /// Keys strictly under a path, as written so far by the running kernel if any.
+    fn subkeys_of(&self, path: &str) -> Vec<String> {
+        match &self.run {
+            Some(write_txn) => {
+                let table = write_txn.open_table(TABLE).unwrap();
+                keys_under(&table, path)
+            }
+            None => {
+                let read_txn = self.db.begin_read().unwrap();
+                match read_txn.open_table(TABLE) {
+                    Ok(table) => keys_under(&table, path),
+                    Err(_) => vec![],
+                }
+            }
+        }
+    }
+
+    /// Value of a path and of every key under it, keyed by their suffix after the path.
+    fn read_tree(&self, path: &str) -> Vec<(String, Vec<u8>)> {
+        let mut tree: Vec<_> = self
+            .read_value(path)
+            .map(|value| (String::new(), value))
+            .into_iter()
+            .collect();
+        for key in self.subkeys_of(path) {
+            if let Some(value) = self.read_value(&key) {
+                tree.push((key[path.len()..].to_string(), value));
+            }
+        }
+        tree
+    }
+
+    /// Deletes the value of a path and every key under it.
+    fn delete_tree(&mut self, path: &str) {
+        let mut keys = self.subkeys_of(path);
+        keys.push(path.to_string());
+        for key in &keys {
+            self.track_write(key);
+        }
+        self.update_table(|table| {
+            for key in &keys {
+                table.remove(key.as_str()).unwrap();
+            }
+        });
+    }
+
+    /// Replaces whatever is at `to` with the tree read at another path.
+    fn write_tree(&mut self, to: &str, tree: Vec<(String, Vec<u8>)>) {
+        self.delete_tree(to);
+        for (suffix, value) in tree {
+            self.write_value(&format!("{}{}", to, suffix), value);
         }
-        history
+    }
+
+    /// Runs the kernel on the queued inputs, again as long as it asks to reboot, in a single
+    /// transaction. A panic of the kernel rolls the whole run back and drops its inputs, as
+    /// the rollup reverts an aborted run.
+    pub fn run_kernel(&mut self) -> Result<(), String> {
+        self.run(false).map_err(|e| match e {
+            RunError::Aborted(reason) => reason,
+            RunError::Refused(_) => unreachable!(),
+        })
+    }
+
+    /// Runs a single input, rolled back if the kernel refuses it so that it never reaches
+    /// the rollup.
+    pub fn run_input(&mut self, input: Vec<u8>) -> Result<(), RunError> {
+        self.add_inputs(vec![input]);
+        self.run(true)
+    }
+
+    fn run(&mut self, keep_out_refused: bool) -> Result<(), RunError> {
+        let notified = self.event_to_notify.len();
+        self.run = Some(self.db.begin_write().unwrap());
+        self.last_rejection = None;
+        let mut refused = false;
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            loop {
+                // The kernel loop, with the inputs it refused
+                while let Some(msg) = self.read_input().unwrap() {
+                    refused |= tradez_kernel::handle_input(self, msg).is_err();
+                }
+                if !std::mem::take(&mut self.reboot_requested) {
+                    break;
+                }
+            }
+        }));
+        self.reboot_requested = false;
+        self.reboots_left = MAX_REBOOTS;
+        let run = self.run.take().unwrap();
+        let inputs = std::mem::take(&mut self.run_inputs);
+        let written = std::mem::take(&mut self.run_written);
+        let error = match outcome {
+            Ok(()) if keep_out_refused && refused => RunError::Refused(self.last_rejection.take()),
+            Ok(()) => {
+                // Held over the commit, so that no reader caches what the run replaces
+                let mut cache = self.cache.write().unwrap();
+                run.commit().unwrap();
+                cache.refresh(&written);
+                drop(cache);
+                if !inputs.is_empty() {
+                    self.oldest_pending_since.get_or_insert_with(Instant::now);
+                }
+                self.input_to_send_to_rollup.extend(inputs);
+                self.last_run_aborted = false;
+                self.publish_progress();
+                return Ok(());
+            }
+            Err(panic) => {
+                let reason = panic
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                println!("Kernel run aborted and rolled back: {}", reason);
+                RunError::Aborted(reason)
+            }
+        };
+        run.abort().unwrap();
+        self.last_run_aborted = true;
+        self.inputs.clear();
+        self.event_to_notify.truncate(notified);
+        Err(error)
+    }
+
+    /// Reads a value, as written so far by the running kernel if any.
+    pub fn read_value(&self, path: &str) -> Option<Vec<u8>> {
+        match &self.run {
+            Some(write_txn) => {
+                let table = write_txn.open_table(TABLE).unwrap();
+                table.get(path).unwrap().map(|value| value.value())
+            }
+            None => {
+                let read_txn = self.db.begin_read().unwrap();
+                let table = read_txn.open_table(TABLE).ok()?;
+                table.get(path).unwrap().map(|value| value.value())
+            }
+        }
+    }
+}
+
+impl SequencerHost {
+    /// Keeps what the proofs against a commitment need: its root and leaf count, with the
+    /// nodes the kernel hashed again for it and the accounts of the leaves among them, as they
+    /// are now. What only older commitments than the kept ones need is pruned.
+    fn record_commitment(&mut self, level: u32, root: Hash) -> Result<(), TradezError> {
+        let leaf_count = Account::count(self)? + 1;
+        let mut rlp_stream = rlp::RlpStream::new_list(2);
+        rlp_stream.append(&root).append(&leaf_count);
+        let path = format!("{}{:010}", PATH_COMMITMENTS, level);
+        self.write_value(&path, rlp_stream.out().to_vec());
+
+        // The kernel writes the nodes of the tree when it commits only
+        let prefix = format!("{}/", STATE_TREE_STR_PATH);
+        let nodes: Vec<String> = self
+            .run_written
+            .iter()
+            .filter(|path| path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        let mut paths = vec![];
+        for node in nodes {
+            let mut parts = node[prefix.len()..].split('/');
+            // Leaves of the accounts come after the one of the order book
+            if parts.next() == Some("0")
+                && let Some(position) = parts
+                    .next()
+                    .and_then(|position| position.parse::<u64>().ok())
+                && position > 0
+                && let Some(address) = Account::address_at(self, position - 1)?
+            {
+                paths.push(format!("{}/{:x}", ACCOUNT_KEY_PREFIX, address.0));
+            }
+            paths.push(node);
+        }
+
+        let cutoff = level.saturating_sub(KEPT_COMMITMENTS);
+        for path in paths {
+            if let Some(value) = self.read_value(&path) {
+                self.write_value(&version_key(&path, level), value);
+            }
+            // The last version up to the cutoff is still the one of the kept commitments
+            self.remove_keys(&version_key(&path, 0), &version_key(&path, cutoff + 1), 1);
+        }
+        let end = format!("{}{:010}", PATH_COMMITMENTS, cutoff);
+        self.remove_keys(PATH_COMMITMENTS, &end, 0);
+        let end = format!("{}~", PATH_LEGACY_COMMITMENTS);
+        self.remove_keys(PATH_LEGACY_COMMITMENTS, &end, 0);
+        Ok(())
+    }
+
+    /// Removes the keys from `start` to `end` but the last `kept` ones.
+    fn remove_keys(&mut self, start: &str, end: &str, kept: usize) {
+        self.update_table(|table| {
+            let keys: Vec<String> = table
+                .range(start..end)
+                .unwrap()
+                .map(|entry| entry.unwrap().0.value().to_string())
+                .collect();
+            for key in &keys[..keys.len().saturating_sub(kept)] {
+                table.remove(key.as_str()).unwrap();
+            }
+        });
     }
 }
 
 impl Runtime for SequencerHost {
     fn read_input(&mut self) -> Result<Option<Message>, RuntimeError> {
-        self.inputs
-            .pop_front()
-            .map(|data| {
-                let inbox_message = InboxMessage::External::<MichelsonUnit>(&data);
-                let mut bytes = Vec::new();
-                inbox_message.serialize(&mut bytes).unwrap();
-                Ok(Some(Message::new(1, 1, bytes)))
-            })
-            .unwrap_or(Ok(None))
+        let Some(data) = self.inputs.pop_front() else {
+            return Ok(None);
+        };
+        let id: u64 = self
+            .read_value(INPUT_COUNT_STR_PATH)
+            .map(|count| rlp::decode(&count).unwrap())
+            .unwrap_or(0);
+        let path = format!("{}{:020}", PATH_INJECTION_QUEUE, id);
+        self.write_value(&path, data.clone());
+        let inbox_message = InboxMessage::External::<MichelsonUnit>(&data);
+        let mut bytes = Vec::new();
+        inbox_message.serialize(&mut bytes).unwrap();
+        // The level it was stamped with, the earliest inbox the rollup can read it from
+        let level = rlp::decode::<SequencedInput>(&data).unwrap().level;
+        self.run_inputs.push((id, data));
+        Ok(Some(Message::new(level, 1, bytes)))
     }
 
     fn write_output(&mut self, msg: &[u8]) -> Result<(), RuntimeError> {
         let event = Event::decode(&rlp::Rlp::new(msg)).map_err(|_| RuntimeError::DecodingError)?;
         self.event_to_notify.push(event.clone());
         match event {
+            Event::Rejected { reason, .. } | Event::TransferRejected { reason, .. } => {
+                self.last_rejection = Some(reason);
+                Ok(())
+            }
             Event::Trade {
                 maker_id: _,
                 maker_user: _,
@@ -98,24 +618,22 @@ impl Runtime for SequencerHost {
                     timestamp, price, qty, origin_side
                 );
                 // Append to history in the db
-                let write_txn = self.db.begin_write().unwrap();
-                {
-                    let mut table = write_txn.open_table(TABLE).unwrap();
-                    let path = format!("{}_{}", PATH_HISTORY, timestamp);
-                    let mut rlp_stream = rlp::RlpStream::new();
-                    rlp_stream
-                        .begin_list(4)
-                        .append(&timestamp)
-                        .append(&price)
-                        .append(&qty)
-                        .append(&(origin_side as u8));
-                    table
-                        .insert(path.as_str(), rlp_stream.out().to_vec())
-                        .unwrap();
-                }
-                write_txn.commit().unwrap();
+                let path = format!("{}_{}", PATH_HISTORY, timestamp);
+                let mut rlp_stream = rlp::RlpStream::new();
+                rlp_stream
+                    .begin_list(4)
+                    .append(&timestamp)
+                    .append(&price)
+                    .append(&qty)
+                    .append(&(origin_side as u8));
+                self.write_value(&path, rlp_stream.out().to_vec());
                 Ok(())
             }
+            Event::StateCommitment { level, root } => {
+                // Proofs are served against what was committed, even once the state moved on
+                self.record_commitment(level, root)
+                    .map_err(|_| RuntimeError::DecodingError)
+            }
             _ => Ok(()),
         }
     }
@@ -125,19 +643,24 @@ impl Runtime for SequencerHost {
     }
 
     fn last_run_aborted(&self) -> Result<bool, RuntimeError> {
-        unimplemented!()
+        Ok(self.last_run_aborted)
     }
 
     fn mark_for_reboot(&mut self) -> Result<(), RuntimeError> {
-        unimplemented!()
+        if self.reboots_left == 0 {
+            return Err(RuntimeError::HostErr(Error::GenericInvalidAccess));
+        }
+        self.reboots_left -= 1;
+        self.reboot_requested = true;
+        Ok(())
     }
 
     fn reboot_left(&self) -> Result<u32, RuntimeError> {
-        unimplemented!()
+        Ok(self.reboots_left)
     }
 
     fn restart_forced(&self) -> Result<bool, RuntimeError> {
-        unimplemented!()
+        Ok(false)
     }
 
     fn reveal_dal_page(
@@ -147,15 +670,21 @@ impl Runtime for SequencerHost {
         _page_index: i16,
         _destination: &mut [u8],
     ) -> Result<usize, RuntimeError> {
-        unimplemented!()
+        // The sequencer follows no DAL slot, every page reads as unattested
+        Ok(0)
     }
 
     fn reveal_dal_parameters(&self) -> RollupDalParameters {
-        unimplemented!()
+        RollupDalParameters {
+            number_of_slots: 0,
+            attestation_lag: 0,
+            slot_size: 0,
+            page_size: 0,
+        }
     }
 
     fn reveal_metadata(&self) -> RollupMetadata {
-        unimplemented!()
+        self.rollup_metadata.clone()
     }
 
     fn reveal_preimage(
@@ -163,123 +692,922 @@ impl Runtime for SequencerHost {
         _hash: &[u8; 33],
         _destination: &mut [u8],
     ) -> Result<usize, RuntimeError> {
-        unimplemented!()
+        // No reveal data directory is shared with the rollup node
+        Err(RuntimeError::HostErr(Error::GenericInvalidAccess))
     }
 
     fn runtime_version(&self) -> Result<String, RuntimeError> {
-        unimplemented!()
+        Ok(format!("tradez-sequencer-{}", env!("CARGO_PKG_VERSION")))
     }
 
     fn store_copy(
         &mut self,
-        _from_path: &impl tezos_smart_rollup_host::path::Path,
-        _to_path: &impl tezos_smart_rollup_host::path::Path,
+        from_path: &impl Path,
+        to_path: &impl Path,
     ) -> Result<(), RuntimeError> {
-        unimplemented!()
+        let tree = self.read_tree(&from_path.to_string());
+        if tree.is_empty() {
+            return Err(RuntimeError::PathNotFound);
+        }
+        self.write_tree(&to_path.to_string(), tree);
+        Ok(())
     }
 
-    fn store_count_subkeys<T: tezos_smart_rollup_host::path::Path>(
-        &self,
-        _prefix: &T,
-    ) -> Result<u64, RuntimeError> {
-        unimplemented!()
+    fn store_count_subkeys<T: Path>(&self, prefix: &T) -> Result<u64, RuntimeError> {
+        let prefix = prefix.to_string();
+        let children: BTreeSet<String> = self
+            .subkeys_of(&prefix)
+            .iter()
+            .filter_map(|key| key[prefix.len() + 1..].split('/').next().map(String::from))
+            .collect();
+        // The value of the path counts as one more subkey, as in the PVM
+        let value = self.read_value(&prefix).is_some() as u64;
+        Ok(children.len() as u64 + value)
     }
 
-    fn store_delete<T: tezos_smart_rollup_host::path::Path>(
-        &mut self,
-        _path: &T,
-    ) -> Result<(), RuntimeError> {
-        unimplemented!()
+    fn store_delete<T: Path>(&mut self, path: &T) -> Result<(), RuntimeError> {
+        if self.store_has(path)?.is_none() {
+            return Err(RuntimeError::PathNotFound);
+        }
+        self.delete_tree(&path.to_string());
+        Ok(())
     }
 
-    fn store_delete_value<T: tezos_smart_rollup_host::path::Path>(
-        &mut self,
-        _path: &T,
-    ) -> Result<(), RuntimeError> {
-        unimplemented!()
+    fn store_delete_value<T: Path>(&mut self, path: &T) -> Result<(), RuntimeError> {
+        let path = path.to_string();
+        if self.read_value(&path).is_none() {
+            return Err(RuntimeError::PathNotFound);
+        }
+        self.track_write(&path);
+        self.update_table(|table| {
+            table.remove(path.as_str()).unwrap();
+        });
+        Ok(())
     }
 
-    fn store_has<T: tezos_smart_rollup_host::path::Path>(
-        &self,
-        _path: &T,
-    ) -> Result<Option<tezos_smart_rollup_host::runtime::ValueType>, RuntimeError> {
-        unimplemented!()
+    fn store_has<T: Path>(&self, path: &T) -> Result<Option<ValueType>, RuntimeError> {
+        let path = path.to_string();
+        let has_value = self.read_value(&path).is_some();
+        let has_subtree = !self.subkeys_of(&path).is_empty();
+        Ok(match (has_value, has_subtree) {
+            (false, false) => None,
+            (true, false) => Some(ValueType::Value),
+            (false, true) => Some(ValueType::Subtree),
+            (true, true) => Some(ValueType::ValueWithSubtree),
+        })
     }
 
     fn store_move(
         &mut self,
-        _from_path: &impl tezos_smart_rollup_host::path::Path,
-        _to_path: &impl tezos_smart_rollup_host::path::Path,
+        from_path: &impl Path,
+        to_path: &impl Path,
     ) -> Result<(), RuntimeError> {
-        unimplemented!()
-    }
-
-    fn store_read<T: tezos_smart_rollup_host::path::Path>(
-        &self,
-        _path: &T,
-        _from_offset: usize,
-        _max_bytes: usize,
-    ) -> Result<Vec<u8>, RuntimeError> {
-        unimplemented!()
+        let from = from_path.to_string();
+        let tree = self.read_tree(&from);
+        if tree.is_empty() {
+            return Err(RuntimeError::PathNotFound);
+        }
+        self.delete_tree(&from);
+        self.write_tree(&to_path.to_string(), tree);
+        Ok(())
     }
 
-    fn store_read_all(
+    fn store_read<T: Path>(
         &self,
-        path: &impl tezos_smart_rollup_host::path::Path,
+        path: &T,
+        from_offset: usize,
+        max_bytes: usize,
     ) -> Result<Vec<u8>, RuntimeError> {
-        let read_txn = self.db.begin_read().unwrap();
-        let Ok(table) = read_txn.open_table(TABLE) else {
-            return Err(RuntimeError::PathNotFound);
-        };
-        Ok(table
-            .get(path.to_string().as_str())
-            .unwrap()
-            .ok_or(RuntimeError::PathNotFound)?
-            .value())
+        let value = self.store_read_all(path)?;
+        if from_offset > value.len() {
+            return Err(RuntimeError::HostErr(Error::StoreInvalidAccess));
+        }
+        let end = value
+            .len()
+            .min(from_offset + max_bytes.min(MAX_FILE_CHUNK_SIZE));
+        Ok(value[from_offset..end].to_vec())
     }
 
-    fn store_read_slice<T: tezos_smart_rollup_host::path::Path>(
-        &self,
-        _path: &T,
-        _from_offset: usize,
-        _buffer: &mut [u8],
-    ) -> Result<usize, RuntimeError> {
-        unimplemented!()
+    fn store_read_all(&self, path: &impl Path) -> Result<Vec<u8>, RuntimeError> {
+        self.read_value(&path.to_string())
+            .ok_or(RuntimeError::PathNotFound)
     }
 
-    fn store_value_size(
+    fn store_read_slice<T: Path>(
         &self,
-        _path: &impl tezos_smart_rollup_host::path::Path,
+        path: &T,
+        from_offset: usize,
+        buffer: &mut [u8],
     ) -> Result<usize, RuntimeError> {
-        unimplemented!()
+        let read = self.store_read(path, from_offset, buffer.len())?;
+        buffer[..read.len()].copy_from_slice(&read);
+        Ok(read.len())
     }
 
-    fn store_write<T: tezos_smart_rollup_host::path::Path>(
-        &mut self,
-        _path: &T,
-        _src: &[u8],
-        _at_offset: usize,
-    ) -> Result<(), RuntimeError> {
-        unimplemented!()
+    fn store_value_size(&self, path: &impl Path) -> Result<usize, RuntimeError> {
+        self.store_read_all(path).map(|value| value.len())
     }
 
-    fn store_write_all<T: tezos_smart_rollup_host::path::Path>(
+    fn store_write<T: Path>(
         &mut self,
         path: &T,
         src: &[u8],
+        at_offset: usize,
     ) -> Result<(), RuntimeError> {
-        let write_txn = self.db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(TABLE).unwrap();
-            table
-                .insert(path.to_string().as_str(), src.to_vec())
-                .unwrap();
+        if src.len() > MAX_FILE_CHUNK_SIZE {
+            return Err(RuntimeError::HostErr(Error::InputOutputTooLarge));
         }
-        write_txn.commit().unwrap();
+        let path = path.to_string();
+        let mut value = self.read_value(&path).unwrap_or_default();
+        if at_offset > value.len() {
+            return Err(RuntimeError::HostErr(Error::StoreInvalidAccess));
+        }
+        // Writes over the value from the offset, growing it when they go past its end
+        let end = value.len().min(at_offset + src.len());
+        value.splice(at_offset..end, src.iter().copied());
+        self.write_value(&path, value);
+        Ok(())
+    }
+
+    fn store_write_all<T: Path>(&mut self, path: &T, src: &[u8]) -> Result<(), RuntimeError> {
+        self.write_value(&path.to_string(), src.to_vec());
         Ok(())
     }
 
     fn upgrade_failed(&self) -> Result<bool, RuntimeError> {
-        unimplemented!()
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_smart_rollup_host::path::RefPath;
+    use tradez_kernel::liquidation;
+    use tradez_types::{
+        KernelMessage, SignedInput,
+        address::Address,
+        commitment::{StateCommitment, merkle_root},
+        currencies::Currencies,
+        delegate::{DelegateGrant, DelegateScope, RevokeDelegate},
+        market::{MARKET_CONFIG_STR_PATH, MarketConfig, MarketKind},
+        oracle::{OracleConfig, OracleFeed, OracleUpdate},
+        orderbook::{Event, OrderBook},
+        position::{APIOrder, CancelOrder, Faucet, Side},
+        setup::RollupSetup,
+        status::SequencerStatus,
+        units::{Price, Qty},
+    };
+
+    use super::*;
+
+    fn test_host(name: &str) -> SequencerHost {
+        let data_dir =
+            std::env::temp_dir().join(format!("tradez-host-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        SequencerHost::new(data_dir.to_string_lossy().to_string())
+    }
+
+    #[test]
+    fn subkeys() {
+        let mut host = test_host("subkeys");
+        let a = RefPath::assert_from(b"/a");
+        host.store_write_all(&a, b"root").unwrap();
+        host.store_write_all(&RefPath::assert_from(b"/a/x"), b"x")
+            .unwrap();
+        host.store_write_all(&RefPath::assert_from(b"/a/x/y"), b"y")
+            .unwrap();
+        host.store_write_all(&RefPath::assert_from(b"/a/z"), b"z")
+            .unwrap();
+        host.store_write_all(&RefPath::assert_from(b"/ab"), b"sibling")
+            .unwrap();
+
+        assert_eq!(
+            host.store_has(&a).unwrap(),
+            Some(ValueType::ValueWithSubtree)
+        );
+        assert_eq!(host.store_count_subkeys(&a).unwrap(), 3);
+
+        host.store_copy(&a, &RefPath::assert_from(b"/b")).unwrap();
+        host.store_move(&RefPath::assert_from(b"/a/x"), &RefPath::assert_from(b"/c"))
+            .unwrap();
+        assert_eq!(
+            host.store_read_all(&RefPath::assert_from(b"/c/y")).unwrap(),
+            b"y"
+        );
+        assert_eq!(
+            host.store_read_all(&RefPath::assert_from(b"/b/x/y"))
+                .unwrap(),
+            b"y"
+        );
+        assert_eq!(host.store_count_subkeys(&a).unwrap(), 2);
+
+        host.store_delete_value(&a).unwrap();
+        assert_eq!(host.store_has(&a).unwrap(), Some(ValueType::Subtree));
+        host.store_delete(&a).unwrap();
+        assert_eq!(host.store_has(&a).unwrap(), None);
+        assert_eq!(
+            host.store_read_all(&RefPath::assert_from(b"/ab")).unwrap(),
+            b"sibling"
+        );
+        assert!(matches!(
+            host.store_delete(&a),
+            Err(RuntimeError::PathNotFound)
+        ));
+    }
+
+    #[test]
+    fn offsets() {
+        let mut host = test_host("offsets");
+        let path = RefPath::assert_from(b"/value");
+        host.store_write(&path, b"hello", 0).unwrap();
+        host.store_write(&path, b"p!!", 3).unwrap();
+        assert_eq!(host.store_read_all(&path).unwrap(), b"help!!");
+        assert_eq!(host.store_read(&path, 2, 3).unwrap(), b"lp!");
+        assert_eq!(host.store_read(&path, 6, 3).unwrap(), b"");
+        assert!(host.store_read(&path, 7, 1).is_err());
+        assert!(host.store_write(&path, b"?", 7).is_err());
+
+        let mut buffer = [0; 4];
+        assert_eq!(host.store_read_slice(&path, 4, &mut buffer).unwrap(), 2);
+        assert_eq!(&buffer[..2], b"!!");
+        assert_eq!(host.store_value_size(&path).unwrap(), 6);
+    }
+
+    #[test]
+    fn aborted_run_rolls_back() {
+        let mut host = test_host("aborted");
+        // Refused without aborting the run
+        assert!(matches!(
+            host.run_input(vec![0xff, 0x01, 0x02]),
+            Err(RunError::Refused(_))
+        ));
+        assert_eq!(host.read_value(INPUT_COUNT_STR_PATH), None);
+
+        // The kernel panics on a config it can't decode
+        let config = RefPath::assert_from(MARKET_CONFIG_STR_PATH.as_bytes());
+        host.store_write_all(&config, &[0xff]).unwrap();
+        let faucet = Faucet {
+            amount: 1_000_000,
+            currency: Currencies::USDC,
+        };
+        let signature = sign(&PrivateKeySigner::random(), &faucet.rlp_bytes());
+        let input = SignedInput::new(KernelMessage::Faucet(faucet), signature);
+        host.add_inputs(vec![input.rlp_bytes().to_vec()]);
+        assert!(host.run_kernel().is_err());
+        assert!(host.input_to_send_to_rollup.is_empty());
+        assert!(read_injection_log(&host.db).unwrap().is_empty());
+        assert_eq!(host.read_value(INPUT_COUNT_STR_PATH), None);
+        assert_eq!(host.last_run_aborted(), Ok(true));
+
+        host.store_delete(&config).unwrap();
+        host.store_write_all(&RefPath::assert_from(b"/after"), b"ok")
+            .unwrap();
+        assert!(host.run_kernel().is_ok());
+        assert_eq!(host.read_value("/after"), Some(b"ok".to_vec()));
+        assert_eq!(host.last_run_aborted(), Ok(false));
+    }
+
+    fn sign(signer: &PrivateKeySigner, payload: &[u8]) -> Vec<u8> {
+        signer
+            .sign_message_sync(payload)
+            .unwrap()
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn run(host: &mut SequencerHost, message: KernelMessage, signature: Vec<u8>) {
+        let input = SignedInput::new(message, signature);
+        host.add_inputs(vec![input.rlp_bytes().to_vec()]);
+        host.run_kernel().unwrap();
+    }
+
+    #[test]
+    fn oracles_whitelisted_at_setup() {
+        let mut host = test_host("setup");
+        let (oracle, outsider) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        RollupSetup {
+            oracle_config: OracleConfig {
+                oracles: vec![Address::from(oracle.address().0.0)],
+                ..OracleConfig::default()
+            },
+            ..RollupSetup::default()
+        }
+        .save(&mut host)
+        .unwrap();
+        host.level = 100;
+
+        let update = OracleUpdate {
+            price: Price(2_000_000),
+            level: 100,
+        };
+        for signer in [&outsider, &oracle] {
+            let signature = sign(signer, &update.rlp_bytes());
+            run(&mut host, KernelMessage::OracleUpdate(update), signature);
+        }
+        let feed = OracleFeed::load(&mut host).unwrap();
+        assert_eq!(feed.index_price, Price(2_000_000));
+        assert_eq!(feed.quotes.len(), 1);
+    }
+
+    #[test]
+    fn perpetual_market_at_setup() {
+        let mut host = test_host("perpetual");
+        RollupSetup {
+            market_config: MarketConfig {
+                kind: MarketKind::Perpetual,
+                ..MarketConfig::default()
+            },
+            ..RollupSetup::default()
+        }
+        .save(&mut host)
+        .unwrap();
+
+        let (long, short) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        for (signer, side) in [(&long, Side::Bid), (&short, Side::Ask)] {
+            let faucet = Faucet {
+                amount: 100_000_000,
+                currency: Currencies::USDC,
+            };
+            let signature = sign(signer, &faucet.rlp_bytes());
+            run(&mut host, KernelMessage::Faucet(faucet), signature);
+            let order = APIOrder {
+                side,
+                size: Qty(1_000_000),
+                price: Price(2_000_000),
+                nonce: 1,
+                display: Qty::ZERO,
+            };
+            let signature = sign(signer, &order.rlp_bytes());
+            run(&mut host, KernelMessage::PlaceOrder(order), signature);
+        }
+        let position = |host: &mut SequencerHost, signer: &PrivateKeySigner| {
+            Account::load(host, &Address::from(signer.address().0.0))
+                .unwrap()
+                .unwrap()
+                .position
+                .size
+        };
+        assert_eq!(position(&mut host, &long), 1_000_000);
+        assert_eq!(position(&mut host, &short), -1_000_000);
+    }
+
+    #[test]
+    fn levels_signed_by_the_sequencer() {
+        let mut host = test_host("levels");
+        let (sequencer, outsider) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        RollupSetup {
+            sequencer: Some(Address::from(sequencer.address().0.0)),
+            ..RollupSetup::default()
+        }
+        .save(&mut host)
+        .unwrap();
+
+        let faucet = Faucet {
+            amount: 1_000_000,
+            currency: Currencies::USDC,
+        };
+        let signature = sign(&outsider, &faucet.rlp_bytes());
+        let input = SignedInput::new(KernelMessage::Faucet(faucet), signature)
+            .rlp_bytes()
+            .to_vec();
+        // Level the kernel takes for an input stamped `level`, read from the inbox at `inbox_level`
+        let mut deliver = |signer: &PrivateKeySigner, level: u32, inbox_level: u32| {
+            let sequenced = SequencedInput {
+                level,
+                input: input.clone(),
+                signature: sign(signer, &SequencedInput::payload(level, &input)),
+            }
+            .rlp_bytes();
+            let mut bytes = Vec::new();
+            InboxMessage::External::<MichelsonUnit>(&sequenced)
+                .serialize(&mut bytes)
+                .unwrap();
+            tradez_kernel::handle_input(&mut host, Message::new(inbox_level, 0, bytes)).unwrap();
+            rlp::decode::<u32>(&host.read_value("/tradez/input_level").unwrap()).unwrap()
+        };
+        assert_eq!(deliver(&sequencer, 100, 120), 100);
+        assert_eq!(deliver(&outsider, 300, 130), 130);
+        assert_eq!(deliver(&sequencer, 110, 140), 130);
+        assert_eq!(deliver(&sequencer, 500, 150), 150);
+    }
+
+    #[test]
+    fn snapshot_reads_committed_state() {
+        let mut host = test_host("snapshot");
+        host.follow_level(7);
+        let user = PrivateKeySigner::random();
+        let faucet = Faucet {
+            amount: 1_000_000,
+            currency: Currencies::USDC,
+        };
+        let signature = sign(&user, &faucet.rlp_bytes());
+        run(&mut host, KernelMessage::Faucet(faucet), signature);
+
+        let mut snapshot = crate::cache::snapshot(&host.cache);
+        assert_eq!(snapshot.level, 7);
+        let address = Address::from(user.address().0.0);
+        let account = Account::load(&mut snapshot, &address).unwrap().unwrap();
+        assert_eq!(account.available(Currencies::USDC), 1_000_000);
+        let path = RefPath::assert_from(b"/written");
+        assert!(snapshot.store_write_all(&path, b"no").is_err());
+        assert_eq!(host.cache.read().unwrap().queues.pending, 1);
+    }
+
+    fn faucet(host: &mut SequencerHost, signer: &PrivateKeySigner) {
+        let faucet = Faucet {
+            amount: 1_000_000,
+            currency: Currencies::USDC,
+        };
+        let signature = sign(signer, &faucet.rlp_bytes());
+        run(host, KernelMessage::Faucet(faucet), signature);
+    }
+
+    /// Root of the whole state, hashed from scratch.
+    fn full_root(host: &mut SequencerHost) -> Hash {
+        let mut leaves = vec![Hash::leaf(&OrderBook::load(host).unwrap().rlp_bytes())];
+        for address in Account::addresses(host).unwrap() {
+            let account = Account::load(host, &address).unwrap().unwrap();
+            leaves.push(Hash::leaf(&account.rlp_bytes()));
+        }
+        merkle_root(&leaves)
+    }
+
+    #[test]
+    fn incremental_commitments() {
+        let mut host = test_host("commitments");
+        let users: Vec<PrivateKeySigner> = (0..5).map(|_| PrivateKeySigner::random()).collect();
+        // Level by level: new accounts, accounts saved again, and both
+        let levels: [(u32, &[usize]); 5] = [
+            (1, &[0, 1]),
+            (2, &[2]),
+            (3, &[0]),
+            (4, &[1, 3, 4]),
+            (2000, &[2]),
+        ];
+        let mut committed = None;
+        for (level, active) in levels {
+            host.level = level;
+            let expected = full_root(&mut host);
+            for user in active {
+                faucet(&mut host, &users[*user]);
+            }
+            if let Some(previous) = committed {
+                let commitment = StateCommitment::load(&mut host).unwrap().unwrap();
+                assert_eq!(commitment.level, previous);
+                assert_eq!(commitment.root, expected);
+            }
+            committed = Some(level);
+        }
+
+        // Proofs at a level keep the accounts as they were then
+        let mut snapshot = crate::cache::snapshot(&host.cache);
+        let address = Address::from(users[0].address().0.0);
+        let early = snapshot.account_proof(&address, Some(2)).unwrap();
+        let late = snapshot.account_proof(&address, Some(4)).unwrap();
+        assert!(early.verify() && late.verify());
+        assert_ne!(early.account, late.account);
+        let latest = snapshot.account_proof(&address, None).unwrap();
+        assert_eq!((latest.level, &latest.account), (4, &late.account));
+        // Created after that commitment
+        let created = Address::from(users[3].address().0.0);
+        assert!(snapshot.account_proof(&created, Some(3)).is_none());
+        drop(snapshot);
+
+        // Committing level 2000 prunes the commitments older than the kept ones
+        host.level = 3000;
+        faucet(&mut host, &users[4]);
+        let mut snapshot = crate::cache::snapshot(&host.cache);
+        assert!(snapshot.account_proof(&address, Some(2)).is_none());
+        assert!(snapshot.account_proof(&address, Some(4)).is_none());
+        let latest = snapshot.account_proof(&address, None).unwrap();
+        assert_eq!((latest.level, &latest.account), (2000, &late.account));
+        assert!(latest.verify());
+    }
+
+    #[test]
+    fn legacy_account_index() {
+        let mut host = test_host("legacy-index");
+        let addresses: Vec<Address> = (1..=3).map(|i| Address::from([i; 20])).collect();
+        host.write_value("/accounts_index", rlp::encode_list(&addresses).to_vec());
+        assert_eq!(Account::count(&mut host).unwrap(), 3);
+        assert_eq!(Account::addresses(&mut host).unwrap(), addresses);
+        assert_eq!(
+            Account::position(&mut host, &addresses[2]).unwrap(),
+            Some(2)
+        );
+        assert_eq!(host.read_value("/accounts_index"), None);
+    }
+
+    #[test]
+    fn reconciliation_at_committed_levels() {
+        let mut host = test_host("reconciliation");
+        let user = PrivateKeySigner::random();
+        for level in [10, 11, 13, 14] {
+            host.level = level;
+            faucet(&mut host, &user);
+        }
+        let snapshot = crate::cache::snapshot(&host.cache);
+        let (_, root, _) = snapshot.commitment(Some(11)).unwrap();
+        let mut status = SequencerStatus::default();
+        let compare = |status: &mut SequencerStatus, level, root| {
+            let remote = StateCommitment { level, root };
+            crate::server::compare_commitment(&snapshot, &remote, status);
+        };
+
+        compare(&mut status, 11, root);
+        assert_eq!((status.reconciliations, status.divergences), (1, 0));
+        compare(&mut status, 13, root);
+        assert_eq!(status.diverged_level, Some(13));
+        // Level 12 had no input, the sequencer didn't commit it
+        compare(&mut status, 12, root);
+        assert_eq!(status.divergences, 2);
+        // Not committed by the sequencer yet
+        compare(&mut status, 14, root);
+        compare(&mut status, 9, root);
+        assert_eq!(status.skipped_reconciliations, 2);
+        assert_eq!(status.last_reconciled_level, Some(9));
+    }
+
+    #[test]
+    fn failed_bootstrap_keeps_the_db() {
+        let mut host = test_host("bootstrap");
+        let user = PrivateKeySigner::random();
+        host.level = 1;
+        faucet(&mut host, &user);
+        let data_dir = std::env::temp_dir()
+            .join(format!("tradez-host-bootstrap-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        drop(host);
+
+        // No rollup node to copy the state from
+        let client = tradez_octez::smart_rollup_node::SmartRollupClient::new("http://127.0.0.1:1");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        assert!(
+            runtime
+                .block_on(crate::bootstrap::bootstrap(&data_dir, &client))
+                .is_err()
+        );
+        assert!(!std::path::Path::new(&format!("{}/bootstrap", data_dir)).exists());
+
+        let mut host = SequencerHost::new(data_dir);
+        let address = Address::from(user.address().0.0);
+        assert!(Account::load(&mut host, &address).unwrap().is_some());
+        assert_eq!(read_injection_log(&host.db).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn partial_liquidation() {
+        let mut host = test_host("partial-liquidation");
+        let oracle = PrivateKeySigner::random();
+        RollupSetup {
+            market_config: MarketConfig {
+                kind: MarketKind::Perpetual,
+                price_band_bps: 0,
+                circuit_breaker_bps: 0,
+                funding_interval: 10_000,
+                ..MarketConfig::default()
+            },
+            oracle_config: OracleConfig {
+                oracles: vec![Address::from(oracle.address().0.0)],
+                min_sources: 1,
+                max_age: 100,
+            },
+            ..RollupSetup::default()
+        }
+        .save(&mut host)
+        .unwrap();
+        let index_price = |host: &mut SequencerHost, price: u64| {
+            host.level += 1;
+            let update = OracleUpdate {
+                price: Price(price),
+                level: host.level,
+            };
+            let signature = sign(&oracle, &update.rlp_bytes());
+            run(host, KernelMessage::OracleUpdate(update), signature);
+            std::mem::take(&mut host.event_to_notify)
+                .into_iter()
+                .filter(|event| matches!(event, Event::Liquidated { .. }))
+                .collect::<Vec<_>>()
+        };
+        let fund = |host: &mut SequencerHost| {
+            Account::load(host, &Address::INSURANCE_FUND)
+                .unwrap()
+                .map_or(0, |fund| fund.available(Currencies::USDC))
+        };
+        host.level = 100;
+        index_price(&mut host, 2_000_000);
+
+        // 5 USDC backing a 10 XTZ long, the short side leaves no bid behind
+        let (long, short) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        for (signer, amount, side) in [
+            (&long, 5_000_000, Side::Bid),
+            (&short, 1_000_000_000, Side::Ask),
+        ] {
+            let faucet = Faucet {
+                amount,
+                currency: Currencies::USDC,
+            };
+            let signature = sign(signer, &faucet.rlp_bytes());
+            run(&mut host, KernelMessage::Faucet(faucet), signature);
+            let order = APIOrder {
+                side,
+                size: Qty(10_000_000),
+                price: Price(2_000_000),
+                nonce: 1,
+                display: Qty::ZERO,
+            };
+            let signature = sign(signer, &order.rlp_bytes());
+            run(&mut host, KernelMessage::PlaceOrder(order), signature);
+        }
+        let address = Address::from(long.address().0.0);
+        let account = |host: &mut SequencerHost| Account::load(host, &address).unwrap().unwrap();
+        assert_eq!(account(&mut host).position.size, 10_000_000);
+
+        // Below the maintenance margin at 1.6, with nothing to close it out against
+        let liquidations = index_price(&mut host, 1_600_000);
+        let fee = 160_000;
+        assert_eq!(
+            liquidations,
+            vec![Event::Liquidated {
+                user: address,
+                mark_price: Price(1_600_000),
+                fee,
+                bad_debt: 0,
+            }]
+        );
+        assert!(account(&mut host).liquidating);
+        assert_eq!(fund(&mut host), fee);
+        // Charged once
+        faucet(&mut host, &short);
+        assert!(
+            !host
+                .event_to_notify
+                .iter()
+                .any(|event| matches!(event, Event::Liquidated { .. }))
+        );
+        assert_eq!(fund(&mut host), fee);
+
+        // Underwater at 1.4: the fund covers what it can of the deficit
+        let liquidations = index_price(&mut host, 1_400_000);
+        let [Event::Liquidated { fee, bad_debt, .. }] = liquidations[..] else {
+            panic!("{:?}", liquidations);
+        };
+        assert_eq!(fee, 0);
+        assert!(bad_debt > 0);
+        assert_eq!(fund(&mut host), 0);
+        assert!(
+            account(&mut host)
+                .margin(Price(1_400_000))
+                .unwrap()
+                .equity()
+                < 0
+        );
+
+        // Back above the maintenance margin, a later liquidation charges the fee again
+        assert!(index_price(&mut host, 2_000_000).is_empty());
+        assert!(!account(&mut host).liquidating);
+    }
+
+    #[test]
+    fn liquidation_scan_scope() {
+        let mut host = test_host("liquidation-scan");
+        let oracle = PrivateKeySigner::random();
+        RollupSetup {
+            oracle_config: OracleConfig {
+                oracles: vec![Address::from(oracle.address().0.0)],
+                min_sources: 1,
+                max_age: 100,
+            },
+            ..RollupSetup::default()
+        }
+        .save(&mut host)
+        .unwrap();
+        host.level = 100;
+        let update = OracleUpdate {
+            price: Price(2_000_000),
+            level: 100,
+        };
+        let signature = sign(&oracle, &update.rlp_bytes());
+        run(&mut host, KernelMessage::OracleUpdate(update), signature);
+        let liquidated = |host: &mut SequencerHost| {
+            std::mem::take(&mut host.event_to_notify)
+                .iter()
+                .any(|event| matches!(event, Event::Liquidated { .. }))
+        };
+        liquidated(&mut host);
+
+        // Underwater without the kernel knowing: only a scan finds it
+        let (underwater, other) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        let address = Address::from(underwater.address().0.0);
+        let mut account = Account::new(address);
+        account.balances.insert(Currencies::XTZ, 300_000_000);
+        account.debts.insert(Currencies::USDC, 1_000_000_000);
+        account.debt_level = 100;
+        account.save(&mut host).unwrap();
+        let mut margin_accounts = liquidation::load_margin_accounts(&mut host).unwrap();
+        margin_accounts.push(address);
+        liquidation::save_margin_accounts(&mut host, &margin_accounts).unwrap();
+
+        // Same mark price, level and trades: only the accounts the input saves are checked
+        faucet(&mut host, &other);
+        assert!(!liquidated(&mut host));
+        faucet(&mut host, &underwater);
+        assert!(liquidated(&mut host));
+    }
+
+    #[test]
+    fn delegate_refusals_give_a_reason() {
+        let mut host = test_host("delegate-refusals");
+        host.level = 100;
+        let (owner, key) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        faucet(&mut host, &owner);
+        let owner_address = Address::from(owner.address().0.0);
+        let key_address = Address::from(key.address().0.0);
+        let refusal = |host: &mut SequencerHost, message: KernelMessage, signature| {
+            let input = SignedInput::new(message, signature);
+            match host.run_input(input.rlp_bytes().to_vec()) {
+                Err(RunError::Refused(reason)) => reason,
+                outcome => panic!("{:?}", outcome),
+            }
+        };
+
+        let grant = |key| DelegateGrant {
+            key,
+            scope: DelegateScope::Trade,
+            expiry_level: 1_000,
+            max_notional: 0,
+        };
+        let own_key = grant(owner_address);
+        let signature = sign(&owner, &own_key.rlp_bytes());
+        assert_eq!(
+            refusal(&mut host, KernelMessage::AddDelegate(own_key), signature).as_deref(),
+            Some("delegate_key_in_use")
+        );
+
+        let revoke = RevokeDelegate { key: key_address };
+        let signature = sign(&owner, &revoke.rlp_bytes());
+        assert_eq!(
+            refusal(&mut host, KernelMessage::RevokeDelegate(revoke), signature).as_deref(),
+            Some("delegate_not_found")
+        );
+
+        // A revoked key can't cancel for the owner anymore
+        let signature = sign(&owner, &grant(key_address).rlp_bytes());
+        run(
+            &mut host,
+            KernelMessage::AddDelegate(grant(key_address)),
+            signature,
+        );
+        let signature = sign(&owner, &revoke.rlp_bytes());
+        run(&mut host, KernelMessage::RevokeDelegate(revoke), signature);
+        let cancel = CancelOrder { order_id: 1 };
+        let signature = sign(&key, &cancel.rlp_bytes());
+        assert_eq!(
+            refusal(&mut host, KernelMessage::CancelOrder(cancel), signature).as_deref(),
+            Some("delegate_revoked")
+        );
+    }
+
+    #[test]
+    fn margin_counts_the_taker_fee() {
+        let mut host = test_host("taker-fee-margin");
+        let trader = PrivateKeySigner::random();
+        faucet(&mut host, &trader);
+        let place = |host: &mut SequencerHost, size, nonce| {
+            let order = APIOrder {
+                side: Side::Bid,
+                size: Qty(size),
+                price: Price(2_000_000),
+                nonce,
+                display: Qty::ZERO,
+            };
+            let signature = sign(&trader, &order.rlp_bytes());
+            let input = SignedInput::new(KernelMessage::PlaceOrder(order), signature);
+            host.run_input(input.rlp_bytes().to_vec())
+        };
+
+        // 6 USDC borrows 5 on 1 of equity, exactly the initial margin before its fee
+        match place(&mut host, 3_000_000, 1) {
+            Err(RunError::Refused(reason)) => {
+                assert_eq!(reason.as_deref(), Some("insufficient_margin"))
+            }
+            outcome => panic!("{:?}", outcome),
+        }
+        place(&mut host, 2_997_000, 2).unwrap();
+    }
+
+    #[test]
+    fn locked_balances_follow_orders() {
+        let mut host = test_host("locked-balances");
+        let (buyer, seller) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        for (signer, currency) in [(&buyer, Currencies::USDC), (&seller, Currencies::XTZ)] {
+            let faucet = Faucet {
+                amount: 10_000_000,
+                currency,
+            };
+            let signature = sign(signer, &faucet.rlp_bytes());
+            run(&mut host, KernelMessage::Faucet(faucet), signature);
+        }
+        let place = |host: &mut SequencerHost, signer, side, size, nonce| {
+            let order = APIOrder {
+                side,
+                size: Qty(size),
+                price: Price(2_000_000),
+                nonce,
+                display: Qty::ZERO,
+            };
+            let signature = sign(signer, &order.rlp_bytes());
+            run(host, KernelMessage::PlaceOrder(order), signature);
+        };
+        let locked = |host: &mut SequencerHost| {
+            let account = Account::load(host, &Address::from(buyer.address().0.0))
+                .unwrap()
+                .unwrap();
+            let orderbook = OrderBook::load(host).unwrap();
+            let reserved = tradez_kernel::audit::account_reservations(
+                &MarketConfig::default(),
+                &orderbook,
+                &account,
+            );
+            assert_eq!(reserved, (account.locked(Currencies::USDC), 0));
+            (account.locked(Currencies::USDC), account.orders)
+        };
+
+        // Locked when placed, spent as it fills
+        place(&mut host, &buyer, Side::Bid, 3_000_000, 1);
+        let (usdc, orders) = locked(&mut host);
+        assert_eq!(usdc, 6_000_000);
+        let order_id = *orders.first().unwrap();
+        place(&mut host, &seller, Side::Ask, 1_000_000, 1);
+        assert_eq!(locked(&mut host).0, 4_000_000);
+
+        // Given back when cancelled
+        let cancel = CancelOrder { order_id };
+        let signature = sign(&buyer, &cancel.rlp_bytes());
+        run(&mut host, KernelMessage::CancelOrder(cancel), signature);
+        assert_eq!(locked(&mut host), (0, Default::default()));
+
+        // Released as the last fill completes the order
+        place(&mut host, &buyer, Side::Bid, 1_000_000, 2);
+        assert_eq!(locked(&mut host).0, 2_000_000);
+        place(&mut host, &seller, Side::Ask, 1_000_000, 2);
+        assert_eq!(locked(&mut host), (0, Default::default()));
+    }
+
+    #[test]
+    fn fill_history_pages() {
+        use tradez_types::fills::{self, FILLS_PAGE_SIZE, Fill, FillRole, MAX_FILLS_PER_ACCOUNT};
+
+        let mut host = test_host("fill-history");
+        let address = Address::from([7u8; 20]);
+        let fill = |trade_id| Fill {
+            trade_id,
+            order_id: 1,
+            role: FillRole::Maker,
+            side: Side::Bid,
+            price: Price(2_000_000),
+            qty: Qty(1_000),
+            fee: 0,
+            fee_currency: Currencies::USDC,
+            level: 1,
+        };
+        let trade_ids = |page: &fills::FillPage| -> Vec<u64> {
+            page.fills.iter().map(|fill| fill.trade_id).collect()
+        };
+
+        let page = fills::load_fills(&mut host, &address, 0).unwrap();
+        assert!(page.fills.is_empty());
+        assert_eq!(page.next_cursor, 0);
+
+        // Pages follow each other up to the last fill
+        for trade_id in 0..150 {
+            fills::append_fill(&mut host, &address, &fill(trade_id)).unwrap();
+        }
+        let page = fills::load_fills(&mut host, &address, 0).unwrap();
+        assert_eq!(trade_ids(&page), (0..FILLS_PAGE_SIZE).collect::<Vec<_>>());
+        let page = fills::load_fills(&mut host, &address, page.next_cursor).unwrap();
+        assert_eq!(trade_ids(&page), (FILLS_PAGE_SIZE..150).collect::<Vec<_>>());
+        assert_eq!(page.next_cursor, 150);
+        let page = fills::load_fills(&mut host, &address, 500).unwrap();
+        assert!(page.fills.is_empty());
+        assert_eq!(page.next_cursor, 150);
+
+        // Past the ring size, the oldest fills are overwritten
+        let count = MAX_FILLS_PER_ACCOUNT + 50;
+        for trade_id in 150..count {
+            fills::append_fill(&mut host, &address, &fill(trade_id)).unwrap();
+        }
+        assert_eq!(fills::fill_count(&mut host, &address).unwrap(), count);
+        let page = fills::load_fills(&mut host, &address, 10).unwrap();
+        assert_eq!(
+            trade_ids(&page),
+            (50..50 + FILLS_PAGE_SIZE).collect::<Vec<_>>()
+        );
+        assert_eq!(page.next_cursor, 50 + FILLS_PAGE_SIZE);
+        let page = fills::load_fills(&mut host, &address, count - 20).unwrap();
+        assert_eq!(trade_ids(&page), (count - 20..count).collect::<Vec<_>>());
+        assert_eq!(page.next_cursor, count);
     }
 }
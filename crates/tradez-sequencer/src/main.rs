@@ -1,7 +1,11 @@
+use alloy_signer_local::PrivateKeySigner;
 use clap::Parser;
 
+mod bootstrap;
+mod cache;
 mod host;
 mod server;
+mod validation;
 
 #[derive(Parser)]
 pub struct Args {
@@ -13,11 +17,49 @@ pub struct Args {
 
     #[clap(long, default_value_t = String::from("tradez-sequencer/"))]
     pub data_dir: String,
+
+    /// Longest an input waits before it is injected into the rollup, in milliseconds
+    #[clap(long, default_value_t = 2000)]
+    pub batch_max_latency_ms: u64,
+
+    /// Size of the pending inputs, in bytes, past which they are injected right away
+    #[clap(long, default_value_t = 30_000)]
+    pub batch_max_bytes: usize,
+
+    /// Rebuild the db from the rollup state before starting, the previous one is kept aside
+    #[clap(long)]
+    pub bootstrap: bool,
+
+    /// Private key of the sequencer in the rollup setup, in hex. It signs the level of every
+    /// input, the rollup ignores the levels of an unknown key
+    #[clap(long)]
+    pub sequencer_key: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
-    server::launch_server(args.rpc_port, args.smart_rollup_addr, args.data_dir).await?;
+    let signer = args
+        .sequencer_key
+        .map(|key| key.parse::<PrivateKeySigner>())
+        .transpose()
+        .map_err(std::io::Error::other)?;
+    if args.bootstrap {
+        let client =
+            tradez_octez::smart_rollup_node::SmartRollupClient::new(&args.smart_rollup_addr);
+        bootstrap::bootstrap(&args.data_dir, &client).await?;
+    }
+    let batch_config = server::BatchConfig {
+        max_latency: std::time::Duration::from_millis(args.batch_max_latency_ms),
+        max_bytes: args.batch_max_bytes,
+    };
+    server::launch_server(
+        args.rpc_port,
+        args.smart_rollup_addr,
+        args.data_dir,
+        batch_config,
+        signer,
+    )
+    .await?;
     Ok(())
 }
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use alloy_primitives::hex::FromHex;
+use alloy_signer_local::PrivateKeySigner;
 use hyper::Method;
 use jsonrpsee::{
     PendingSubscriptionSink, SubscriptionSink,
@@ -9,166 +10,340 @@ use jsonrpsee::{
     types::ErrorObject,
 };
 use rlp::Encodable;
+use tezos_smart_rollup_host::runtime::Runtime;
 use tokio::sync::Mutex;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
-use tradez_kernel::{account::Account, kernel_loop};
+use tradez_kernel::{account::Account, audit, commitment};
+use tradez_octez::smart_rollup_node::BatcherMessageStatus;
 use tradez_types::{
     KernelMessage, SignedInput,
     address::Address,
-    api::TradezRpcServer,
-    currencies::Currencies,
-    orderbook::OrderBook,
-    position::{APIOrder, CancelOrder, Faucet, Price, Qty, Side, UserOrder},
+    api::{TradezRpcServer, input_error},
+    commitment::{AccountProof, STATE_COMMITMENT_STR_PATH, StateCommitment},
+    currencies::{Balance, Currencies},
+    delegate::{self, DelegateGrant, RevokeDelegate},
+    fills::{self, FillPage},
+    margin::MarginState,
+    market::{MarketConfig, MarketStatus},
+    oracle::{OracleConfig, OracleFeed, OracleUpdate},
+    orderbook::Event,
+    perpetual::{PerpPosition, PerpStatus},
+    position::{APIOrder, CancelOrder, Faucet, OrdType, Side, Transfer, UserOrder},
+    setup::RollupSetup,
+    status::SequencerStatus,
+    stops::{APIOcoOrder, APIStopOrder},
+    supply::AuditReport,
+    units::{Price, Qty},
 };
 
-use crate::host::SequencerHost;
+use crate::{
+    cache::{self, StateCache},
+    host::SequencerHost,
+    validation,
+};
+
+pub const LEVEL_POLLING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+pub const FLUSH_POLLING_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+pub const INCLUSION_POLLING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+pub const MIN_INJECTION_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+pub const MAX_INJECTION_BACKOFF: std::time::Duration = std::time::Duration::from_secs(60);
+/// Time after which an injected input that isn't in the rollup inbox is reported as stuck.
+pub const STUCK_INJECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+pub const RECONCILIATION_POLLING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
 
-pub const NUMBER_INPUTS_IN_ONE_ROLLUP_MESSAGE: usize = 10;
+/// When the inputs the kernel ran are sent to the rollup node.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfig {
+    /// Longest an input waits before its batch is injected.
+    pub max_latency: std::time::Duration,
+    /// Size of the inputs past which a batch is injected without waiting, kept below the size
+    /// of an L1 operation.
+    pub max_bytes: usize,
+}
 
 pub struct TradezRpcImpl {
     pub smart_rollup_node_client: tradez_octez::smart_rollup_node::SmartRollupClient,
     pub host: Arc<Mutex<SequencerHost>>,
+    /// Shared with the host, read RPCs don't wait on it.
+    pub cache: Arc<std::sync::RwLock<StateCache>>,
     pub subscribers: Arc<Mutex<Vec<SubscriptionSink>>>,
+    pub batch_config: BatchConfig,
+    /// Comparisons with the rollup state, the rest of the status comes from the host.
+    pub reconciliation: Arc<Mutex<SequencerStatus>>,
 }
 
 impl TradezRpcImpl {
-    async fn process_inputs_with_host<F, R>(&self, inputs: Vec<Vec<u8>>, with_host: F) -> R
+    /// Encodes an input once it passed the checks the sequencer makes without the kernel.
+    fn signed_input(&self, message: KernelMessage, signature: Vec<u8>) -> RpcResult<Vec<u8>> {
+        validation::validate(&self.cache, &message, &signature)?;
+        Ok(SignedInput::new(message, signature).rlp_bytes().to_vec())
+    }
+
+    /// Runs an input, then queues it for the rollup unless the kernel refused it.
+    async fn process_input_with_host<F, R>(&self, input: Vec<u8>, with_host: F) -> RpcResult<R>
     where
         F: FnOnce(&mut SequencerHost) -> R,
     {
         let mut host = self.host.lock().await;
-        host.add_inputs(inputs);
-        kernel_loop(&mut *host);
-        let result = with_host(&mut host);
-        let maybe_batch =
-            if host.input_to_send_to_rollup.len() >= NUMBER_INPUTS_IN_ONE_ROLLUP_MESSAGE {
-                Some(
-                    host.input_to_send_to_rollup
-                        .drain(0..NUMBER_INPUTS_IN_ONE_ROLLUP_MESSAGE)
-                        .collect(),
-                )
-            } else {
-                None
-            };
-        if let Some(batch) = maybe_batch
-            && let Err(e) = self
-                .smart_rollup_node_client
-                .inject_inbox_messages(batch)
-                .await
-        {
-            println!("Failed to inject inbox message: {:?}", e);
+        // An input stamped with no level would run with the clock of another time
+        if host.level == 0 {
+            return Err(ErrorObject::owned(
+                input_error::NOT_READY,
+                "Input refused: the L1 level isn't known yet",
+                None::<()>,
+            ));
         }
-        result
+        host.run_input(input).map_err(validation::run_error)?;
+        let result = with_host(&mut host);
+        inject_pending(
+            &self.smart_rollup_node_client,
+            &mut host,
+            &self.batch_config,
+            false,
+        )
+        .await;
+        Ok(result)
     }
 
-    async fn process_inputs(&self, inputs: Vec<Vec<u8>>) {
-        self.process_inputs_with_host(inputs, |_| ()).await;
-    }
-}
-
-#[async_trait::async_trait]
-impl TradezRpcServer for TradezRpcImpl {
-    async fn send_order(&self, api_order: APIOrder, signature: Vec<u8>) -> RpcResult<String> {
-        let inputs = vec![
-            SignedInput::new(KernelMessage::PlaceOrder(api_order), signature)
-                .rlp_bytes()
-                .to_vec(),
-        ];
+    /// Runs an input, then notifies the subscribers of the book and of the events it raised.
+    /// Any input may raise events, a new index price or a trade of a triggered stop.
+    async fn process_input_and_notify(&self, input: Vec<u8>) -> RpcResult<()> {
         let (bids, asks, events) = self
-            .process_inputs_with_host(inputs, |host| {
-                let orderbook = OrderBook::load(&mut *host).unwrap();
-                let (bids, asks) = orderbook.bids_and_asks();
+            .process_input_with_host(input, |host| {
+                let (bids, asks) = host.cache.read().unwrap().orderbook.bids_and_asks();
                 let events = std::mem::take(&mut host.event_to_notify);
                 (bids, asks, events)
             })
-            .await;
+            .await?;
 
-        {
-            let mut subscribers = self.subscribers.lock().await;
-            subscribers.retain(|subscriber| !subscriber.is_closed());
-            for subscriber in subscribers.iter() {
-                match subscriber.method_name() {
-                    "subscribeEvent" => {
-                        for event in &events {
+        let mut subscribers = self.subscribers.lock().await;
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+        for subscriber in subscribers.iter() {
+            match subscriber.method_name() {
+                "subscribeEvent" => {
+                    for event in &events {
+                        subscriber
+                            .send(serde_json::value::to_raw_value(event).unwrap())
+                            .await
+                            .unwrap();
+                    }
+                }
+                "subscribeIndexPrice" => {
+                    for event in &events {
+                        if let Event::IndexPrice { price, .. } = event {
                             subscriber
-                                .send(serde_json::value::to_raw_value(event).unwrap())
+                                .send(serde_json::value::to_raw_value(price).unwrap())
                                 .await
                                 .unwrap();
                         }
                     }
-                    "subscribeOrderBookState" => {
-                        subscriber
-                            .send(
-                                serde_json::value::to_raw_value(&(bids.clone(), asks.clone()))
-                                    .unwrap(),
-                            )
-                            .await
-                            .unwrap();
-                    }
-                    _ => {}
                 }
+                "subscribeOrderBookState" => {
+                    subscriber
+                        .send(
+                            serde_json::value::to_raw_value(&(bids.clone(), asks.clone())).unwrap(),
+                        )
+                        .await
+                        .unwrap();
+                }
+                _ => {}
             }
         }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl TradezRpcServer for TradezRpcImpl {
+    async fn send_order(&self, api_order: APIOrder, signature: Vec<u8>) -> RpcResult<String> {
+        let input = self.signed_input(KernelMessage::PlaceOrder(api_order), signature)?;
+        self.process_input_and_notify(input).await?;
         Ok(String::from("Order received"))
     }
 
+    async fn send_stop_order(
+        &self,
+        stop_order: APIStopOrder,
+        signature: Vec<u8>,
+    ) -> RpcResult<String> {
+        let input = self.signed_input(KernelMessage::PlaceStop(stop_order), signature)?;
+        self.process_input_and_notify(input).await?;
+        Ok(String::from("Stop order received"))
+    }
+
+    async fn send_oco_order(
+        &self,
+        oco_order: APIOcoOrder,
+        signature: Vec<u8>,
+    ) -> RpcResult<String> {
+        let input = self.signed_input(KernelMessage::PlaceOco(oco_order), signature)?;
+        self.process_input_and_notify(input).await?;
+        Ok(String::from("OCO order received"))
+    }
+
+    async fn send_oracle_update(
+        &self,
+        update: OracleUpdate,
+        signature: Vec<u8>,
+    ) -> RpcResult<String> {
+        let input = self.signed_input(KernelMessage::OracleUpdate(update), signature)?;
+        self.process_input_and_notify(input).await?;
+        Ok(String::from("Oracle update received"))
+    }
+
+    async fn add_delegate(&self, grant: DelegateGrant, signature: Vec<u8>) -> RpcResult<String> {
+        let input = self.signed_input(KernelMessage::AddDelegate(grant), signature)?;
+        self.process_input_and_notify(input).await?;
+        Ok(String::from("Delegate request received"))
+    }
+
+    async fn revoke_delegate(
+        &self,
+        revoke: RevokeDelegate,
+        signature: Vec<u8>,
+    ) -> RpcResult<String> {
+        let input = self.signed_input(KernelMessage::RevokeDelegate(revoke), signature)?;
+        self.process_input_and_notify(input).await?;
+        Ok(String::from("Revoke request received"))
+    }
+
     async fn cancel_order(&self, params: CancelOrder, signature: Vec<u8>) -> RpcResult<String> {
-        let inputs = vec![
-            SignedInput::new(KernelMessage::CancelOrder(params), signature)
-                .rlp_bytes()
-                .to_vec(),
-        ];
-        self.process_inputs(inputs).await;
+        let input = self.signed_input(KernelMessage::CancelOrder(params), signature)?;
+        self.process_input_and_notify(input).await?;
         Ok(String::from("Cancel request received"))
     }
 
     async fn faucet(&self, params: Faucet, signature: Vec<u8>) -> RpcResult<String> {
-        let inputs = vec![
-            SignedInput::new(KernelMessage::Faucet(params), signature)
-                .rlp_bytes()
-                .to_vec(),
-        ];
-        self.process_inputs(inputs).await;
+        let input = self.signed_input(KernelMessage::Faucet(params), signature)?;
+        self.process_input_and_notify(input).await?;
         Ok(String::from("Faucet request received"))
     }
 
-    async fn get_balances(&self, address: String) -> RpcResult<Vec<(Currencies, u64)>> {
+    async fn transfer(&self, transfer: Transfer, signature: Vec<u8>) -> RpcResult<String> {
+        let input = self.signed_input(KernelMessage::Transfer(transfer), signature)?;
+        self.process_input_and_notify(input).await?;
+        Ok(String::from("Transfer received"))
+    }
+
+    async fn get_balances(&self, address: String) -> RpcResult<Vec<(Currencies, Balance)>> {
         let addr = Address::from_hex(&address).map_err(|e| {
             ErrorObject::owned::<()>(-32000, format!("Failed to decode address: {:?}", e), None)
         })?;
-        let account_result = {
-            let mut host = self.host.lock().await;
-            Account::load(&mut *host, &addr)
-        };
-        let account = account_result
+        let account = cache::account(&self.cache, &addr)
             .map_err(|e| {
                 ErrorObject::owned::<()>(-32000, format!("Failed to load account: {:?}", e), None)
             })?
             .unwrap_or_else(|| Account::new(addr));
-        let balances: Vec<(tradez_types::currencies::Currencies, u64)> =
-            account.balances.into_iter().collect();
+        let balances = [Currencies::USDC, Currencies::XTZ]
+            .into_iter()
+            .filter(|currency| {
+                account.balances.contains_key(currency)
+                    || account.locked.contains_key(currency)
+                    || account.debts.contains_key(currency)
+            })
+            .map(|currency| {
+                let balance = Balance {
+                    available: account.available(currency),
+                    locked: account.locked(currency),
+                    debt: account.debt(currency),
+                };
+                (currency, balance)
+            })
+            .collect();
         Ok(balances)
     }
 
-    async fn get_orders(&self, address: String) -> RpcResult<Vec<(u64, UserOrder)>> {
+    async fn get_margin(&self, address: String) -> RpcResult<MarginState> {
         let addr = Address::from_hex(&address).map_err(|e| {
             ErrorObject::owned::<()>(-32000, format!("Failed to decode address: {:?}", e), None)
         })?;
-        let (account, orderbook) = {
-            let mut host = self.host.lock().await;
-            let account = Account::load(&mut *host, &addr).map_err(|e| {
-                ErrorObject::owned::<()>(-32000, format!("Failed to load account: {:?}", e), None)
+        let account = cache::account(&self.cache, &addr).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to load account: {:?}", e), None)
+        })?;
+        let (mut account, config, mark_price, level) = {
+            let mut snapshot = cache::snapshot(&self.cache);
+            let config = MarketConfig::load(&mut snapshot).map_err(|e| {
+                ErrorObject::owned::<()>(
+                    -32000,
+                    format!("Failed to load market config: {:?}", e),
+                    None,
+                )
             })?;
-            let orderbook = OrderBook::load(&mut *host).map_err(|e| {
-                ErrorObject::owned::<()>(-32000, format!("Failed to load orderbook: {:?}", e), None)
+            let status = MarketStatus::load(&mut snapshot).map_err(|e| {
+                ErrorObject::owned::<()>(
+                    -32000,
+                    format!("Failed to load market status: {:?}", e),
+                    None,
+                )
             })?;
-            (account.unwrap_or_else(|| Account::new(addr)), orderbook)
+            let level = snapshot.level;
+            let index_price = load_index_price(&mut snapshot, level)?;
+            let orderbook = &self.cache.read().unwrap().orderbook;
+            (
+                account.unwrap_or_else(|| Account::new(addr)),
+                config,
+                index_price.or_else(|| status.reference_price(orderbook)),
+                level,
+            )
         };
+        let mark_price = mark_price.ok_or_else(|| {
+            ErrorObject::owned::<()>(-32000, "No mark price yet".to_string(), None)
+        })?;
+        // Interest is only charged when the account is touched, include what is due so far
+        account.accrue_interest(level, config.borrow_rate_ppm);
+        account.margin(mark_price).ok_or_else(|| {
+            ErrorObject::owned::<()>(-32000, "Margin value overflow".to_string(), None)
+        })
+    }
+
+    async fn get_position(&self, address: String) -> RpcResult<PerpPosition> {
+        let addr = Address::from_hex(&address).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to decode address: {:?}", e), None)
+        })?;
+        let account = cache::account(&self.cache, &addr).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to load account: {:?}", e), None)
+        })?;
+        Ok(account.map(|account| account.position).unwrap_or_default())
+    }
+
+    async fn get_delegates(&self, address: String) -> RpcResult<Vec<DelegateGrant>> {
+        let addr = Address::from_hex(&address).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to decode address: {:?}", e), None)
+        })?;
+        delegate::load_delegates(&mut cache::snapshot(&self.cache), &addr).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to load delegates: {:?}", e), None)
+        })
+    }
+
+    async fn get_fills(&self, address: String, cursor: Option<u64>) -> RpcResult<FillPage> {
+        let addr = Address::from_hex(&address).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to decode address: {:?}", e), None)
+        })?;
+        fills::load_fills(
+            &mut cache::snapshot(&self.cache),
+            &addr,
+            cursor.unwrap_or(0),
+        )
+        .map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to load fills: {:?}", e), None)
+        })
+    }
+
+    async fn get_orders(&self, address: String) -> RpcResult<Vec<(u64, UserOrder)>> {
+        let addr = Address::from_hex(&address).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to decode address: {:?}", e), None)
+        })?;
+        let account = cache::account(&self.cache, &addr)
+            .map_err(|e| {
+                ErrorObject::owned::<()>(-32000, format!("Failed to load account: {:?}", e), None)
+            })?
+            .unwrap_or_else(|| Account::new(addr));
+        let cache = self.cache.read().unwrap();
 
         let mut orders: Vec<(u64, UserOrder)> = Vec::new();
         for id in account.orders {
-            if let Some(order) = orderbook.get_order(id) {
+            if let Some(order) = cache.orderbook.get_order(id) {
                 let user_order = UserOrder {
                     side: order.side,
                     ord_type: order.ord_type,
@@ -176,6 +351,18 @@ impl TradezRpcServer for TradezRpcImpl {
                     qty: order.qty,
                     remaining: order.remaining,
                     nonce: order.nonce,
+                    display: order.display,
+                };
+                orders.push((id, user_order));
+            } else if let Some(stop) = cache.stop_book.stops.get(&id) {
+                let user_order = UserOrder {
+                    side: stop.side,
+                    ord_type: OrdType::Stop,
+                    price: stop.trigger_price,
+                    qty: stop.size,
+                    remaining: stop.size,
+                    nonce: stop.nonce,
+                    display: Qty::ZERO,
                 };
                 orders.push((id, user_order));
             }
@@ -184,18 +371,88 @@ impl TradezRpcServer for TradezRpcImpl {
     }
 
     async fn get_orderbook_state(&self) -> RpcResult<(Vec<(Price, Qty)>, Vec<(Price, Qty)>)> {
-        let orderbook_result = {
-            let mut host = self.host.lock().await;
-            OrderBook::load(&mut *host)
-        };
-        let orderbook = orderbook_result.map_err(|e| {
-            ErrorObject::owned::<()>(-32000, format!("Failed to load orderbook: {:?}", e), None)
+        Ok(self.cache.read().unwrap().orderbook.bids_and_asks())
+    }
+
+    async fn get_market_config(&self) -> RpcResult<MarketConfig> {
+        MarketConfig::load(&mut cache::snapshot(&self.cache)).map_err(|e| {
+            ErrorObject::owned::<()>(
+                -32000,
+                format!("Failed to load market config: {:?}", e),
+                None,
+            )
+        })
+    }
+
+    async fn get_market_status(&self) -> RpcResult<MarketStatus> {
+        MarketStatus::load(&mut cache::snapshot(&self.cache)).map_err(|e| {
+            ErrorObject::owned::<()>(
+                -32000,
+                format!("Failed to load market status: {:?}", e),
+                None,
+            )
+        })
+    }
+
+    async fn get_perp_status(&self) -> RpcResult<PerpStatus> {
+        PerpStatus::load(&mut cache::snapshot(&self.cache)).map_err(|e| {
+            ErrorObject::owned::<()>(
+                -32000,
+                format!("Failed to load perpetual status: {:?}", e),
+                None,
+            )
+        })
+    }
+
+    async fn get_index_price(&self) -> RpcResult<Option<Price>> {
+        let mut snapshot = cache::snapshot(&self.cache);
+        let level = snapshot.level;
+        load_index_price(&mut snapshot, level)
+    }
+
+    async fn get_audit(&self) -> RpcResult<AuditReport> {
+        audit::audit(&mut cache::snapshot(&self.cache)).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to audit state: {:?}", e), None)
+        })
+    }
+
+    async fn get_status(&self) -> RpcResult<SequencerStatus> {
+        let mut status = self.reconciliation.lock().await.clone();
+        status.input_count = commitment::load_input_count(&mut cache::snapshot(&self.cache))
+            .map_err(|e| {
+                ErrorObject::owned::<()>(
+                    -32000,
+                    format!("Failed to load input count: {:?}", e),
+                    None,
+                )
+            })?;
+        let queues = self.cache.read().unwrap().queues;
+        status.pending_inputs = queues.pending;
+        status.injected_inputs = queues.injected;
+        status.stuck_inputs = queues.stuck;
+        Ok(status)
+    }
+
+    async fn get_account_proof(
+        &self,
+        address: String,
+        level: Option<u32>,
+    ) -> RpcResult<AccountProof> {
+        let addr = Address::from_hex(&address).map_err(|e| {
+            ErrorObject::owned::<()>(-32000, format!("Failed to decode address: {:?}", e), None)
         })?;
-        Ok(orderbook.bids_and_asks())
+        let proof = cache::snapshot(&self.cache).account_proof(&addr, level);
+        proof.ok_or_else(|| {
+            ErrorObject::owned::<()>(
+                -32000,
+                "No committed state for this account at this level".to_string(),
+                None,
+            )
+        })
     }
 
     async fn get_history(&self) -> RpcResult<Vec<(u128, Qty, Price, Side)>> {
-        Ok(self.host.lock().await.read_history())
+        Ok(cache::snapshot(&self.cache).history())
     }
 
     async fn subscribe_order_book_state(
@@ -212,23 +469,298 @@ impl TradezRpcServer for TradezRpcImpl {
         self.subscribers.lock().await.push(sink);
         Ok(())
     }
+
+    async fn subscribe_index_price(&self, pending: PendingSubscriptionSink) -> SubscriptionResult {
+        let sink = pending.accept().await?;
+        self.subscribers.lock().await.push(sink);
+        Ok(())
+    }
+}
+
+/// Injects the queued inputs in batches of at most `max_bytes`, only full ones unless `flush`.
+/// They leave the queue once the rollup node accepted them. A failed batch stays first in line
+/// and is tried again after a delay that doubles with each failure.
+async fn inject_pending(
+    client: &tradez_octez::smart_rollup_node::SmartRollupClient,
+    host: &mut SequencerHost,
+    config: &BatchConfig,
+    flush: bool,
+) {
+    if let Some((retry_at, _)) = host.injection_backoff
+        && std::time::Instant::now() < retry_at
+    {
+        return;
+    }
+    while !host.input_to_send_to_rollup.is_empty()
+        && (flush || host.pending_bytes() >= config.max_bytes)
+    {
+        let (ids, batch): (Vec<u64>, Vec<Vec<u8>>) = host
+            .pending_injections(config.max_bytes)
+            .into_iter()
+            .unzip();
+        let message_ids = match client.inject_inbox_messages(batch).await {
+            Ok(message_ids) => message_ids,
+            Err(e) => {
+                let delay = host
+                    .injection_backoff
+                    .map(|(_, delay)| (delay * 2).min(MAX_INJECTION_BACKOFF))
+                    .unwrap_or(MIN_INJECTION_BACKOFF);
+                println!(
+                    "Failed to inject inbox messages, retrying in {:?}: {:?}",
+                    delay, e
+                );
+                host.injection_backoff = Some((std::time::Instant::now() + delay, delay));
+                return;
+            }
+        };
+        host.injection_backoff = None;
+        if message_ids.len() != ids.len() {
+            println!(
+                "Batcher returned {} ids for {} messages, their inclusion can't be tracked",
+                message_ids.len(),
+                ids.len()
+            );
+        }
+        let accepted: Vec<(u64, String)> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, message_ids.get(i).cloned().unwrap_or_default()))
+            .collect();
+        host.acknowledge_injections(&accepted);
+    }
+}
+
+/// Asks the batcher where the injected inputs are, forgets the ones in the rollup inbox or
+/// that it doesn't know, and reports the ones that take too long. Inputs aren't injected
+/// again: one that reached the rollup twice would run twice there. An input whose status
+/// can't be fetched is asked about again next time, without holding back the others.
+async fn track_inclusions(
+    client: &tradez_octez::smart_rollup_node::SmartRollupClient,
+    host: &Mutex<SequencerHost>,
+) {
+    let injected: Vec<(u64, String)> = host
+        .lock()
+        .await
+        .injected
+        .iter()
+        .map(|injected| (injected.id, injected.message_id.clone()))
+        .collect();
+    let mut forgotten = vec![];
+    let mut failed = vec![];
+    for (id, message_id) in injected {
+        match client.get_batcher_message_status(&message_id).await {
+            Ok(BatcherMessageStatus::Included { l1_level }) => {
+                println!("Input {} included at L1 level {:?}", id, l1_level);
+                forgotten.push(id);
+            }
+            Ok(BatcherMessageStatus::Unknown) => {
+                println!(
+                    "ALERT: input {} unknown to the batcher, its inclusion is no longer tracked",
+                    id
+                );
+                forgotten.push(id);
+            }
+            Ok(_) => {}
+            Err(e) => failed.push((id, e)),
+        }
+    }
+
+    let mut host = host.lock().await;
+    host.confirm_inclusions(&forgotten);
+    let mut stuck = vec![];
+    for injected in host.injected.iter_mut() {
+        match failed.iter().find(|(id, _)| *id == injected.id) {
+            Some((_, e)) => {
+                injected.failed_fetches += 1;
+                println!(
+                    "Failed to fetch the status of input {} ({} times in a row): {:?}",
+                    injected.id, injected.failed_fetches, e
+                );
+            }
+            None => injected.failed_fetches = 0,
+        }
+        if !injected.stuck && injected.injected_at.elapsed() >= STUCK_INJECTION_TIMEOUT {
+            injected.stuck = true;
+            stuck.push(injected.id);
+        }
+    }
+    if !stuck.is_empty() {
+        println!(
+            "ALERT: inputs {:?} not included in the rollup inbox after {:?}",
+            stuck, STUCK_INJECTION_TIMEOUT
+        );
+    }
+    host.publish_progress();
+}
+
+/// Compares the last state commitment of the rollup with the one the sequencer made at the same
+/// level. Both commit when the first input of a new level runs, so the rollup can lag behind
+/// without the check waiting for it to run exactly the inputs the sequencer ran.
+async fn reconcile(
+    client: &tradez_octez::smart_rollup_node::SmartRollupClient,
+    cache: &std::sync::RwLock<StateCache>,
+    status: &Mutex<SequencerStatus>,
+) {
+    let remote = match client.get_value(STATE_COMMITMENT_STR_PATH).await {
+        Ok(Some(data)) => match rlp::decode::<StateCommitment>(&data) {
+            Ok(commitment) => commitment,
+            Err(e) => {
+                println!("Failed to decode the rollup state commitment: {:?}", e);
+                return;
+            }
+        },
+        // Nothing committed yet
+        Ok(None) => return,
+        Err(e) => {
+            println!("Failed to fetch the rollup state commitment: {:?}", e);
+            return;
+        }
+    };
+    let mut status = status.lock().await;
+    if status.last_reconciled_level != Some(remote.level) {
+        compare_commitment(&cache::snapshot(cache), &remote, &mut status);
+    }
+}
+
+/// Compares a commitment of the rollup with the one the sequencer kept for its level.
+pub(crate) fn compare_commitment(
+    snapshot: &cache::Snapshot,
+    remote: &StateCommitment,
+    status: &mut SequencerStatus,
+) {
+    status.last_reconciled_level = Some(remote.level);
+    let committed = snapshot.committed_levels();
+    match snapshot.commitment(Some(remote.level)) {
+        Some((_, root, _)) if root == remote.root => status.reconciliations += 1,
+        Some((_, root, _)) => {
+            println!(
+                "ALERT: sequencer and rollup states diverge at level {}: root {:?} against {:?}",
+                remote.level, root, remote.root
+            );
+            status.divergences += 1;
+            status.diverged_level = Some(remote.level);
+        }
+        // The rollup committed a level the sequencer went through without committing
+        None if committed
+            .as_ref()
+            .is_some_and(|levels| levels.contains(&remote.level)) =>
+        {
+            println!(
+                "ALERT: the rollup committed level {} that the sequencer didn't commit",
+                remote.level
+            );
+            status.divergences += 1;
+            status.diverged_level = Some(remote.level);
+        }
+        None => {
+            println!(
+                "Skipped comparing the rollup commitment of level {}, the sequencer keeps the ones of levels {:?}",
+                remote.level, committed
+            );
+            status.skipped_reconciliations += 1;
+        }
+    }
+}
+
+fn load_index_price(host: &mut impl Runtime, level: u32) -> RpcResult<Option<Price>> {
+    let config = OracleConfig::load(host).map_err(|e| {
+        ErrorObject::owned::<()>(
+            -32000,
+            format!("Failed to load oracle config: {:?}", e),
+            None,
+        )
+    })?;
+    let feed = OracleFeed::load(host).map_err(|e| {
+        ErrorObject::owned::<()>(-32000, format!("Failed to load oracle feed: {:?}", e), None)
+    })?;
+    Ok(feed.median_price(&config, level))
 }
 
 pub async fn launch_server(
     rpc_port: u16,
     smart_rollup_addr: String,
     data_dir: String,
+    batch_config: BatchConfig,
+    signer: Option<PrivateKeySigner>,
 ) -> std::io::Result<()> {
     println!("Starting TradEZ JSON-RPC server...");
 
+    let mut host = SequencerHost::new(data_dir);
+    let client = tradez_octez::smart_rollup_node::SmartRollupClient::new(&smart_rollup_addr);
+    let copied = crate::bootstrap::copy_setup(&client, &mut host)
+        .await
+        .map_err(std::io::Error::other)?;
+    println!("Copied {} values of the rollup setup", copied);
+    let sequencer = RollupSetup::load_sequencer(&mut host).map_err(std::io::Error::other)?;
+    let key = signer
+        .as_ref()
+        .map(|signer| Address::from(signer.address()));
+    if sequencer.is_none() || key != sequencer {
+        println!(
+            "WARNING: the rollup expects the sequencer key {:?}, the inputs are signed with {:?}. \
+             The rollup will date them with its inbox and may diverge.",
+            sequencer, key
+        );
+    }
+    host.signer = signer;
     let rpc_impl = TradezRpcImpl {
         smart_rollup_node_client: tradez_octez::smart_rollup_node::SmartRollupClient::new(
             &smart_rollup_addr,
         ),
-        host: Arc::new(Mutex::new(SequencerHost::new(data_dir))),
+        cache: host.cache.clone(),
+        host: Arc::new(Mutex::new(host)),
         subscribers: Arc::new(Mutex::new(Vec::new())),
+        batch_config,
+        reconciliation: Arc::new(Mutex::new(SequencerStatus::default())),
     };
 
+    // Inputs that ran before a restart but never reached the rollup go first
+    {
+        let mut host = rpc_impl.host.lock().await;
+        println!(
+            "Resuming injection of {} pending inputs",
+            host.input_to_send_to_rollup.len()
+        );
+        inject_pending(
+            &rpc_impl.smart_rollup_node_client,
+            &mut host,
+            &batch_config,
+            true,
+        )
+        .await;
+    }
+
+    // A quiet market doesn't fill batches, what waited long enough goes as it is
+    let host = rpc_impl.host.clone();
+    let flush_client = tradez_octez::smart_rollup_node::SmartRollupClient::new(&smart_rollup_addr);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(FLUSH_POLLING_INTERVAL).await;
+            let mut host = host.lock().await;
+            if host
+                .oldest_pending_since
+                .is_some_and(|since| since.elapsed() >= batch_config.max_latency)
+            {
+                inject_pending(&flush_client, &mut host, &batch_config, true).await;
+            }
+        }
+    });
+
+    // Follow the L1 level so inputs are stamped with the clock the kernel will replay them with
+    let host = rpc_impl.host.clone();
+    let level_client = tradez_octez::smart_rollup_node::SmartRollupClient::new(&smart_rollup_addr);
+    tokio::spawn(async move {
+        loop {
+            match level_client.get_l1_level().await {
+                Ok(level) => {
+                    host.lock().await.follow_level(level);
+                }
+                Err(e) => println!("Failed to fetch L1 level: {:?}", e),
+            }
+            tokio::time::sleep(LEVEL_POLLING_INTERVAL).await;
+        }
+    });
+
     let cors = CorsLayer::new()
         .allow_methods([Method::POST])
         .allow_origin(Any)
@@ -239,13 +771,59 @@ pub async fn launch_server(
         .set_http_middleware(middleware)
         .build(&format!("127.0.0.1:{}", rpc_port))
         .await?;
+    // Follow the injected inputs until they reach the rollup inbox
+    let host = rpc_impl.host.clone();
+    let inclusion_client =
+        tradez_octez::smart_rollup_node::SmartRollupClient::new(&smart_rollup_addr);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(INCLUSION_POLLING_INTERVAL).await;
+            track_inclusions(&inclusion_client, &host).await;
+        }
+    });
+
+    // Check that the rollup reaches the state the sequencer computed
+    let cache = rpc_impl.cache.clone();
+    let reconciliation = rpc_impl.reconciliation.clone();
+    let reconciliation_client =
+        tradez_octez::smart_rollup_node::SmartRollupClient::new(&smart_rollup_addr);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RECONCILIATION_POLLING_INTERVAL).await;
+            reconcile(&reconciliation_client, &cache, &reconciliation).await;
+        }
+    });
+
+    let host = rpc_impl.host.clone();
     let handle = server.start(TradezRpcServer::into_rpc(rpc_impl));
 
     println!("JSON-RPC server running on http://127.0.0.1:{}", rpc_port);
     println!("Available methods:");
     println!("  - send_order");
+    println!("  - send_stop_order");
+    println!("  - send_oco_order");
     println!("  - cancel_order");
+    println!("  - send_oracle_update");
+    println!("  - add_delegate");
+    println!("  - revoke_delegate");
+    println!("  - transfer");
 
-    handle.stopped().await;
+    tokio::select! {
+        _ = handle.clone().stopped() => {}
+        _ = tokio::signal::ctrl_c() => {
+            println!("Shutting down, flushing pending inputs...");
+            handle.stop().ok();
+        }
+    }
+    let mut host = host.lock().await;
+    let client = tradez_octez::smart_rollup_node::SmartRollupClient::new(&smart_rollup_addr);
+    host.injection_backoff = None;
+    inject_pending(&client, &mut host, &batch_config, true).await;
+    if !host.input_to_send_to_rollup.is_empty() {
+        println!(
+            "{} inputs left to inject on the next start",
+            host.input_to_send_to_rollup.len()
+        );
+    }
     Ok(())
 }
@@ -0,0 +1,288 @@
+use std::sync::RwLock;
+
+use alloy_primitives::Signature;
+use jsonrpsee::{
+    core::RpcResult,
+    types::{ErrorObject, ErrorObjectOwned},
+};
+use rlp::Encodable;
+use tradez_kernel::account::Account;
+use tradez_types::{KernelMessage, address::Address, api::input_error};
+
+use crate::{
+    cache::{self, StateCache},
+    host::RunError,
+};
+
+fn refused(code: i32, reason: &str) -> ErrorObjectOwned {
+    ErrorObject::owned(code, format!("Input refused: {}", reason), Some(reason))
+}
+
+/// Address that signed a message, as the kernel recovers it.
+fn signer(message: &KernelMessage, signature: &[u8]) -> Option<Address> {
+    let payload = match message {
+        KernelMessage::PlaceOrder(order) => order.rlp_bytes(),
+        KernelMessage::CancelOrder(cancel_order) => cancel_order.rlp_bytes(),
+        KernelMessage::Faucet(faucet) => faucet.rlp_bytes(),
+        KernelMessage::PlaceStop(stop) => stop.rlp_bytes(),
+        KernelMessage::PlaceOco(oco) => oco.rlp_bytes(),
+        KernelMessage::OracleUpdate(update) => update.rlp_bytes(),
+        KernelMessage::AddDelegate(grant) => grant.rlp_bytes(),
+        KernelMessage::RevokeDelegate(revoke) => revoke.rlp_bytes(),
+        KernelMessage::Transfer(transfer) => transfer.rlp_bytes(),
+    };
+    let signature = Signature::from_raw(signature).ok()?;
+    Some(Address::from(
+        signature.recover_address_from_msg(payload).ok()?,
+    ))
+}
+
+/// Refuses the inputs the kernel would refuse that can be told from the committed state,
+/// before taking the host. The rest is found out by running them.
+pub fn validate(
+    cache: &RwLock<StateCache>,
+    message: &KernelMessage,
+    signature: &[u8],
+) -> RpcResult<()> {
+    let Some(signer) = signer(message, signature) else {
+        return Err(refused(input_error::INVALID_SIGNATURE, "invalid_signature"));
+    };
+    if let KernelMessage::Transfer(transfer) = message {
+        if transfer.amount == 0 || transfer.to == signer {
+            return Err(refused(input_error::INVALID_INPUT, "invalid_transfer"));
+        }
+        let account = cache::account(cache, &signer)
+            .map_err(|e| {
+                ErrorObject::owned::<()>(-32000, format!("Failed to load account: {:?}", e), None)
+            })?
+            .unwrap_or_else(|| Account::new(signer));
+        if transfer.nonce <= account.nonce {
+            return Err(refused(input_error::INVALID_NONCE, "invalid_nonce"));
+        }
+        if account.available(transfer.currency) < transfer.amount {
+            return Err(refused(
+                input_error::INSUFFICIENT_BALANCE,
+                "insufficient_balance",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Error code of the reason the kernel rejected an input for. Every error carries the reason
+/// as its data.
+fn reason_code(reason: &str) -> i32 {
+    match reason {
+        "invalid_transfer"
+        | "zero_qty"
+        | "zero_price"
+        | "invalid_tick_size"
+        | "invalid_lot_size"
+        | "below_min_qty"
+        | "below_min_notional"
+        | "invalid_display_qty"
+        | "invalid_oco_group"
+        | "notional_overflow" => input_error::INVALID_INPUT,
+        "invalid_nonce" => input_error::INVALID_NONCE,
+        "insufficient_balance" | "insufficient_margin" => input_error::INSUFFICIENT_BALANCE,
+        "order_not_found" => input_error::ORDER_NOT_FOUND,
+        "delegate_key_in_use"
+        | "delegate_already_expired"
+        | "delegate_revoked"
+        | "delegate_expired"
+        | "delegate_out_of_scope"
+        | "delegate_above_max_notional"
+        | "delegate_not_found" => input_error::DELEGATE_REFUSED,
+        "unknown_oracle" | "zero_quote" | "future_quote" | "stale_quote" | "outdated_quote" => {
+            input_error::ORACLE_REFUSED
+        }
+        _ => input_error::REJECTED,
+    }
+}
+
+/// Error of an input the kernel run was rolled back for, by the reason the kernel gave.
+pub fn run_error(error: RunError) -> ErrorObjectOwned {
+    match error {
+        RunError::Aborted(reason) => ErrorObject::owned(
+            input_error::ABORTED,
+            format!("Input aborted: {}", reason),
+            None::<()>,
+        ),
+        RunError::Refused(reason) => {
+            let reason = reason.unwrap_or_else(|| String::from("refused"));
+            refused(reason_code(&reason), &reason)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_signer::SignerSync;
+    use alloy_signer_local::PrivateKeySigner;
+    use tradez_types::{
+        SignedInput,
+        currencies::Currencies,
+        delegate::DelegateRejection,
+        market::OrderRejection,
+        oracle::OracleRejection,
+        position::{Faucet, Transfer},
+    };
+
+    use super::*;
+    use crate::host::SequencerHost;
+
+    fn code(error: RunError) -> i32 {
+        run_error(error).code()
+    }
+
+    fn refused_for(reason: &str) -> i32 {
+        code(RunError::Refused(Some(reason.to_string())))
+    }
+
+    #[test]
+    fn run_error_codes() {
+        use OrderRejection::*;
+        for rejection in [
+            ZeroQty,
+            ZeroPrice,
+            InvalidTickSize,
+            InvalidLotSize,
+            BelowMinQty,
+            BelowMinNotional,
+            InvalidDisplayQty,
+            InvalidOcoGroup,
+            NotionalOverflow,
+        ] {
+            assert_eq!(refused_for(rejection.as_str()), input_error::INVALID_INPUT);
+        }
+        for rejection in [OutsidePriceBand, MarketHalted] {
+            assert_eq!(refused_for(rejection.as_str()), input_error::REJECTED);
+        }
+        assert_eq!(refused_for("invalid_transfer"), input_error::INVALID_INPUT);
+        assert_eq!(refused_for("invalid_nonce"), input_error::INVALID_NONCE);
+        assert_eq!(
+            refused_for("insufficient_balance"),
+            input_error::INSUFFICIENT_BALANCE
+        );
+        assert_eq!(
+            refused_for("insufficient_margin"),
+            input_error::INSUFFICIENT_BALANCE
+        );
+        assert_eq!(refused_for("order_not_found"), input_error::ORDER_NOT_FOUND);
+        for rejection in [
+            DelegateRejection::KeyInUse,
+            DelegateRejection::AlreadyExpired,
+            DelegateRejection::Revoked,
+            DelegateRejection::Expired,
+            DelegateRejection::OutOfScope,
+            DelegateRejection::AboveMaxNotional,
+            DelegateRejection::NotFound,
+        ] {
+            assert_eq!(
+                refused_for(rejection.as_str()),
+                input_error::DELEGATE_REFUSED
+            );
+        }
+        for rejection in [
+            OracleRejection::UnknownOracle,
+            OracleRejection::ZeroPrice,
+            OracleRejection::FutureQuote,
+            OracleRejection::StaleQuote,
+            OracleRejection::OutdatedQuote,
+        ] {
+            assert_eq!(refused_for(rejection.as_str()), input_error::ORACLE_REFUSED);
+        }
+
+        let error = run_error(RunError::Refused(None));
+        assert_eq!(error.code(), input_error::REJECTED);
+        assert_eq!(error.data().unwrap().get(), "\"refused\"");
+        assert_eq!(
+            code(RunError::Aborted(String::from("panicked"))),
+            input_error::ABORTED
+        );
+    }
+
+    fn transfer(to: Address, amount: u64, nonce: u64) -> KernelMessage {
+        KernelMessage::Transfer(Transfer {
+            to,
+            currency: Currencies::USDC,
+            amount,
+            nonce,
+        })
+    }
+
+    fn signed(signer: &PrivateKeySigner, message: &KernelMessage) -> Vec<u8> {
+        let KernelMessage::Transfer(transfer) = message else {
+            unreachable!()
+        };
+        signer
+            .sign_message_sync(&transfer.rlp_bytes())
+            .unwrap()
+            .as_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn validate_codes() {
+        let data_dir =
+            std::env::temp_dir().join(format!("tradez-validation-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let mut host = SequencerHost::new(data_dir.to_string_lossy().to_string());
+        let (sender, receiver) = (PrivateKeySigner::random(), PrivateKeySigner::random());
+        let (from, to) = (
+            Address::from(sender.address().0.0),
+            Address::from(receiver.address().0.0),
+        );
+        let validate_code = |host: &SequencerHost, message: KernelMessage, signature: &[u8]| {
+            validate(&host.cache, &message, signature).map_err(|e| e.code())
+        };
+
+        let message = transfer(to, 10, 1);
+        assert_eq!(
+            validate_code(&host, message, &[0; 65]),
+            Err(input_error::INVALID_SIGNATURE)
+        );
+        let message = transfer(to, 0, 1);
+        let signature = signed(&sender, &message);
+        assert_eq!(
+            validate_code(&host, message, &signature),
+            Err(input_error::INVALID_INPUT)
+        );
+        let message = transfer(from, 10, 1);
+        let signature = signed(&sender, &message);
+        assert_eq!(
+            validate_code(&host, message, &signature),
+            Err(input_error::INVALID_INPUT)
+        );
+        let message = transfer(to, 10, 0);
+        let signature = signed(&sender, &message);
+        assert_eq!(
+            validate_code(&host, message, &signature),
+            Err(input_error::INVALID_NONCE)
+        );
+        let message = transfer(to, 10, 1);
+        let signature = signed(&sender, &message);
+        assert_eq!(
+            validate_code(&host, message, &signature),
+            Err(input_error::INSUFFICIENT_BALANCE)
+        );
+
+        let faucet = Faucet {
+            amount: 1_000_000,
+            currency: Currencies::USDC,
+        };
+        let faucet_signature = sender
+            .sign_message_sync(&faucet.rlp_bytes())
+            .unwrap()
+            .as_bytes()
+            .to_vec();
+        let input = SignedInput::new(KernelMessage::Faucet(faucet), faucet_signature);
+        host.add_inputs(vec![input.rlp_bytes().to_vec()]);
+        host.run_kernel().unwrap();
+        assert_eq!(
+            validate_code(&host, transfer(to, 10, 1), &signature),
+            Ok(())
+        );
+    }
+}
@@ -9,7 +9,11 @@ mod setup;
 mod tests {
     use crate::setup::{TestConfig, tradez_test_wrapper};
     use rlp::{Decodable, Rlp};
-    use tradez_types::orderbook::{ORDER_BOOK_STR_PATH, OrderBook};
+    use tradez_types::{
+        orderbook::{ORDER_BOOK_STR_PATH, OrderBook},
+        setup::RollupSetup,
+        units::{Price, Qty},
+    };
 
     // Here you can write integration tests that use tradez (sequencer + client) and an L1 node + smart rollup node + tezos client
     #[tokio::test]
@@ -18,6 +22,7 @@ mod tests {
             verbose: true,
             print_commands: true,
             sequencer_rpc_port: None,
+            setup: RollupSetup::default(),
         };
         tradez_test_wrapper(
             config,
@@ -27,7 +32,7 @@ mod tests {
                         smart_rollup_client,
                         _tradez_sequencer,
                         tradez_client| {
-                tradez_client.sell(10, 1000);
+                tradez_client.sell(10_000_000, 1_000_000);
                 std::thread::sleep(std::time::Duration::from_secs(2));
                 octez_client.bake_l1_blocks(2);
                 std::thread::sleep(std::time::Duration::from_secs(2));
@@ -37,7 +42,7 @@ mod tests {
                     .unwrap()
                     .unwrap();
                 let order_book = OrderBook::decode(&Rlp::new(&bytes)).unwrap();
-                assert_eq!(order_book.best_ask(), Some(1000));
+                assert_eq!(order_book.best_ask(), Some(Price(1_000_000)));
                 let orderbook_state = tradez_client.get_orderbook_state();
                 let history = tradez_client.get_history();
                 println!("Orderbook state: {}", orderbook_state);
@@ -54,6 +59,7 @@ mod tests {
             verbose: true,
             print_commands: true,
             sequencer_rpc_port: None,
+            setup: RollupSetup::default(),
         };
         tradez_test_wrapper(
             config,
@@ -66,9 +72,9 @@ mod tests {
                 tradez_client.faucet_usdc(100);
                 tradez_client.faucet_xtz(100);
                 octez_client.bake_l1_blocks(1);
-                tradez_client.buy(10, 1000);
+                tradez_client.buy(10_000_000, 1_000_000);
                 octez_client.bake_l1_blocks(1);
-                tradez_client.sell(5, 900);
+                tradez_client.sell(5_000_000, 900_000);
                 octez_client.bake_l1_blocks(4);
                 let bytes = smart_rollup_client
                     .get_value(ORDER_BOOK_STR_PATH)
@@ -76,7 +82,10 @@ mod tests {
                     .unwrap()
                     .unwrap();
                 let order_book = OrderBook::decode(&Rlp::new(&bytes)).unwrap();
-                assert_eq!(order_book.price_quantity_at(1000), 5);
+                assert_eq!(
+                    order_book.price_quantity_at(Price(1_000_000)),
+                    Qty(5_000_000)
+                );
                 let history = tradez_client.get_history();
                 println!("History: {}", history);
             },
@@ -1,6 +1,7 @@
-use std::{time::Duration, u64};
+use std::time::Duration;
 
 use rand::{Rng, seq::SliceRandom};
+use tradez_types::setup::RollupSetup;
 
 use crate::setup::{TestConfig, tradez_test_wrapper};
 
@@ -14,6 +15,7 @@ async fn main() {
         verbose: true,
         print_commands: false,
         sequencer_rpc_port: Some(19000),
+        setup: RollupSetup::default(),
     };
     tradez_test_wrapper(
         config,
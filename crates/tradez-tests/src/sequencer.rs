@@ -9,6 +9,8 @@ pub struct SequencerConfig {
     pub print_commands: bool,
     pub verbose: bool,
     pub smart_rollup_node_address: String,
+    /// Private key of the sequencer in the rollup setup, in hex.
+    pub sequencer_key: String,
 }
 
 fn pick_unused_port() -> u16 {
@@ -40,7 +42,9 @@ impl Sequencer {
             .arg("--smart-rollup-addr")
             .arg(config.smart_rollup_node_address)
             .arg("--data-dir")
-            .arg(data_dir.path());
+            .arg(data_dir.path())
+            .arg("--sequencer-key")
+            .arg(config.sequencer_key);
         if config.verbose {
             command.stdout(Stdio::piped()).stderr(Stdio::piped());
         } else {
@@ -1,11 +1,15 @@
 use std::path::Path;
 
+use alloy_signer_local::PrivateKeySigner;
 use tradez_octez::l1_node::{L1Node, L1NodeConfig};
+use tradez_types::{address::Address, setup::RollupSetup};
 
 pub struct TestConfig {
     pub verbose: bool,
     pub print_commands: bool,
     pub sequencer_rpc_port: Option<u16>,
+    /// Written to the durable storage by the installer, with the key of the test sequencer.
+    pub setup: RollupSetup,
 }
 
 pub async fn tradez_test_wrapper<F, R>(config: TestConfig, test_fn: F)
@@ -47,6 +51,14 @@ where
         },
         format!("http://localhost:{}", node.rpc_port),
     );
+    let sequencer_key = PrivateKeySigner::random();
+    let setup = RollupSetup {
+        sequencer: Some(Address::from(sequencer_key.address())),
+        ..config.setup
+    };
+    let setup_file = smart_rollup_node.data_path().join("tradez_setup.yaml");
+    std::fs::write(&setup_file, setup.installer_config())
+        .expect("Failed to write the rollup setup file");
     tradez_octez::smart_rollup_installer::create_installer(
         Path::new("tradez_kernel.wasm"),
         smart_rollup_node.pre_images_path().as_path(),
@@ -54,6 +66,7 @@ where
             .data_path()
             .join("tradez_kernel_installer.hex")
             .as_path(),
+        Some(setup_file.as_path()),
         tradez_octez::smart_rollup_installer::SmartRollupInstallerConfig {
             print_commands: config.print_commands,
             verbose: config.verbose,
@@ -76,6 +89,7 @@ where
             print_commands: config.print_commands,
             verbose: config.verbose,
             smart_rollup_node_address: smart_rollup_node.rpc_addr(),
+            sequencer_key: alloy_primitives::hex::encode(sequencer_key.to_bytes()),
         },
         config.sequencer_rpc_port,
     );
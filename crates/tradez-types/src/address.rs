@@ -57,4 +57,6 @@ impl Decodable for Address {
 
 impl Address {
     pub const ZERO: Address = Address(alloy_primitives::Address::ZERO);
+    /// Account liquidation fees are paid to and bad debts covered from. No key signs for it.
+    pub const INSURANCE_FUND: Address = Address(alloy_primitives::Address::new([0xff; 20]));
 }
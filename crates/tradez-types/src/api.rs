@@ -4,24 +4,105 @@ use jsonrpsee::{
 };
 
 use crate::{
-    currencies::Currencies,
+    commitment::AccountProof,
+    currencies::{Balance, Currencies},
+    delegate::{DelegateGrant, RevokeDelegate},
+    fills::FillPage,
+    margin::MarginState,
+    market::{MarketConfig, MarketStatus},
+    oracle::OracleUpdate,
     orderbook::Event,
-    position::{APIOrder, CancelOrder, Faucet, Price, Qty, Side, UserOrder},
+    perpetual::{PerpPosition, PerpStatus},
+    position::{APIOrder, CancelOrder, Faucet, Side, Transfer, UserOrder},
+    status::SequencerStatus,
+    stops::{APIOcoOrder, APIStopOrder},
+    supply::AuditReport,
+    units::{Price, Qty},
 };
 
+/// JSON-RPC error codes of the inputs the sequencer refuses, stable across releases. Refused
+/// inputs are never sent to the rollup.
+pub mod input_error {
+    /// The input is malformed or breaks the rules of the market, such as a transfer of nothing
+    /// or a price off the tick size.
+    pub const INVALID_INPUT: i32 = -32001;
+    /// The signature doesn't parse or doesn't sign the input.
+    pub const INVALID_SIGNATURE: i32 = -32002;
+    /// The nonce was already used by the account.
+    pub const INVALID_NONCE: i32 = -32003;
+    /// The account can't cover the input, balance or margin.
+    pub const INSUFFICIENT_BALANCE: i32 = -32004;
+    /// The market refused the input as it stands, such as a price outside the band or a
+    /// halted market.
+    pub const REJECTED: i32 = -32005;
+    /// The kernel failed on the input.
+    pub const ABORTED: i32 = -32006;
+    /// The sequencer doesn't know the L1 level yet, the input can be sent again.
+    pub const NOT_READY: i32 = -32007;
+    /// The order to cancel isn't open for the account.
+    pub const ORDER_NOT_FOUND: i32 = -32008;
+    /// A delegate key signed beyond its grant, or the delegate keys can't be changed so.
+    pub const DELEGATE_REFUSED: i32 = -32009;
+    /// The oracle update isn't from a whitelisted oracle, or its quote can't be used.
+    pub const ORACLE_REFUSED: i32 = -32010;
+}
+
 #[rpc(client, server)]
 pub trait TradezRpc {
     #[method(name = "send_order")]
     async fn send_order(&self, api_order: APIOrder, signature: Vec<u8>) -> RpcResult<String>;
 
+    #[method(name = "send_stop_order")]
+    async fn send_stop_order(
+        &self,
+        stop_order: APIStopOrder,
+        signature: Vec<u8>,
+    ) -> RpcResult<String>;
+
+    #[method(name = "send_oco_order")]
+    async fn send_oco_order(&self, oco_order: APIOcoOrder, signature: Vec<u8>)
+    -> RpcResult<String>;
+
+    #[method(name = "send_oracle_update")]
+    async fn send_oracle_update(
+        &self,
+        update: OracleUpdate,
+        signature: Vec<u8>,
+    ) -> RpcResult<String>;
+
+    #[method(name = "add_delegate")]
+    async fn add_delegate(&self, grant: DelegateGrant, signature: Vec<u8>) -> RpcResult<String>;
+
+    #[method(name = "revoke_delegate")]
+    async fn revoke_delegate(
+        &self,
+        revoke: RevokeDelegate,
+        signature: Vec<u8>,
+    ) -> RpcResult<String>;
+
     #[method(name = "cancel_order")]
     async fn cancel_order(&self, params: CancelOrder, signature: Vec<u8>) -> RpcResult<String>;
 
     #[method(name = "faucet")]
     async fn faucet(&self, params: Faucet, signature: Vec<u8>) -> RpcResult<String>;
 
+    #[method(name = "transfer")]
+    async fn transfer(&self, transfer: Transfer, signature: Vec<u8>) -> RpcResult<String>;
+
     #[method(name = "get_balances")]
-    async fn get_balances(&self, address: String) -> RpcResult<Vec<(Currencies, u64)>>;
+    async fn get_balances(&self, address: String) -> RpcResult<Vec<(Currencies, Balance)>>;
+
+    #[method(name = "get_margin")]
+    async fn get_margin(&self, address: String) -> RpcResult<MarginState>;
+
+    #[method(name = "get_position")]
+    async fn get_position(&self, address: String) -> RpcResult<PerpPosition>;
+
+    #[method(name = "get_delegates")]
+    async fn get_delegates(&self, address: String) -> RpcResult<Vec<DelegateGrant>>;
+
+    #[method(name = "get_fills")]
+    async fn get_fills(&self, address: String, cursor: Option<u64>) -> RpcResult<FillPage>;
 
     #[method(name = "get_orders")]
     async fn get_orders(&self, address: String) -> RpcResult<Vec<(u64, UserOrder)>>;
@@ -29,6 +110,31 @@ pub trait TradezRpc {
     #[method(name = "get_orderbook_state")]
     async fn get_orderbook_state(&self) -> RpcResult<(Vec<(Price, Qty)>, Vec<(Price, Qty)>)>;
 
+    #[method(name = "get_market_config")]
+    async fn get_market_config(&self) -> RpcResult<MarketConfig>;
+
+    #[method(name = "get_market_status")]
+    async fn get_market_status(&self) -> RpcResult<MarketStatus>;
+
+    #[method(name = "get_perp_status")]
+    async fn get_perp_status(&self) -> RpcResult<PerpStatus>;
+
+    #[method(name = "get_index_price")]
+    async fn get_index_price(&self) -> RpcResult<Option<Price>>;
+
+    #[method(name = "get_audit")]
+    async fn get_audit(&self) -> RpcResult<AuditReport>;
+
+    #[method(name = "get_status")]
+    async fn get_status(&self) -> RpcResult<SequencerStatus>;
+
+    #[method(name = "get_account_proof")]
+    async fn get_account_proof(
+        &self,
+        address: String,
+        level: Option<u32>,
+    ) -> RpcResult<AccountProof>;
+
     #[method(name = "get_history")]
     async fn get_history(&self) -> RpcResult<Vec<(u128, Qty, Price, Side)>>;
 
@@ -37,4 +143,7 @@ pub trait TradezRpc {
 
     #[subscription(name = "subscribeEvent", item = Event)]
     async fn subscribe_event(&self) -> SubscriptionResult;
+
+    #[subscription(name = "subscribeIndexPrice", item = Price)]
+    async fn subscribe_index_price(&self) -> SubscriptionResult;
 }
@@ -0,0 +1,223 @@
+use alloy_primitives::{B256, keccak256};
+use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::RefPath;
+
+use crate::error::TradezError;
+
+pub const STATE_COMMITMENT_STR_PATH: &str = "/tradez/state_commitment";
+pub const STATE_COMMITMENT_PATH: RefPath = RefPath::assert_from(b"/tradez/state_commitment");
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Hash(pub B256);
+
+impl Encodable for Hash {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.append_internal(&self.0.as_slice());
+    }
+}
+
+impl Decodable for Hash {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let bytes: Vec<u8> = rlp.as_val()?;
+        if bytes.len() != 32 {
+            return Err(rlp::DecoderError::Custom("Invalid hash length"));
+        }
+        Ok(Hash(B256::from_slice(&bytes)))
+    }
+}
+
+impl Hash {
+    /// Hash of a leaf, prefixed so that a leaf can never pass for an inner node.
+    pub fn leaf(data: &[u8]) -> Self {
+        let mut preimage = Vec::with_capacity(data.len() + 1);
+        preimage.push(LEAF_PREFIX);
+        preimage.extend_from_slice(data);
+        Hash(keccak256(preimage))
+    }
+
+    /// Hash of an inner node.
+    pub fn node(left: &Hash, right: &Hash) -> Self {
+        let mut preimage = [0u8; 65];
+        preimage[0] = NODE_PREFIX;
+        preimage[1..33].copy_from_slice(left.0.as_slice());
+        preimage[33..].copy_from_slice(right.0.as_slice());
+        Hash(keccak256(preimage))
+    }
+}
+
+/// Root of the accounts and the order book at the end of a level, published in the outbox
+/// and kept in the durable storage.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, RlpEncodable, RlpDecodable,
+)]
+pub struct StateCommitment {
+    pub level: u32,
+    pub root: Hash,
+}
+
+impl StateCommitment {
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Option<Self>, TradezError> {
+        match host.store_read_all(&STATE_COMMITMENT_PATH) {
+            Ok(data) => StateCommitment::decode(&rlp::Rlp::new(&data))
+                .map(Some)
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(None),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&STATE_COMMITMENT_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+}
+
+/// Hashes the nodes of a level two by two. A node without sibling moves up a level unchanged.
+fn parent_level(nodes: &[Hash]) -> Vec<Hash> {
+    nodes
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => Hash::node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Root of a binary Merkle tree.
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash::default();
+    }
+    let mut nodes = leaves.to_vec();
+    while nodes.len() > 1 {
+        nodes = parent_level(&nodes);
+    }
+    nodes[0]
+}
+
+/// Proof that a leaf sits at `index` in a tree of `leaf_count` leaves.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u64,
+    pub leaf_count: u64,
+    /// Siblings from the leaf up, levels where the node has no sibling are skipped.
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    pub fn new(leaves: &[Hash], index: usize) -> Option<Self> {
+        let mut levels = vec![leaves.to_vec()];
+        while levels.last().is_some_and(|nodes| nodes.len() > 1) {
+            levels.push(parent_level(levels.last().unwrap()));
+        }
+        MerkleProof::from_nodes(index as u64, leaves.len() as u64, |height, position| {
+            levels[height as usize].get(position as usize).copied()
+        })
+    }
+
+    /// Proof read from a stored tree, `node` giving the node at a height and a position,
+    /// leaves at height 0.
+    pub fn from_nodes(
+        index: u64,
+        leaf_count: u64,
+        mut node: impl FnMut(u32, u64) -> Option<Hash>,
+    ) -> Option<Self> {
+        if index >= leaf_count {
+            return None;
+        }
+        let mut siblings = vec![];
+        let mut position = index;
+        let mut count = leaf_count;
+        let mut height = 0;
+        while count > 1 {
+            if position ^ 1 < count {
+                siblings.push(node(height, position ^ 1)?);
+            }
+            position /= 2;
+            count = count.div_ceil(2);
+            height += 1;
+        }
+        Some(MerkleProof {
+            index,
+            leaf_count,
+            siblings,
+        })
+    }
+
+    /// Root the proof leads to from `leaf`, None if the proof doesn't fit its tree.
+    pub fn root(&self, leaf: Hash) -> Option<Hash> {
+        if self.index >= self.leaf_count {
+            return None;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut node = leaf;
+        let mut position = self.index;
+        let mut count = self.leaf_count;
+        while count > 1 {
+            let sibling = position ^ 1;
+            if sibling < count {
+                let sibling = siblings.next()?;
+                node = if position.is_multiple_of(2) {
+                    Hash::node(&node, sibling)
+                } else {
+                    Hash::node(sibling, &node)
+                };
+            }
+            position /= 2;
+            count = count.div_ceil(2);
+        }
+        siblings.next().is_none().then_some(node)
+    }
+}
+
+/// An account record as committed at `level`, with its proof against the state root.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AccountProof {
+    pub level: u32,
+    pub root: Hash,
+    /// RLP encoded account, as hashed into its leaf.
+    pub account: Vec<u8>,
+    pub proof: MerkleProof,
+}
+
+impl AccountProof {
+    /// Checks the account against the root it claims.
+    pub fn verify(&self) -> bool {
+        self.proof.root(Hash::leaf(&self.account)) == Some(self.root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merkle_proofs() {
+        for count in 1..=9usize {
+            let leaves: Vec<Hash> = (0..count as u8).map(|i| Hash::leaf(&[i])).collect();
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = MerkleProof::new(&leaves, index).unwrap();
+                assert_eq!(proof.root(*leaf), Some(root));
+                assert_ne!(proof.root(Hash::leaf(b"forged")), Some(root));
+            }
+            assert!(MerkleProof::new(&leaves, count).is_none());
+        }
+    }
+
+    #[test]
+    fn state_commitment_rlp() {
+        let commitment = StateCommitment {
+            level: 42,
+            root: merkle_root(&[Hash::leaf(b"account"), Hash::leaf(b"orderbook")]),
+        };
+        let decoded: StateCommitment = rlp::decode(&commitment.rlp_bytes()).unwrap();
+        assert_eq!(commitment, decoded);
+    }
+}
@@ -7,11 +7,19 @@ pub enum Currencies {
     XTZ,
 }
 
+/// Balance of one currency: what is free to use, what open orders reserve and what is owed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: u64,
+    pub locked: u64,
+    pub debt: u64,
+}
+
 impl Encodable for Currencies {
     fn rlp_append(&self, s: &mut rlp::RlpStream) {
         match self {
-            Currencies::USDC => s.append(&0u8),
-            Currencies::XTZ => s.append(&1u8),
+            Currencies::USDC => s.append_internal(&0u8),
+            Currencies::XTZ => s.append_internal(&1u8),
         };
     }
 }
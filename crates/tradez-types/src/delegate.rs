@@ -0,0 +1,238 @@
+use std::fmt::Display;
+
+use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::{OwnedPath, RefPath, concat};
+
+use crate::{address::Address, error::TradezError};
+
+pub const DELEGATES_KEY_PREFIX: RefPath = RefPath::assert_from(b"/tradez/delegates");
+pub const DELEGATE_KEYS_KEY_PREFIX: RefPath = RefPath::assert_from(b"/tradez/delegate_keys");
+
+/// What a delegate key may sign for its owner. None of them moves funds out of the account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DelegateScope {
+    /// Place and cancel orders.
+    Trade,
+    /// Only cancel orders, e.g. for a kill switch.
+    Cancel,
+}
+
+impl Encodable for DelegateScope {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        match self {
+            DelegateScope::Trade => s.append_internal(&0u8),
+            DelegateScope::Cancel => s.append_internal(&1u8),
+        };
+    }
+}
+
+impl Decodable for DelegateScope {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        match rlp.as_val::<u8>()? {
+            0 => Ok(DelegateScope::Trade),
+            1 => Ok(DelegateScope::Cancel),
+            _ => Err(rlp::DecoderError::Custom("Invalid DelegateScope value")),
+        }
+    }
+}
+
+/// Key allowed to sign orders on behalf of an account, as signed by the account's main key.
+#[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq, Clone, Copy)]
+pub struct DelegateGrant {
+    pub key: Address,
+    pub scope: DelegateScope,
+    /// Level from which the key can't sign anymore.
+    pub expiry_level: u32,
+    /// Largest value of an order the key may place (microUSDC), zero for no limit.
+    pub max_notional: u64,
+}
+
+/// Revokes a delegate key for good, as signed by the account's main key.
+#[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq, Clone, Copy)]
+pub struct RevokeDelegate {
+    pub key: Address,
+}
+
+/// What a delegate signs for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DelegatedAction {
+    /// Placing an order of this value (microUSDC), None when it can't be computed.
+    Place(Option<u64>),
+    Cancel,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DelegateRejection {
+    KeyInUse,
+    AlreadyExpired,
+    Revoked,
+    Expired,
+    OutOfScope,
+    AboveMaxNotional,
+    NotFound,
+}
+
+impl DelegateRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DelegateRejection::KeyInUse => "delegate_key_in_use",
+            DelegateRejection::AlreadyExpired => "delegate_already_expired",
+            DelegateRejection::Revoked => "delegate_revoked",
+            DelegateRejection::Expired => "delegate_expired",
+            DelegateRejection::OutOfScope => "delegate_out_of_scope",
+            DelegateRejection::AboveMaxNotional => "delegate_above_max_notional",
+            DelegateRejection::NotFound => "delegate_not_found",
+        }
+    }
+}
+
+impl Display for DelegateRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl DelegateGrant {
+    /// Checks that the key may sign `action` at `level`.
+    pub fn allows(&self, action: DelegatedAction, level: u32) -> Result<(), DelegateRejection> {
+        if level >= self.expiry_level {
+            return Err(DelegateRejection::Expired);
+        }
+        match (self.scope, action) {
+            (DelegateScope::Cancel, DelegatedAction::Place(_)) => {
+                Err(DelegateRejection::OutOfScope)
+            }
+            (DelegateScope::Trade, DelegatedAction::Place(notional)) if self.max_notional > 0 => {
+                match notional {
+                    Some(notional) if notional <= self.max_notional => Ok(()),
+                    _ => Err(DelegateRejection::AboveMaxNotional),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Owner of a delegate key. A revoked key stays recorded so that it can't be granted again,
+/// which would let the old grant be replayed.
+#[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq, Clone, Copy)]
+pub struct DelegateKey {
+    pub owner: Address,
+    pub revoked: bool,
+}
+
+fn address_path(prefix: &RefPath, address: &Address) -> Result<OwnedPath, TradezError> {
+    let address = format!("/{:x}", address.0);
+    Ok(concat(prefix, &RefPath::assert_from(address.as_bytes()))?)
+}
+
+impl DelegateKey {
+    pub fn load(
+        host: &mut impl Runtime,
+        key: &Address,
+    ) -> Result<Option<DelegateKey>, TradezError> {
+        match host.store_read_all(&address_path(&DELEGATE_KEYS_KEY_PREFIX, key)?) {
+            Ok(data) => rlp::decode(&data)
+                .map(Some)
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(None),
+            Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+        }
+    }
+
+    pub fn save(&self, host: &mut impl Runtime, key: &Address) -> Result<(), TradezError> {
+        host.store_write_all(
+            &address_path(&DELEGATE_KEYS_KEY_PREFIX, key)?,
+            &self.rlp_bytes(),
+        )
+        .map_err(TradezError::DatabaseRuntimeError)
+    }
+}
+
+/// Delegate keys an account granted and hasn't revoked, expired ones included.
+pub fn load_delegates(
+    host: &mut impl Runtime,
+    owner: &Address,
+) -> Result<Vec<DelegateGrant>, TradezError> {
+    match host.store_read_all(&address_path(&DELEGATES_KEY_PREFIX, owner)?) {
+        Ok(data) => rlp::Rlp::new(&data)
+            .as_list()
+            .map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(vec![]),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+pub fn save_delegates(
+    host: &mut impl Runtime,
+    owner: &Address,
+    delegates: &[DelegateGrant],
+) -> Result<(), TradezError> {
+    host.store_write_all(
+        &address_path(&DELEGATES_KEY_PREFIX, owner)?,
+        &rlp::encode_list(delegates),
+    )
+    .map_err(TradezError::DatabaseRuntimeError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(scope: DelegateScope, max_notional: u64) -> DelegateGrant {
+        DelegateGrant {
+            key: Address::from([7u8; 20]),
+            scope,
+            expiry_level: 100,
+            max_notional,
+        }
+    }
+
+    #[test]
+    fn delegate_permissions() {
+        let trader = grant(DelegateScope::Trade, 0);
+        assert_eq!(trader.allows(DelegatedAction::Place(None), 99), Ok(()));
+        assert_eq!(trader.allows(DelegatedAction::Cancel, 99), Ok(()));
+        assert_eq!(
+            trader.allows(DelegatedAction::Cancel, 100),
+            Err(DelegateRejection::Expired)
+        );
+
+        let capped = grant(DelegateScope::Trade, 1_000_000);
+        assert_eq!(
+            capped.allows(DelegatedAction::Place(Some(1_000_000)), 1),
+            Ok(())
+        );
+        assert_eq!(
+            capped.allows(DelegatedAction::Place(Some(1_000_001)), 1),
+            Err(DelegateRejection::AboveMaxNotional)
+        );
+        assert_eq!(
+            capped.allows(DelegatedAction::Place(None), 1),
+            Err(DelegateRejection::AboveMaxNotional)
+        );
+
+        let kill_switch = grant(DelegateScope::Cancel, 0);
+        assert_eq!(
+            kill_switch.allows(DelegatedAction::Place(Some(1)), 1),
+            Err(DelegateRejection::OutOfScope)
+        );
+        assert_eq!(kill_switch.allows(DelegatedAction::Cancel, 1), Ok(()));
+    }
+
+    #[test]
+    fn delegate_rlp() {
+        let grant = grant(DelegateScope::Cancel, 5);
+        let decoded: DelegateGrant = rlp::decode(&grant.rlp_bytes()).unwrap();
+        assert_eq!(grant, decoded);
+
+        let key = DelegateKey {
+            owner: Address::from([1u8; 20]),
+            revoked: true,
+        };
+        let decoded: DelegateKey = rlp::decode(&key.rlp_bytes()).unwrap();
+        assert_eq!(key, decoded);
+    }
+}
@@ -0,0 +1,167 @@
+use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::{OwnedPath, Path, RefPath, concat};
+
+use crate::{
+    address::Address,
+    currencies::Currencies,
+    error::TradezError,
+    position::Side,
+    units::{Price, Qty},
+};
+
+pub const FILLS_KEY_PREFIX: RefPath = RefPath::assert_from(b"/tradez/fills");
+pub const TRADE_COUNT_PATH: RefPath = RefPath::assert_from(b"/tradez/trade_count");
+
+/// Fills kept per account, older ones are overwritten by the new ones.
+pub const MAX_FILLS_PER_ACCOUNT: u64 = 1000;
+/// Fills returned by one `load_fills` call.
+pub const FILLS_PAGE_SIZE: u64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FillRole {
+    /// The order was resting in the book.
+    Maker,
+    /// The order took liquidity from the book.
+    Taker,
+}
+
+impl Encodable for FillRole {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        match self {
+            FillRole::Maker => s.append_internal(&0u8),
+            FillRole::Taker => s.append_internal(&1u8),
+        };
+    }
+}
+
+impl Decodable for FillRole {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        match rlp.as_val::<u8>()? {
+            0 => Ok(FillRole::Maker),
+            1 => Ok(FillRole::Taker),
+            _ => Err(rlp::DecoderError::Custom("Invalid FillRole value")),
+        }
+    }
+}
+
+/// One side of a trade, as recorded in the history of the account that owned the order.
+#[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq, Clone, Copy)]
+pub struct Fill {
+    /// Shared by the maker and the taker fill of a trade.
+    pub trade_id: u64,
+    pub order_id: u64,
+    pub role: FillRole,
+    pub side: Side,
+    pub price: Price,
+    pub qty: Qty,
+    /// Trading fee the account paid for this fill, zero for makers.
+    pub fee: u64,
+    pub fee_currency: Currencies,
+    pub level: u32,
+}
+
+/// Fills of an account from a cursor, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FillPage {
+    pub fills: Vec<Fill>,
+    /// Cursor to read the following fills from, the number of fills recorded once all of
+    /// them were read.
+    pub next_cursor: u64,
+}
+
+fn fills_path(address: &Address, key: &str) -> Result<OwnedPath, TradezError> {
+    let suffix = format!("/{:x}/{}", address.0, key);
+    Ok(concat(
+        &FILLS_KEY_PREFIX,
+        &RefPath::assert_from(suffix.as_bytes()),
+    )?)
+}
+
+fn read_u64(host: &mut impl Runtime, path: &impl Path) -> Result<u64, TradezError> {
+    match host.store_read_all(path) {
+        Ok(data) => rlp::decode(&data).map_err(|e| TradezError::DataStoreError(e.to_string())),
+        Err(RuntimeError::PathNotFound) => Ok(0),
+        Err(e) => Err(TradezError::DatabaseRuntimeError(e)),
+    }
+}
+
+/// Number of trades the market executed, which is the id of the next one.
+pub fn load_trade_count(host: &mut impl Runtime) -> Result<u64, TradezError> {
+    read_u64(host, &TRADE_COUNT_PATH)
+}
+
+pub fn save_trade_count(host: &mut impl Runtime, count: u64) -> Result<(), TradezError> {
+    host.store_write_all(&TRADE_COUNT_PATH, &count.rlp_bytes())
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+/// Number of fills ever recorded for an account, the retained ones being the last
+/// `MAX_FILLS_PER_ACCOUNT` of them.
+pub fn fill_count(host: &mut impl Runtime, address: &Address) -> Result<u64, TradezError> {
+    read_u64(host, &fills_path(address, "count")?)
+}
+
+/// Records a fill of an account. Fills are stored in a ring of `MAX_FILLS_PER_ACCOUNT` slots
+/// indexed by their sequence number, so that a new one doesn't rewrite the others.
+pub fn append_fill(
+    host: &mut impl Runtime,
+    address: &Address,
+    fill: &Fill,
+) -> Result<(), TradezError> {
+    let count = fill_count(host, address)?;
+    let slot = (count % MAX_FILLS_PER_ACCOUNT).to_string();
+    host.store_write_all(&fills_path(address, &slot)?, &fill.rlp_bytes())
+        .map_err(TradezError::DatabaseRuntimeError)?;
+    host.store_write_all(&fills_path(address, "count")?, &(count + 1).rlp_bytes())
+        .map_err(TradezError::DatabaseRuntimeError)
+}
+
+/// Reads up to `FILLS_PAGE_SIZE` fills of an account from the sequence number `cursor`. A
+/// cursor older than the retained fills reads from the oldest one still kept.
+pub fn load_fills(
+    host: &mut impl Runtime,
+    address: &Address,
+    cursor: u64,
+) -> Result<FillPage, TradezError> {
+    let count = fill_count(host, address)?;
+    let start = cursor
+        .max(count.saturating_sub(MAX_FILLS_PER_ACCOUNT))
+        .min(count);
+    let end = start.saturating_add(FILLS_PAGE_SIZE).min(count);
+    let mut fills = Vec::with_capacity((end - start) as usize);
+    for sequence in start..end {
+        let slot = (sequence % MAX_FILLS_PER_ACCOUNT).to_string();
+        let data = host
+            .store_read_all(&fills_path(address, &slot)?)
+            .map_err(TradezError::DatabaseRuntimeError)?;
+        fills.push(rlp::decode(&data).map_err(|e| TradezError::DataStoreError(e.to_string()))?);
+    }
+    Ok(FillPage {
+        fills,
+        next_cursor: end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_rlp() {
+        let fill = Fill {
+            trade_id: 3,
+            order_id: 12,
+            role: FillRole::Taker,
+            side: Side::Ask,
+            price: Price(2_000_000),
+            qty: Qty(5_000_000),
+            fee: 1_000,
+            fee_currency: Currencies::USDC,
+            level: 42,
+        };
+        let decoded: Fill = rlp::decode(&fill.rlp_bytes()).unwrap();
+        assert_eq!(fill, decoded);
+    }
+}
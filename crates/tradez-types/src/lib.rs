@@ -1,14 +1,31 @@
 use rlp::{Decodable, Encodable};
 
-use crate::position::{APIOrder, CancelOrder, Faucet};
+use crate::{
+    delegate::{DelegateGrant, RevokeDelegate},
+    oracle::OracleUpdate,
+    position::{APIOrder, CancelOrder, Faucet, Transfer},
+    stops::{APIOcoOrder, APIStopOrder},
+};
 
 pub mod address;
 #[cfg(feature = "api")]
 pub mod api;
+pub mod commitment;
 pub mod currencies;
+pub mod delegate;
 pub mod error;
+pub mod fills;
+pub mod margin;
+pub mod market;
+pub mod oracle;
 pub mod orderbook;
+pub mod perpetual;
 pub mod position;
+pub mod setup;
+pub mod status;
+pub mod stops;
+pub mod supply;
+pub mod units;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct SignedInput<T>
@@ -50,11 +67,64 @@ where
     }
 }
 
+/// External message injected by the sequencer: a signed user input stamped with the
+/// L1 level the sequencer executed it at, so the rollup replays it with the same clock.
+/// The kernel only takes that level when the sequencer of the setup signed it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SequencedInput {
+    pub level: u32,
+    pub input: Vec<u8>,
+    /// Signature of [`SequencedInput::payload`] by the sequencer, empty if it has no key.
+    pub signature: Vec<u8>,
+}
+
+impl SequencedInput {
+    /// What the sequencer signs: the level with the input.
+    pub fn payload(level: u32, input: &[u8]) -> Vec<u8> {
+        let mut s = rlp::RlpStream::new_list(2);
+        s.append(&level);
+        s.append(&input);
+        s.out().to_vec()
+    }
+}
+
+// The input and the signature are written as byte strings, not as lists of bytes: they count
+// against the size of the L1 message.
+impl Encodable for SequencedInput {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(3);
+        s.append(&self.level);
+        s.append(&self.input);
+        s.append(&self.signature);
+    }
+}
+
+impl Decodable for SequencedInput {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let signature = match rlp.item_count()? {
+            2 => vec![],
+            3 => rlp.val_at(2)?,
+            _ => return Err(rlp::DecoderError::RlpIncorrectListLen),
+        };
+        Ok(SequencedInput {
+            level: rlp.val_at(0)?,
+            input: rlp.val_at(1)?,
+            signature,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum KernelMessage {
     PlaceOrder(APIOrder),
     CancelOrder(CancelOrder),
     Faucet(Faucet),
+    PlaceStop(APIStopOrder),
+    PlaceOco(APIOcoOrder),
+    OracleUpdate(OracleUpdate),
+    AddDelegate(DelegateGrant),
+    RevokeDelegate(RevokeDelegate),
+    Transfer(Transfer),
 }
 
 impl Encodable for KernelMessage {
@@ -75,6 +145,36 @@ impl Encodable for KernelMessage {
                 s.append(&2u8); // Discriminator for Faucet
                 s.append(faucet);
             }
+            KernelMessage::PlaceStop(stop) => {
+                s.begin_list(2);
+                s.append(&3u8); // Discriminator for PlaceStop
+                s.append(stop);
+            }
+            KernelMessage::PlaceOco(oco) => {
+                s.begin_list(2);
+                s.append(&4u8); // Discriminator for PlaceOco
+                s.append(oco);
+            }
+            KernelMessage::OracleUpdate(update) => {
+                s.begin_list(2);
+                s.append(&5u8); // Discriminator for OracleUpdate
+                s.append(update);
+            }
+            KernelMessage::AddDelegate(grant) => {
+                s.begin_list(2);
+                s.append(&6u8); // Discriminator for AddDelegate
+                s.append(grant);
+            }
+            KernelMessage::RevokeDelegate(revoke) => {
+                s.begin_list(2);
+                s.append(&7u8); // Discriminator for RevokeDelegate
+                s.append(revoke);
+            }
+            KernelMessage::Transfer(transfer) => {
+                s.begin_list(2);
+                s.append(&8u8); // Discriminator for Transfer
+                s.append(transfer);
+            }
         }
     }
 }
@@ -95,6 +195,30 @@ impl Decodable for KernelMessage {
                 let faucet: Faucet = rlp.val_at(1)?;
                 Ok(KernelMessage::Faucet(faucet))
             }
+            3 => {
+                let stop: APIStopOrder = rlp.val_at(1)?;
+                Ok(KernelMessage::PlaceStop(stop))
+            }
+            4 => {
+                let oco: APIOcoOrder = rlp.val_at(1)?;
+                Ok(KernelMessage::PlaceOco(oco))
+            }
+            5 => {
+                let update: OracleUpdate = rlp.val_at(1)?;
+                Ok(KernelMessage::OracleUpdate(update))
+            }
+            6 => {
+                let grant: DelegateGrant = rlp.val_at(1)?;
+                Ok(KernelMessage::AddDelegate(grant))
+            }
+            7 => {
+                let revoke: RevokeDelegate = rlp.val_at(1)?;
+                Ok(KernelMessage::RevokeDelegate(revoke))
+            }
+            8 => {
+                let transfer: Transfer = rlp.val_at(1)?;
+                Ok(KernelMessage::Transfer(transfer))
+            }
             _ => Err(rlp::DecoderError::Custom(
                 "Invalid KernelMessage discriminator",
             )),
@@ -106,7 +230,11 @@ impl Decodable for KernelMessage {
 mod tests {
     use rlp::Encodable;
 
-    use crate::{SignedInput, position::APIOrder};
+    use crate::{
+        SequencedInput, SignedInput,
+        position::APIOrder,
+        units::{Price, Qty},
+    };
 
     #[test]
     fn test_signed_input_rlp() {
@@ -119,4 +247,47 @@ mod tests {
 
         assert_eq!(signed_input, decoded);
     }
+
+    #[test]
+    fn test_iceberg_order_rlp() {
+        let plain = APIOrder {
+            size: Qty(5_000_000),
+            price: Price(1_000_000),
+            ..APIOrder::default()
+        };
+        assert_eq!(rlp::Rlp::new(&plain.rlp_bytes()).item_count(), Ok(4));
+        let iceberg = APIOrder {
+            display: Qty(1_000_000),
+            ..plain
+        };
+        assert_eq!(rlp::Rlp::new(&iceberg.rlp_bytes()).item_count(), Ok(5));
+        for order in [plain, iceberg] {
+            let decoded: APIOrder = rlp::decode(&order.rlp_bytes()).unwrap();
+            assert_eq!(order, decoded);
+        }
+    }
+
+    #[test]
+    fn test_sequenced_input_rlp() {
+        let signed_input = SignedInput::new(APIOrder::default(), vec![5, 6]);
+        let sequenced = SequencedInput {
+            level: 42,
+            input: signed_input.rlp_bytes().to_vec(),
+            signature: vec![7, 8],
+        };
+
+        let encoded = sequenced.rlp_bytes();
+        // Byte strings: a few bytes of headers only
+        assert!(encoded.len() <= sequenced.input.len() + sequenced.signature.len() + 8);
+        let decoded: SequencedInput = rlp::decode(&encoded).unwrap();
+        assert_eq!(sequenced, decoded);
+        let inner: SignedInput<APIOrder> = rlp::decode(&decoded.input).unwrap();
+        assert_eq!(inner, signed_input);
+
+        // Stamped before inputs were signed
+        let unsigned: SequencedInput =
+            rlp::decode(&SequencedInput::payload(42, &decoded.input)).unwrap();
+        assert_eq!(unsigned.level, 42);
+        assert!(unsigned.signature.is_empty());
+    }
 }
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    market::BPS_DENOMINATOR,
+    perpetual::PerpPosition,
+    units::{Price, Qty, Rounding},
+};
+
+/// Value of a cross-margin account at the mark price, in microUSDC. Everything the account
+/// holds backs everything it owes and its perpetual position, whatever the currency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarginState {
+    pub mark_price: Price,
+    /// Available and locked balances of both currencies.
+    pub assets: u64,
+    /// Borrowed amounts of both currencies, interest included.
+    pub debt: u64,
+    /// Profit or loss of the perpetual position if it was closed at the mark price.
+    pub unrealized_pnl: i64,
+    /// Value of the perpetual position at the mark price.
+    pub position_notional: u64,
+}
+
+impl MarginState {
+    /// Values USDC and XTZ amounts (holdings then debts) at `mark_price`. Holdings are
+    /// rounded down and debts up, so the rounding never favors the account.
+    pub fn new(mark_price: Price, holdings: (u64, u64), debts: (u64, u64)) -> Option<Self> {
+        let assets = holdings
+            .0
+            .checked_add(mark_price.checked_notional(Qty(holdings.1), Rounding::Down)?)?;
+        let debt = debts
+            .0
+            .checked_add(mark_price.checked_notional(Qty(debts.1), Rounding::Up)?)?;
+        Some(MarginState {
+            mark_price,
+            assets,
+            debt,
+            unrealized_pnl: 0,
+            position_notional: 0,
+        })
+    }
+
+    /// Adds a perpetual position to the margin.
+    pub fn with_position(self, position: &PerpPosition) -> Option<Self> {
+        Some(MarginState {
+            unrealized_pnl: position.unrealized_pnl(self.mark_price),
+            position_notional: position.notional(self.mark_price)?,
+            ..self
+        })
+    }
+
+    /// Assets and unrealized profit minus debt, negative once the account is underwater.
+    pub fn equity(&self) -> i128 {
+        self.assets as i128 - self.debt as i128 + self.unrealized_pnl as i128
+    }
+
+    /// True when the equity covers `margin_bps` of the debt and of the position notional.
+    pub fn meets(&self, margin_bps: u64) -> bool {
+        let exposure = self.debt as i128 + self.position_notional as i128;
+        self.equity() * BPS_DENOMINATOR as i128 >= exposure * margin_bps as i128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn margin_requirements() {
+        // 1_000 USDC of collateral, 4_000 USDC borrowed and spent on 2_000 XTZ at 2 USDC
+        let state = MarginState::new(
+            Price(2_000_000),
+            (1_000_000_000, 2_000_000_000),
+            (4_000_000_000, 0),
+        )
+        .unwrap();
+        assert_eq!(state.equity(), 1_000_000_000);
+        assert!(state.meets(2_500));
+        assert!(!state.meets(2_501));
+
+        // The price falls to 1.7 USDC: equity is 10% of the debt
+        let state = MarginState::new(
+            Price(1_700_000),
+            (1_000_000_000, 2_000_000_000),
+            (4_000_000_000, 0),
+        )
+        .unwrap();
+        assert_eq!(state.equity(), 400_000_000);
+        assert!(state.meets(1_000));
+        assert!(!state.meets(1_001));
+
+        // Without debt any requirement is met
+        let state = MarginState::new(Price(1), (0, 5), (0, 0)).unwrap();
+        assert!(state.meets(u64::MAX));
+    }
+
+    #[test]
+    fn underwater_short() {
+        // 100 USDC of collateral, 1_000 XTZ borrowed and sold at 1 USDC, then it doubles
+        let state =
+            MarginState::new(Price(2_000_000), (1_100_000_000, 0), (0, 1_000_000_000)).unwrap();
+        assert_eq!(state.equity(), -900_000_000);
+        assert!(!state.meets(0));
+    }
+
+    #[test]
+    fn perp_position_margin() {
+        // 100 USDC backing a 10 XTZ long entered at 2 USDC, marked at 1.9 USDC
+        let position = PerpPosition {
+            size: 10_000_000,
+            entry_price: Price(2_000_000),
+        };
+        let state = MarginState::new(Price(1_900_000), (100_000_000, 0), (0, 0))
+            .unwrap()
+            .with_position(&position)
+            .unwrap();
+        assert_eq!(state.unrealized_pnl, -1_000_000);
+        assert_eq!(state.position_notional, 19_000_000);
+        assert_eq!(state.equity(), 99_000_000);
+        assert!(state.meets(BPS_DENOMINATOR * 5));
+        assert!(!state.meets(BPS_DENOMINATOR * 6));
+    }
+}
@@ -0,0 +1,686 @@
+use std::fmt::Display;
+
+use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::RefPath;
+
+use crate::{
+    error::TradezError,
+    orderbook::OrderBook,
+    position::Side,
+    stops::{APIOcoOrder, APIStopOrder},
+    units::{Price, Qty, Rounding},
+};
+
+pub const MARKET_CONFIG_STR_PATH: &str = "/tradez/market_config";
+pub const MARKET_CONFIG_PATH: RefPath = RefPath::assert_from(b"/tradez/market_config");
+pub const MARKET_STATUS_STR_PATH: &str = "/tradez/market_status";
+pub const MARKET_STATUS_PATH: RefPath = RefPath::assert_from(b"/tradez/market_status");
+
+/// One basis point is 0.01%.
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// What a trade exchanges. A rollup runs a single market, its kind chosen when it is set up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarketKind {
+    /// XTZ against USDC, fully delivered.
+    #[default]
+    Spot,
+    /// Linear perpetual on XTZ settled in USDC: trades open and close positions.
+    Perpetual,
+}
+
+impl Encodable for MarketKind {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        match self {
+            MarketKind::Spot => s.append_internal(&0u8),
+            MarketKind::Perpetual => s.append_internal(&1u8),
+        };
+    }
+}
+
+impl Decodable for MarketKind {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        match rlp.as_val::<u8>()? {
+            0 => Ok(MarketKind::Spot),
+            1 => Ok(MarketKind::Perpetual),
+            _ => Err(rlp::DecoderError::Custom("Invalid MarketKind value")),
+        }
+    }
+}
+
+/// Trading rules of the XTZ/USDC market. Part of the [`RollupSetup`](crate::setup::RollupSetup)
+/// the installer writes, the defaults apply to a rollup set up without them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
+pub struct MarketConfig {
+    /// Prices must be a multiple of this (microUSDC).
+    pub tick_size: Price,
+    /// Quantities must be a multiple of this (microXTZ).
+    pub lot_size: Qty,
+    /// Smallest accepted order quantity (microXTZ).
+    pub min_qty: Qty,
+    /// Smallest accepted order value (microUSDC).
+    pub min_notional: u64,
+    /// Maximum distance of a limit price from the reference price, in bps (0 disables it).
+    pub price_band_bps: u64,
+    /// Price move within `circuit_breaker_window` that halts the market, in bps (0 disables it).
+    pub circuit_breaker_bps: u64,
+    /// Number of levels over which price moves are measured by the circuit breaker.
+    pub circuit_breaker_window: u32,
+    /// Number of levels the market stays halted once the circuit breaker tripped.
+    pub halt_duration: u32,
+    /// Length in levels of the call auction opening the market (0 opens it in continuous trading).
+    pub opening_auction_duration: u32,
+    /// Length in levels of the call auction resuming trading after a halt (0 skips it).
+    pub auction_duration: u32,
+    /// When set, the market runs back-to-back batch auctions of this many levels
+    /// instead of continuous trading.
+    pub batch_auction_interval: u32,
+    /// Equity an account must keep after borrowing to place an order, in bps of its debt.
+    pub initial_margin_bps: u64,
+    /// Equity below which an account is undercollateralized, in bps of its debt.
+    pub maintenance_margin_bps: u64,
+    /// Interest charged on borrowed amounts every level, in millionths of the debt.
+    pub borrow_rate_ppm: u64,
+    /// Fee paid to the insurance fund by a liquidated account, in bps of its debt and position.
+    pub liquidation_fee_bps: u64,
+    pub kind: MarketKind,
+    /// Number of levels between two funding payments of a perpetual.
+    pub funding_interval: u32,
+    /// Largest funding rate charged for a period either way, in ppm of the position notional.
+    pub max_funding_rate_ppm: u64,
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        MarketConfig {
+            tick_size: Price(1_000),    // 0.001 USDC
+            lot_size: Qty(1_000),       // 0.001 XTZ
+            min_qty: Qty(1_000),        // 0.001 XTZ
+            min_notional: 1_000_000,    // 1 USDC
+            price_band_bps: 1_000,      // 10%
+            circuit_breaker_bps: 1_500, // 15%
+            circuit_breaker_window: 10,
+            halt_duration: 5,
+            opening_auction_duration: 0,
+            auction_duration: 2,
+            batch_auction_interval: 0,
+            initial_margin_bps: 2_000, // 20%, up to 5x the equity borrowed
+            maintenance_margin_bps: 1_000, // 10%
+            borrow_rate_ppm: 1,
+            liquidation_fee_bps: 100, // 1%
+            kind: MarketKind::Spot,
+            funding_interval: 60,
+            max_funding_rate_ppm: 1_000, // 0.1%
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderRejection {
+    ZeroQty,
+    ZeroPrice,
+    InvalidTickSize,
+    InvalidLotSize,
+    BelowMinQty,
+    BelowMinNotional,
+    OutsidePriceBand,
+    MarketHalted,
+    InvalidDisplayQty,
+    InvalidOcoGroup,
+    NotionalOverflow,
+}
+
+impl OrderRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderRejection::ZeroQty => "zero_qty",
+            OrderRejection::ZeroPrice => "zero_price",
+            OrderRejection::InvalidTickSize => "invalid_tick_size",
+            OrderRejection::InvalidLotSize => "invalid_lot_size",
+            OrderRejection::BelowMinQty => "below_min_qty",
+            OrderRejection::BelowMinNotional => "below_min_notional",
+            OrderRejection::OutsidePriceBand => "outside_price_band",
+            OrderRejection::MarketHalted => "market_halted",
+            OrderRejection::InvalidDisplayQty => "invalid_display_qty",
+            OrderRejection::InvalidOcoGroup => "invalid_oco_group",
+            OrderRejection::NotionalOverflow => "notional_overflow",
+        }
+    }
+}
+
+impl Display for OrderRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl MarketConfig {
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Self, TradezError> {
+        match host.store_read_all(&MARKET_CONFIG_PATH) {
+            Ok(data) => MarketConfig::decode(&rlp::Rlp::new(&data))
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(MarketConfig::default()),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&MARKET_CONFIG_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+
+    /// Checks a limit order against the market rules.
+    pub fn validate_limit(&self, price: Price, qty: Qty) -> Result<(), OrderRejection> {
+        if price.is_zero() {
+            return Err(OrderRejection::ZeroPrice);
+        }
+        if !self.tick_size.is_zero() && !price.raw().is_multiple_of(self.tick_size.raw()) {
+            return Err(OrderRejection::InvalidTickSize);
+        }
+        self.validate_qty(qty)?;
+        match price.checked_notional(qty, Rounding::Down) {
+            Some(notional) if notional >= self.min_notional => Ok(()),
+            // Overflowing notionals are refused later on by the balance checks
+            None => Ok(()),
+            Some(_) => Err(OrderRejection::BelowMinNotional),
+        }
+    }
+
+    /// Checks the quantity of an order against the market rules.
+    pub fn validate_qty(&self, qty: Qty) -> Result<(), OrderRejection> {
+        if qty.is_zero() {
+            return Err(OrderRejection::ZeroQty);
+        }
+        if !self.lot_size.is_zero() && !qty.raw().is_multiple_of(self.lot_size.raw()) {
+            return Err(OrderRejection::InvalidLotSize);
+        }
+        if qty < self.min_qty {
+            return Err(OrderRejection::BelowMinQty);
+        }
+        Ok(())
+    }
+
+    /// Checks the peak of an iceberg order, a zero `display` being a plain limit order.
+    pub fn validate_display(&self, display: Qty, qty: Qty) -> Result<(), OrderRejection> {
+        if display.is_zero() {
+            return Ok(());
+        }
+        if display >= qty {
+            return Err(OrderRejection::InvalidDisplayQty);
+        }
+        self.validate_qty(display)
+            .map_err(|_| OrderRejection::InvalidDisplayQty)
+    }
+
+    /// Checks a stop order: its trigger like a limit price, its offsets on the tick size.
+    pub fn validate_stop(&self, stop: &APIStopOrder) -> Result<(), OrderRejection> {
+        self.validate_limit(stop.trigger_price, stop.size)?;
+        let tick = self.tick_size.raw();
+        if tick != 0
+            && !(stop.trail.raw().is_multiple_of(tick) && stop.slippage.raw().is_multiple_of(tick))
+        {
+            return Err(OrderRejection::InvalidTickSize);
+        }
+        Ok(())
+    }
+
+    /// Both legs of an OCO group close the same position: same side and same size.
+    pub fn validate_oco(&self, oco: &APIOcoOrder) -> Result<(), OrderRejection> {
+        if oco.limit.side != oco.stop.side || oco.limit.size != oco.stop.size {
+            return Err(OrderRejection::InvalidOcoGroup);
+        }
+        self.validate_limit(oco.limit.price, oco.limit.size)?;
+        self.validate_display(oco.limit.display, oco.limit.size)?;
+        self.validate_stop(&oco.stop)
+    }
+
+    /// Checks that a limit price stays within the price band around `reference`.
+    /// Without reference (no trade yet and one side of the book empty) every price is accepted.
+    pub fn validate_band(
+        &self,
+        price: Price,
+        reference: Option<Price>,
+    ) -> Result<(), OrderRejection> {
+        match reference {
+            Some(reference) if self.price_band_bps > 0 && !reference.is_zero() => {
+                if deviation_bps(reference, price) > self.price_band_bps {
+                    Err(OrderRejection::OutsidePriceBand)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rounds a price to the tick size, towards the passive side of the book
+    /// (down for bids, up for asks) so the rounded order is never more aggressive.
+    pub fn round_price(&self, price: Price, side: Side) -> Price {
+        let tick = self.tick_size.raw();
+        if tick == 0 {
+            return price;
+        }
+        let down = price.raw() - price.raw() % tick;
+        match side {
+            Side::Bid => Price(down),
+            Side::Ask if down == price.raw() => price,
+            Side::Ask => Price(down.saturating_add(tick)),
+        }
+    }
+
+    /// Rounds a quantity down to the lot size.
+    pub fn round_qty(&self, qty: Qty) -> Qty {
+        let lot = self.lot_size.raw();
+        if lot == 0 {
+            return qty;
+        }
+        Qty(qty.raw() - qty.raw() % lot)
+    }
+}
+
+/// Relative distance between `price` and `reference`, in bps.
+fn deviation_bps(reference: Price, price: Price) -> u64 {
+    let diff = reference.raw().abs_diff(price.raw()) as u128;
+    let bps = diff * BPS_DENOMINATOR as u128 / reference.raw() as u128;
+    u64::try_from(bps).unwrap_or(u64::MAX)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarketPhase {
+    #[default]
+    Continuous,
+    Halted {
+        until_level: u32,
+    },
+    /// Orders accumulate without matching until the book is uncrossed at `until_level`.
+    Auction {
+        until_level: u32,
+    },
+}
+
+impl Encodable for MarketPhase {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        match self {
+            MarketPhase::Continuous => {
+                s.begin_list(1);
+                s.append(&0u8); // tag
+            }
+            MarketPhase::Halted { until_level } => {
+                s.begin_list(2);
+                s.append(&1u8); // tag
+                s.append(until_level);
+            }
+            MarketPhase::Auction { until_level } => {
+                s.begin_list(2);
+                s.append(&2u8); // tag
+                s.append(until_level);
+            }
+        }
+    }
+}
+
+impl Decodable for MarketPhase {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let tag: u8 = rlp.val_at(0)?;
+        match tag {
+            0 => Ok(MarketPhase::Continuous),
+            1 => Ok(MarketPhase::Halted {
+                until_level: rlp.val_at(1)?,
+            }),
+            2 => Ok(MarketPhase::Auction {
+                until_level: rlp.val_at(1)?,
+            }),
+            _ => Err(rlp::DecoderError::Custom("Unknown market phase tag")),
+        }
+    }
+}
+
+/// Trading state of the market, used by the price bands, the circuit breaker and the auctions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketStatus {
+    pub phase: MarketPhase,
+    pub last_trade_price: Option<Price>,
+    /// First level of the circuit breaker window.
+    pub window_start_level: u32,
+    /// Last trade price when the circuit breaker window started.
+    pub window_start_price: Option<Price>,
+}
+
+// A trade never happens at a zero price so it is used to encode `None`.
+impl Encodable for MarketStatus {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(4);
+        s.append(&self.phase);
+        s.append(&self.last_trade_price.unwrap_or(Price::ZERO));
+        s.append(&self.window_start_level);
+        s.append(&self.window_start_price.unwrap_or(Price::ZERO));
+    }
+}
+
+impl Decodable for MarketStatus {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let some_price = |price: Price| (!price.is_zero()).then_some(price);
+        Ok(MarketStatus {
+            phase: rlp.val_at(0)?,
+            last_trade_price: some_price(rlp.val_at(1)?),
+            window_start_level: rlp.val_at(2)?,
+            window_start_price: some_price(rlp.val_at(3)?),
+        })
+    }
+}
+
+impl MarketStatus {
+    /// Status of a market opening at `level`.
+    pub fn opening(config: &MarketConfig, level: u32) -> Self {
+        let phase = match (
+            config.opening_auction_duration,
+            config.batch_auction_interval,
+        ) {
+            (0, 0) => MarketPhase::Continuous,
+            (0, interval) | (interval, _) => MarketPhase::Auction {
+                until_level: level.saturating_add(interval),
+            },
+        };
+        MarketStatus {
+            phase,
+            window_start_level: level,
+            ..MarketStatus::default()
+        }
+    }
+
+    /// Loads the status, `None` if the market never opened.
+    pub fn try_load<Host: Runtime>(host: &mut Host) -> Result<Option<Self>, TradezError> {
+        match host.store_read_all(&MARKET_STATUS_PATH) {
+            Ok(data) => MarketStatus::decode(&rlp::Rlp::new(&data))
+                .map(Some)
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(None),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Self, TradezError> {
+        Ok(Self::try_load(host)?.unwrap_or_default())
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&MARKET_STATUS_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+
+    /// Price the bands are computed from: the last trade, or the mid price before the first one.
+    pub fn reference_price(&self, orderbook: &OrderBook) -> Option<Price> {
+        self.last_trade_price.or_else(|| orderbook.mid_price())
+    }
+
+    pub fn is_halted(&self, level: u32) -> bool {
+        matches!(self.phase, MarketPhase::Halted { until_level } if level < until_level)
+    }
+
+    pub fn is_auction(&self) -> bool {
+        matches!(self.phase, MarketPhase::Auction { .. })
+    }
+
+    /// Moves to the next phase once the current one is over and returns the phase that ended.
+    /// When it is an auction, the caller has to uncross the book.
+    pub fn advance(&mut self, config: &MarketConfig, level: u32) -> Option<MarketPhase> {
+        let auction = |duration: u32| match duration {
+            0 => MarketPhase::Continuous,
+            duration => MarketPhase::Auction {
+                until_level: level.saturating_add(duration),
+            },
+        };
+        let next = match self.phase {
+            MarketPhase::Halted { until_level } if level >= until_level => {
+                // The price move that tripped the breaker shouldn't count against the resumption
+                self.window_start_level = level;
+                self.window_start_price = self.last_trade_price;
+                if config.auction_duration > 0 {
+                    auction(config.auction_duration)
+                } else {
+                    auction(config.batch_auction_interval)
+                }
+            }
+            MarketPhase::Auction { until_level } if level >= until_level => {
+                auction(config.batch_auction_interval)
+            }
+            MarketPhase::Continuous if config.batch_auction_interval > 0 => {
+                auction(config.batch_auction_interval)
+            }
+            _ => return None,
+        };
+        Some(std::mem::replace(&mut self.phase, next))
+    }
+
+    /// Records a trade and trips the circuit breaker if the price moved too far within
+    /// the window. Returns the level at which trading resumes when the market got halted.
+    pub fn record_trade(&mut self, config: &MarketConfig, level: u32, price: Price) -> Option<u32> {
+        if self.window_start_price.is_none()
+            || level.saturating_sub(self.window_start_level) > config.circuit_breaker_window
+        {
+            self.window_start_level = level;
+            self.window_start_price = Some(self.last_trade_price.unwrap_or(price));
+        }
+        self.last_trade_price = Some(price);
+
+        if config.circuit_breaker_bps == 0 || matches!(self.phase, MarketPhase::Halted { .. }) {
+            return None;
+        }
+        let start_price = self.window_start_price?;
+        if deviation_bps(start_price, price) > config.circuit_breaker_bps {
+            let until_level = level.saturating_add(config.halt_duration);
+            self.phase = MarketPhase::Halted { until_level };
+            Some(until_level)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_limit_orders() {
+        let config = MarketConfig::default();
+        assert_eq!(
+            config.validate_limit(Price(2_000_000), Qty(1_000_000)),
+            Ok(())
+        );
+        assert_eq!(
+            config.validate_limit(Price(2_000_500), Qty(1_000_000)),
+            Err(OrderRejection::InvalidTickSize)
+        );
+        assert_eq!(
+            config.validate_limit(Price(2_000_000), Qty(1_000_500)),
+            Err(OrderRejection::InvalidLotSize)
+        );
+        assert_eq!(
+            config.validate_limit(Price(2_000_000), Qty::ZERO),
+            Err(OrderRejection::ZeroQty)
+        );
+        // 0.1 XTZ at 2 USDC is below the 1 USDC minimum notional
+        assert_eq!(
+            config.validate_limit(Price(2_000_000), Qty(100_000)),
+            Err(OrderRejection::BelowMinNotional)
+        );
+        let config = MarketConfig {
+            min_qty: Qty(10_000),
+            ..MarketConfig::default()
+        };
+        assert_eq!(
+            config.validate_limit(Price(2_000_000_000), Qty(1_000)),
+            Err(OrderRejection::BelowMinQty)
+        );
+    }
+
+    #[test]
+    fn rounding_to_market_rules() {
+        let config = MarketConfig::default();
+        assert_eq!(
+            config.round_price(Price(1_234_567), Side::Bid),
+            Price(1_234_000)
+        );
+        assert_eq!(
+            config.round_price(Price(1_234_567), Side::Ask),
+            Price(1_235_000)
+        );
+        assert_eq!(
+            config.round_price(Price(1_234_000), Side::Ask),
+            Price(1_234_000)
+        );
+        assert_eq!(config.round_qty(Qty(1_999_999)), Qty(1_999_000));
+    }
+
+    #[test]
+    fn validate_iceberg_display() {
+        let config = MarketConfig::default();
+        assert_eq!(config.validate_display(Qty::ZERO, Qty(5_000_000)), Ok(()));
+        assert_eq!(
+            config.validate_display(Qty(1_000_000), Qty(5_000_000)),
+            Ok(())
+        );
+        assert_eq!(
+            config.validate_display(Qty(5_000_000), Qty(5_000_000)),
+            Err(OrderRejection::InvalidDisplayQty)
+        );
+        assert_eq!(
+            config.validate_display(Qty(1_000_500), Qty(5_000_000)),
+            Err(OrderRejection::InvalidDisplayQty)
+        );
+    }
+
+    #[test]
+    fn price_bands() {
+        let config = MarketConfig::default();
+        let reference = Some(Price(2_000_000));
+        assert_eq!(config.validate_band(Price(2_200_000), reference), Ok(()));
+        assert_eq!(config.validate_band(Price(1_800_000), reference), Ok(()));
+        assert_eq!(
+            config.validate_band(Price(2_201_000), reference),
+            Err(OrderRejection::OutsidePriceBand)
+        );
+        // A fat-fingered bid at 100x
+        assert_eq!(
+            config.validate_band(Price(200_000_000), reference),
+            Err(OrderRejection::OutsidePriceBand)
+        );
+        assert_eq!(config.validate_band(Price(200_000_000), None), Ok(()));
+    }
+
+    #[test]
+    fn reference_price_falls_back_to_mid() {
+        let mut orderbook = OrderBook::new();
+        let mut events = vec![];
+        let user = crate::address::Address::from([1u8; 20]);
+        orderbook.place_limit(
+            user,
+            Side::Bid,
+            Price(1_900_000),
+            Qty(1_000_000),
+            0,
+            &mut events,
+        );
+        let mut status = MarketStatus::default();
+        assert_eq!(status.reference_price(&orderbook), None);
+        orderbook.place_limit(
+            user,
+            Side::Ask,
+            Price(2_100_000),
+            Qty(1_000_000),
+            1,
+            &mut events,
+        );
+        assert_eq!(status.reference_price(&orderbook), Some(Price(2_000_000)));
+        status.last_trade_price = Some(Price(2_050_000));
+        assert_eq!(status.reference_price(&orderbook), Some(Price(2_050_000)));
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let config = MarketConfig::default();
+        let mut status = MarketStatus::default();
+        assert_eq!(status.record_trade(&config, 1, Price(2_000_000)), None);
+        assert_eq!(status.record_trade(&config, 3, Price(2_200_000)), None);
+        // +16% since the start of the window
+        assert_eq!(status.record_trade(&config, 5, Price(2_320_000)), Some(10));
+        assert!(status.is_halted(9));
+        assert_eq!(status.advance(&config, 9), None);
+        assert_eq!(
+            status.advance(&config, 10),
+            Some(MarketPhase::Halted { until_level: 10 })
+        );
+        assert!(!status.is_halted(10));
+        assert_eq!(status.window_start_price, Some(Price(2_320_000)));
+
+        // The same move spread over more than a window doesn't halt the market
+        let mut status = MarketStatus::default();
+        assert_eq!(status.record_trade(&config, 1, Price(2_000_000)), None);
+        assert_eq!(status.record_trade(&config, 9, Price(2_200_000)), None);
+        assert_eq!(status.record_trade(&config, 20, Price(2_320_000)), None);
+        assert_eq!(status.phase, MarketPhase::Continuous);
+    }
+
+    #[test]
+    fn auction_phases() {
+        let config = MarketConfig {
+            opening_auction_duration: 3,
+            ..MarketConfig::default()
+        };
+        let mut status = MarketStatus::opening(&config, 10);
+        assert_eq!(status.phase, MarketPhase::Auction { until_level: 13 });
+        assert_eq!(status.advance(&config, 12), None);
+        assert_eq!(
+            status.advance(&config, 13),
+            Some(MarketPhase::Auction { until_level: 13 })
+        );
+        assert_eq!(status.phase, MarketPhase::Continuous);
+
+        // Resuming after a halt goes through an auction
+        status.phase = MarketPhase::Halted { until_level: 20 };
+        assert_eq!(
+            status.advance(&config, 21),
+            Some(MarketPhase::Halted { until_level: 20 })
+        );
+        assert_eq!(status.phase, MarketPhase::Auction { until_level: 23 });
+
+        // Frequent batch auctions chain one auction after the other
+        let config = MarketConfig {
+            batch_auction_interval: 1,
+            ..MarketConfig::default()
+        };
+        let mut status = MarketStatus::opening(&config, 10);
+        assert_eq!(status.phase, MarketPhase::Auction { until_level: 11 });
+        assert_eq!(
+            status.advance(&config, 11),
+            Some(MarketPhase::Auction { until_level: 11 })
+        );
+        assert_eq!(status.phase, MarketPhase::Auction { until_level: 12 });
+        assert_eq!(
+            MarketStatus::opening(&MarketConfig::default(), 10).phase,
+            MarketPhase::Continuous
+        );
+    }
+
+    #[test]
+    fn market_status_rlp() {
+        let status = MarketStatus {
+            phase: MarketPhase::Halted { until_level: 12 },
+            last_trade_price: Some(Price(2_000_000)),
+            window_start_level: 3,
+            window_start_price: None,
+        };
+        let decoded: MarketStatus = rlp::decode(&status.rlp_bytes()).unwrap();
+        assert_eq!(status, decoded);
+    }
+
+    #[test]
+    fn market_config_rlp() {
+        let config = MarketConfig::default();
+        let decoded: MarketConfig = rlp::decode(&config.rlp_bytes()).unwrap();
+        assert_eq!(config, decoded);
+    }
+}
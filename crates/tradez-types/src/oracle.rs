@@ -0,0 +1,321 @@
+use std::fmt::Display;
+
+use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::RefPath;
+
+use crate::{address::Address, error::TradezError, units::Price};
+
+pub const ORACLE_CONFIG_STR_PATH: &str = "/tradez/oracle_config";
+pub const ORACLE_CONFIG_PATH: RefPath = RefPath::assert_from(b"/tradez/oracle_config");
+pub const ORACLE_FEED_STR_PATH: &str = "/tradez/oracle_feed";
+pub const ORACLE_FEED_PATH: RefPath = RefPath::assert_from(b"/tradez/oracle_feed");
+
+/// XTZ/USDC price as signed by an oracle.
+#[derive(
+    Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, Default, PartialEq, Eq, Clone, Copy,
+)]
+pub struct OracleUpdate {
+    pub price: Price,
+    /// Level the price was observed at. An oracle's levels must increase, so an update can't
+    /// be replayed.
+    pub level: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OracleRejection {
+    UnknownOracle,
+    ZeroPrice,
+    FutureQuote,
+    StaleQuote,
+    OutdatedQuote,
+}
+
+impl OracleRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OracleRejection::UnknownOracle => "unknown_oracle",
+            OracleRejection::ZeroPrice => "zero_quote",
+            OracleRejection::FutureQuote => "future_quote",
+            OracleRejection::StaleQuote => "stale_quote",
+            OracleRejection::OutdatedQuote => "outdated_quote",
+        }
+    }
+}
+
+impl Display for OracleRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Oracles allowed to feed the index price and how their quotes are aggregated. Part of the
+/// [`RollupSetup`](crate::setup::RollupSetup) the installer writes: no input changes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OracleConfig {
+    pub oracles: Vec<Address>,
+    /// Fresh quotes needed for an index price.
+    pub min_sources: u32,
+    /// Number of levels after which a quote is stale.
+    pub max_age: u32,
+}
+
+impl Default for OracleConfig {
+    fn default() -> Self {
+        OracleConfig {
+            oracles: vec![],
+            min_sources: 1,
+            max_age: 10,
+        }
+    }
+}
+
+impl Encodable for OracleConfig {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(3);
+        s.append_list(&self.oracles);
+        s.append(&self.min_sources);
+        s.append(&self.max_age);
+    }
+}
+
+impl Decodable for OracleConfig {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        Ok(OracleConfig {
+            oracles: rlp.list_at(0)?,
+            min_sources: rlp.val_at(1)?,
+            max_age: rlp.val_at(2)?,
+        })
+    }
+}
+
+impl OracleConfig {
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Self, TradezError> {
+        match host.store_read_all(&ORACLE_CONFIG_PATH) {
+            Ok(data) => OracleConfig::decode(&rlp::Rlp::new(&data))
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(OracleConfig::default()),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&ORACLE_CONFIG_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+}
+
+/// Last price an oracle sent.
+#[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq, Clone, Copy)]
+pub struct OracleQuote {
+    pub oracle: Address,
+    pub price: Price,
+    pub level: u32,
+}
+
+/// Last quote of each oracle of the market and the index price published from them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OracleFeed {
+    /// Zero while there aren't enough fresh quotes.
+    pub index_price: Price,
+    pub quotes: Vec<OracleQuote>,
+}
+
+impl Encodable for OracleFeed {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(2);
+        s.append(&self.index_price);
+        s.append_list(&self.quotes);
+    }
+}
+
+impl Decodable for OracleFeed {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        Ok(OracleFeed {
+            index_price: rlp.val_at(0)?,
+            quotes: rlp.list_at(1)?,
+        })
+    }
+}
+
+impl OracleFeed {
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Self, TradezError> {
+        match host.store_read_all(&ORACLE_FEED_PATH) {
+            Ok(data) => OracleFeed::decode(&rlp::Rlp::new(&data))
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(OracleFeed::default()),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&ORACLE_FEED_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+
+    /// Records the quote of `oracle`, replacing its previous one, when it is whitelisted,
+    /// fresh at `level` and more recent than the previous one.
+    pub fn submit(
+        &mut self,
+        config: &OracleConfig,
+        oracle: Address,
+        update: OracleUpdate,
+        level: u32,
+    ) -> Result<(), OracleRejection> {
+        if !config.oracles.contains(&oracle) {
+            return Err(OracleRejection::UnknownOracle);
+        }
+        if update.price.is_zero() {
+            return Err(OracleRejection::ZeroPrice);
+        }
+        if update.level > level {
+            return Err(OracleRejection::FutureQuote);
+        }
+        if update.level.saturating_add(config.max_age) < level {
+            return Err(OracleRejection::StaleQuote);
+        }
+        let quote = OracleQuote {
+            oracle,
+            price: update.price,
+            level: update.level,
+        };
+        match self.quotes.iter_mut().find(|quote| quote.oracle == oracle) {
+            Some(previous) if previous.level >= update.level => Err(OracleRejection::OutdatedQuote),
+            Some(previous) => {
+                *previous = quote;
+                Ok(())
+            }
+            None => {
+                self.quotes.push(quote);
+                Ok(())
+            }
+        }
+    }
+
+    /// Quotes of the whitelisted oracles that are still fresh at `level`.
+    pub fn fresh_quotes(&self, config: &OracleConfig, level: u32) -> Vec<OracleQuote> {
+        self.quotes
+            .iter()
+            .filter(|quote| config.oracles.contains(&quote.oracle))
+            .filter(|quote| quote.level.saturating_add(config.max_age) >= level)
+            .copied()
+            .collect()
+    }
+
+    /// Median of the fresh quotes at `level`, the lower one of the two middle quotes when
+    /// there is an even number of them. None with fewer than `min_sources` fresh quotes.
+    pub fn median_price(&self, config: &OracleConfig, level: u32) -> Option<Price> {
+        let mut prices: Vec<Price> = self
+            .fresh_quotes(config, level)
+            .iter()
+            .map(|quote| quote.price)
+            .collect();
+        if prices.is_empty() || prices.len() < config.min_sources as usize {
+            return None;
+        }
+        prices.sort();
+        Some(prices[(prices.len() - 1) / 2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oracle(n: u8) -> Address {
+        Address::from([n; 20])
+    }
+
+    fn config() -> OracleConfig {
+        OracleConfig {
+            oracles: vec![oracle(1), oracle(2), oracle(3)],
+            min_sources: 2,
+            max_age: 10,
+        }
+    }
+
+    #[test]
+    fn median_of_fresh_quotes() {
+        let config = config();
+        let mut feed = OracleFeed::default();
+        let update = |price, level| OracleUpdate {
+            price: Price(price),
+            level,
+        };
+        assert_eq!(
+            feed.submit(&config, oracle(1), update(2_000_000, 100), 100),
+            Ok(())
+        );
+        // A single source isn't enough
+        assert_eq!(feed.median_price(&config, 100), None);
+        assert_eq!(
+            feed.submit(&config, oracle(2), update(2_100_000, 101), 101),
+            Ok(())
+        );
+        assert_eq!(feed.median_price(&config, 101), Some(Price(2_000_000)));
+        assert_eq!(
+            feed.submit(&config, oracle(3), update(5_000_000, 101), 102),
+            Ok(())
+        );
+        // The outlier doesn't move the median
+        assert_eq!(feed.median_price(&config, 102), Some(Price(2_100_000)));
+        // Once the first quote is stale, two are left
+        assert_eq!(feed.median_price(&config, 111), Some(Price(2_100_000)));
+        assert_eq!(feed.median_price(&config, 112), None);
+    }
+
+    #[test]
+    fn rejected_quotes() {
+        let config = config();
+        let mut feed = OracleFeed::default();
+        let update = OracleUpdate {
+            price: Price(2_000_000),
+            level: 50,
+        };
+        assert_eq!(
+            feed.submit(&config, oracle(4), update, 50),
+            Err(OracleRejection::UnknownOracle)
+        );
+        assert_eq!(
+            feed.submit(&config, oracle(1), update, 49),
+            Err(OracleRejection::FutureQuote)
+        );
+        assert_eq!(
+            feed.submit(&config, oracle(1), update, 61),
+            Err(OracleRejection::StaleQuote)
+        );
+        assert_eq!(feed.submit(&config, oracle(1), update, 55), Ok(()));
+        // Replayed
+        assert_eq!(
+            feed.submit(&config, oracle(1), update, 55),
+            Err(OracleRejection::OutdatedQuote)
+        );
+        let zero = OracleUpdate {
+            price: Price::ZERO,
+            level: 55,
+        };
+        assert_eq!(
+            feed.submit(&config, oracle(2), zero, 55),
+            Err(OracleRejection::ZeroPrice)
+        );
+    }
+
+    #[test]
+    fn oracle_rlp() {
+        let config = config();
+        let decoded: OracleConfig = rlp::decode(&config.rlp_bytes()).unwrap();
+        assert_eq!(config, decoded);
+
+        let feed = OracleFeed {
+            index_price: Price(2_000_000),
+            quotes: vec![OracleQuote {
+                oracle: oracle(1),
+                price: Price(2_000_000),
+                level: 7,
+            }],
+        };
+        let decoded: OracleFeed = rlp::decode(&feed.rlp_bytes()).unwrap();
+        assert_eq!(feed, decoded);
+    }
+}
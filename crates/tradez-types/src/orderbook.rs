@@ -2,8 +2,11 @@ use std::collections::{BTreeMap, VecDeque};
 
 use crate::{
     address::Address,
+    commitment::Hash,
+    currencies::Currencies,
     error::TradezError,
-    position::{OrdType, Order, Price, Qty, Side},
+    position::{OrdType, Order, Side},
+    units::{Price, Qty},
 };
 use rlp::{Decodable, Encodable};
 use serde::{Deserialize, Serialize};
@@ -37,6 +40,69 @@ pub enum Event {
         user: Address,
         reason: String,
     },
+    Rejected {
+        user: Address,
+        nonce: u64,
+        reason: String,
+    }, // ordre refusé avant d'entrer dans le carnet, ou annulation et délégation refusées (nonce nul)
+    MarketHalted {
+        reference_price: Price,
+        last_price: Price,
+        until_level: u32,
+    },
+    MarketResumed {
+        level: u32,
+    },
+    AuctionStarted {
+        until_level: u32,
+    },
+    AuctionUncrossed {
+        price: Price,
+        qty: Qty,
+    }, // clôture d'enchère : volume exécuté au prix d'équilibre
+    StopTriggered {
+        id: u64,
+        user: Address,
+        price: Price,
+    }, // stop déclenché, son ordre limite suit
+    StateCommitment {
+        level: u32,
+        root: Hash,
+    }, // racine de Merkle des comptes et du carnet en fin de niveau
+    Funding {
+        level: u32,
+        rate_ppm: i64,
+        mark_price: Price,
+        index_price: Price,
+    }, // financement du perpétuel : les longs paient les shorts si le taux est positif
+    Liquidated {
+        user: Address,
+        mark_price: Price,
+        fee: u64,
+        bad_debt: u64,
+    }, // compte sous la marge de maintenance soldé, dette irrécouvrable valorisée en USDC
+    IndexPrice {
+        level: u32,
+        price: Price,
+        sources: u32,
+    }, // nouveau prix indice : médiane des cotations fraîches des oracles, zéro si périmé
+    DelegateUpdated {
+        owner: Address,
+        key: Address,
+        expiry_level: u32,
+        revoked: bool,
+    }, // clé déléguée ajoutée ou révoquée par le compte propriétaire
+    Transfer {
+        from: Address,
+        to: Address,
+        currency: Currencies,
+        amount: u64,
+    }, // solde disponible déplacé d'un compte à l'autre
+    TransferRejected {
+        from: Address,
+        nonce: u64,
+        reason: String,
+    }, // transfert refusé, aucun solde déplacé
 }
 
 impl Encodable for Event {
@@ -89,6 +155,131 @@ impl Encodable for Event {
                 s.append(user);
                 s.append(reason);
             }
+            Event::Rejected {
+                user,
+                nonce,
+                reason,
+            } => {
+                s.begin_list(4);
+                s.append(&4u8); // tag
+                s.append(user);
+                s.append(nonce);
+                s.append(reason);
+            }
+            Event::MarketHalted {
+                reference_price,
+                last_price,
+                until_level,
+            } => {
+                s.begin_list(4);
+                s.append(&5u8); // tag
+                s.append(reference_price);
+                s.append(last_price);
+                s.append(until_level);
+            }
+            Event::MarketResumed { level } => {
+                s.begin_list(2);
+                s.append(&6u8); // tag
+                s.append(level);
+            }
+            Event::AuctionStarted { until_level } => {
+                s.begin_list(2);
+                s.append(&7u8); // tag
+                s.append(until_level);
+            }
+            Event::AuctionUncrossed { price, qty } => {
+                s.begin_list(3);
+                s.append(&8u8); // tag
+                s.append(price);
+                s.append(qty);
+            }
+            Event::StopTriggered { id, user, price } => {
+                s.begin_list(4);
+                s.append(&9u8); // tag
+                s.append(id);
+                s.append(user);
+                s.append(price);
+            }
+            Event::StateCommitment { level, root } => {
+                s.begin_list(3);
+                s.append(&10u8); // tag
+                s.append(level);
+                s.append(root);
+            }
+            Event::Funding {
+                level,
+                rate_ppm,
+                mark_price,
+                index_price,
+            } => {
+                s.begin_list(5);
+                s.append(&11u8); // tag
+                s.append(level);
+                s.append(&(*rate_ppm as u64)); // complément à deux
+                s.append(mark_price);
+                s.append(index_price);
+            }
+            Event::Liquidated {
+                user,
+                mark_price,
+                fee,
+                bad_debt,
+            } => {
+                s.begin_list(5);
+                s.append(&12u8); // tag
+                s.append(user);
+                s.append(mark_price);
+                s.append(fee);
+                s.append(bad_debt);
+            }
+            Event::IndexPrice {
+                level,
+                price,
+                sources,
+            } => {
+                s.begin_list(4);
+                s.append(&13u8); // tag
+                s.append(level);
+                s.append(price);
+                s.append(sources);
+            }
+            Event::DelegateUpdated {
+                owner,
+                key,
+                expiry_level,
+                revoked,
+            } => {
+                s.begin_list(5);
+                s.append(&14u8); // tag
+                s.append(owner);
+                s.append(key);
+                s.append(expiry_level);
+                s.append(revoked);
+            }
+            Event::Transfer {
+                from,
+                to,
+                currency,
+                amount,
+            } => {
+                s.begin_list(5);
+                s.append(&15u8); // tag
+                s.append(from);
+                s.append(to);
+                s.append(currency);
+                s.append(amount);
+            }
+            Event::TransferRejected {
+                from,
+                nonce,
+                reason,
+            } => {
+                s.begin_list(4);
+                s.append(&16u8); // tag
+                s.append(from);
+                s.append(nonce);
+                s.append(reason);
+            }
         }
     }
 }
@@ -205,12 +396,237 @@ impl Decodable for Event {
                     .as_val()?;
                 Ok(Event::Cancelled { id, user, reason })
             }
+            4 => {
+                let user: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let nonce: u64 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let reason: String = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::Rejected {
+                    user,
+                    nonce,
+                    reason,
+                })
+            }
+            5 => {
+                let reference_price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let last_price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let until_level: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::MarketHalted {
+                    reference_price,
+                    last_price,
+                    until_level,
+                })
+            }
+            6 => {
+                let level: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::MarketResumed { level })
+            }
+            7 => {
+                let until_level: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::AuctionStarted { until_level })
+            }
+            8 => {
+                let price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let qty: Qty = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::AuctionUncrossed { price, qty })
+            }
+            9 => {
+                let id: u64 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let user: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::StopTriggered { id, user, price })
+            }
+            10 => {
+                let level: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let root: Hash = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::StateCommitment { level, root })
+            }
+            11 => {
+                let level: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let rate_ppm: u64 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let mark_price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let index_price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::Funding {
+                    level,
+                    rate_ppm: rate_ppm as i64,
+                    mark_price,
+                    index_price,
+                })
+            }
+            12 => {
+                let user: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let mark_price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let fee: u64 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let bad_debt: u64 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::Liquidated {
+                    user,
+                    mark_price,
+                    fee,
+                    bad_debt,
+                })
+            }
+            13 => {
+                let level: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let price: Price = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let sources: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::IndexPrice {
+                    level,
+                    price,
+                    sources,
+                })
+            }
+            14 => {
+                let owner: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let key: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let expiry_level: u32 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let revoked: bool = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::DelegateUpdated {
+                    owner,
+                    key,
+                    expiry_level,
+                    revoked,
+                })
+            }
+            15 => {
+                let from: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let to: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let currency: Currencies = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let amount: u64 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::Transfer {
+                    from,
+                    to,
+                    currency,
+                    amount,
+                })
+            }
+            16 => {
+                let from: Address = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let nonce: u64 = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                let reason: String = it
+                    .next()
+                    .ok_or(rlp::DecoderError::RlpIncorrectListLen)?
+                    .as_val()?;
+                Ok(Event::TransferRejected {
+                    from,
+                    nonce,
+                    reason,
+                })
+            }
             _ => Err(rlp::DecoderError::Custom("Invalid event tag")),
         }
     }
 }
 
 pub type SideLadder = BTreeMap<Price, VecDeque<Order>>;
+/// Aggregated public depth, one `(price, total visible)` entry per level.
+pub type DepthLevels = Vec<(Price, Qty)>;
 
 #[derive(Default, Debug)]
 pub struct OrderBook {
@@ -328,6 +744,14 @@ impl OrderBook {
     pub fn best_ask(&self) -> Option<Price> {
         self.asks.keys().next().copied()
     }
+    pub fn mid_price(&self) -> Option<Price> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => {
+                Some(Price(((bid.raw() as u128 + ask.raw() as u128) / 2) as u64))
+            }
+            _ => None,
+        }
+    }
     pub fn spread(&self) -> Option<Price> {
         match (self.best_bid(), self.best_ask()) {
             (Some(bid), Some(ask)) => Some(ask.saturating_sub(bid)),
@@ -335,17 +759,17 @@ impl OrderBook {
         }
     }
     pub fn price_quantity_at(&self, price: Price) -> Qty {
-        let bid_qty = self
+        let bid_qty: Qty = self
             .bids
             .get(&price)
-            .map(|queue| queue.iter().map(|o| o.remaining).sum())
-            .unwrap_or(0);
-        let ask_qty = self
+            .map(|queue| queue.iter().map(|o| o.visible()).sum())
+            .unwrap_or(Qty::ZERO);
+        let ask_qty: Qty = self
             .asks
             .get(&price)
-            .map(|queue| queue.iter().map(|o| o.remaining).sum())
-            .unwrap_or(0);
-        bid_qty + ask_qty
+            .map(|queue| queue.iter().map(|o| o.visible()).sum())
+            .unwrap_or(Qty::ZERO);
+        bid_qty.saturating_add(ask_qty)
     }
     pub fn is_empty(&self) -> bool {
         self.bids.is_empty() && self.asks.is_empty()
@@ -361,9 +785,26 @@ impl OrderBook {
         nonce: u64,
         out: &mut Vec<Event>,
     ) -> u64 {
-        assert!(qty > 0, "qty must be > 0");
+        self.place_iceberg(user, side, price, qty, Qty::ZERO, nonce, out)
+    }
+
+    /// Place un LIMIT iceberg dont seul le pic `display` est visible dans le carnet
+    /// (`display` nul pour un LIMIT classique). L'ordre entrant s'exécute sur toute sa taille,
+    /// seul le reliquat au repos est découpé en tranches.
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_iceberg(
+        &mut self,
+        user: Address,
+        side: Side,
+        price: Price,
+        qty: Qty,
+        display: Qty,
+        nonce: u64,
+        out: &mut Vec<Event>,
+    ) -> u64 {
+        assert!(!qty.is_zero(), "qty must be > 0");
         if side == Side::Bid {
-            assert!(price > 0, "bid price must be > 0");
+            assert!(!price.is_zero(), "bid price must be > 0");
         }
         let id = self.alloc_id();
         let mut taker = Order {
@@ -375,18 +816,21 @@ impl OrderBook {
             qty,
             remaining: qty,
             nonce,
+            display,
+            hidden: Qty::ZERO,
         };
         out.push(Event::Placed {
             id,
             side,
             price,
-            qty,
+            qty: Self::public_qty(qty, display),
             user,
         });
 
         self.match_incoming(&mut taker, out);
 
-        if taker.remaining > 0 {
+        if !taker.remaining.is_zero() {
+            taker.hide_reserve();
             let book = match side {
                 Side::Bid => &mut self.bids,
                 Side::Ask => &mut self.asks,
@@ -398,6 +842,186 @@ impl OrderBook {
         id
     }
 
+    /// Place un LIMIT pendant une enchère : l'ordre rejoint le carnet sans être apparié.
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_auction_limit(
+        &mut self,
+        user: Address,
+        side: Side,
+        price: Price,
+        qty: Qty,
+        display: Qty,
+        nonce: u64,
+        out: &mut Vec<Event>,
+    ) -> u64 {
+        assert!(!qty.is_zero(), "qty must be > 0");
+        assert!(!price.is_zero(), "auction price must be > 0");
+        let id = self.alloc_id();
+        let mut order = Order {
+            id,
+            user,
+            side,
+            ord_type: OrdType::Limit,
+            price,
+            qty,
+            remaining: qty,
+            nonce,
+            display,
+            hidden: Qty::ZERO,
+        };
+        order.hide_reserve();
+        out.push(Event::Placed {
+            id,
+            side,
+            price,
+            qty: Self::public_qty(qty, display),
+            user,
+        });
+        let book = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        book.entry(price).or_default().push_back(order);
+        id
+    }
+
+    /// Prix d'équilibre de l'enchère et volume exécutable, `None` si le carnet ne se croise pas.
+    ///
+    /// Parmi les prix limites du carnet on garde celui qui maximise le volume exécuté, puis
+    /// celui qui minimise le déséquilibre. S'il reste plusieurs candidats : le plus haut si la
+    /// demande excède l'offre partout, le plus bas si c'est l'inverse, sinon le plus proche de
+    /// `reference` (et à défaut le plus bas).
+    pub fn clearing_price(&self, reference: Option<Price>) -> Option<(Price, Qty)> {
+        let level_qty =
+            |queue: &VecDeque<Order>| -> Qty { queue.iter().map(|o| o.remaining).sum() };
+        let mut prices: Vec<Price> = self.bids.keys().chain(self.asks.keys()).copied().collect();
+        prices.sort();
+        prices.dedup();
+
+        // (prix, volume exécutable, demande, offre)
+        let candidates: Vec<(Price, Qty, Qty, Qty)> = prices
+            .into_iter()
+            .map(|price| {
+                let demand: Qty = self.bids.range(price..).map(|(_, q)| level_qty(q)).sum();
+                let supply: Qty = self.asks.range(..=price).map(|(_, q)| level_qty(q)).sum();
+                (price, demand.min(supply), demand, supply)
+            })
+            .filter(|(_, volume, _, _)| !volume.is_zero())
+            .collect();
+
+        let max_volume = candidates.iter().map(|c| c.1).max()?;
+        let imbalance = |c: &(Price, Qty, Qty, Qty)| c.2.raw().abs_diff(c.3.raw());
+        let min_imbalance = candidates
+            .iter()
+            .filter(|c| c.1 == max_volume)
+            .map(imbalance)
+            .min()?;
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|c| c.1 == max_volume && imbalance(c) == min_imbalance)
+            .collect();
+
+        let chosen = if candidates.iter().all(|c| c.2 > c.3) {
+            candidates.last()
+        } else if candidates.iter().all(|c| c.2 < c.3) {
+            candidates.first()
+        } else if let Some(reference) = reference {
+            candidates
+                .iter()
+                .min_by_key(|c| c.0.raw().abs_diff(reference.raw()))
+        } else {
+            candidates.first()
+        }?;
+        Some((chosen.0, max_volume))
+    }
+
+    /// Clôture l'enchère : les ordres qui se croisent s'exécutent tous au prix d'équilibre,
+    /// par priorité prix-temps. Dans chaque paire, l'ordre le plus récent est le taker.
+    pub fn uncross(
+        &mut self,
+        reference: Option<Price>,
+        out: &mut Vec<Event>,
+    ) -> Option<(Price, Qty)> {
+        let (price, volume) = self.clearing_price(reference)?;
+        let mut bids = Self::take_crossing(&mut self.bids, Side::Bid, volume);
+        let mut asks = Self::take_crossing(&mut self.asks, Side::Ask, volume);
+
+        let mut remaining = volume;
+        while !remaining.is_zero() {
+            let (Some(bid), Some(ask)) = (bids.front_mut(), asks.front_mut()) else {
+                break;
+            };
+            let exec_qty = bid.remaining.min(ask.remaining).min(remaining);
+            let (maker, taker) = if bid.id < ask.id {
+                (&*bid, &*ask)
+            } else {
+                (&*ask, &*bid)
+            };
+            out.push(Event::Trade {
+                maker_id: maker.id,
+                maker_user: maker.user,
+                taker_id: taker.id,
+                taker_user: taker.user,
+                price,
+                qty: exec_qty,
+                origin_side: taker.side,
+            });
+            bid.fill(exec_qty);
+            ask.fill(exec_qty);
+            remaining = remaining.saturating_sub(exec_qty);
+
+            for queue in [&mut bids, &mut asks] {
+                if queue.front().is_some_and(|o| o.remaining.is_zero()) {
+                    let done = queue.pop_front().expect("exists");
+                    out.push(Event::Done {
+                        user: done.user,
+                        id: done.id,
+                    });
+                }
+            }
+        }
+
+        // Les reliquats reprennent leur place en tête de leur niveau,
+        // sauf un iceberg rechargé qui repasse en fin de file
+        for (ladder, queue) in [(&mut self.bids, bids), (&mut self.asks, asks)] {
+            for mut order in queue.into_iter().rev() {
+                if order.replenish() {
+                    ladder.entry(order.price).or_default().push_back(order);
+                } else {
+                    ladder.entry(order.price).or_default().push_front(order);
+                }
+            }
+        }
+        Some((price, volume))
+    }
+
+    /// Retire du carnet, par priorité prix-temps, les ordres couvrant au moins `volume`.
+    fn take_crossing(ladder: &mut SideLadder, side: Side, volume: Qty) -> VecDeque<Order> {
+        let mut taken = VecDeque::new();
+        let mut total = Qty::ZERO;
+        while total < volume {
+            let best = match side {
+                Side::Bid => ladder.keys().next_back().copied(),
+                Side::Ask => ladder.keys().next().copied(),
+            };
+            let Some(level) = best else {
+                break;
+            };
+            let mut queue = ladder.remove(&level).expect("exists");
+            while total < volume {
+                let Some(order) = queue.pop_front() else {
+                    break;
+                };
+                total = total.saturating_add(order.remaining);
+                taken.push_back(order);
+            }
+            if !queue.is_empty() {
+                ladder.insert(level, queue);
+            }
+        }
+        taken
+    }
+
     /// Place un MARKET. Retourne l'id de l'ordre. Le reliquat non exécuté est annulé.
     pub fn place_market(
         &mut self,
@@ -407,29 +1031,31 @@ impl OrderBook {
         nonce: u64,
         out: &mut Vec<Event>,
     ) -> u64 {
-        assert!(qty > 0, "qty must be > 0");
+        assert!(!qty.is_zero(), "qty must be > 0");
         let id = self.alloc_id();
         let mut taker = Order {
             id,
             user,
             side,
             ord_type: OrdType::Market,
-            price: 0,
+            price: Price::ZERO,
             qty,
             remaining: qty,
             nonce,
+            display: Qty::ZERO,
+            hidden: Qty::ZERO,
         };
         out.push(Event::Placed {
             id,
             side,
-            price: 0,
+            price: Price::ZERO,
             qty,
             user,
         });
 
         self.match_incoming(&mut taker, out);
 
-        if taker.remaining > 0 {
+        if !taker.remaining.is_zero() {
             out.push(Event::Cancelled {
                 id,
                 user,
@@ -443,6 +1069,18 @@ impl OrderBook {
 
     /// Annule un ordre par id sur un côté donné. Retourne true si trouvé.
     pub fn cancel(&mut self, side: Side, id: u64, user: Address, out: &mut Vec<Event>) -> bool {
+        self.cancel_with_reason(side, id, user, "by_user", out)
+    }
+
+    /// Comme `cancel`, en précisant la raison publiée dans l'événement.
+    pub fn cancel_with_reason(
+        &mut self,
+        side: Side,
+        id: u64,
+        user: Address,
+        reason: &str,
+        out: &mut Vec<Event>,
+    ) -> bool {
         let ladder = match side {
             Side::Bid => &mut self.bids,
             Side::Ask => &mut self.asks,
@@ -467,7 +1105,7 @@ impl OrderBook {
             out.push(Event::Cancelled {
                 id,
                 user,
-                reason: "by_user".to_string(),
+                reason: reason.to_string(),
             });
         }
         removed
@@ -484,7 +1122,7 @@ impl OrderBook {
 
     fn consume_asks(&mut self, taker: &mut Order, out: &mut Vec<Event>) {
         loop {
-            if taker.remaining == 0 {
+            if taker.remaining.is_zero() {
                 break;
             }
             let best_ask_price = match self.asks.keys().next().copied() {
@@ -497,13 +1135,13 @@ impl OrderBook {
             }
 
             let mut queue = self.asks.remove(&best_ask_price).expect("exists");
-            while taker.remaining > 0 {
+            while !taker.remaining.is_zero() {
                 let Some(mut maker) = queue.pop_front() else {
                     break;
                 };
-                let exec_qty = taker.remaining.min(maker.remaining);
-                taker.remaining -= exec_qty;
-                maker.remaining -= exec_qty;
+                let exec_qty = taker.remaining.min(maker.visible());
+                taker.remaining = taker.remaining.saturating_sub(exec_qty);
+                maker.fill(exec_qty);
                 out.push(Event::Trade {
                     maker_id: maker.id,
                     maker_user: maker.user,
@@ -514,7 +1152,9 @@ impl OrderBook {
                     origin_side: taker.side,
                 });
 
-                if maker.remaining > 0 {
+                if maker.replenish() {
+                    queue.push_back(maker); // nouvelle tranche en fin de file
+                } else if !maker.remaining.is_zero() {
                     queue.push_front(maker); // FIFO conservé
                     break;
                 } else {
@@ -532,7 +1172,7 @@ impl OrderBook {
 
     fn consume_bids(&mut self, taker: &mut Order, out: &mut Vec<Event>) {
         loop {
-            if taker.remaining == 0 {
+            if taker.remaining.is_zero() {
                 break;
             }
             let best_bid_price = match self.bids.keys().next_back().copied() {
@@ -545,13 +1185,13 @@ impl OrderBook {
             }
 
             let mut queue = self.bids.remove(&best_bid_price).expect("exists");
-            while taker.remaining > 0 {
+            while !taker.remaining.is_zero() {
                 let Some(mut maker) = queue.pop_front() else {
                     break;
                 };
-                let exec_qty = taker.remaining.min(maker.remaining);
-                taker.remaining -= exec_qty;
-                maker.remaining -= exec_qty;
+                let exec_qty = taker.remaining.min(maker.visible());
+                taker.remaining = taker.remaining.saturating_sub(exec_qty);
+                maker.fill(exec_qty);
                 out.push(Event::Trade {
                     maker_id: maker.id,
                     maker_user: maker.user,
@@ -562,7 +1202,9 @@ impl OrderBook {
                     origin_side: taker.side,
                 });
 
-                if maker.remaining > 0 {
+                if maker.replenish() {
+                    queue.push_back(maker);
+                } else if !maker.remaining.is_zero() {
                     queue.push_front(maker);
                     break;
                 } else {
@@ -578,12 +1220,27 @@ impl OrderBook {
         }
     }
 
-    fn alloc_id(&mut self) -> u64 {
+    /// Quantité annoncée publiquement pour un ordre : le pic s'il s'agit d'un iceberg.
+    fn public_qty(qty: Qty, display: Qty) -> Qty {
+        if display.is_zero() {
+            qty
+        } else {
+            qty.min(display)
+        }
+    }
+
+    /// Alloue un id d'ordre, aussi utilisé pour les ordres stop conservés hors du carnet.
+    pub fn alloc_id(&mut self) -> u64 {
         let id = self.next_id;
         self.next_id += 1;
         id
     }
 
+    /// Tous les ordres au repos, asks puis bids.
+    pub fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.asks.values().chain(self.bids.values()).flatten()
+    }
+
     pub fn get_order(&self, id: u64) -> Option<Order> {
         for queue in self.asks.values() {
             for order in queue {
@@ -602,15 +1259,15 @@ impl OrderBook {
         None
     }
 
-    pub fn bids_and_asks(&self) -> (Vec<(Price, Qty)>, Vec<(Price, Qty)>) {
+    pub fn bids_and_asks(&self) -> (DepthLevels, DepthLevels) {
         let mut bids = Vec::new();
         for (price, levels) in self.bids.iter().rev() {
-            let total_qty: Qty = levels.iter().map(|level| level.remaining).sum();
+            let total_qty: Qty = levels.iter().map(|level| level.visible()).sum();
             bids.push((*price, total_qty));
         }
         let mut asks = Vec::new();
         for (price, levels) in self.asks.iter() {
-            let total_qty: Qty = levels.iter().map(|level| level.remaining).sum();
+            let total_qty: Qty = levels.iter().map(|level| level.visible()).sum();
             asks.push((*price, total_qty));
         }
         (bids, asks)
@@ -655,22 +1312,36 @@ mod tests {
         let mut ev = vec![];
 
         // Place deux asks : 3.50 et 3.60 (1 XTZ chacune)
-        let _a1 = ob.place_limit(uid(1), Side::Ask, 3_500_000, 1_000_000, 1, &mut ev);
-        let _a2 = ob.place_limit(uid(2), Side::Ask, 3_600_000, 1_000_000, 2, &mut ev);
+        let _a1 = ob.place_limit(
+            uid(1),
+            Side::Ask,
+            Price(3_500_000),
+            Qty(1_000_000),
+            1,
+            &mut ev,
+        );
+        let _a2 = ob.place_limit(
+            uid(2),
+            Side::Ask,
+            Price(3_600_000),
+            Qty(1_000_000),
+            2,
+            &mut ev,
+        );
 
-        assert_eq!(ob.best_ask(), Some(3_500_000));
+        assert_eq!(ob.best_ask(), Some(Price(3_500_000)));
         assert!(ob.best_bid().is_none());
 
         // Market BID 1.5 XTZ → consomme 1.0 @3.50 puis 0.5 @3.60
-        let _m = ob.place_market(uid(9), Side::Bid, 1_500_000, 3, &mut ev);
+        let _m = ob.place_market(uid(9), Side::Bid, Qty(1_500_000), 3, &mut ev);
 
         // Il doit rester 0.5 XTZ à 3.60 en ask
-        assert_eq!(ob.best_ask(), Some(3_600_000));
+        assert_eq!(ob.best_ask(), Some(Price(3_600_000)));
 
         // Cancel de l'ask restant
         // (dans une vraie intégration, on garderait l'id ; ici on le retrouve depuis le niveau)
         let mut cancelled = false;
-        if let Some(q) = ob.asks.get(&3_600_000) {
+        if let Some(q) = ob.asks.get(&Price(3_600_000)) {
             let id = q.front().unwrap().id;
             let user = q.front().unwrap().user;
             cancelled = ob.cancel(Side::Ask, id, user, &mut ev);
@@ -688,14 +1359,28 @@ mod tests {
         let mut ev = vec![];
 
         // Best bid à 3.40
-        let _b1 = ob.place_limit(uid(1), Side::Bid, 3_400_000, 1_000_000, 1, &mut ev);
+        let _b1 = ob.place_limit(
+            uid(1),
+            Side::Bid,
+            Price(3_400_000),
+            Qty(1_000_000),
+            1,
+            &mut ev,
+        );
         // On poste un ask limit "crossé" à 3.30 → doit s'exécuter immédiatement à 3.40 (maker price)
-        let _a = ob.place_limit(uid(2), Side::Ask, 3_300_000, 700_000, 2, &mut ev);
+        let _a = ob.place_limit(
+            uid(2),
+            Side::Ask,
+            Price(3_300_000),
+            Qty(700_000),
+            2,
+            &mut ev,
+        );
 
         // Best bid a diminué à 3.40 avec 0.3 XTZ restant
-        assert_eq!(ob.best_bid(), Some(3_400_000));
-        let q = ob.bids.get(&3_400_000).unwrap();
-        assert_eq!(q.front().unwrap().remaining, 300_000);
+        assert_eq!(ob.best_bid(), Some(Price(3_400_000)));
+        let q = ob.bids.get(&Price(3_400_000)).unwrap();
+        assert_eq!(q.front().unwrap().remaining, Qty(300_000));
 
         let bytes = {
             let mut stream = rlp::RlpStream::new();
@@ -706,14 +1391,220 @@ mod tests {
         compare_orderbooks(&ob, &ob2);
     }
 
+    #[test]
+    fn iceberg_replenishes_at_back_of_queue() {
+        let mut ob = OrderBook::new();
+        let mut ev = vec![];
+
+        let iceberg = ob.place_iceberg(
+            uid(1),
+            Side::Ask,
+            Price(3_500_000),
+            Qty(3_000_000),
+            Qty(1_000_000),
+            1,
+            &mut ev,
+        );
+        // Seul le pic est annoncé
+        assert_eq!(
+            ev[0],
+            Event::Placed {
+                user: uid(1),
+                id: iceberg,
+                side: Side::Ask,
+                price: Price(3_500_000),
+                qty: Qty(1_000_000),
+            }
+        );
+        let plain = ob.place_limit(
+            uid(2),
+            Side::Ask,
+            Price(3_500_000),
+            Qty(1_000_000),
+            1,
+            &mut ev,
+        );
+        assert_eq!(
+            ob.bids_and_asks().1,
+            vec![(Price(3_500_000), Qty(2_000_000))]
+        );
+        assert_eq!(ob.get_order(iceberg).unwrap().remaining, Qty(3_000_000));
+
+        // Le pic est consommé puis rechargé derrière l'ordre classique
+        ev.clear();
+        let taker = ob.place_limit(
+            uid(3),
+            Side::Bid,
+            Price(3_500_000),
+            Qty(1_500_000),
+            1,
+            &mut ev,
+        );
+        let trades: Vec<_> = ev
+            .iter()
+            .filter_map(|e| match e {
+                Event::Trade { maker_id, qty, .. } => Some((*maker_id, *qty)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            trades,
+            vec![(iceberg, Qty(1_000_000)), (plain, Qty(500_000))]
+        );
+        assert!(ev.contains(&Event::Done {
+            user: uid(3),
+            id: taker
+        }));
+
+        let queue = ob.asks.get(&Price(3_500_000)).unwrap();
+        assert_eq!(
+            queue.iter().map(|o| o.id).collect::<Vec<_>>(),
+            vec![plain, iceberg]
+        );
+        let order = ob.get_order(iceberg).unwrap();
+        assert_eq!(order.remaining, Qty(2_000_000));
+        assert_eq!(order.visible(), Qty(1_000_000));
+        assert_eq!(
+            ob.bids_and_asks().1,
+            vec![(Price(3_500_000), Qty(1_500_000))]
+        );
+        assert_eq!(ob.price_quantity_at(Price(3_500_000)), Qty(1_500_000));
+    }
+
+    #[test]
+    fn auction_uncrossing() {
+        let mut ob = OrderBook::new();
+        let mut ev = vec![];
+
+        // Aucun appariement pendant l'enchère
+        let b1 = ob.place_auction_limit(
+            uid(1),
+            Side::Bid,
+            Price(3_600_000),
+            Qty(1_000_000),
+            Qty::ZERO,
+            1,
+            &mut ev,
+        );
+        let a1 = ob.place_auction_limit(
+            uid(2),
+            Side::Ask,
+            Price(3_400_000),
+            Qty(1_500_000),
+            Qty::ZERO,
+            1,
+            &mut ev,
+        );
+        let b2 = ob.place_auction_limit(
+            uid(3),
+            Side::Bid,
+            Price(3_500_000),
+            Qty(1_000_000),
+            Qty::ZERO,
+            1,
+            &mut ev,
+        );
+        let _a2 = ob.place_auction_limit(
+            uid(4),
+            Side::Ask,
+            Price(3_700_000),
+            Qty(1_000_000),
+            Qty::ZERO,
+            1,
+            &mut ev,
+        );
+        assert!(ev.iter().all(|e| matches!(e, Event::Placed { .. })));
+        assert_eq!(ob.best_bid(), Some(Price(3_600_000)));
+        assert_eq!(ob.best_ask(), Some(Price(3_400_000)));
+
+        // 3.40 : 2 demandés / 1.5 offerts, 3.50 : 2 / 1.5, 3.60 : 1 / 1.5
+        // Même volume et même déséquilibre à 3.40 et 3.50, la demande domine : 3.50
+        assert_eq!(
+            ob.clearing_price(None),
+            Some((Price(3_500_000), Qty(1_500_000)))
+        );
+
+        ev.clear();
+        assert_eq!(
+            ob.uncross(None, &mut ev),
+            Some((Price(3_500_000), Qty(1_500_000)))
+        );
+        let trades: Vec<_> = ev
+            .iter()
+            .filter_map(|e| match e {
+                Event::Trade {
+                    maker_id,
+                    taker_id,
+                    price,
+                    qty,
+                    ..
+                } => Some((*maker_id, *taker_id, *price, *qty)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            trades,
+            vec![
+                (b1, a1, Price(3_500_000), Qty(1_000_000)),
+                (a1, b2, Price(3_500_000), Qty(500_000)),
+            ]
+        );
+        assert!(ev.contains(&Event::Done {
+            user: uid(1),
+            id: b1
+        }));
+        assert!(ev.contains(&Event::Done {
+            user: uid(2),
+            id: a1
+        }));
+
+        // Le reliquat de b2 reste au carnet, qui ne se croise plus
+        assert_eq!(ob.get_order(b2).unwrap().remaining, Qty(500_000));
+        assert_eq!(ob.best_bid(), Some(Price(3_500_000)));
+        assert_eq!(ob.best_ask(), Some(Price(3_700_000)));
+        assert_eq!(ob.clearing_price(None), None);
+    }
+
+    #[test]
+    fn clearing_price_uses_reference_on_balanced_book() {
+        let mut ob = OrderBook::new();
+        let mut ev = vec![];
+        ob.place_auction_limit(
+            uid(1),
+            Side::Bid,
+            Price(3_600_000),
+            Qty(1_000_000),
+            Qty::ZERO,
+            1,
+            &mut ev,
+        );
+        ob.place_auction_limit(
+            uid(2),
+            Side::Ask,
+            Price(3_400_000),
+            Qty(1_000_000),
+            Qty::ZERO,
+            1,
+            &mut ev,
+        );
+        assert_eq!(
+            ob.clearing_price(None),
+            Some((Price(3_400_000), Qty(1_000_000)))
+        );
+        assert_eq!(
+            ob.clearing_price(Some(Price(3_550_000))),
+            Some((Price(3_600_000), Qty(1_000_000)))
+        );
+    }
+
     #[test]
     fn event_serialization() {
         let events = vec![
             Event::Placed {
                 id: 1,
                 side: Side::Bid,
-                price: 3_500_000,
-                qty: 1_000_000,
+                price: Price(3_500_000),
+                qty: Qty(1_000_000),
                 user: Address::ZERO,
             },
             Event::Trade {
@@ -721,8 +1612,8 @@ mod tests {
                 maker_user: uid(1),
                 taker_id: 2,
                 taker_user: uid(2),
-                price: 3_500_000,
-                qty: 500_000,
+                price: Price(3_500_000),
+                qty: Qty(500_000),
                 origin_side: Side::Bid,
             },
             Event::Done {
@@ -734,6 +1625,65 @@ mod tests {
                 user: Address::ZERO,
                 reason: "by_user".to_string(),
             },
+            Event::Rejected {
+                user: uid(3),
+                nonce: 7,
+                reason: "invalid_tick_size".to_string(),
+            },
+            Event::MarketHalted {
+                reference_price: Price(3_000_000),
+                last_price: Price(3_600_000),
+                until_level: 12,
+            },
+            Event::MarketResumed { level: 12 },
+            Event::AuctionStarted { until_level: 15 },
+            Event::AuctionUncrossed {
+                price: Price(3_500_000),
+                qty: Qty(2_000_000),
+            },
+            Event::StopTriggered {
+                id: 4,
+                user: uid(2),
+                price: Price(3_400_000),
+            },
+            Event::StateCommitment {
+                level: 16,
+                root: Hash::leaf(b"state"),
+            },
+            Event::Funding {
+                level: 17,
+                rate_ppm: -120,
+                mark_price: Price(3_490_000),
+                index_price: Price(3_500_000),
+            },
+            Event::Liquidated {
+                user: uid(3),
+                mark_price: Price(3_200_000),
+                fee: 40_000,
+                bad_debt: 0,
+            },
+            Event::IndexPrice {
+                level: 12,
+                price: Price(3_480_000),
+                sources: 3,
+            },
+            Event::DelegateUpdated {
+                owner: uid(1),
+                key: uid(2),
+                expiry_level: 500,
+                revoked: false,
+            },
+            Event::Transfer {
+                from: uid(1),
+                to: uid(2),
+                currency: Currencies::XTZ,
+                amount: 1_500_000,
+            },
+            Event::TransferRejected {
+                from: uid(1),
+                nonce: 7,
+                reason: "insufficient_balance".to_string(),
+            },
         ];
 
         for event in events {
@@ -0,0 +1,283 @@
+use rlp::{Decodable, Encodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::RefPath;
+
+use crate::{
+    error::TradezError,
+    position::Side,
+    units::{Price, Qty, Rounding},
+};
+
+pub const PERP_STATUS_STR_PATH: &str = "/tradez/perp_status";
+pub const PERP_STATUS_PATH: RefPath = RefPath::assert_from(b"/tradez/perp_status");
+
+/// One ppm is a millionth.
+pub const PPM_DENOMINATOR: i128 = 1_000_000;
+
+/// Position of an account in the perpetual, settled in USDC.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PerpPosition {
+    /// Signed size in microXTZ, positive for a long and negative for a short.
+    pub size: i64,
+    /// Average price the open size was entered at.
+    pub entry_price: Price,
+}
+
+// Signed values are written as their two's complement, RLP only knows unsigned integers.
+impl Encodable for PerpPosition {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(2);
+        s.append(&(self.size as u64));
+        s.append(&self.entry_price);
+    }
+}
+
+impl Decodable for PerpPosition {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let size: u64 = rlp.val_at(0)?;
+        Ok(PerpPosition {
+            size: size as i64,
+            entry_price: rlp.val_at(1)?,
+        })
+    }
+}
+
+/// Divides by a million, rounding towards minus infinity: what an account receives is rounded
+/// down and what it pays is rounded up.
+fn floor_ppm(value: i128) -> i128 {
+    value.div_euclid(PPM_DENOMINATOR)
+}
+
+fn saturate(value: i128) -> i64 {
+    value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+impl PerpPosition {
+    pub fn is_flat(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn abs_size(&self) -> Qty {
+        Qty(self.size.unsigned_abs())
+    }
+
+    /// Notional value of the position at `mark_price`.
+    pub fn notional(&self, mark_price: Price) -> Option<u64> {
+        mark_price.checked_notional(self.abs_size(), Rounding::Up)
+    }
+
+    /// Profit, or loss when negative, the position would realize at `mark_price`.
+    pub fn unrealized_pnl(&self, mark_price: Price) -> i64 {
+        let gap = mark_price.raw() as i128 - self.entry_price.raw() as i128;
+        saturate(floor_ppm(gap * self.size as i128))
+    }
+
+    /// Applies a fill of `qty` at `price`, bought for a bid and sold for an ask. Opening or
+    /// adding to the position moves its average entry price, reducing it realizes the profit
+    /// or loss on the closed size. Returns the realized profit or loss in microUSDC.
+    pub fn fill(&mut self, side: Side, qty: Qty, price: Price) -> Option<i64> {
+        let delta = match side {
+            Side::Bid => i64::try_from(qty.raw()).ok()?,
+            Side::Ask => -i64::try_from(qty.raw()).ok()?,
+        };
+        let new_size = self.size.checked_add(delta)?;
+        if self.size == 0 || self.size.signum() == delta.signum() {
+            let (held, added) = (self.size.unsigned_abs() as u128, qty.raw() as u128);
+            let cost = held * self.entry_price.raw() as u128 + added * price.raw() as u128;
+            // Rounded against the account: up for a long, down for a short
+            let entry = match side {
+                Side::Bid => cost.div_ceil(held + added),
+                Side::Ask => cost / (held + added),
+            };
+            self.entry_price = Price(u64::try_from(entry).ok()?);
+            self.size = new_size;
+            return Some(0);
+        }
+
+        let closed = self.size.unsigned_abs().min(qty.raw()) as i128;
+        let gap = price.raw() as i128 - self.entry_price.raw() as i128;
+        let realized = floor_ppm(gap * closed * self.size.signum() as i128);
+        self.size = new_size;
+        if self.size == 0 {
+            self.entry_price = Price::ZERO;
+        } else if self.size.signum() == delta.signum() {
+            // Flipped: what is left of the fill opens the other way
+            self.entry_price = price;
+        }
+        i64::try_from(realized).ok()
+    }
+
+    /// What the position pays for a funding period at `rate_ppm` of its notional, longs paying
+    /// shorts when the rate is positive. Negative when the position receives.
+    pub fn funding_payment(&self, mark_price: Price, rate_ppm: i64) -> i64 {
+        let rate = rate_ppm.unsigned_abs() as u128;
+        // Rounded against the account: a payment is rounded up, a receipt down
+        if self.size.signum() * rate_ppm.signum() > 0 {
+            let notional = mark_price
+                .checked_notional(self.abs_size(), Rounding::Up)
+                .unwrap_or(u64::MAX) as u128;
+            saturate((notional * rate).div_ceil(PPM_DENOMINATOR as u128) as i128)
+        } else {
+            let notional = mark_price
+                .checked_notional(self.abs_size(), Rounding::Down)
+                .unwrap_or(u64::MAX) as u128;
+            -saturate((notional * rate / PPM_DENOMINATOR as u128) as i128)
+        }
+    }
+}
+
+/// Funding state of the perpetual.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PerpStatus {
+    /// Price of the underlying the perpetual tracks, zero until one is known.
+    pub index_price: Price,
+    /// Level the last funding period started at.
+    pub funding_level: u32,
+    /// Rate of the last funding period, in ppm of the position notional.
+    pub funding_rate_ppm: i64,
+}
+
+impl Encodable for PerpStatus {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(3);
+        s.append(&self.index_price);
+        s.append(&self.funding_level);
+        s.append(&(self.funding_rate_ppm as u64));
+    }
+}
+
+impl Decodable for PerpStatus {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let funding_rate_ppm: u64 = rlp.val_at(2)?;
+        Ok(PerpStatus {
+            index_price: rlp.val_at(0)?,
+            funding_level: rlp.val_at(1)?,
+            funding_rate_ppm: funding_rate_ppm as i64,
+        })
+    }
+}
+
+impl PerpStatus {
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Self, TradezError> {
+        match host.store_read_all(&PERP_STATUS_PATH) {
+            Ok(data) => PerpStatus::decode(&rlp::Rlp::new(&data))
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(PerpStatus::default()),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&PERP_STATUS_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+
+    /// Funding rate for the gap between the mark and the index price, capped at
+    /// `max_rate_ppm` either way. None while the index price is unknown.
+    pub fn funding_rate(&self, mark_price: Price, max_rate_ppm: u64) -> Option<i64> {
+        if self.index_price.is_zero() {
+            return None;
+        }
+        let gap = mark_price.raw() as i128 - self.index_price.raw() as i128;
+        let rate = gap * PPM_DENOMINATOR / self.index_price.raw() as i128;
+        let max_rate = max_rate_ppm.min(i64::MAX as u64) as i128;
+        Some(rate.clamp(-max_rate, max_rate) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_fills() {
+        let mut position = PerpPosition::default();
+        assert_eq!(
+            position.fill(Side::Bid, Qty(1_000_000), Price(2_000_000)),
+            Some(0)
+        );
+        assert_eq!(
+            position.fill(Side::Bid, Qty(3_000_000), Price(2_400_000)),
+            Some(0)
+        );
+        assert_eq!(position.size, 4_000_000);
+        assert_eq!(position.entry_price, Price(2_300_000));
+        assert_eq!(position.unrealized_pnl(Price(2_500_000)), 800_000);
+
+        // Closes 1 XTZ with a 0.2 USDC profit
+        assert_eq!(
+            position.fill(Side::Ask, Qty(1_000_000), Price(2_500_000)),
+            Some(200_000)
+        );
+        // Closes the remaining 3 XTZ at a loss, then opens a 2 XTZ short
+        assert_eq!(
+            position.fill(Side::Ask, Qty(5_000_000), Price(2_100_000)),
+            Some(-600_000)
+        );
+        assert_eq!(position.size, -2_000_000);
+        assert_eq!(position.entry_price, Price(2_100_000));
+        assert_eq!(position.unrealized_pnl(Price(2_000_000)), 200_000);
+
+        assert_eq!(
+            position.fill(Side::Bid, Qty(2_000_000), Price(2_000_000)),
+            Some(200_000)
+        );
+        assert!(position.is_flat());
+        assert_eq!(position.entry_price, Price::ZERO);
+    }
+
+    #[test]
+    fn losses_round_up() {
+        let mut position = PerpPosition {
+            size: 1,
+            entry_price: Price(2_000_000),
+        };
+        assert_eq!(position.unrealized_pnl(Price(1_999_999)), -1);
+        assert_eq!(position.fill(Side::Ask, Qty(1), Price(1_999_999)), Some(-1));
+    }
+
+    #[test]
+    fn funding() {
+        let mut status = PerpStatus::default();
+        assert_eq!(status.funding_rate(Price(2_000_000), 1_000), None);
+        status.index_price = Price(2_000_000);
+        assert_eq!(status.funding_rate(Price(2_001_000), 1_000), Some(500));
+        assert_eq!(status.funding_rate(Price(1_000_000), 1_000), Some(-1_000));
+
+        let long = PerpPosition {
+            size: 10_000_000,
+            entry_price: Price(2_000_000),
+        };
+        let short = PerpPosition {
+            size: -10_000_000,
+            ..long
+        };
+        // 20 USDC of notional at 0.05%
+        assert_eq!(long.funding_payment(Price(2_000_000), 500), 10_000);
+        assert_eq!(short.funding_payment(Price(2_000_000), 500), -10_000);
+        // 3 microUSDC at 0.05%: the long pays a rounded up 1, the short receives nothing
+        let long = PerpPosition { size: 1, ..long };
+        let short = PerpPosition { size: -1, ..short };
+        assert_eq!(long.funding_payment(Price(3_000_000), 500), 1);
+        assert_eq!(short.funding_payment(Price(3_000_000), 500), 0);
+    }
+
+    #[test]
+    fn perp_rlp() {
+        let position = PerpPosition {
+            size: -3_000_000,
+            entry_price: Price(2_100_000),
+        };
+        let decoded: PerpPosition = rlp::decode(&position.rlp_bytes()).unwrap();
+        assert_eq!(position, decoded);
+
+        let status = PerpStatus {
+            index_price: Price(2_000_000),
+            funding_level: 12,
+            funding_rate_ppm: -250,
+        };
+        let decoded: PerpStatus = rlp::decode(&status.rlp_bytes()).unwrap();
+        assert_eq!(status, decoded);
+    }
+}
@@ -3,19 +3,54 @@ use std::fmt::Display;
 use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
 use serde::{Deserialize, Serialize};
 
-use crate::{address::Address, currencies::Currencies};
+use crate::{
+    address::Address,
+    currencies::Currencies,
+    units::{Price, Qty},
+};
 
-pub type Price = u64; // microUSDC par XTZ (1e6)
-pub type Qty = u64; // microXTZ (1e6)
-
-#[derive(
-    Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, Default, PartialEq, Eq, Clone, Copy,
-)]
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
 pub struct APIOrder {
     pub side: Side,
     pub size: Qty,
     pub price: Price,
+    /// Must be above the nonce of the account, which it becomes, like the one of a transfer.
     pub nonce: u64,
+    /// Peak shown in the book for an iceberg order, zero for a plain limit order.
+    #[serde(default)]
+    pub display: Qty,
+}
+
+// Plain orders keep the 4 fields encoding so signatures made before icebergs stay valid.
+impl Encodable for APIOrder {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        let iceberg = !self.display.is_zero();
+        s.begin_list(if iceberg { 5 } else { 4 });
+        s.append(&self.side);
+        s.append(&self.size);
+        s.append(&self.price);
+        s.append(&self.nonce);
+        if iceberg {
+            s.append(&self.display);
+        }
+    }
+}
+
+impl Decodable for APIOrder {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let display = match rlp.item_count()? {
+            4 => Qty::ZERO,
+            5 => rlp.val_at(4)?,
+            _ => return Err(rlp::DecoderError::RlpIncorrectListLen),
+        };
+        Ok(APIOrder {
+            side: rlp.val_at(0)?,
+            size: rlp.val_at(1)?,
+            price: rlp.val_at(2)?,
+            nonce: rlp.val_at(3)?,
+            display,
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq)]
@@ -25,11 +60,22 @@ pub struct CancelOrder {
 
 #[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq)]
 pub struct Faucet {
-    pub amount: Qty,
+    pub amount: u64,
     pub currency: Currencies,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, RlpDecodable, RlpEncodable)]
+/// Moves available balance to another account.
+#[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, PartialEq, Eq, Clone, Copy)]
+pub struct Transfer {
+    pub to: Address,
+    pub currency: Currencies,
+    pub amount: u64,
+    /// Must be above the nonce of the sending account, which it becomes, so that a transfer
+    /// can't be replayed.
+    pub nonce: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, RlpEncodable)]
 pub struct Order {
     pub id: u64,
     pub user: Address,
@@ -39,16 +85,93 @@ pub struct Order {
     pub qty: Qty,       // quantité initiale
     pub remaining: Qty, // quantité restante
     pub nonce: u64,
+    pub display: Qty, // pic affiché d'un iceberg, zéro sinon
+    pub hidden: Qty,  // part de `remaining` cachée du carnet
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, RlpDecodable, RlpEncodable)]
+// Orders stored before icebergs have 8 fields, they show all of their quantity.
+impl Decodable for Order {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let (display, hidden) = match rlp.item_count()? {
+            8 => (Qty::ZERO, Qty::ZERO),
+            10 => (rlp.val_at(8)?, rlp.val_at(9)?),
+            _ => return Err(rlp::DecoderError::RlpIncorrectListLen),
+        };
+        Ok(Order {
+            id: rlp.val_at(0)?,
+            user: rlp.val_at(1)?,
+            side: rlp.val_at(2)?,
+            ord_type: rlp.val_at(3)?,
+            price: rlp.val_at(4)?,
+            qty: rlp.val_at(5)?,
+            remaining: rlp.val_at(6)?,
+            nonce: rlp.val_at(7)?,
+            display,
+            hidden,
+        })
+    }
+}
+
+impl Order {
+    /// Quantité visible dans le carnet public.
+    pub fn visible(&self) -> Qty {
+        self.remaining.saturating_sub(self.hidden)
+    }
+
+    /// Exécute `qty` sur l'ordre, en consommant d'abord la partie visible.
+    pub fn fill(&mut self, qty: Qty) {
+        let from_hidden = qty.saturating_sub(self.visible());
+        self.hidden = self.hidden.saturating_sub(from_hidden);
+        self.remaining = self.remaining.saturating_sub(qty);
+    }
+
+    /// Cache tout ce qui dépasse le pic d'un iceberg.
+    pub fn hide_reserve(&mut self) {
+        if !self.display.is_zero() {
+            self.hidden = self.remaining.saturating_sub(self.display);
+        }
+    }
+
+    /// Expose une nouvelle tranche quand le pic d'un iceberg est épuisé.
+    /// Retourne true si l'ordre a été rechargé (il doit alors repasser en fin de file).
+    pub fn replenish(&mut self) -> bool {
+        if !self.visible().is_zero() || self.hidden.is_zero() {
+            return false;
+        }
+        self.hide_reserve();
+        true
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, RlpEncodable)]
 pub struct UserOrder {
     pub side: Side,
     pub ord_type: OrdType,
     pub price: Price,   // ignoré si Market
     pub qty: Qty,       // quantité initiale
-    pub remaining: Qty, // quantité restante
+    pub remaining: Qty, // quantité restante, partie cachée comprise
     pub nonce: u64,
+    pub display: Qty, // pic affiché d'un iceberg, zéro sinon
+}
+
+// User orders stored before icebergs have 6 fields.
+impl Decodable for UserOrder {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let display = match rlp.item_count()? {
+            6 => Qty::ZERO,
+            7 => rlp.val_at(6)?,
+            _ => return Err(rlp::DecoderError::RlpIncorrectListLen),
+        };
+        Ok(UserOrder {
+            side: rlp.val_at(0)?,
+            ord_type: rlp.val_at(1)?,
+            price: rlp.val_at(2)?,
+            qty: rlp.val_at(3)?,
+            remaining: rlp.val_at(4)?,
+            nonce: rlp.val_at(5)?,
+            display,
+        })
+    }
 }
 
 impl From<APIOrder> for UserOrder {
@@ -60,6 +183,7 @@ impl From<APIOrder> for UserOrder {
             qty: api_order.size,
             remaining: api_order.size,
             nonce: api_order.nonce,
+            display: api_order.display,
         }
     }
 }
@@ -104,6 +228,7 @@ impl Decodable for Side {
 pub enum OrdType {
     Limit,
     Market,
+    Stop, // en attente de son déclenchement, hors carnet
 }
 
 impl Encodable for OrdType {
@@ -111,6 +236,7 @@ impl Encodable for OrdType {
         match self {
             OrdType::Limit => s.append_internal(&0u8),
             OrdType::Market => s.append_internal(&1u8),
+            OrdType::Stop => s.append_internal(&2u8),
         };
     }
 }
@@ -121,7 +247,62 @@ impl Decodable for OrdType {
         match value {
             0 => Ok(OrdType::Limit),
             1 => Ok(OrdType::Market),
+            2 => Ok(OrdType::Stop),
             _ => Err(rlp::DecoderError::Custom("Invalid OrdType value")),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_orders_stored_before_icebergs() {
+        let order = Order {
+            id: 3,
+            user: Address::from([1; 20]),
+            side: Side::Ask,
+            ord_type: OrdType::Limit,
+            price: Price(2_000_000),
+            qty: Qty(5_000_000),
+            remaining: Qty(4_000_000),
+            nonce: 7,
+            display: Qty::ZERO,
+            hidden: Qty::ZERO,
+        };
+        let mut s = rlp::RlpStream::new_list(8);
+        s.append(&order.id)
+            .append(&order.user)
+            .append(&order.side)
+            .append(&order.ord_type)
+            .append(&order.price)
+            .append(&order.qty)
+            .append(&order.remaining)
+            .append(&order.nonce);
+        assert_eq!(rlp::decode::<Order>(&s.out()), Ok(order.clone()));
+        assert_eq!(rlp::decode::<Order>(&order.rlp_bytes()), Ok(order.clone()));
+
+        let user_order = UserOrder {
+            side: order.side,
+            ord_type: order.ord_type,
+            price: order.price,
+            qty: order.qty,
+            remaining: order.remaining,
+            nonce: order.nonce,
+            display: Qty::ZERO,
+        };
+        let mut s = rlp::RlpStream::new_list(6);
+        s.append(&user_order.side)
+            .append(&user_order.ord_type)
+            .append(&user_order.price)
+            .append(&user_order.qty)
+            .append(&user_order.remaining)
+            .append(&user_order.nonce);
+        assert_eq!(rlp::decode::<UserOrder>(&s.out()), Ok(user_order.clone()));
+        assert_eq!(
+            rlp::decode::<UserOrder>(&user_order.rlp_bytes()),
+            Ok(user_order)
+        );
+    }
+}
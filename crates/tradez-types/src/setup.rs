@@ -0,0 +1,122 @@
+use rlp::{Decodable, Encodable};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::{OwnedPath, RefPath};
+
+use crate::{
+    address::Address,
+    error::TradezError,
+    market::{MARKET_CONFIG_STR_PATH, MarketConfig},
+    oracle::{ORACLE_CONFIG_STR_PATH, OracleConfig},
+};
+
+pub const SEQUENCER_STR_PATH: &str = "/tradez/sequencer";
+const SEQUENCER_PATH: RefPath = RefPath::assert_from(b"/tradez/sequencer");
+
+/// Configuration of a rollup, written to the durable storage by the installer when the
+/// rollup is originated, before the kernel reads any input. No input changes it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RollupSetup {
+    /// Rules of the one market of the rollup, spot or perpetual.
+    pub market_config: MarketConfig,
+    pub oracle_config: OracleConfig,
+    /// Key the sequencer signs the level of its inputs with. Without it the kernel dates
+    /// every input with the level of the inbox it came in.
+    pub sequencer: Option<Address>,
+}
+
+impl RollupSetup {
+    /// Paths the setup writes, that the sequencer copies from the rollup.
+    pub const PATHS: [&str; 3] = [
+        MARKET_CONFIG_STR_PATH,
+        ORACLE_CONFIG_STR_PATH,
+        SEQUENCER_STR_PATH,
+    ];
+
+    /// Values the setup writes, by path.
+    pub fn values(&self) -> Vec<(&'static str, Vec<u8>)> {
+        let mut values = vec![
+            (
+                MARKET_CONFIG_STR_PATH,
+                self.market_config.rlp_bytes().to_vec(),
+            ),
+            (
+                ORACLE_CONFIG_STR_PATH,
+                self.oracle_config.rlp_bytes().to_vec(),
+            ),
+        ];
+        if let Some(sequencer) = &self.sequencer {
+            values.push((SEQUENCER_STR_PATH, sequencer.rlp_bytes().to_vec()));
+        }
+        values
+    }
+
+    /// Setup file of the smart rollup installer writing the values.
+    pub fn installer_config(&self) -> String {
+        let mut config = String::from("instructions:\n");
+        for (path, value) in self.values() {
+            config.push_str(&format!(
+                "  - set:\n      value: {}\n      to: {}\n",
+                alloy_primitives::hex::encode(value),
+                path
+            ));
+        }
+        config
+    }
+
+    /// Writes the values with the host, as the installer does.
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        for (path, value) in self.values() {
+            let path = OwnedPath::try_from(path.to_string())
+                .map_err(|e| TradezError::DataStoreError(format!("{:?}", e)))?;
+            host.store_write_all(&path, &value)
+                .map_err(|e| TradezError::DataStoreError(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Key of the sequencer, if the setup has one.
+    pub fn load_sequencer<Host: Runtime>(host: &mut Host) -> Result<Option<Address>, TradezError> {
+        match host.store_read_all(&SEQUENCER_PATH) {
+            Ok(data) => Address::decode(&rlp::Rlp::new(&data))
+                .map(Some)
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(None),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market::MarketKind;
+
+    #[test]
+    fn installer_config() {
+        let setup = RollupSetup {
+            market_config: MarketConfig {
+                kind: MarketKind::Perpetual,
+                ..MarketConfig::default()
+            },
+            oracle_config: OracleConfig {
+                oracles: vec![Address::from([1; 20])],
+                min_sources: 1,
+                max_age: 10,
+            },
+            sequencer: Some(Address::from([2; 20])),
+        };
+        let market = alloy_primitives::hex::encode(setup.market_config.rlp_bytes());
+        let oracle = alloy_primitives::hex::encode(setup.oracle_config.rlp_bytes());
+        let sequencer = alloy_primitives::hex::encode(Address::from([2; 20]).rlp_bytes());
+        assert_eq!(
+            setup.installer_config(),
+            format!(
+                "instructions:\n\
+                 \x20 - set:\n      value: {}\n      to: /tradez/market_config\n\
+                 \x20 - set:\n      value: {}\n      to: /tradez/oracle_config\n\
+                 \x20 - set:\n      value: {}\n      to: /tradez/sequencer\n",
+                market, oracle, sequencer
+            )
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Progress of the sequencer towards the rollup and result of the last comparisons of their
+/// states.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SequencerStatus {
+    /// Inputs the sequencer ran.
+    pub input_count: u64,
+    /// Inputs waiting to be handed to the rollup node.
+    pub pending_inputs: u64,
+    /// Inputs the rollup node accepted that aren't in the rollup inbox yet.
+    pub injected_inputs: u64,
+    /// Injected inputs reported as stuck.
+    pub stuck_inputs: u64,
+    /// Level of the last state commitment compared with the one of the rollup.
+    pub last_reconciled_level: Option<u32>,
+    /// Comparisons where the states matched.
+    pub reconciliations: u64,
+    /// Comparisons where they didn't.
+    pub divergences: u64,
+    /// Level of the last commitment where they didn't.
+    pub diverged_level: Option<u32>,
+    /// Commitments of the rollup that couldn't be compared, the sequencer not having committed
+    /// their level yet or having pruned it.
+    pub skipped_reconciliations: u64,
+}
+
+impl SequencerStatus {
+    pub fn is_diverged(&self) -> bool {
+        self.diverged_level.is_some()
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+
+use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::RefPath;
+
+use crate::{
+    address::Address,
+    error::TradezError,
+    position::{APIOrder, Side},
+    units::{Price, Qty},
+};
+
+pub const STOP_BOOK_STR_PATH: &str = "/tradez/stop_book";
+pub const STOP_BOOK_PATH: RefPath = RefPath::assert_from(b"/tradez/stop_book");
+
+/// Stop order as signed by the user.
+#[derive(
+    Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, Default, PartialEq, Eq, Clone, Copy,
+)]
+pub struct APIStopOrder {
+    pub side: Side,
+    pub size: Qty,
+    /// A limit order is placed once a trade happens at or beyond this price.
+    pub trigger_price: Price,
+    /// Distance kept between the trigger and the best price since placement, zero for a fixed stop.
+    pub trail: Price,
+    /// How much worse than the trigger the limit order placed on trigger is priced.
+    pub slippage: Price,
+    /// Must be above the nonce of the account, which it becomes. Unused on an OCO stop leg.
+    pub nonce: u64,
+}
+
+/// Bracket order: a take-profit limit order and a stop-loss, filling one cancels the other.
+#[derive(
+    Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable, Default, PartialEq, Eq, Clone, Copy,
+)]
+pub struct APIOcoOrder {
+    pub limit: APIOrder,
+    pub stop: APIStopOrder,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct StopOrder {
+    pub id: u64,
+    pub user: Address,
+    pub side: Side,
+    pub size: Qty,
+    pub trigger_price: Price,
+    pub trail: Price,
+    pub slippage: Price,
+    pub nonce: u64,
+}
+
+impl StopOrder {
+    pub fn new(id: u64, user: Address, order: &APIStopOrder) -> Self {
+        StopOrder {
+            id,
+            user,
+            side: order.side,
+            size: order.size,
+            trigger_price: order.trigger_price,
+            trail: order.trail,
+            slippage: order.slippage,
+            nonce: order.nonce,
+        }
+    }
+
+    /// A sell stop triggers when the price falls to its trigger, a buy stop when it rises to it.
+    pub fn is_triggered(&self, price: Price) -> bool {
+        match self.side {
+            Side::Ask => price <= self.trigger_price,
+            Side::Bid => price >= self.trigger_price,
+        }
+    }
+
+    /// Moves the trigger of a trailing stop along with a favorable price, never back.
+    pub fn follow(&mut self, price: Price) {
+        if self.trail.is_zero() {
+            return;
+        }
+        match self.side {
+            Side::Ask => {
+                let trigger = price.saturating_sub(self.trail);
+                if trigger > self.trigger_price {
+                    self.trigger_price = trigger;
+                }
+            }
+            Side::Bid => {
+                if let Some(trigger) = price.checked_add(self.trail)
+                    && trigger < self.trigger_price
+                {
+                    self.trigger_price = trigger;
+                }
+            }
+        }
+    }
+
+    /// Price of the limit order placed on trigger.
+    pub fn limit_price(&self) -> Price {
+        match self.side {
+            Side::Ask => self.trigger_price.saturating_sub(self.slippage),
+            Side::Bid => Price(self.trigger_price.raw().saturating_add(self.slippage.raw())),
+        }
+    }
+
+    /// Limit order placed when the stop triggers.
+    pub fn to_limit_order(&self, price: Price) -> APIOrder {
+        APIOrder {
+            side: self.side,
+            size: self.size,
+            price,
+            nonce: self.nonce,
+            display: Qty::ZERO,
+        }
+    }
+}
+
+/// Stop orders waiting for their trigger and the one-cancels-other links between orders.
+///
+/// Stops take their ids from the order book so a single id space covers both, and
+/// `CancelOrder` works the same for resting and stop orders.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct StopBook {
+    pub stops: BTreeMap<u64, StopOrder>,
+    /// OCO links, stored in both directions (order or stop id -> sibling id).
+    pub oco: BTreeMap<u64, u64>,
+}
+
+impl Encodable for StopBook {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(2);
+        s.begin_list(self.stops.len());
+        for stop in self.stops.values() {
+            s.append(stop);
+        }
+        s.begin_list(self.oco.len());
+        for (id, sibling) in &self.oco {
+            s.begin_list(2);
+            s.append(id);
+            s.append(sibling);
+        }
+    }
+}
+
+impl Decodable for StopBook {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        let mut stop_book = StopBook::default();
+        for stop_rlp in rlp.at(0)?.iter() {
+            let stop: StopOrder = Decodable::decode(&stop_rlp)?;
+            stop_book.stops.insert(stop.id, stop);
+        }
+        for link_rlp in rlp.at(1)?.iter() {
+            stop_book
+                .oco
+                .insert(link_rlp.val_at(0)?, link_rlp.val_at(1)?);
+        }
+        Ok(stop_book)
+    }
+}
+
+impl StopBook {
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Self, TradezError> {
+        match host.store_read_all(&STOP_BOOK_PATH) {
+            Ok(data) => StopBook::decode(&rlp::Rlp::new(&data))
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(StopBook::default()),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&STOP_BOOK_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+
+    pub fn insert(&mut self, stop: StopOrder) {
+        self.stops.insert(stop.id, stop);
+    }
+
+    /// Links two orders so that the first one to trade cancels the other.
+    pub fn link(&mut self, first: u64, second: u64) {
+        self.oco.insert(first, second);
+        self.oco.insert(second, first);
+    }
+
+    /// Removes the OCO link of `id` and returns its sibling.
+    pub fn unlink(&mut self, id: u64) -> Option<u64> {
+        let sibling = self.oco.remove(&id)?;
+        self.oco.remove(&sibling);
+        Some(sibling)
+    }
+
+    /// Walks the stops through the traded prices, in order. Returns the triggered stops,
+    /// removed from the book, in the order they triggered.
+    pub fn trigger(&mut self, prices: &[Price]) -> Vec<StopOrder> {
+        let mut triggered = vec![];
+        for price in prices {
+            let ids: Vec<u64> = self
+                .stops
+                .values()
+                .filter(|stop| stop.is_triggered(*price))
+                .map(|stop| stop.id)
+                .collect();
+            for id in ids {
+                triggered.extend(self.stops.remove(&id));
+            }
+            for stop in self.stops.values_mut() {
+                stop.follow(*price);
+            }
+        }
+        triggered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(id: u64, side: Side, trigger: u64, trail: u64) -> StopOrder {
+        StopOrder {
+            id,
+            user: Address::from([id as u8; 20]),
+            side,
+            size: Qty(1_000_000),
+            trigger_price: Price(trigger),
+            trail: Price(trail),
+            slippage: Price(10_000),
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn fixed_stops_trigger() {
+        let mut stop_book = StopBook::default();
+        stop_book.insert(stop(1, Side::Ask, 1_900_000, 0));
+        stop_book.insert(stop(2, Side::Bid, 2_100_000, 0));
+
+        assert!(stop_book.trigger(&[Price(2_000_000)]).is_empty());
+        let triggered = stop_book.trigger(&[Price(2_050_000), Price(1_900_000)]);
+        assert_eq!(triggered.iter().map(|s| s.id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(triggered[0].limit_price(), Price(1_890_000));
+        let triggered = stop_book.trigger(&[Price(2_200_000)]);
+        assert_eq!(triggered[0].limit_price(), Price(2_110_000));
+        assert!(stop_book.stops.is_empty());
+    }
+
+    #[test]
+    fn trailing_stop_follows_the_market() {
+        let mut stop_book = StopBook::default();
+        stop_book.insert(stop(1, Side::Ask, 1_800_000, 200_000));
+
+        assert!(
+            stop_book
+                .trigger(&[Price(2_100_000), Price(2_300_000)])
+                .is_empty()
+        );
+        assert_eq!(stop_book.stops[&1].trigger_price, Price(2_100_000));
+        // Never moves back when the price falls
+        assert!(stop_book.trigger(&[Price(2_150_000)]).is_empty());
+        assert_eq!(stop_book.stops[&1].trigger_price, Price(2_100_000));
+        assert_eq!(stop_book.trigger(&[Price(2_100_000)]).len(), 1);
+
+        let mut buy = stop(2, Side::Bid, 2_500_000, 200_000);
+        buy.follow(Price(1_900_000));
+        assert_eq!(buy.trigger_price, Price(2_100_000));
+        buy.follow(Price(2_000_000));
+        assert_eq!(buy.trigger_price, Price(2_100_000));
+    }
+
+    #[test]
+    fn oco_links() {
+        let mut stop_book = StopBook::default();
+        stop_book.link(3, 4);
+        assert_eq!(stop_book.unlink(4), Some(3));
+        assert_eq!(stop_book.unlink(3), None);
+        assert!(stop_book.oco.is_empty());
+    }
+
+    #[test]
+    fn stop_book_rlp() {
+        let mut stop_book = StopBook::default();
+        stop_book.insert(stop(1, Side::Ask, 1_900_000, 0));
+        stop_book.insert(stop(2, Side::Bid, 2_100_000, 50_000));
+        stop_book.link(1, 7);
+        let decoded: StopBook = rlp::decode(&stop_book.rlp_bytes()).unwrap();
+        assert_eq!(stop_book, decoded);
+    }
+}
@@ -0,0 +1,194 @@
+use rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use tezos_smart_rollup::host::{Runtime, RuntimeError};
+use tezos_smart_rollup_host::path::RefPath;
+
+use crate::{currencies::Currencies, error::TradezError};
+
+pub const SUPPLY_LEDGER_STR_PATH: &str = "/tradez/supply";
+pub const SUPPLY_LEDGER_PATH: RefPath = RefPath::assert_from(b"/tradez/supply");
+
+/// Funds of one currency that entered and left the rollup.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, RlpEncodable, RlpDecodable,
+)]
+pub struct Supply {
+    /// Minted by the faucet.
+    pub minted: u64,
+    /// Trading fees taken from the accounts.
+    pub fees: u64,
+    /// Lent to margin accounts.
+    pub borrowed: u64,
+    /// Interest charged on the debts of margin accounts.
+    pub interest: u64,
+    /// Paid back by margin accounts, interest included.
+    pub repaid: u64,
+    /// Paid to accounts by the perpetual: realized profits and funding received.
+    pub perp_credited: u64,
+    /// Taken from accounts by the perpetual: realized losses and funding paid.
+    pub perp_debited: u64,
+    /// Debts of liquidated accounts neither they nor the insurance fund could pay back.
+    pub written_off: u64,
+}
+
+impl Supply {
+    /// What the accounts should hold, available and locked together.
+    pub fn circulating(&self) -> Option<u64> {
+        self.minted
+            .checked_add(self.borrowed)?
+            .checked_add(self.perp_credited)?
+            .checked_sub(self.fees)?
+            .checked_sub(self.repaid)?
+            .checked_sub(self.perp_debited)
+    }
+
+    /// What the accounts should owe, interest included.
+    pub fn outstanding_debt(&self) -> Option<u64> {
+        self.borrowed
+            .checked_add(self.interest)?
+            .checked_sub(self.repaid)?
+            .checked_sub(self.written_off)
+    }
+}
+
+/// Global supply of every currency, updated by every path that creates or destroys funds.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, RlpEncodable, RlpDecodable,
+)]
+pub struct SupplyLedger {
+    pub usdc: Supply,
+    pub xtz: Supply,
+}
+
+impl SupplyLedger {
+    pub fn load<Host: Runtime>(host: &mut Host) -> Result<Self, TradezError> {
+        match host.store_read_all(&SUPPLY_LEDGER_PATH) {
+            Ok(data) => SupplyLedger::decode(&rlp::Rlp::new(&data))
+                .map_err(|e| TradezError::DataStoreError(e.to_string())),
+            Err(RuntimeError::PathNotFound) => Ok(SupplyLedger::default()),
+            Err(e) => Err(TradezError::DataStoreError(e.to_string())),
+        }
+    }
+
+    pub fn save<Host: Runtime>(&self, host: &mut Host) -> Result<(), TradezError> {
+        host.store_write_all(&SUPPLY_LEDGER_PATH, &self.rlp_bytes())
+            .map_err(|e| TradezError::DataStoreError(e.to_string()))
+    }
+
+    pub fn get(&self, currency: Currencies) -> &Supply {
+        match currency {
+            Currencies::USDC => &self.usdc,
+            Currencies::XTZ => &self.xtz,
+        }
+    }
+
+    fn get_mut(&mut self, currency: Currencies) -> &mut Supply {
+        match currency {
+            Currencies::USDC => &mut self.usdc,
+            Currencies::XTZ => &mut self.xtz,
+        }
+    }
+
+    pub fn mint(&mut self, currency: Currencies, amount: u64) {
+        let supply = self.get_mut(currency);
+        supply.minted = supply.minted.checked_add(amount).unwrap();
+    }
+
+    pub fn collect_fee(&mut self, currency: Currencies, amount: u64) {
+        let supply = self.get_mut(currency);
+        supply.fees = supply.fees.checked_add(amount).unwrap();
+    }
+
+    pub fn borrow(&mut self, currency: Currencies, amount: u64) {
+        let supply = self.get_mut(currency);
+        supply.borrowed = supply.borrowed.checked_add(amount).unwrap();
+    }
+
+    pub fn charge_interest(&mut self, currency: Currencies, amount: u64) {
+        let supply = self.get_mut(currency);
+        supply.interest = supply.interest.checked_add(amount).unwrap();
+    }
+
+    pub fn repay(&mut self, currency: Currencies, amount: u64) {
+        let supply = self.get_mut(currency);
+        supply.repaid = supply.repaid.checked_add(amount).unwrap();
+    }
+
+    pub fn write_off(&mut self, currency: Currencies, amount: u64) {
+        let supply = self.get_mut(currency);
+        supply.written_off = supply.written_off.checked_add(amount).unwrap();
+    }
+
+    /// Records a perpetual settlement of `amount` USDC, paid to the account when positive.
+    pub fn settle_perp(&mut self, amount: i64) {
+        let supply = &mut self.usdc;
+        if amount >= 0 {
+            supply.perp_credited = supply.perp_credited.checked_add(amount as u64).unwrap();
+        } else {
+            supply.perp_debited = supply
+                .perp_debited
+                .checked_add(amount.unsigned_abs())
+                .unwrap();
+        }
+    }
+}
+
+/// Totals of one currency found by an audit.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CurrencyAudit {
+    pub currency: Currencies,
+    pub supply: Supply,
+    /// Sum of the available balances of every account.
+    pub available: u64,
+    /// Sum of the locked balances of every account.
+    pub locked: u64,
+    /// What the orders resting in the book reserve.
+    pub reserved_by_orders: u64,
+    /// Sum of the debts of every account.
+    pub debt: u64,
+}
+
+/// Result of checking the accounts and the order book against the supply ledger.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditReport {
+    pub accounts: u64,
+    pub currencies: Vec<CurrencyAudit>,
+    pub discrepancies: Vec<String>,
+}
+
+impl AuditReport {
+    pub fn is_sound(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supply_ledger() {
+        let mut ledger = SupplyLedger::default();
+        ledger.mint(Currencies::USDC, 1_000);
+        ledger.collect_fee(Currencies::USDC, 1);
+        ledger.mint(Currencies::XTZ, 50);
+        assert_eq!(ledger.get(Currencies::USDC).circulating(), Some(999));
+        assert_eq!(ledger.get(Currencies::XTZ).circulating(), Some(50));
+
+        ledger.borrow(Currencies::XTZ, 20);
+        ledger.charge_interest(Currencies::XTZ, 2);
+        ledger.repay(Currencies::XTZ, 12);
+        assert_eq!(ledger.get(Currencies::XTZ).circulating(), Some(58));
+        assert_eq!(ledger.get(Currencies::XTZ).outstanding_debt(), Some(10));
+        ledger.write_off(Currencies::XTZ, 4);
+        assert_eq!(ledger.get(Currencies::XTZ).outstanding_debt(), Some(6));
+        assert_eq!(ledger.get(Currencies::XTZ).circulating(), Some(58));
+
+        ledger.settle_perp(300);
+        ledger.settle_perp(-250);
+        assert_eq!(ledger.get(Currencies::USDC).circulating(), Some(1049));
+
+        let decoded: SupplyLedger = rlp::decode(&ledger.rlp_bytes()).unwrap();
+        assert_eq!(ledger, decoded);
+    }
+}
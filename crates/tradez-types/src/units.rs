@@ -0,0 +1,208 @@
+use std::{fmt::Display, iter::Sum};
+
+use rlp::{Decodable, Encodable};
+use serde::{Deserialize, Serialize};
+
+/// Number of decimals carried by both `Price` (microUSDC) and `Qty` (microXTZ).
+pub const DECIMALS: u64 = 1_000_000;
+
+/// Rounding mode applied when a fixed-point operation can't be represented exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// Towards zero, the user receives at most the exact value.
+    Down,
+    /// Away from zero, the user pays at least the exact value.
+    Up,
+}
+
+fn div_rounded(numerator: u128, denominator: u128, rounding: Rounding) -> Option<u128> {
+    if denominator == 0 {
+        return None;
+    }
+    let quotient = numerator / denominator;
+    match rounding {
+        Rounding::Down => Some(quotient),
+        Rounding::Up if !numerator.is_multiple_of(denominator) => quotient.checked_add(1),
+        Rounding::Up => Some(quotient),
+    }
+}
+
+/// Price of one XTZ in microUSDC (1e6).
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Price(pub u64);
+
+/// Quantity of XTZ in microXTZ (1e6).
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Qty(pub u64);
+
+impl Price {
+    pub const ZERO: Price = Price(0);
+
+    pub const fn raw(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Price) -> Option<Price> {
+        self.0.checked_add(other.0).map(Price)
+    }
+
+    pub fn checked_sub(self, other: Price) -> Option<Price> {
+        self.0.checked_sub(other.0).map(Price)
+    }
+
+    pub fn saturating_sub(self, other: Price) -> Price {
+        Price(self.0.saturating_sub(other.0))
+    }
+
+    /// Notional value in microUSDC of `qty` at this price.
+    ///
+    /// The product is computed on u128 so only the final value has to fit in a u64.
+    pub fn checked_notional(self, qty: Qty, rounding: Rounding) -> Option<u64> {
+        let product = (self.0 as u128).checked_mul(qty.0 as u128)?;
+        let value = div_rounded(product, DECIMALS as u128, rounding)?;
+        u64::try_from(value).ok()
+    }
+}
+
+impl Qty {
+    pub const ZERO: Qty = Qty(0);
+
+    pub const fn raw(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Qty) -> Option<Qty> {
+        self.0.checked_add(other.0).map(Qty)
+    }
+
+    pub fn checked_sub(self, other: Qty) -> Option<Qty> {
+        self.0.checked_sub(other.0).map(Qty)
+    }
+
+    pub fn saturating_add(self, other: Qty) -> Qty {
+        Qty(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Qty) -> Qty {
+        Qty(self.0.saturating_sub(other.0))
+    }
+
+    /// Quantity that `notional` microUSDC buys at `price`.
+    pub fn checked_from_notional(notional: u64, price: Price, rounding: Rounding) -> Option<Qty> {
+        let scaled = (notional as u128).checked_mul(DECIMALS as u128)?;
+        let qty = div_rounded(scaled, price.0 as u128, rounding)?;
+        u64::try_from(qty).ok().map(Qty)
+    }
+}
+
+impl Sum for Qty {
+    fn sum<I: Iterator<Item = Qty>>(iter: I) -> Qty {
+        iter.fold(Qty::ZERO, |acc, qty| acc.saturating_add(qty))
+    }
+}
+
+impl Display for Price {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Display for Qty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Encodable for Price {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.append_internal(&self.0);
+    }
+}
+
+impl Decodable for Price {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        Ok(Price(rlp.as_val()?))
+    }
+}
+
+impl Encodable for Qty {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.append_internal(&self.0);
+    }
+}
+
+impl Decodable for Qty {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        Ok(Qty(rlp.as_val()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notional_rounding() {
+        // 0.333333 XTZ at 1.000001 USDC
+        let price = Price(1_000_001);
+        let qty = Qty(333_333);
+        assert_eq!(price.checked_notional(qty, Rounding::Down), Some(333_333));
+        assert_eq!(price.checked_notional(qty, Rounding::Up), Some(333_334));
+        // Exact values are not rounded up
+        assert_eq!(
+            Price(2_000_000).checked_notional(Qty(1_500_000), Rounding::Up),
+            Some(3_000_000)
+        );
+    }
+
+    #[test]
+    fn notional_overflow() {
+        assert_eq!(
+            Price(u64::MAX).checked_notional(Qty(u64::MAX), Rounding::Down),
+            None
+        );
+        // The intermediate product doesn't fit in a u64 but the notional does
+        assert_eq!(
+            Price(u64::MAX / 2).checked_notional(Qty(DECIMALS), Rounding::Down),
+            Some(u64::MAX / 2)
+        );
+    }
+
+    #[test]
+    fn qty_from_notional() {
+        let price = Price(3_000_000);
+        assert_eq!(
+            Qty::checked_from_notional(1_000_000, price, Rounding::Down),
+            Some(Qty(333_333))
+        );
+        assert_eq!(
+            Qty::checked_from_notional(1_000_000, price, Rounding::Up),
+            Some(Qty(333_334))
+        );
+        assert_eq!(
+            Qty::checked_from_notional(1_000_000, Price::ZERO, Rounding::Down),
+            None
+        );
+    }
+
+    #[test]
+    fn rlp_is_transparent() {
+        assert_eq!(Price(42).rlp_bytes(), 42u64.rlp_bytes());
+        let qty: Qty = rlp::decode(&7u64.rlp_bytes()).unwrap();
+        assert_eq!(qty, Qty(7));
+    }
+}
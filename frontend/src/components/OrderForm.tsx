@@ -14,7 +14,8 @@ const DECIMALS = 6;
 
 const OrderForm = () => {
   const { account, signMessage } = useWallet();
-  const { sendOrder, getBalances, subscribeEvent, isApiConfigured } = useTradezApi();
+  const { sendOrder, getBalances, getMarketConfig, subscribeEvent, isApiConfigured } =
+    useTradezApi();
   const { toast } = useToast();
   const [orderType, setOrderType] = useState<"limit" | "market">("limit");
   const [price, setPrice] = useState("1.2353");
@@ -46,8 +47,8 @@ const OrderForm = () => {
         const result = await getBalances(account);
         let nextXtz: string | null = "0";
         let nextUsdc: string | null = "0";
-        for (const [currency, value] of result) {
-          const units = BigInt(Math.trunc(value));
+        for (const [currency, balance] of result) {
+          const units = BigInt(Math.trunc(balance.available));
           const formatted = ethers.formatUnits(units, DECIMALS);
           if (currency === "XTZ") {
             nextXtz = formatted;
@@ -89,6 +90,10 @@ const OrderForm = () => {
         const user = normalizeAddressLike(event.Cancelled.user);
         return user === normalized;
       }
+      if ("Rejected" in event) {
+        const user = normalizeAddressLike(event.Rejected.user);
+        return user === normalized;
+      }
       return false;
     },
     []
@@ -175,8 +180,22 @@ const OrderForm = () => {
     try {
       setSubmitting(true);
 
-      const sizeUnits = ethers.parseUnits(amount, DECIMALS);
-      const priceUnits = orderType === "limit" ? ethers.parseUnits(price, DECIMALS) : 0n;
+      // Round to the market rules, otherwise the kernel rejects the order
+      const marketConfig = await getMarketConfig();
+      const lotSize = BigInt(marketConfig.lot_size);
+      const tickSize = BigInt(marketConfig.tick_size);
+      const rawSize = ethers.parseUnits(amount, DECIMALS);
+      const sizeUnits = lotSize > 0n ? rawSize - (rawSize % lotSize) : rawSize;
+      const rawPrice = orderType === "limit" ? ethers.parseUnits(price, DECIMALS) : 0n;
+      let priceUnits = tickSize > 0n ? rawPrice - (rawPrice % tickSize) : rawPrice;
+      if (side === "sell" && priceUnits !== rawPrice) {
+        priceUnits += tickSize;
+      }
+      if (sizeUnits < BigInt(marketConfig.min_qty)) {
+        throw new Error(
+          `Minimum amount is ${ethers.formatUnits(BigInt(marketConfig.min_qty), DECIMALS)} XTZ`
+        );
+      }
       const nonce = BigInt(Date.now());
       const apiOrder = {
         side: side === "buy" ? ("Bid" as const) : ("Ask" as const),
@@ -12,6 +12,20 @@ export type RpcOrder = {
   nonce: number;
 };
 
+export type RpcStopOrder = {
+  side: "Bid" | "Ask";
+  size: RpcQty;
+  trigger_price: RpcPrice;
+  trail: RpcPrice;
+  slippage: RpcPrice;
+  nonce: number;
+};
+
+export type RpcOcoOrder = {
+  limit: RpcOrder;
+  stop: RpcStopOrder;
+};
+
 export type RpcCancelOrder = {
   order_id: number;
 };
@@ -21,18 +35,149 @@ export type RpcFaucet = {
   currency: RpcCurrency;
 };
 
-export type RpcBalancesResult = Array<[RpcCurrency, RpcQty]>;
+export type RpcBalance = {
+  available: RpcQty;
+  locked: RpcQty;
+  debt: RpcQty;
+};
+export type RpcMarginState = {
+  mark_price: RpcPrice;
+  assets: number;
+  debt: number;
+  unrealized_pnl: number;
+  position_notional: number;
+};
+export type RpcPerpPosition = {
+  size: number;
+  entry_price: RpcPrice;
+};
+export type RpcPerpStatus = {
+  index_price: RpcPrice;
+  funding_level: number;
+  funding_rate_ppm: number;
+};
+export type RpcTransfer = {
+  to: string;
+  currency: RpcCurrency;
+  amount: number;
+  nonce: number;
+};
+export type RpcDelegateScope = "Trade" | "Cancel";
+export type RpcDelegateGrant = {
+  key: string;
+  scope: RpcDelegateScope;
+  expiry_level: number;
+  max_notional: number;
+};
+export type RpcRevokeDelegate = {
+  key: string;
+};
+export type RpcFillRole = "Maker" | "Taker";
+export type RpcFill = {
+  trade_id: number;
+  order_id: number;
+  role: RpcFillRole;
+  side: "Bid" | "Ask";
+  price: RpcPrice;
+  qty: RpcQty;
+  fee: number;
+  fee_currency: RpcCurrency;
+  level: number;
+};
+export type RpcFillPage = {
+  fills: RpcFill[];
+  next_cursor: number;
+};
+export type RpcOracleUpdate = {
+  price: RpcPrice;
+  level: number;
+};
+export type RpcBalancesResult = Array<[RpcCurrency, RpcBalance]>;
 export type RpcOrderbookLevels = Array<[RpcPrice, RpcQty]>;
 export type RpcOrderbookState = [RpcOrderbookLevels, RpcOrderbookLevels];
 export type RpcUserOrder = {
   side: "Bid" | "Ask";
-  ord_type: "Limit" | "Market";
+  ord_type: "Limit" | "Market" | "Stop";
   price: RpcPrice;
   qty: RpcQty;
   remaining: RpcQty;
   nonce: number;
+  display: RpcQty;
 };
 export type RpcOrdersResult = Array<[number, RpcUserOrder]>;
+export type RpcMarketConfig = {
+  tick_size: RpcPrice;
+  lot_size: RpcQty;
+  min_qty: RpcQty;
+  min_notional: number;
+  price_band_bps: number;
+  circuit_breaker_bps: number;
+  circuit_breaker_window: number;
+  halt_duration: number;
+  opening_auction_duration: number;
+  auction_duration: number;
+  batch_auction_interval: number;
+  initial_margin_bps: number;
+  maintenance_margin_bps: number;
+  borrow_rate_ppm: number;
+  liquidation_fee_bps: number;
+  kind: "Spot" | "Perpetual";
+  funding_interval: number;
+  max_funding_rate_ppm: number;
+};
+export type RpcMarketPhase =
+  | "Continuous"
+  | { Halted: { until_level: number } }
+  | { Auction: { until_level: number } };
+export type RpcMarketStatus = {
+  phase: RpcMarketPhase;
+  last_trade_price: RpcPrice | null;
+  window_start_level: number;
+  window_start_price: RpcPrice | null;
+};
+export type RpcSupply = {
+  minted: number;
+  fees: number;
+  borrowed: number;
+  interest: number;
+  repaid: number;
+  perp_credited: number;
+  perp_debited: number;
+  written_off: number;
+};
+export type RpcAuditReport = {
+  accounts: number;
+  currencies: Array<{
+    currency: RpcCurrency;
+    supply: RpcSupply;
+    available: number;
+    locked: number;
+    reserved_by_orders: number;
+    debt: number;
+  }>;
+  discrepancies: string[];
+};
+export type RpcSequencerStatus = {
+  input_count: number;
+  pending_inputs: number;
+  injected_inputs: number;
+  stuck_inputs: number;
+  last_reconciled_level: number | null;
+  reconciliations: number;
+  divergences: number;
+  diverged_level: number | null;
+  skipped_reconciliations: number;
+};
+export type RpcAccountProof = {
+  level: number;
+  root: string;
+  account: number[];
+  proof: {
+    index: number;
+    leaf_count: number;
+    siblings: string[];
+  };
+};
 export type RpcEvent =
   | {
       Placed: {
@@ -66,6 +211,95 @@ export type RpcEvent =
         id: number;
         reason: string;
       };
+    }
+  | {
+      Rejected: {
+        user: unknown;
+        nonce: number;
+        reason: string;
+      };
+    }
+  | {
+      MarketHalted: {
+        reference_price: RpcPrice;
+        last_price: RpcPrice;
+        until_level: number;
+      };
+    }
+  | {
+      MarketResumed: {
+        level: number;
+      };
+    }
+  | {
+      AuctionStarted: {
+        until_level: number;
+      };
+    }
+  | {
+      AuctionUncrossed: {
+        price: RpcPrice;
+        qty: RpcQty;
+      };
+    }
+  | {
+      StopTriggered: {
+        id: number;
+        user: unknown;
+        price: RpcPrice;
+      };
+    }
+  | {
+      StateCommitment: {
+        level: number;
+        root: string;
+      };
+    }
+  | {
+      Funding: {
+        level: number;
+        rate_ppm: number;
+        mark_price: RpcPrice;
+        index_price: RpcPrice;
+      };
+    }
+  | {
+      Liquidated: {
+        user: unknown;
+        mark_price: RpcPrice;
+        fee: number;
+        bad_debt: number;
+      };
+    }
+  | {
+      IndexPrice: {
+        level: number;
+        price: RpcPrice;
+        sources: number;
+      };
+    }
+  | {
+      DelegateUpdated: {
+        owner: unknown;
+        key: unknown;
+        expiry_level: number;
+        revoked: boolean;
+      };
+    }
+  | {
+      Transfer: {
+        from: unknown;
+        to: unknown;
+        currency: RpcCurrency;
+        amount: number;
+      };
+    }
+  | {
+      TransferRejected: {
+        from: unknown;
+        nonce: number;
+        reason: string;
+      };
     };
 
 const trimTrailingSlash = (value?: string) => value?.replace(/\/+$/, "");
@@ -291,6 +525,32 @@ type JsonRpcError = {
   };
 };
 
+/** Error codes of the inputs the sequencer refuses, stable across releases. */
+export const INPUT_ERROR = {
+  INVALID_INPUT: -32001,
+  INVALID_SIGNATURE: -32002,
+  INVALID_NONCE: -32003,
+  INSUFFICIENT_BALANCE: -32004,
+  REJECTED: -32005,
+  ABORTED: -32006,
+  NOT_READY: -32007,
+  ORDER_NOT_FOUND: -32008,
+  DELEGATE_REFUSED: -32009,
+  ORACLE_REFUSED: -32010,
+} as const;
+
+export class RpcError extends Error {
+  code: number;
+  data?: unknown;
+
+  constructor(error: JsonRpcError["error"]) {
+    super(error.message);
+    this.name = "RpcError";
+    this.code = error.code;
+    this.data = error.data;
+  }
+}
+
 export const useTradezApi = () => {
   const callRpc = useCallback(
     async <T>(method: string, params: unknown[]) => {
@@ -332,7 +592,7 @@ export const useTradezApi = () => {
       }
 
       if ("error" in payload) {
-        throw new Error(payload.error.message);
+        throw new RpcError(payload.error);
       }
 
       return (payload as JsonRpcSuccess<T>).result;
@@ -347,6 +607,48 @@ export const useTradezApi = () => {
     [callRpc]
   );
 
+  const sendStopOrder = useCallback(
+    async (order: RpcStopOrder, signature: RpcSignatureInput) => {
+      return callRpc<string>("send_stop_order", [order, toByteArray(signature)]);
+    },
+    [callRpc]
+  );
+
+  const sendOcoOrder = useCallback(
+    async (order: RpcOcoOrder, signature: RpcSignatureInput) => {
+      return callRpc<string>("send_oco_order", [order, toByteArray(signature)]);
+    },
+    [callRpc]
+  );
+
+  const sendOracleUpdate = useCallback(
+    async (update: RpcOracleUpdate, signature: RpcSignatureInput) => {
+      return callRpc<string>("send_oracle_update", [update, toByteArray(signature)]);
+    },
+    [callRpc]
+  );
+
+  const transfer = useCallback(
+    async (params: RpcTransfer, signature: RpcSignatureInput) => {
+      return callRpc<string>("transfer", [params, toByteArray(signature)]);
+    },
+    [callRpc]
+  );
+
+  const addDelegate = useCallback(
+    async (grant: RpcDelegateGrant, signature: RpcSignatureInput) => {
+      return callRpc<string>("add_delegate", [grant, toByteArray(signature)]);
+    },
+    [callRpc]
+  );
+
+  const revokeDelegate = useCallback(
+    async (revoke: RpcRevokeDelegate, signature: RpcSignatureInput) => {
+      return callRpc<string>("revoke_delegate", [revoke, toByteArray(signature)]);
+    },
+    [callRpc]
+  );
+
   const cancelOrder = useCallback(
     async (params: RpcCancelOrder, signature: RpcSignatureInput) => {
       return callRpc<string>("cancel_order", [params, toByteArray(signature)]);
@@ -368,6 +670,34 @@ export const useTradezApi = () => {
     [callRpc]
   );
 
+  const getMargin = useCallback(
+    async (address: string) => {
+      return callRpc<RpcMarginState>("get_margin", [address]);
+    },
+    [callRpc]
+  );
+
+  const getPosition = useCallback(
+    async (address: string) => {
+      return callRpc<RpcPerpPosition>("get_position", [address]);
+    },
+    [callRpc]
+  );
+
+  const getDelegates = useCallback(
+    async (address: string) => {
+      return callRpc<RpcDelegateGrant[]>("get_delegates", [address]);
+    },
+    [callRpc]
+  );
+
+  const getFills = useCallback(
+    async (address: string, cursor?: number) => {
+      return callRpc<RpcFillPage>("get_fills", [address, cursor ?? null]);
+    },
+    [callRpc]
+  );
+
   const getOrders = useCallback(
     async (address: string) => {
       return callRpc<RpcOrdersResult>("get_orders", [address]);
@@ -379,6 +709,37 @@ export const useTradezApi = () => {
     return callRpc<RpcOrderbookState>("get_orderbook_state", []);
   }, [callRpc]);
 
+  const getMarketConfig = useCallback(async () => {
+    return callRpc<RpcMarketConfig>("get_market_config", []);
+  }, [callRpc]);
+
+  const getMarketStatus = useCallback(async () => {
+    return callRpc<RpcMarketStatus>("get_market_status", []);
+  }, [callRpc]);
+
+  const getPerpStatus = useCallback(async () => {
+    return callRpc<RpcPerpStatus>("get_perp_status", []);
+  }, [callRpc]);
+
+  const getIndexPrice = useCallback(async () => {
+    return callRpc<RpcPrice | null>("get_index_price", []);
+  }, [callRpc]);
+
+  const getAudit = useCallback(async () => {
+    return callRpc<RpcAuditReport>("get_audit", []);
+  }, [callRpc]);
+
+  const getStatus = useCallback(async () => {
+    return callRpc<RpcSequencerStatus>("get_status", []);
+  }, [callRpc]);
+
+  const getAccountProof = useCallback(
+    async (address: string, level?: number) => {
+      return callRpc<RpcAccountProof>("get_account_proof", [address, level ?? null]);
+    },
+    [callRpc]
+  );
+
   const subscribeJsonRpc = useCallback((method: string, onMessage: (payload: any) => void) => {
     return subscriptionManager.subscribe(method, onMessage);
   }, []);
@@ -406,16 +767,48 @@ export const useTradezApi = () => {
     [subscribeJsonRpc]
   );
 
+  // A zero index price means the oracle quotes went stale
+  const subscribeIndexPrice = useCallback(
+    (onMessage: (price: RpcPrice) => void) =>
+      subscribeJsonRpc("subscribeIndexPrice", (payload) => {
+        if (
+          payload?.method === "subscribeIndexPrice" &&
+          payload?.params?.result !== undefined
+        ) {
+          onMessage(payload.params.result as RpcPrice);
+        }
+      }),
+    [subscribeJsonRpc]
+  );
+
   return {
     apiUrl: API_BASE_URL,
     isApiConfigured: Boolean(API_BASE_URL),
     sendOrder,
+    sendStopOrder,
+    sendOcoOrder,
+    sendOracleUpdate,
+    addDelegate,
+    revokeDelegate,
     cancelOrder,
     faucet,
+    transfer,
     getBalances,
+    getMargin,
+    getPosition,
+    getDelegates,
+    getFills,
     getOrders,
     getOrderbookState,
+    getMarketConfig,
+    getMarketStatus,
+    getPerpStatus,
+    getIndexPrice,
+    getAudit,
+    getStatus,
+    getAccountProof,
     subscribeOrderbookState,
     subscribeEvent,
+    subscribeIndexPrice,
   };
 };